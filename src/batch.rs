@@ -0,0 +1,138 @@
+//! One program against many inputs, for search loops that score a candidate
+//! on a whole test set.
+//!
+//! Workers share one copy of the program and pull inputs off a shared counter.
+//! Each owns one tape for all its runs and zeroes only the cells up to the
+//! VM's high-water mark in between, so the cost of a run is the run itself
+//! rather than a fresh 4 MiB allocation.
+
+use std::{
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use crate::{
+    program::Program,
+    tape::Tape,
+    vm::{VmError, VmOptions, MEMORY_SIZE, VM},
+};
+
+impl Program {
+    /// Run the program once per input on a zeroed tape, returning each run's
+    /// output in input order.
+    ///
+    /// `limits` applies to every run on its own; set `fuel` and `max_output`
+    /// when candidates may loop forever. A run that fails only fails its own
+    /// entry. `parallelism` is the number of worker threads, 0 for one per
+    /// core.
+    pub fn evaluate_batch(
+        &self,
+        inputs: &[&[u8]],
+        limits: &VmOptions,
+        parallelism: usize,
+    ) -> Vec<Result<Vec<u8>, VmError>> {
+        let workers = match parallelism {
+            0 => thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            n => n,
+        };
+        let program = Arc::new(self.clone());
+        let next = AtomicUsize::new(0);
+        let mut results: Vec<Option<Result<Vec<u8>, VmError>>> =
+            inputs.iter().map(|_| None).collect();
+        thread::scope(|scope| {
+            let handles: Vec<_> = (0..workers.min(inputs.len()))
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut tape = vec![0_u8; MEMORY_SIZE];
+                        let mut done = vec![];
+                        loop {
+                            let i = next.fetch_add(1, Ordering::Relaxed);
+                            let Some(input) = inputs.get(i) else {
+                                return done;
+                            };
+                            done.push((i, evaluate_one(&program, input, limits, &mut tape)));
+                        }
+                    })
+                })
+                .collect();
+            for handle in handles {
+                for (i, result) in handle.join().expect("batch worker panicked") {
+                    results[i] = Some(result);
+                }
+            }
+        });
+        results.into_iter().map(Option::unwrap).collect()
+    }
+}
+
+// one run on `tape`, which is zero again afterwards
+fn evaluate_one(
+    program: &Arc<Program>,
+    input: &[u8],
+    limits: &VmOptions,
+    tape: &mut [u8],
+) -> Result<Vec<u8>, VmError> {
+    let mut output = vec![];
+    let mut vm = VM::build(Arc::clone(program), Tape::Borrowed(&mut *tape))?
+        .with_options(limits.clone())
+        .with_io(input, &mut output);
+    let result = vm.run();
+    let dirty = vm.high_water();
+    drop(vm);
+    tape[..=dirty].fill(0);
+    result.map(|()| output)
+}
+
+#[test]
+fn test_evaluate_batch() {
+    let program = Program::compile(",[+.[-],]>>+++[<++>-]<.").unwrap();
+    let inputs: Vec<&[u8]> = vec![b"", b"abc", b"\x7f", b"hello, world", b"x"];
+    let serial: Vec<Vec<u8>> = inputs
+        .iter()
+        .map(|&input| {
+            let mut output = vec![];
+            let mut tape = vec![0_u8; MEMORY_SIZE];
+            let mut vm = VM::with_tape(program.clone(), &mut tape)
+                .unwrap()
+                .with_io(input, &mut output);
+            vm.run().unwrap();
+            drop(vm);
+            output
+        })
+        .collect();
+    for parallelism in [0, 1, 2, 8] {
+        let batch = program.evaluate_batch(&inputs, &VmOptions::default(), parallelism);
+        let batch: Vec<Vec<u8>> = batch.into_iter().map(Result::unwrap).collect();
+        assert_eq!(batch, serial, "{} workers", parallelism);
+    }
+
+    // candidates that never stop are cut off without taking the others along
+    let limits = VmOptions {
+        fuel: Some(10_000),
+        max_output: Some(64),
+        ..Default::default()
+    };
+    let spin = Program::compile(",[]").unwrap();
+    let results = spin.evaluate_batch(&[b"\x01", b"", b"\x02"], &limits, 2);
+    assert!(matches!(results[0], Err(VmError::OutOfFuel(10_000))));
+    assert_eq!(results[1].as_ref().unwrap(), b"");
+    assert!(matches!(results[2], Err(VmError::OutOfFuel(10_000))));
+    let flood = Program::compile(",[.]").unwrap();
+    let results = flood.evaluate_batch(&[b"", b"!"], &limits, 2);
+    assert_eq!(results[0].as_ref().unwrap(), b"");
+    assert!(matches!(results[1], Err(VmError::OutputLimit(64))));
+
+    // a worker's tape is clean again for the next input
+    let dirty = Program::compile(",>>+>,<<<.>>.").unwrap();
+    let inputs: Vec<&[u8]> = vec![b"ab"; 6];
+    for output in dirty.evaluate_batch(&inputs, &VmOptions::default(), 1) {
+        assert_eq!(output.unwrap(), b"a\x01");
+    }
+    assert!(program
+        .evaluate_batch(&[], &VmOptions::default(), 4)
+        .is_empty());
+}
@@ -0,0 +1,330 @@
+//! `bfjit bench`: time one program under several engines.
+//!
+//! Every engine first does an untimed warmup run, then `runs` timed ones,
+//! each on a fresh tape and a fresh copy of the input. Timings are only
+//! reported once every run of every engine produced the same output and final
+//! tape as the first engine's warmup; anything else is a `Mismatch`, since
+//! comparing the speed of engines that disagree means nothing.
+//!
+//! Instructions per second measure work in the interpreter's steps, which the
+//! other engines do not count; without the interpreter in the set that
+//! column is empty.
+//!
+//! `front_end` times compiling instead: the sequential lexer and folder
+//! against the threaded ones, which must produce the same program.
+
+use std::{
+    fmt::Write,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    engine::{Engine, ExecContext},
+    error::ErrorCategory,
+    json::Json,
+    program::{OptLevel, Program},
+    tokenizer::{Dialect, StartTape, TokenizerError},
+    vm::{CellOverflow, VmError, VmOptions, MEMORY_SIZE},
+};
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum BenchError {
+    #[error("{} Engine {0} Failed", .1.code())]
+    Run(String, #[source] VmError),
+
+    #[error("E0601 Engine {engine} Disagrees With {reference}, Refusing To Report Timings")]
+    Mismatch { engine: String, reference: String },
+
+    #[error(transparent)]
+    Compile(#[from] TokenizerError),
+}
+
+impl BenchError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            BenchError::Run(_, e) => e.code(),
+            BenchError::Mismatch { .. } => "E0601",
+            BenchError::Compile(e) => e.code(),
+        }
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            BenchError::Run(_, e) => e.category(),
+            BenchError::Mismatch { .. } => ErrorCategory::Runtime,
+            BenchError::Compile(e) => e.category(),
+        }
+    }
+}
+
+/// Timings of one engine over all its runs.
+#[derive(Debug, Clone)]
+pub struct EngineTiming {
+    pub engine: String,
+    pub compile: Option<Duration>,
+    pub min: Duration,
+    pub median: Duration,
+    pub max: Duration,
+    /// Instructions per second at the median time.
+    pub ips: Option<f64>,
+}
+
+// what a run left behind, compared across engines
+type RunResult = (Vec<u8>, Vec<u8>);
+
+/// Warm up and time `program` under each of `engines`, `runs` times each.
+pub fn bench(
+    engines: &mut [&mut dyn Engine],
+    program: &Program,
+    input: &[u8],
+    options: &VmOptions,
+    runs: usize,
+) -> Result<Vec<EngineTiming>, BenchError> {
+    let runs = runs.max(1);
+    let mut reference: Option<(String, RunResult)> = None;
+    let mut steps = None;
+    let mut timings = vec![];
+    let mut tape = vec![0_u8; MEMORY_SIZE];
+    for engine in engines.iter_mut() {
+        let name = engine.name().to_string();
+        let mut times = vec![];
+        let mut compile = None;
+        for run in 0..=runs {
+            tape.fill(0);
+            let (mut input, mut output) = (input, vec![]);
+            let mut ctx =
+                ExecContext::new(&mut tape, &mut input, &mut output).with_options(options.clone());
+            let start = Instant::now();
+            let outcome = engine
+                .run(program, &mut ctx)
+                .map_err(|e| BenchError::Run(name.clone(), e))?;
+            // code generation is reported on its own, not as part of the run
+            let elapsed = start.elapsed() - outcome.compile_time.unwrap_or_default();
+            steps = steps.or(outcome.steps);
+            compile = compile.or(outcome.compile_time);
+
+            let result = (output, tape.clone());
+            match &reference {
+                None => reference = Some((name.clone(), result)),
+                Some((first, expected)) if *expected != result => {
+                    return Err(BenchError::Mismatch {
+                        engine: name,
+                        reference: first.clone(),
+                    })
+                }
+                Some(_) => {}
+            }
+            if run > 0 {
+                times.push(elapsed);
+            }
+        }
+        times.sort();
+        timings.push(EngineTiming {
+            engine: name,
+            compile,
+            min: times[0],
+            median: times[times.len() / 2],
+            max: times[times.len() - 1],
+            ips: None,
+        });
+    }
+    if let Some(steps) = steps {
+        for timing in &mut timings {
+            timing.ips = Some(steps as f64 / timing.median.as_secs_f64().max(1e-9));
+        }
+    }
+    Ok(timings)
+}
+
+/// Median compile times of one source on one thread and on `threads`.
+#[derive(Debug, Clone)]
+pub struct FrontEndTiming {
+    pub threads: usize,
+    pub sequential: Duration,
+    pub parallel: Duration,
+}
+
+impl FrontEndTiming {
+    pub fn speedup(&self) -> f64 {
+        self.sequential.as_secs_f64() / self.parallel.as_secs_f64().max(1e-9)
+    }
+}
+
+/// Compile `src` at O2 `runs` times each way, after a warmup of each.
+pub fn front_end(src: &str, threads: usize, runs: usize) -> Result<FrontEndTiming, BenchError> {
+    let compile = |threads| {
+        Program::compile_parallel(
+            src,
+            OptLevel::O2,
+            Dialect::Standard,
+            StartTape::Unknown,
+            CellOverflow::Wrap,
+            threads,
+        )
+    };
+    let expected = compile(1)?.to_bytecode(false);
+    if compile(threads)?.to_bytecode(false) != expected {
+        return Err(BenchError::Mismatch {
+            engine: format!("front end on {} threads", threads),
+            reference: "front end on 1 thread".to_string(),
+        });
+    }
+    let median = |threads| -> Result<Duration, BenchError> {
+        let mut times = vec![];
+        for _ in 0..runs.max(1) {
+            let start = Instant::now();
+            compile(threads)?;
+            times.push(start.elapsed());
+        }
+        times.sort();
+        Ok(times[times.len() / 2])
+    };
+    Ok(FrontEndTiming {
+        threads,
+        sequential: median(1)?,
+        parallel: median(threads)?,
+    })
+}
+
+pub(crate) fn millis(duration: Duration) -> String {
+    format!("{:.3}ms", duration.as_secs_f64() * 1e3)
+}
+
+/// One row per engine under a header, columns separated by whitespace.
+pub fn render_table(timings: &[EngineTiming]) -> String {
+    let mut table = format!(
+        "{:<16} {:>12} {:>12} {:>12} {:>12} {:>14}\n",
+        "engine", "compile", "min", "median", "max", "instr/s"
+    );
+    for t in timings {
+        let compile = t.compile.map_or("-".to_string(), millis);
+        let ips = t.ips.map_or("-".to_string(), |ips| format!("{:.0}", ips));
+        writeln!(
+            table,
+            "{:<16} {:>12} {:>12} {:>12} {:>12} {:>14}",
+            t.engine,
+            compile,
+            millis(t.min),
+            millis(t.median),
+            millis(t.max),
+            ips
+        )
+        .unwrap();
+    }
+    table
+}
+
+/// One line: both times and the speedup.
+pub fn render_front_end(timing: &FrontEndTiming) -> String {
+    format!(
+        "front end: {} on 1 thread, {} on {} threads, {:.2}x\n",
+        millis(timing.sequential),
+        millis(timing.parallel),
+        timing.threads,
+        timing.speedup()
+    )
+}
+
+pub fn front_end_json(timing: &FrontEndTiming) -> Json {
+    Json::Object(vec![
+        ("threads".to_string(), Json::Number(timing.threads as f64)),
+        (
+            "sequential".to_string(),
+            Json::Number(timing.sequential.as_secs_f64()),
+        ),
+        (
+            "parallel".to_string(),
+            Json::Number(timing.parallel.as_secs_f64()),
+        ),
+        ("speedup".to_string(), Json::Number(timing.speedup())),
+    ])
+}
+
+/// The timings as a JSON array, durations in seconds.
+pub fn to_json(timings: &[EngineTiming]) -> Json {
+    let seconds = |d: Duration| Json::Number(d.as_secs_f64());
+    Json::Array(
+        timings
+            .iter()
+            .map(|t| {
+                Json::Object(vec![
+                    ("engine".to_string(), t.engine.as_str().into()),
+                    ("compile".to_string(), t.compile.map_or(Json::Null, seconds)),
+                    ("min".to_string(), seconds(t.min)),
+                    ("median".to_string(), seconds(t.median)),
+                    ("max".to_string(), seconds(t.max)),
+                    (
+                        "instr_per_sec".to_string(),
+                        t.ips.map_or(Json::Null, Json::Number),
+                    ),
+                ])
+            })
+            .collect(),
+    )
+}
+
+#[test]
+fn test_bench() {
+    use crate::engine::{EngineRegistry, Interpreter, Outcome};
+
+    let program = Program::compile("++++++++[>++++++++<-]>+.+.+.").unwrap();
+    let mut registry = EngineRegistry::builtin();
+    let mut engines: Vec<_> = registry.iter_mut().collect();
+    let timings = bench(&mut engines, &program, b"", &VmOptions::default(), 3).unwrap();
+    assert_eq!(timings.len(), engines.len());
+
+    let table = render_table(&timings);
+    let mut lines = table.lines();
+    let header: Vec<_> = lines.next().unwrap().split_whitespace().collect();
+    assert_eq!(
+        header,
+        ["engine", "compile", "min", "median", "max", "instr/s"]
+    );
+    for (line, timing) in lines.zip(&timings) {
+        let row: Vec<_> = line.split_whitespace().collect();
+        assert_eq!(row.len(), header.len());
+        assert_eq!(row[0], timing.engine);
+        for cell in &row[2..5] {
+            cell.strip_suffix("ms").unwrap().parse::<f64>().unwrap();
+        }
+        row[5].parse::<f64>().unwrap();
+        assert!(timing.min <= timing.median && timing.median <= timing.max);
+    }
+    match Json::parse(&to_json(&timings).to_string()).unwrap() {
+        Json::Array(rows) => assert_eq!(rows.len(), timings.len()),
+        json => panic!("{}", json),
+    }
+
+    // an engine that gets the output wrong poisons the whole report
+    struct OffByOne;
+    impl Engine for OffByOne {
+        fn run(&mut self, program: &Program, ctx: &mut ExecContext) -> Result<Outcome, VmError> {
+            let outcome = Interpreter.run(program, ctx)?;
+            ctx.output.write_all(b"!")?;
+            Ok(outcome)
+        }
+        fn name(&self) -> &str {
+            "off-by-one"
+        }
+        fn detect() -> Result<(), String> {
+            Ok(())
+        }
+    }
+    let (mut interp, mut wrong) = (Interpreter, OffByOne);
+    let mut engines: Vec<&mut dyn Engine> = vec![&mut interp, &mut wrong];
+    match bench(&mut engines, &program, b"", &VmOptions::default(), 1) {
+        Err(BenchError::Mismatch { engine, reference }) => {
+            assert_eq!(
+                (engine.as_str(), reference.as_str()),
+                ("off-by-one", "interpreter")
+            );
+        }
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+
+    let src = "+[->+<]".repeat(2000);
+    let timing = front_end(&src, 4, 2).unwrap();
+    assert!(render_front_end(&timing).starts_with("front end: "));
+    assert!(matches!(front_end("[", 4, 1), Err(BenchError::Compile(_))));
+}
@@ -0,0 +1,326 @@
+//! Unbounded cells, for `--cells=big`.
+//!
+//! Every cell is an exact integer: `+` and `-` never wrap, `.` writes the
+//! value mod 256, `,` sets the cell to the byte read and loops test for
+//! nonzero. The tape starts empty and grows to the right as the pointer
+//! moves, since a fixed tape of big integers would be absurd.
+//!
+//! The usual pipeline cannot be reused. Tokens fold `+` runs mod 256 and the
+//! O2 passes reason about wrapping, so `[-]` is only a clear when cells wrap.
+//! This mode reads the unoptimized tokens and folds runs itself, into
+//! `i64` deltas that are applied exactly. It is slow, and that is fine.
+
+use std::{
+    cmp::Ordering,
+    fmt,
+    io::{Read, Write},
+};
+
+use crate::{
+    tokenizer::{self, Token},
+    vm::{EofBehavior, VmError},
+};
+
+/// An integer of any size, as a sign and little-endian base 2^32 digits.
+///
+/// Only what a cell needs: small additions, a byte in and a byte out.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BigInt {
+    negative: bool,
+    digits: Vec<u32>, // no trailing zero digits; empty for zero
+}
+
+impl BigInt {
+    pub fn is_zero(&self) -> bool {
+        self.digits.is_empty()
+    }
+
+    pub fn from_byte(byte: u8) -> Self {
+        BigInt::from(byte as i64)
+    }
+
+    /// The value mod 256, what `.` writes.
+    pub fn low_byte(&self) -> u8 {
+        let low = self.digits.first().map_or(0, |&d| d as u8);
+        match self.negative {
+            true => low.wrapping_neg(),
+            false => low,
+        }
+    }
+
+    pub fn add(&mut self, delta: i64) {
+        let magnitude = delta.unsigned_abs();
+        if self.is_zero() || self.negative == (delta < 0) {
+            self.negative = delta < 0;
+            add_magnitude(&mut self.digits, magnitude);
+            return;
+        }
+        // opposite signs: subtract the smaller magnitude from the larger
+        let small = BigInt::from(magnitude as i128);
+        match compare_magnitude(&self.digits, &small.digits) {
+            Ordering::Less => {
+                let mut digits = small.digits;
+                sub_magnitude(&mut digits, &self.digits);
+                self.digits = digits;
+                self.negative = !self.negative;
+            }
+            _ => sub_magnitude(&mut self.digits, &small.digits),
+        }
+        if self.digits.is_empty() {
+            self.negative = false;
+        }
+    }
+}
+
+impl From<i64> for BigInt {
+    fn from(value: i64) -> Self {
+        let mut n = BigInt::from(value.unsigned_abs() as i128);
+        n.negative = value < 0;
+        n
+    }
+}
+
+impl From<i128> for BigInt {
+    fn from(value: i128) -> Self {
+        let mut digits = vec![];
+        let mut magnitude = value.unsigned_abs();
+        while magnitude > 0 {
+            digits.push(magnitude as u32);
+            magnitude >>= 32;
+        }
+        BigInt {
+            negative: value < 0,
+            digits,
+        }
+    }
+}
+
+fn add_magnitude(digits: &mut Vec<u32>, value: u64) {
+    let mut carry = value as u128;
+    for digit in digits.iter_mut() {
+        if carry == 0 {
+            return;
+        }
+        carry += *digit as u128;
+        *digit = carry as u32;
+        carry >>= 32;
+    }
+    while carry > 0 {
+        digits.push(carry as u32);
+        carry >>= 32;
+    }
+}
+
+fn compare_magnitude(a: &[u32], b: &[u32]) -> Ordering {
+    a.len()
+        .cmp(&b.len())
+        .then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+// `a -= b`, with `a` at least as large as `b`
+fn sub_magnitude(a: &mut Vec<u32>, b: &[u32]) {
+    let mut borrow = 0_i64;
+    for (i, digit) in a.iter_mut().enumerate() {
+        let mut d = *digit as i64 - borrow - b.get(i).map_or(0, |&d| d as i64);
+        borrow = (d < 0) as i64;
+        if d < 0 {
+            d += 1 << 32;
+        }
+        *digit = d as u32;
+    }
+    while a.last() == Some(&0) {
+        a.pop();
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_zero() {
+            return write!(f, "0");
+        }
+        // peel off nine decimal digits at a time
+        let (mut digits, mut chunks) = (self.digits.clone(), vec![]);
+        while !digits.is_empty() {
+            let mut rem = 0_u64;
+            for digit in digits.iter_mut().rev() {
+                let cur = (rem << 32) | *digit as u64;
+                *digit = (cur / 1_000_000_000) as u32;
+                rem = cur % 1_000_000_000;
+            }
+            while digits.last() == Some(&0) {
+                digits.pop();
+            }
+            chunks.push(rem);
+        }
+        if self.negative {
+            write!(f, "-")?;
+        }
+        write!(f, "{}", chunks.pop().unwrap())?;
+        for chunk in chunks.iter().rev() {
+            write!(f, "{:09}", chunk)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Op {
+    Add(i64),
+    Move(isize),
+    Input,
+    Output,
+    Open(usize),  // index of the matching `Close`
+    Close(usize), // index of the matching `Open`
+}
+
+// fold runs of `+`/`-` and `<`/`>` exactly, then link the brackets
+pub(crate) fn compile(tokens: &[Token]) -> Vec<Op> {
+    let mut ops: Vec<Op> = vec![];
+    let mut stk = vec![];
+    for &token in tokens {
+        let op = match token {
+            Token::IncrementData(x) => Op::Add(x as i64),
+            Token::DecrementData(x) => Op::Add(-(x as i64)),
+            Token::IncrementPointer(x) => Op::Move(x as isize),
+            Token::DecrementPointer(x) => Op::Move(-(x as isize)),
+            Token::Input => Op::Input,
+            Token::Output => Op::Output,
+            Token::LoopStart(_) => {
+                stk.push(ops.len());
+                Op::Open(0)
+            }
+            Token::LoopEnd(_) => {
+                let open = stk.pop().expect("tokenizer balanced the brackets");
+                ops[open] = Op::Open(ops.len());
+                Op::Close(open)
+            }
+            // the tokenizer never produces anything else
+            token => unreachable!("{:?} in an unoptimized program", token),
+        };
+        match (ops.last_mut(), op) {
+            (Some(Op::Add(a)), Op::Add(b)) => *a += b,
+            (Some(Op::Move(a)), Op::Move(b)) => *a += b,
+            _ => ops.push(op),
+        }
+    }
+    ops
+}
+
+/// The tape after a run, only as long as the pointer ever got.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BigTape {
+    pub cells: Vec<BigInt>,
+    pub pointer: usize,
+}
+
+/// Run `src` with unbounded cells.
+pub fn run(
+    src: &[u8],
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+    eof: EofBehavior,
+) -> Result<BigTape, VmError> {
+    let ops = compile(&tokenizer::tokenizer_from_reader(src)?);
+    let mut cells = vec![BigInt::default()];
+    let (mut pc, mut pointer) = (0, 0_usize);
+    while pc < ops.len() {
+        match ops[pc] {
+            Op::Add(delta) => cells[pointer].add(delta),
+            Op::Move(delta) => {
+                pointer = pointer
+                    .checked_add_signed(delta)
+                    .ok_or(VmError::PointerOverFlow(None))?;
+                if pointer >= cells.len() {
+                    cells.resize(pointer + 1, BigInt::default());
+                }
+            }
+            Op::Input => {
+                let mut byte = [0];
+                if input.read(&mut byte)? == 1 {
+                    cells[pointer] = BigInt::from_byte(byte[0]);
+                } else {
+                    match eof {
+                        EofBehavior::Unchanged => {}
+                        EofBehavior::SetZero => cells[pointer] = BigInt::default(),
+                        EofBehavior::SetMinusOne => cells[pointer] = BigInt::from(-1_i64),
+                        EofBehavior::Halt => break,
+                    }
+                }
+            }
+            Op::Output => output.write_all(&[cells[pointer].low_byte()])?,
+            Op::Open(close) if cells[pointer].is_zero() => pc = close,
+            Op::Close(open) if !cells[pointer].is_zero() => pc = open,
+            Op::Open(_) | Op::Close(_) => {}
+        }
+        pc += 1;
+    }
+    output.flush()?;
+    Ok(BigTape { cells, pointer })
+}
+
+#[test]
+fn test_bigint() {
+    let mut n = BigInt::default();
+    for (delta, expected) in [
+        (255, "255"),
+        (1, "256"),
+        (i64::MAX, "9223372036854776063"),
+        (i64::MAX, "18446744073709551870"),
+        (-i64::MAX, "9223372036854776063"),
+        (-i64::MAX, "256"),
+        (-300, "-44"),
+        (-i64::MAX, "-9223372036854775851"),
+        (i64::MAX, "-44"),
+        (44, "0"),
+        (-1, "-1"),
+    ] {
+        n.add(delta);
+        assert_eq!(n.to_string(), expected, "after {}", delta);
+    }
+    assert_eq!(n.low_byte(), 255);
+    assert_eq!(BigInt::from(-256_i64).low_byte(), 0);
+    assert_eq!(BigInt::from(300_i64).low_byte(), 44);
+    let mut big = BigInt::from(1_i128 << 100);
+    big.add(-1);
+    assert_eq!(big.to_string(), ((1_u128 << 100) - 1).to_string());
+    assert_eq!(BigInt::from(0_i64), BigInt::default());
+}
+
+#[test]
+fn test_big_cells() {
+    let run = |src: &str, input: &[u8]| {
+        let mut output = vec![];
+        let tape = run(
+            src.as_bytes(),
+            &mut &input[..],
+            &mut output,
+            EofBehavior::Unchanged,
+        );
+        (tape.unwrap(), output)
+    };
+
+    // 16 * 16 is 256, which is not zero here, so the loop runs
+    let counted = "++++++++++++++++[>++++++++++++++++<-]>[>+.<[-]]>+";
+    let (tape, output) = run(counted, b"");
+    assert_eq!(output, [1]);
+    assert_eq!(tape.cells[1].to_string(), "0");
+    assert_eq!((tape.cells[2].to_string(), tape.pointer), ("2".into(), 2));
+    let mut wrapped = vec![];
+    let on_eof = crate::reference::OnEof::Unchanged;
+    crate::reference::run(counted, 8, &mut &b""[..], &mut wrapped, on_eof).unwrap();
+    assert!(wrapped.is_empty());
+
+    // count to 300 and back below zero; `.` writes the value mod 256
+    let src = format!("{}.{}.>,-.", "+".repeat(300), "-".repeat(301));
+    let (tape, output) = run(&src, b"A");
+    assert_eq!(output, [44, 255, b'@']);
+    assert_eq!(tape.cells[0].to_string(), "-1");
+
+    // a negative cell is cleared by `[+]`, `[-]` would never reach zero;
+    // the tape grows as far as the pointer goes
+    let (tape, _) = run("--[+]>>>>", b"");
+    assert_eq!((tape.cells.len(), tape.pointer), (5, 4));
+    let mut output = vec![];
+    let err = self::run(b"<", &mut &b""[..], &mut output, EofBehavior::Unchanged);
+    assert!(matches!(err, Err(VmError::PointerOverFlow(_))));
+}
@@ -0,0 +1,202 @@
+//! Brainloller: brainfuck drawn as a PNG, for `bfjit run picture.png`.
+//!
+//! The instruction pointer starts on the top left pixel heading right and
+//! walks one pixel per step until it leaves the image. Eight colors are the
+//! brainfuck commands, two turn the walk, and every other color is a no-op:
+//!
+//! ```text
+//! ff0000 >    800000 <    00ff00 +    008000 -
+//! 0000ff .    000080 ,    ffff00 [    808000 ]
+//! 00ffff turn clockwise   008080 turn counterclockwise
+//! ```
+//!
+//! The commands met on the walk, in order, are the program; from there it is
+//! compiled like text. Each op's span holds its pixel, 1-based like text: the
+//! line is the row and the column the column. `pixel` turns a span back into
+//! the 0-based coordinates image tools show, which is what the errors here
+//! report.
+
+use crate::{
+    error::ErrorCategory,
+    png::{self, Image, PngError},
+    program::{OptLevel, Program},
+    tokenizer::{RawOp, Span, StartTape, Token, TokenizerError},
+    vm::CellOverflow,
+};
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum ImageError {
+    #[error(transparent)]
+    Png(#[from] PngError),
+
+    #[error("{} {} at pixel ({x}, {y})", .error.code(), .error.kind())]
+    Bracket {
+        x: u32,
+        y: u32,
+        error: TokenizerError,
+    },
+}
+
+impl ImageError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ImageError::Png(e) => e.code(),
+            ImageError::Bracket { error, .. } => error.code(),
+        }
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        ErrorCategory::Compile
+    }
+}
+
+/// The 0-based pixel of an op read from an image.
+pub fn pixel(span: Span) -> (u32, u32) {
+    ((span.col - 1) as u32, (span.line - 1) as u32)
+}
+
+enum Color {
+    Command(Token),
+    Clockwise,
+    Counterclockwise,
+    Nothing,
+}
+
+fn color(rgb: [u8; 3]) -> Color {
+    let command = match rgb {
+        [0xff, 0, 0] => Token::IncrementPointer(1),
+        [0x80, 0, 0] => Token::DecrementPointer(1),
+        [0, 0xff, 0] => Token::IncrementData(1),
+        [0, 0x80, 0] => Token::DecrementData(1),
+        [0, 0, 0xff] => Token::Output,
+        [0, 0, 0x80] => Token::Input,
+        [0xff, 0xff, 0] => Token::LoopStart(0),
+        [0x80, 0x80, 0] => Token::LoopEnd(0),
+        [0, 0xff, 0xff] => return Color::Clockwise,
+        [0, 0x80, 0x80] => return Color::Counterclockwise,
+        _ => return Color::Nothing,
+    };
+    Color::Command(command)
+}
+
+// right, down, left, up: clockwise is the next one
+const DIRECTIONS: [(i64, i64); 4] = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+
+/// The commands on the walk through `image`, each spanned by its pixel.
+///
+/// The walk always ends. A step can be undone from the pixel and direction
+/// it leads to, so no pixel is met twice heading the same way: that would
+/// take the walk back to its start, which is only reached from outside.
+pub fn walk(image: &Image) -> Vec<RawOp> {
+    let (width, height) = (image.width() as i64, image.height() as i64);
+    let mut ops = vec![];
+    let (mut x, mut y, mut dir) = (0_i64, 0_i64, 0);
+    while (0..width).contains(&x) && (0..height).contains(&y) {
+        match color(image.pixel(x as u32, y as u32)) {
+            Color::Command(token) => {
+                let span = Span {
+                    line: y as i32 + 1,
+                    col: x as i32 + 1,
+                };
+                ops.push(RawOp { token, span });
+            }
+            Color::Clockwise => dir = (dir + 1) % 4,
+            Color::Counterclockwise => dir = (dir + 3) % 4,
+            Color::Nothing => {}
+        }
+        x += DIRECTIONS[dir].0;
+        y += DIRECTIONS[dir].1;
+    }
+    ops
+}
+
+/// Decode a PNG and compile the program it draws, to run with `overflow`.
+pub fn compile(
+    bytes: &[u8],
+    level: OptLevel,
+    overflow: CellOverflow,
+) -> Result<Program, ImageError> {
+    compile_image(&png::decode(bytes)?, level, overflow)
+}
+
+pub fn compile_image(
+    image: &Image,
+    level: OptLevel,
+    overflow: CellOverflow,
+) -> Result<Program, ImageError> {
+    let ops = walk(image);
+    Program::compile_ops(&ops, level, StartTape::Unknown, overflow).map_err(|error| {
+        let (x, y) = pixel(Span {
+            line: error.line(),
+            col: error.col(),
+        });
+        ImageError::Bracket { x, y, error }
+    })
+}
+
+#[test]
+fn test_brainloller() {
+    use crate::{
+        engine::{Engine, ExecContext, Interpreter},
+        reference::{self, OnEof},
+        vm::MEMORY_SIZE,
+    };
+
+    // hello world drawn in rows that snake down the picture
+    let bytes = std::fs::read("bfcode/hellow.png").unwrap();
+    let program = compile(&bytes, OptLevel::O2, CellOverflow::Wrap).unwrap();
+    let src = std::fs::read_to_string("bfcode/hellow.bf").unwrap();
+    let mut tape = vec![0; MEMORY_SIZE];
+    let (mut input, mut output) = (&b""[..], vec![]);
+    let mut ctx = ExecContext::new(&mut tape, &mut input, &mut output);
+    Interpreter.run(&program, &mut ctx).unwrap();
+    let mut expected = vec![];
+    reference::run(&src, 1000, &mut &b""[..], &mut expected, OnEof::Unchanged).unwrap();
+    assert_eq!(output, expected);
+    // the same tokens as the text, only the spans differ
+    let text = Program::compile_with(&src, OptLevel::O2).unwrap();
+    assert_eq!(program.tokens(), text.tokens());
+    assert_eq!(pixel(program.spans()[0]), (0, 0));
+
+    // an image made of pixels, a row a string: `.` no-op, `R` clockwise,
+    // `L` counterclockwise, and the rest commands
+    let draw = |rows: &[&str]| {
+        let pixels: Vec<[u8; 3]> = rows
+            .iter()
+            .flat_map(|row| row.chars())
+            .map(|c| match c {
+                '>' => [0xff, 0, 0],
+                '<' => [0x80, 0, 0],
+                '+' => [0, 0xff, 0],
+                '-' => [0, 0x80, 0],
+                '[' => [0xff, 0xff, 0],
+                ']' => [0x80, 0x80, 0],
+                'R' => [0, 0xff, 0xff],
+                'L' => [0, 0x80, 0x80],
+                _ => [9, 9, 9],
+            })
+            .collect();
+        Image::new(rows[0].len() as u32, rows.len() as u32, pixels)
+    };
+    // right along the top, down the right edge, left along the bottom
+    let ops = walk(&draw(&["+>.R", "...-", "L<.R"]));
+    let commands: Vec<_> = ops.iter().map(|op| (op.token, pixel(op.span))).collect();
+    assert_eq!(
+        commands,
+        [
+            (Token::IncrementData(1), (0, 0)),
+            (Token::IncrementPointer(1), (1, 0)),
+            (Token::DecrementData(1), (3, 1)),
+            (Token::DecrementPointer(1), (1, 2)),
+        ]
+    );
+    // turning round in place is no trap either
+    assert!(walk(&draw(&["RR", "RR"])).is_empty());
+    let err = compile_image(&draw(&["+R", "]]"]), OptLevel::O2, CellOverflow::Wrap).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "E0101 Unclose left bracket at pixel (1, 1)"
+    );
+    assert_eq!(err.code(), "E0101");
+}
@@ -0,0 +1,556 @@
+//! The `.bfc` serialized program format.
+//!
+//! ```text
+//! magic    "BFC\0"
+//! version  u8
+//! flags    u8, bit 0 set when a source map follows the code
+//! count    varint, number of instructions
+//! code     count x (opcode u8, operand)
+//! map      varint file name length, file name, u64 source hash,
+//!          varint section length, then per instruction:
+//!          zigzag line delta, then the column, absolute after a line
+//!          change and as a zigzag delta otherwise
+//! ```
+//!
+//! Varints are LEB128 and everything fixed-width is little-endian. Operands
+//! are a u8 for `add` and `print`, a varint for moves, scans, jump targets
+//! and repeat counts, nothing for single I/O, a zigzag start offset then a
+//! varint length for `clear`, a zigzag offset then a u8 factor for
+//! `muladd` or a u8 value for `addat`, and the index into `ExtOp::ALL` as a u8 for
+//! extended ops.
+
+use crate::{
+    error::ErrorCategory,
+    program::{Program, SourceInfo},
+    tokenizer::{self, ExtOp, Span, Token},
+};
+
+const MAGIC: &[u8; 4] = b"BFC\0";
+const VERSION: u8 = 1;
+const HAS_SOURCE_MAP: u8 = 1;
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum LoadError {
+    #[error("E0301 Not a bytecode file")]
+    BadMagic,
+
+    #[error("E0302 Unsupported bytecode version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("E0303 Truncated bytecode at byte {0}")]
+    Truncated(usize),
+
+    #[error("E0304 Unknown opcode {opcode} at byte {offset}")]
+    BadOpcode { opcode: u8, offset: usize },
+
+    #[error("E0305 Instruction count {0} does not fit in the file")]
+    CountOverflow(u64),
+
+    #[error("E0306 Operand out of range at byte {0}")]
+    OperandOutOfRange(usize),
+
+    #[error("E0307 Broken block structure at instruction {0}")]
+    BadJump(usize),
+
+    #[error("E0308 Malformed source map")]
+    BadSourceMap,
+
+    #[error("E0309 Trailing data at byte {0}")]
+    TrailingData(usize),
+}
+
+impl LoadError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            LoadError::BadMagic => "E0301",
+            LoadError::UnsupportedVersion(_) => "E0302",
+            LoadError::Truncated(_) => "E0303",
+            LoadError::BadOpcode { .. } => "E0304",
+            LoadError::CountOverflow(_) => "E0305",
+            LoadError::OperandOutOfRange(_) => "E0306",
+            LoadError::BadJump(_) => "E0307",
+            LoadError::BadSourceMap => "E0308",
+            LoadError::TrailingData(_) => "E0309",
+        }
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        ErrorCategory::Compile
+    }
+}
+
+// longest file name a source map may record
+const MAX_FILE_NAME: usize = 4096;
+
+/// FNV-1a, to tell whether a source map still matches the file it names.
+pub fn source_hash(src: &str) -> u64 {
+    fnv1a(src.as_bytes())
+}
+
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+pub(crate) fn put_varint(out: &mut Vec<u8>, mut x: u64) {
+    while x >= 0x80 {
+        out.push(x as u8 | 0x80);
+        x >>= 7;
+    }
+    out.push(x as u8);
+}
+
+fn zigzag(x: i64) -> u64 {
+    ((x << 1) ^ (x >> 63)) as u64
+}
+
+fn unzigzag(x: u64) -> i64 {
+    (x >> 1) as i64 ^ -((x & 1) as i64)
+}
+
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, pos: 0 }
+    }
+
+    pub(crate) fn byte(&mut self) -> Result<u8, LoadError> {
+        let b = *self
+            .bytes
+            .get(self.pos)
+            .ok_or(LoadError::Truncated(self.pos))?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    pub(crate) fn take(&mut self, n: usize) -> Result<&'a [u8], LoadError> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|&end| end <= self.bytes.len());
+        let end = end.ok_or(LoadError::Truncated(self.bytes.len()))?;
+        let taken = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(taken)
+    }
+
+    pub(crate) fn remaining(&self) -> usize {
+        self.bytes.len() - self.pos
+    }
+
+    pub(crate) fn varint(&mut self) -> Result<u64, LoadError> {
+        let start = self.pos;
+        let mut x = 0_u64;
+        for shift in (0..64).step_by(7) {
+            let b = self.byte()?;
+            let bits = (b & 0x7f) as u64;
+            // the tenth byte may only carry the top bit
+            if shift == 63 && bits > 1 {
+                return Err(LoadError::OperandOutOfRange(start));
+            }
+            x |= bits << shift;
+            if b & 0x80 == 0 {
+                return Ok(x);
+            }
+        }
+        Err(LoadError::OperandOutOfRange(start))
+    }
+
+    pub(crate) fn varint_as<T: TryFrom<u64>>(&mut self) -> Result<T, LoadError> {
+        let start = self.pos;
+        T::try_from(self.varint()?).map_err(|_| LoadError::OperandOutOfRange(start))
+    }
+}
+
+impl Program {
+    /// Serialize, carrying the source map unless `strip` is set.
+    pub fn to_bytecode(&self, strip: bool) -> Vec<u8> {
+        let with_map = !strip && self.source_info().is_some();
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        out.push(if with_map { HAS_SOURCE_MAP } else { 0 });
+        put_varint(&mut out, self.tokens().len() as u64);
+        for token in self.tokens() {
+            let (opcode, operand) = match *token {
+                Token::IncrementData(x) => (0, x as u64),
+                Token::DecrementData(x) => (1, x as u64),
+                Token::IncrementPointer(x) => (2, x as u64),
+                Token::DecrementPointer(x) => (3, x as u64),
+                Token::Input => (4, 0),
+                Token::Output => (5, 0),
+                Token::LoopStart(x) => (6, x as u64),
+                Token::LoopEnd(x) => (7, x as u64),
+                Token::IfStart(x) => (8, x as u64),
+                Token::IfEnd(x) => (9, x as u64),
+                Token::OutputRepeat(n) => (10, n as u64),
+                Token::Print(byte) => (12, byte as u64),
+                Token::ScanRight(x) => (15, x as u64),
+                Token::ScanLeft(x) => (16, x as u64),
+                Token::Ext(op) => (13, ExtOp::ALL.iter().position(|&o| o == op).unwrap() as u64),
+                Token::Breakpoint => (17, 0),
+                Token::ClearRange { start_offset, len } => {
+                    out.push(11);
+                    put_varint(&mut out, zigzag(start_offset as i64));
+                    put_varint(&mut out, len as u64);
+                    continue;
+                }
+                Token::MulAdd { offset, factor } => {
+                    out.push(14);
+                    put_varint(&mut out, zigzag(offset as i64));
+                    out.push(factor);
+                    continue;
+                }
+                Token::AddAt { offset, value } => {
+                    out.push(18);
+                    put_varint(&mut out, zigzag(offset as i64));
+                    out.push(value);
+                    continue;
+                }
+            };
+            out.push(opcode);
+            match opcode {
+                0 | 1 | 12 | 13 => out.push(operand as u8),
+                4 | 5 | 17 => {}
+                _ => put_varint(&mut out, operand),
+            }
+        }
+
+        if let (true, Some(info)) = (with_map, self.source_info()) {
+            put_varint(&mut out, info.file.len() as u64);
+            out.extend_from_slice(info.file.as_bytes());
+            out.extend_from_slice(&info.hash.to_le_bytes());
+            let mut map = vec![];
+            let mut last = Span { line: 1, col: 0 };
+            for span in self.spans() {
+                let line = (span.line - last.line) as i64;
+                put_varint(&mut map, zigzag(line));
+                if line == 0 {
+                    put_varint(&mut map, zigzag((span.col - last.col) as i64));
+                } else {
+                    put_varint(&mut map, span.col as u64);
+                }
+                last = *span;
+            }
+            put_varint(&mut out, map.len() as u64);
+            out.extend_from_slice(&map);
+        }
+        out
+    }
+
+    pub fn from_bytecode(bytes: &[u8]) -> Result<Self, LoadError> {
+        let mut r = Reader { bytes, pos: 0 };
+        if r.take(4).map_err(|_| LoadError::BadMagic)? != MAGIC {
+            return Err(LoadError::BadMagic);
+        }
+        let version = r.byte()?;
+        if version != VERSION {
+            return Err(LoadError::UnsupportedVersion(version));
+        }
+        let flags = r.byte()?;
+
+        // every instruction takes at least a byte, so a count larger than
+        // the rest of the file is a lie and must not size an allocation
+        let declared = r.varint()?;
+        let count = usize::try_from(declared)
+            .ok()
+            .filter(|&count| count <= r.remaining())
+            .ok_or(LoadError::CountOverflow(declared))?;
+        let mut tokens = Vec::with_capacity(count);
+        for _ in 0..count {
+            let offset = r.pos;
+            let opcode = r.byte()?;
+            tokens.push(match opcode {
+                0 => Token::IncrementData(r.byte()?),
+                1 => Token::DecrementData(r.byte()?),
+                2 => Token::IncrementPointer(r.varint_as()?),
+                3 => Token::DecrementPointer(r.varint_as()?),
+                4 => Token::Input,
+                5 => Token::Output,
+                6 => Token::LoopStart(r.varint_as()?),
+                7 => Token::LoopEnd(r.varint_as()?),
+                8 => Token::IfStart(r.varint_as()?),
+                9 => Token::IfEnd(r.varint_as()?),
+                10 => Token::OutputRepeat(r.varint_as()?),
+                11 => {
+                    let at = r.pos;
+                    let start_offset = i32::try_from(unzigzag(r.varint()?))
+                        .map_err(|_| LoadError::OperandOutOfRange(at))?;
+                    Token::ClearRange {
+                        start_offset,
+                        len: r.varint_as()?,
+                    }
+                }
+                12 => Token::Print(r.byte()?),
+                13 => {
+                    let at = r.pos;
+                    let op = ExtOp::ALL.get(r.byte()? as usize);
+                    Token::Ext(*op.ok_or(LoadError::OperandOutOfRange(at))?)
+                }
+                14 => {
+                    let at = r.pos;
+                    let offset = i32::try_from(unzigzag(r.varint()?))
+                        .map_err(|_| LoadError::OperandOutOfRange(at))?;
+                    Token::MulAdd {
+                        offset,
+                        factor: r.byte()?,
+                    }
+                }
+                15 => Token::ScanRight(r.varint_as()?),
+                16 => Token::ScanLeft(r.varint_as()?),
+                17 => Token::Breakpoint,
+                18 => {
+                    let at = r.pos;
+                    let offset = i32::try_from(unzigzag(r.varint()?))
+                        .map_err(|_| LoadError::OperandOutOfRange(at))?;
+                    Token::AddAt {
+                        offset,
+                        value: r.byte()?,
+                    }
+                }
+                _ => return Err(LoadError::BadOpcode { opcode, offset }),
+            });
+        }
+        // the VM trusts jump targets, so a tampered one must stop here
+        tokenizer::verify(&tokens).map_err(LoadError::BadJump)?;
+        if flags & HAS_SOURCE_MAP == 0 {
+            return match r.remaining() {
+                0 => Ok(Program::new(tokens)),
+                _ => Err(LoadError::TrailingData(r.pos)),
+            };
+        }
+
+        let name_len: usize = r.varint_as()?;
+        if name_len > MAX_FILE_NAME {
+            return Err(LoadError::BadSourceMap);
+        }
+        let file =
+            String::from_utf8(r.take(name_len)?.to_vec()).map_err(|_| LoadError::BadSourceMap)?;
+        let hash = u64::from_le_bytes(r.take(8)?.try_into().unwrap());
+        let map_len: usize = r.varint_as()?;
+        let mut map = Reader {
+            bytes: r.take(map_len)?,
+            pos: 0,
+        };
+        if r.remaining() != 0 {
+            return Err(LoadError::TrailingData(r.pos));
+        }
+
+        // a truncated or overflowing entry is the map's fault, whatever the
+        // reader calls it
+        let bad = |_| LoadError::BadSourceMap;
+        let mut spans = Vec::with_capacity(count);
+        let mut last = Span { line: 1, col: 0 };
+        for _ in 0..count {
+            let line = unzigzag(map.varint().map_err(bad)?);
+            let col = map.varint().map_err(bad)?;
+            let span = if line == 0 {
+                let col = i32::try_from(unzigzag(col))
+                    .ok()
+                    .and_then(|delta| last.col.checked_add(delta));
+                Span {
+                    line: last.line,
+                    col: col.ok_or(LoadError::BadSourceMap)?,
+                }
+            } else {
+                let line = i32::try_from(line)
+                    .ok()
+                    .and_then(|delta| last.line.checked_add(delta));
+                Span {
+                    line: line.ok_or(LoadError::BadSourceMap)?,
+                    col: i32::try_from(col).map_err(|_| LoadError::BadSourceMap)?,
+                }
+            };
+            spans.push(span);
+            last = span;
+        }
+        if map.remaining() != 0 {
+            return Err(LoadError::BadSourceMap);
+        }
+        Ok(Program::from_parts(tokens, spans).with_source_info(SourceInfo { file, hash }))
+    }
+}
+
+#[test]
+fn test_bytecode_source_map() {
+    use crate::vm::{VmError, VM};
+
+    // runs off the right end of the tape
+    let src = "+++[>+++\n\n++<-]\r\n>>> .    .\n+[\n  >+\n]";
+    let program = Program::compile(src).unwrap().with_source_info(SourceInfo {
+        file: String::from("boom.bf"),
+        hash: source_hash(src),
+    });
+
+    let bytes = program.to_bytecode(false);
+    let loaded = Program::from_bytecode(&bytes).unwrap();
+    assert_eq!(loaded, program);
+    assert_eq!(loaded.source_info().unwrap().file, "boom.bf");
+
+    // the same failure at the same place, from source and from bytecode
+    let fail = |program: Program| {
+        let mut vm = VM::from_program(program)
+            .unwrap()
+            .with_io(std::io::empty(), std::io::sink());
+        let err = vm.run().unwrap_err();
+        (err, vm.current_span())
+    };
+    let (direct, direct_at) = fail(program.clone());
+    let (loaded, loaded_at) = fail(loaded);
+    assert!(matches!(direct, VmError::PointerOverFlow(_)));
+    assert!(matches!(loaded, VmError::PointerOverFlow(_)));
+    assert_eq!(direct_at, Some(Span { line: 6, col: 3 }));
+    assert_eq!(loaded_at, direct_at);
+
+    // stripped files keep the code and lose the locations
+    let stripped = program.to_bytecode(true);
+    assert!(stripped.len() < bytes.len());
+    let stripped = Program::from_bytecode(&stripped).unwrap();
+    assert_eq!(stripped.tokens(), program.tokens());
+    assert!(stripped.source_info().is_none());
+    assert_eq!(fail(stripped).1, None);
+
+    assert!(matches!(
+        Program::from_bytecode(b"BFX\0\x01\0\0"),
+        Err(LoadError::BadMagic)
+    ));
+    assert!(matches!(
+        Program::from_bytecode(b"BFC\0\x07\0\0"),
+        Err(LoadError::UnsupportedVersion(7))
+    ));
+
+    // multi-operand and repeat tokens survive the trip, in IR text too
+    let program = Program::compile("+[-]<[-]<<....[-]>[-]>.").unwrap();
+    assert!(program.tokens().iter().any(|t| matches!(
+        t,
+        Token::ClearRange {
+            start_offset: -1,
+            len: 2
+        }
+    )));
+    let loaded = Program::from_bytecode(&program.to_bytecode(true)).unwrap();
+    assert_eq!(loaded.tokens(), program.tokens());
+    let parsed = Program::from_ir_text(&program.to_ir_text()).unwrap();
+    assert_eq!(parsed.tokens(), program.tokens());
+}
+
+#[test]
+fn test_load_corrupted() {
+    use crate::{
+        program::OptLevel,
+        tokenizer::{Dialect, StartTape},
+        vm::CellOverflow,
+    };
+
+    let src = "++[>+<--]>.";
+    let program = Program::compile_for(
+        src,
+        OptLevel::O2,
+        Dialect::Standard,
+        StartTape::Zeroed,
+        CellOverflow::Wrap,
+    )
+    .unwrap()
+    .with_source_info(SourceInfo {
+        file: String::from("a.bf"),
+        hash: 0,
+    });
+    let good = program.to_bytecode(false);
+    let load = |bytes: &[u8]| Program::from_bytecode(bytes).unwrap_err();
+
+    assert!(matches!(load(b"\x7fELF\x02\x01"), LoadError::BadMagic));
+    assert!(matches!(load(b"BF"), LoadError::BadMagic));
+
+    // every proper prefix is cut off somewhere
+    for len in 0..good.len() {
+        assert!(Program::from_bytecode(&good[..len]).is_err(), "{}", len);
+    }
+    // a body cut short declares more instructions than bytes are left
+    assert!(matches!(load(&good[..9]), LoadError::CountOverflow(9)));
+
+    // a huge count in a tiny file must not be allocated for
+    let mut huge = b"BFC\0\x01\0".to_vec();
+    huge.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f]);
+    assert!(matches!(load(&huge), LoadError::CountOverflow(_)));
+    let mut overlong = b"BFC\0\x01\0".to_vec();
+    overlong.extend_from_slice(&[0xff; 11]);
+    assert!(matches!(load(&overlong), LoadError::OperandOutOfRange(6)));
+
+    // loop start pointing past the end, and a loop end pointing at itself
+    let stripped = program.to_bytecode(true);
+    let loop_start = stripped.iter().position(|&b| b == 6).unwrap();
+    let mut bad = stripped.clone();
+    bad[loop_start + 1] = 0x7f;
+    assert!(matches!(load(&bad), LoadError::BadJump(_)));
+    let loop_end = stripped.iter().rposition(|&b| b == 7).unwrap();
+    let mut bad = stripped.clone();
+    bad[loop_end + 1] = loop_end as u8;
+    assert!(matches!(load(&bad), LoadError::BadJump(_)));
+
+    // a target beyond u32
+    let mut far = b"BFC\0\x01\0\x01\x06".to_vec();
+    far.extend_from_slice(&[0x80, 0x80, 0x80, 0x80, 0x10]);
+    assert!(matches!(load(&far), LoadError::OperandOutOfRange(8)));
+
+    assert!(matches!(
+        load(&[&stripped[..], b"x"].concat()),
+        LoadError::TrailingData(_)
+    ));
+    assert!(matches!(
+        load(&[&good[..], b"x"].concat()),
+        LoadError::TrailingData(_)
+    ));
+}
+
+// fuzz target: arbitrary and mutated bytes must never make the loader panic,
+// and whatever it accepts must have jump targets the VM can trust
+#[test]
+fn fuzz_loader() {
+    let seeds: Vec<Vec<u8>> = ["++[>+<-]>.", ",[.,]", "+[[-]>[<+>-]]", ""]
+        .iter()
+        .map(|src| {
+            Program::compile(src)
+                .unwrap()
+                .with_source_info(SourceInfo {
+                    file: String::from("f.bf"),
+                    hash: source_hash(src),
+                })
+                .to_bytecode(false)
+        })
+        .collect();
+    let mut state: u64 = 0x853c_49e6_748f_ea9b;
+    let mut next = |n: usize| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state as usize % n.max(1)
+    };
+    for round in 0..20_000 {
+        let mut bytes = seeds[round % seeds.len()].clone();
+        if round % 7 == 0 {
+            bytes = MAGIC.iter().copied().chain([VERSION]).collect();
+            bytes.extend((0..next(64)).map(|_| next(256) as u8));
+        }
+        for _ in 0..1 + next(4) {
+            let i = next(bytes.len());
+            match next(4) {
+                0 if i < bytes.len() => bytes[i] = next(256) as u8,
+                1 => bytes.truncate(i),
+                2 => bytes.insert(i, next(256) as u8),
+                _ if i < bytes.len() => bytes[i] ^= 1 << next(8),
+                _ => {}
+            }
+        }
+        let Ok(program) = Program::from_bytecode(&bytes) else {
+            continue;
+        };
+        // verified targets are what keeps `VM::step` indexing in bounds
+        assert!(tokenizer::verify(program.tokens()).is_ok());
+        assert_eq!(program.tokens().len(), program.spans().len());
+    }
+}
@@ -0,0 +1,207 @@
+//! Execution counts in the callgrind format, for `--profile-callgrind`.
+//!
+//! kcachegrind and friends only know functions and lines, so every block is
+//! a "function" named after the span of its brackets, called from the block
+//! around it (or `main`) as many times as its `[` ran. Each instruction
+//! costs what `VM::profile` counted for it, charged to its source line in
+//! the innermost block holding it. Calls carry the inclusive cost of the
+//! block, so the call tree adds up the way the tools expect.
+
+use std::{collections::BTreeMap, fmt::Write};
+
+use crate::{program::Program, tokenizer::Token};
+
+// a block as a profiled "function"; `None` for the whole program
+struct Function {
+    block: Option<(usize, usize)>, // `[` and `]` indices
+    id: usize,
+}
+
+/// Render `counts`, one per instruction of `program`, as a callgrind file
+/// whose source lines point into `file`.
+pub fn render(program: &Program, counts: &[u64], file: &str) -> String {
+    let tokens = program.tokens();
+    let spans = program.spans();
+    assert_eq!(tokens.len(), counts.len());
+    let line = |pc: usize| spans.get(pc).map_or(0, |span| span.line);
+    let name = |block: Option<(usize, usize)>| match block {
+        None => "main".to_string(),
+        Some((start, end)) => {
+            let kind = match tokens[start] {
+                Token::IfStart(_) => "if",
+                _ => "loop",
+            };
+            format!("{} {}-{}", kind, spans[start], spans[end])
+        }
+    };
+    // every block reached at least once, numbered in program order
+    let mut ids = BTreeMap::new();
+    for (pc, token) in tokens.iter().enumerate() {
+        if let Token::LoopStart(end) | Token::IfStart(end) = *token {
+            if counts[pc] > 0 {
+                ids.insert(pc, (end as usize, ids.len() + 2));
+            }
+        }
+    }
+    let inclusive = |start: usize, end: usize| counts[start..=end].iter().sum::<u64>();
+    let total: u64 = counts.iter().sum();
+
+    let mut out = String::new();
+    // names are spelled out once and referred to by id after
+    let mut named = vec![false; ids.len() + 2];
+    let mut compress = |id: usize, name: String| {
+        let first = !std::mem::replace(&mut named[id], true);
+        match first {
+            true => format!("({}) {}", id, name),
+            false => format!("({})", id),
+        }
+    };
+    writeln!(out, "# callgrind format").unwrap();
+    writeln!(out, "version: 1").unwrap();
+    writeln!(out, "creator: bfjit").unwrap();
+    writeln!(out, "positions: line").unwrap();
+    writeln!(out, "events: Instructions").unwrap();
+    writeln!(out, "summary: {}", total).unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "fl=(1) {}", file).unwrap();
+
+    let mut functions = vec![Function { block: None, id: 1 }];
+    functions.extend(ids.iter().map(|(&start, &(end, id))| Function {
+        block: Some((start, end)),
+        id,
+    }));
+    for function in functions {
+        writeln!(out, "fn={}", compress(function.id, name(function.block))).unwrap();
+        let (mut pc, last) = match function.block {
+            None => (0, tokens.len()),
+            Some((start, end)) => {
+                // the brackets themselves belong to the block
+                let mut own = BTreeMap::new();
+                *own.entry(line(start)).or_insert(0) += counts[start];
+                *own.entry(line(end)).or_insert(0) += counts[end];
+                for (line, cost) in own.into_iter().filter(|&(_, cost)| cost > 0) {
+                    writeln!(out, "{} {}", line, cost).unwrap();
+                }
+                (start + 1, end)
+            }
+        };
+        let mut own = BTreeMap::new();
+        let mut calls = vec![];
+        while pc < last {
+            match ids.get(&pc) {
+                Some(&(end, id)) => {
+                    calls.push((pc, end, id));
+                    pc = end + 1;
+                }
+                None => {
+                    if counts[pc] > 0 {
+                        *own.entry(line(pc)).or_insert(0) += counts[pc];
+                    }
+                    // a block never entered costs only its `[`
+                    pc = match tokens[pc] {
+                        Token::LoopStart(end) | Token::IfStart(end) => end as usize + 1,
+                        _ => pc + 1,
+                    };
+                }
+            }
+        }
+        for (line, cost) in own {
+            writeln!(out, "{} {}", line, cost).unwrap();
+        }
+        for (start, end, id) in calls {
+            writeln!(out, "cfn={}", compress(id, name(Some((start, end))))).unwrap();
+            writeln!(out, "calls={} {}", counts[start], line(start)).unwrap();
+            writeln!(out, "{} {}", line(start), inclusive(start, end)).unwrap();
+        }
+        writeln!(out).unwrap();
+    }
+    out
+}
+
+#[test]
+fn test_callgrind() {
+    use crate::{
+        engine::{Engine, ExecContext, Interpreter},
+        program::OptLevel,
+        vm::VmOptions,
+    };
+
+    // at O1 every loop is still a loop
+    let src = "++\n[>+++\n[>+<-]<-]\n>>.[-]\n";
+    let program = Program::compile_with(src, OptLevel::O1).unwrap();
+    let mut tape = vec![0_u8; 16];
+    let (mut input, mut output) = (&b""[..], vec![]);
+    let options = VmOptions {
+        profile: true,
+        ..Default::default()
+    };
+    let mut ctx = ExecContext::new(&mut tape, &mut input, &mut output).with_options(options);
+    Interpreter.run(&program, &mut ctx).unwrap();
+    let counts = ctx.profile.take().unwrap();
+    let text = render(&program, &counts, "loops.bf");
+
+    // header first, then `fl=` before any function
+    let mut lines = text.lines();
+    assert_eq!(lines.next(), Some("# callgrind format"));
+    let header: Vec<_> = lines.by_ref().take_while(|l| !l.is_empty()).collect();
+    assert!(header.contains(&"events: Instructions"));
+    let summary: u64 = header
+        .iter()
+        .find_map(|l| l.strip_prefix("summary: "))
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert_eq!(summary, counts.iter().sum::<u64>());
+    assert_eq!(lines.next(), Some("fl=(1) loops.bf"));
+
+    // self costs add up to the summary, and each call's inclusive cost is
+    // what the callee and everything it calls cost
+    let (mut own, mut inclusive) = (BTreeMap::<String, u64>::new(), vec![]);
+    let (mut current, mut names) = (String::new(), BTreeMap::new());
+    let mut lines = lines.filter(|l| !l.is_empty());
+    let id = |record: &str, names: &mut BTreeMap<String, String>| {
+        let (id, name) = record.split_once(')').unwrap();
+        let name = name.trim_start();
+        if !name.is_empty() {
+            names.insert(id.to_string(), name.to_string());
+        }
+        names[id].clone()
+    };
+    while let Some(l) = lines.next() {
+        if let Some(record) = l.strip_prefix("fn=") {
+            current = id(record, &mut names);
+            assert!(own.insert(current.clone(), 0).is_none(), "{}", l);
+        } else if let Some(record) = l.strip_prefix("cfn=") {
+            let callee = id(record, &mut names);
+            let calls = lines.next().unwrap().strip_prefix("calls=").unwrap();
+            let (count, _) = calls.split_once(' ').unwrap();
+            assert!(count.parse::<u64>().unwrap() > 0);
+            let cost = lines.next().unwrap().split_once(' ').unwrap().1;
+            inclusive.push((current.clone(), callee, cost.parse::<u64>().unwrap()));
+        } else {
+            let (line, cost) = l.split_once(' ').expect(l);
+            assert!(line.parse::<u32>().unwrap() <= 4);
+            *own.get_mut(&current).unwrap() += cost.parse::<u64>().unwrap();
+        }
+    }
+    assert_eq!(own.values().sum::<u64>(), summary);
+    assert_eq!(
+        names.values().cloned().collect::<Vec<_>>(),
+        ["main", "loop 2:1-3:9", "loop 3:1-3:6", "loop 4:4-4:6"]
+    );
+    let total = |name: &str| -> u64 {
+        fn walk(name: &str, own: &BTreeMap<String, u64>, calls: &[(String, String, u64)]) -> u64 {
+            own[name]
+                + calls
+                    .iter()
+                    .filter(|(caller, _, _)| caller == name)
+                    .map(|(_, callee, _)| walk(callee, own, calls))
+                    .sum::<u64>()
+        }
+        walk(name, &own, &inclusive)
+    };
+    for (_, callee, cost) in &inclusive {
+        assert_eq!(total(callee), *cost, "{}", callee);
+    }
+    assert_eq!(total("main"), summary);
+}
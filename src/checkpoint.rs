@@ -0,0 +1,329 @@
+//! Paused runs saved to disk, for `--checkpoint` and `--resume`.
+//!
+//! ```text
+//! magic    "BFS\0"
+//! version  u8
+//! program  u64, FNV-1a of the program's stripped bytecode
+//! state    varint pc, varint pointer, varint furthest cell, u8 register
+//! tape     varint tape length, varint run count, then per run a varint
+//!          count of zero cells skipped since the last one, a varint
+//!          length and that many cells
+//! ```
+//!
+//! Only the nonzero stretches of the tape are kept, so a 4 MiB tape that a
+//! program used a few hundred cells of saves in a few hundred bytes. Input
+//! is not part of the state: a resumed run reads on from whatever input it
+//! is given. Counts start over too, so `--max-steps` is per leg.
+
+use std::{fs, path::PathBuf, sync::Arc};
+
+use crate::{
+    bytecode::{self, LoadError, Reader},
+    engine::{Engine, ExecContext, Outcome},
+    error::ErrorCategory,
+    program::Program,
+    vm::{VmError, VM},
+};
+
+const MAGIC: &[u8; 4] = b"BFS\0";
+const VERSION: u8 = 1;
+// zero cells a run takes in rather than ending, since a varint pair costs
+// as much
+const GAP: usize = 4;
+
+// only ever reported through `VmError::Snapshot`, whose message carries the
+// code
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum SnapshotError {
+    #[error("Not a snapshot file")]
+    BadMagic,
+
+    #[error("Unsupported snapshot version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("Truncated snapshot at byte {0}")]
+    Truncated(usize),
+
+    #[error("Malformed snapshot at byte {0}")]
+    Malformed(usize),
+
+    #[error("Snapshot of another program ({found:016x}, this one is {expected:016x})")]
+    OtherProgram { expected: u64, found: u64 },
+
+    #[error("Snapshot does not fit, it needs {0} cells")]
+    TooLarge(usize),
+}
+
+impl SnapshotError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            SnapshotError::BadMagic => "E0801",
+            SnapshotError::UnsupportedVersion(_) => "E0802",
+            SnapshotError::Truncated(_) => "E0803",
+            SnapshotError::Malformed(_) => "E0804",
+            SnapshotError::OtherProgram { .. } => "E0805",
+            SnapshotError::TooLarge(_) => "E0806",
+        }
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        ErrorCategory::Compile
+    }
+}
+
+impl From<LoadError> for SnapshotError {
+    fn from(e: LoadError) -> Self {
+        match e {
+            LoadError::Truncated(at) => SnapshotError::Truncated(at),
+            LoadError::OperandOutOfRange(at) => SnapshotError::Malformed(at),
+            _ => unreachable!("a reader only runs short or out of range"),
+        }
+    }
+}
+
+/// Where a paused VM was, enough to carry on with `VM::restore`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    /// `program_hash` of the program it ran.
+    pub program: u64,
+    pub pc: usize,
+    pub pointer: usize,
+    pub high_water: usize,
+    pub storage: u8,
+    pub tape_len: usize,
+    /// The stretches of the tape holding anything, by the cell each starts
+    /// at; the rest is zero.
+    pub runs: Vec<(usize, Vec<u8>)>,
+}
+
+/// What a snapshot is checked against: the program's instructions, without
+/// where in the source they came from.
+pub fn program_hash(program: &Program) -> u64 {
+    bytecode::fnv1a(&program.to_bytecode(true))
+}
+
+/// The nonzero stretches of `cells`, with gaps of up to `GAP` zeros kept
+/// inside a stretch.
+pub fn nonzero_runs(cells: &[u8]) -> Vec<(usize, Vec<u8>)> {
+    let mut runs: Vec<(usize, Vec<u8>)> = vec![];
+    for (i, &cell) in cells.iter().enumerate().filter(|(_, &cell)| cell != 0) {
+        match runs.last_mut() {
+            Some((start, run)) if i - (*start + run.len()) <= GAP => {
+                run.resize(i - *start, 0);
+                run.push(cell);
+            }
+            _ => runs.push((i, vec![cell])),
+        }
+    }
+    runs
+}
+
+impl Snapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        out.extend_from_slice(&self.program.to_le_bytes());
+        for x in [self.pc, self.pointer, self.high_water] {
+            bytecode::put_varint(&mut out, x as u64);
+        }
+        out.push(self.storage);
+        bytecode::put_varint(&mut out, self.tape_len as u64);
+        bytecode::put_varint(&mut out, self.runs.len() as u64);
+        let mut end = 0;
+        for (start, run) in &self.runs {
+            bytecode::put_varint(&mut out, (start - end) as u64);
+            bytecode::put_varint(&mut out, run.len() as u64);
+            out.extend_from_slice(run);
+            end = start + run.len();
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        if !bytes.starts_with(MAGIC) {
+            return Err(SnapshotError::BadMagic);
+        }
+        let mut r = Reader::new(bytes);
+        r.take(MAGIC.len())?;
+        let version = r.byte()?;
+        if version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let program = u64::from_le_bytes(r.take(8)?.try_into().unwrap());
+        let (pc, pointer, high_water) = (r.varint_as()?, r.varint_as()?, r.varint_as()?);
+        let storage = r.byte()?;
+        let tape_len: usize = r.varint_as()?;
+        let count: usize = r.varint_as()?;
+        // every run takes at least three bytes
+        if count > r.remaining() / 3 {
+            return Err(SnapshotError::Truncated(bytes.len()));
+        }
+        let mut runs = Vec::with_capacity(count);
+        let mut end = 0_usize;
+        for _ in 0..count {
+            let at = bytes.len() - r.remaining();
+            let skip: usize = r.varint_as()?;
+            let len: usize = r.varint_as()?;
+            let start = end.checked_add(skip);
+            let run_end = start.and_then(|start| start.checked_add(len));
+            let (Some(start), Some(run_end)) = (start, run_end) else {
+                return Err(SnapshotError::Malformed(at));
+            };
+            if run_end > tape_len {
+                return Err(SnapshotError::Malformed(at));
+            }
+            runs.push((start, r.take(len)?.to_vec()));
+            end = run_end;
+        }
+        if r.remaining() > 0 {
+            return Err(SnapshotError::Malformed(bytes.len() - r.remaining()));
+        }
+        Ok(Snapshot {
+            program,
+            pc,
+            pointer,
+            high_water,
+            storage,
+            tape_len,
+            runs,
+        })
+    }
+}
+
+/// The interpreter, starting from the snapshot in `resume` when there is one
+/// and saving one to `checkpoint` when fuel runs out.
+///
+/// The tape is `ctx.tape` as it is, never grown, so that a snapshot restores
+/// into exactly the cells it was taken from.
+#[derive(Debug, Default)]
+pub struct Checkpointing {
+    pub checkpoint: Option<PathBuf>,
+    pub resume: Option<PathBuf>,
+}
+
+impl Engine for Checkpointing {
+    fn run(&mut self, program: &Program, ctx: &mut ExecContext) -> Result<Outcome, VmError> {
+        let program = Arc::new(program.clone());
+        let mut vm = VM::with_tape(program, &mut *ctx.tape)?
+            .with_options(ctx.options.clone())
+            .with_io(&mut *ctx.input, &mut *ctx.output);
+        if let Some(tracer) = ctx.trace.take() {
+            vm = vm.with_trace(tracer);
+        }
+        let mut result = match &self.resume {
+            Some(path) => {
+                let snapshot = Snapshot::from_bytes(&fs::read(path)?)?;
+                vm.restore(&snapshot)?;
+                vm.resume()
+            }
+            None => vm.run(),
+        };
+        while result.is_ok() && !vm.halted() {
+            result = vm.resume();
+        }
+        if let (Err(VmError::OutOfFuel(_)), Some(path)) = (&result, &self.checkpoint) {
+            fs::write(path, vm.snapshot().to_bytes())?;
+        }
+        ctx.profile = vm.profile().map(<[u64]>::to_vec);
+        ctx.trace = vm.take_trace();
+        match result {
+            Ok(()) => Ok(Outcome {
+                termination: vm.stats().termination,
+                pointer: vm.pointer(),
+                steps: Some(vm.stats().steps),
+                compile_time: None,
+            }),
+            Err(e) => {
+                ctx.error_span = vm.current_span();
+                ctx.error_pointer = Some(vm.pointer());
+                Err(e)
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        "checkpointing"
+    }
+
+    fn detect() -> Result<(), String> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_checkpoint() {
+    use crate::vm::{SharedOutput, VmOptions};
+
+    // prints as it goes, so each half writes some of it
+    let src = format!("+++++[>++++++++++<-]>>++[<{}>-]", ".+".repeat(10));
+    let program = Arc::new(Program::compile(&src).unwrap());
+    let vm = |output: &SharedOutput| {
+        VM::new(program.clone())
+            .unwrap()
+            .with_io(std::io::empty(), output.clone())
+    };
+    let whole = SharedOutput::default();
+    let mut straight = vm(&whole);
+    straight.run().unwrap();
+    let half = straight.stats().steps / 2;
+
+    // half the instructions, then a fresh VM in place of the first
+    let (first, second) = (SharedOutput::default(), SharedOutput::default());
+    let mut before = vm(&first).with_options(VmOptions {
+        fuel: Some(half),
+        ..Default::default()
+    });
+    assert!(matches!(before.run(), Err(VmError::OutOfFuel(_))));
+    let bytes = before.snapshot().to_bytes();
+    // a 4 MiB tape with a few cells in use keeps to a few bytes
+    assert!(bytes.len() < 64, "{} bytes", bytes.len());
+    let snapshot = Snapshot::from_bytes(&bytes).unwrap();
+    assert_eq!(snapshot, before.snapshot());
+    let mut after = vm(&second);
+    after.restore(&snapshot).unwrap();
+    after.resume().unwrap();
+    let (first, second) = (first.take(), second.take());
+    assert!(!first.is_empty() && !second.is_empty());
+    assert_eq!([first, second].concat(), whole.take());
+    assert_eq!(after.cells(0..16), straight.cells(0..16));
+    assert_eq!(after.pointer(), straight.pointer());
+
+    // a snapshot only restores into the program it came from
+    let other = Program::compile("+[>+]").unwrap();
+    let err = VM::new(other).unwrap().restore(&snapshot).unwrap_err();
+    assert_eq!(err.code(), "E0805");
+    let report = crate::error::report(&err);
+    assert!(
+        report.starts_with("E0805 Snapshot Error: Snapshot of another program"),
+        "{}",
+        report
+    );
+    let err = VM::builder(program.clone())
+        .memory_size(2)
+        .build()
+        .unwrap()
+        .restore(&snapshot)
+        .unwrap_err();
+    assert_eq!(err.code(), "E0806");
+
+    // damage is found before anything is restored
+    assert!(matches!(
+        Snapshot::from_bytes(b"BFC\0"),
+        Err(SnapshotError::BadMagic)
+    ));
+    let short = Snapshot::from_bytes(&bytes[..bytes.len() - 1]);
+    assert!(matches!(short, Err(SnapshotError::Truncated(_))));
+    let mut long = bytes.clone();
+    long.push(0);
+    assert!(matches!(
+        Snapshot::from_bytes(&long),
+        Err(SnapshotError::Malformed(_))
+    ));
+
+    // short gaps stay inside a run, long ones end it
+    assert_eq!(
+        nonzero_runs(&[0, 1, 0, 0, 2, 0, 0, 0, 0, 0, 0, 3, 0]),
+        [(1, vec![1, 0, 0, 2]), (11, vec![3])]
+    );
+}
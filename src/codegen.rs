@@ -0,0 +1,464 @@
+//! `bfjit --emit=c|rust`: the optimized program as one C or Rust source file.
+//!
+//! The output needs nothing but the language's standard library. The tape is
+//! `MEMORY_SIZE` cells, loops and ifs are `while`/`if` blocks nested as the
+//! brackets nest, whatever targets their tokens carry, and pointer moves are
+//! checked as `VM::run` checks them: off either end, the program flushes what
+//! it wrote, prints the VM's E0403 message and exits with the runtime error
+//! status. `,` and `.` are `getchar`/`putchar` in C and locked stdio in Rust.
+
+use std::fmt::Write;
+
+use crate::{
+    error::ErrorCategory,
+    tokenizer::{ExtOp, Token},
+    vm::{EofBehavior, VmError, MEMORY_SIZE},
+};
+
+/// The language to emit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    C,
+    Rust,
+}
+
+impl Target {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "c" => Some(Target::C),
+            "rust" => Some(Target::Rust),
+            _ => None,
+        }
+    }
+
+    // the statement `cond` guards with a pointer overflow
+    fn guard(self, cond: &str) -> String {
+        match self {
+            Target::C => format!("if ({}) overflow();", cond),
+            Target::Rust => format!("if {} {{ overflow(&mut out); }}", cond),
+        }
+    }
+}
+
+// `p` moved by `offset`, as written in an index
+fn at(offset: i64) -> String {
+    match offset {
+        0 => "p".to_string(),
+        n if n < 0 => format!("p - {}", -n),
+        n => format!("p + {}", n),
+    }
+}
+
+// whether any of the `len` cells from `p + offset` is off the tape
+fn off_tape(offset: i64, len: u64) -> String {
+    let end = format!("{} > MEM", at(offset + len as i64));
+    match offset {
+        n if n < 0 => format!("p < {} || {}", -n, end),
+        _ => end,
+    }
+}
+
+fn c_token(token: Token, eof: EofBehavior) -> Vec<String> {
+    let guard = |cond: &str| Target::C.guard(cond);
+    match token {
+        Token::IncrementData(x) => vec![format!("tape[p] += {};", x)],
+        Token::DecrementData(x) => vec![format!("tape[p] -= {};", x)],
+        Token::IncrementPointer(x) => vec![format!("p += {};", x), guard("p >= MEM")],
+        Token::DecrementPointer(x) => vec![guard(&format!("p < {}", x)), format!("p -= {};", x)],
+        Token::Input => {
+            let mut lines = vec![
+                "fflush(stdout);".to_string(),
+                "if ((c = getchar()) != EOF) tape[p] = c;".to_string(),
+            ];
+            match eof {
+                EofBehavior::Unchanged => {}
+                EofBehavior::SetZero => lines.push("else tape[p] = 0;".into()),
+                EofBehavior::SetMinusOne => lines.push("else tape[p] = 255;".into()),
+                EofBehavior::Halt => lines.push("else return 0;".into()),
+            }
+            lines
+        }
+        Token::Output => vec!["putchar(tape[p]);".into()],
+        Token::OutputRepeat(n) => vec![format!(
+            "for (size_t i = 0; i < {}; i++) putchar(tape[p]);",
+            n
+        )],
+        Token::Print(byte) => vec![format!("putchar({});", byte)],
+        Token::ClearRange { start_offset, len } => {
+            let start = start_offset as i64;
+            vec![
+                guard(&off_tape(start, len as u64)),
+                format!("memset(&tape[{}], 0, {});", at(start), len),
+            ]
+        }
+        Token::MulAdd { offset, factor } => {
+            let offset = offset as i64;
+            vec![
+                "if (tape[p]) {".into(),
+                format!("    {}", guard(&off_tape(offset, 1))),
+                format!("    tape[{}] += tape[p] * {};", at(offset), factor),
+                "}".into(),
+            ]
+        }
+        Token::AddAt { offset, value } => {
+            let offset = offset as i64;
+            vec![
+                guard(&off_tape(offset, 1)),
+                format!("tape[{}] += {};", at(offset), value),
+            ]
+        }
+        Token::ScanRight(x) => vec![
+            "while (tape[p]) {".into(),
+            format!("    p += {};", x),
+            format!("    {}", guard("p >= MEM")),
+            "}".into(),
+        ],
+        Token::ScanLeft(x) => vec![
+            "while (tape[p]) {".into(),
+            format!("    {}", guard(&format!("p < {}", x))),
+            format!("    p -= {};", x),
+            "}".into(),
+        ],
+        // nobody to stop for outside the VM
+        Token::Breakpoint => vec!["/* # */".into()],
+        Token::Ext(op) => vec![match op {
+            ExtOp::Halt => "return 0;",
+            ExtOp::Store => "storage = tape[p];",
+            ExtOp::Load => "tape[p] = storage;",
+            ExtOp::ShiftLeft => "tape[p] <<= 1;",
+            ExtOp::ShiftRight => "tape[p] >>= 1;",
+            ExtOp::Not => "tape[p] ^= 255;",
+            ExtOp::Xor => "tape[p] ^= storage;",
+            ExtOp::And => "tape[p] &= storage;",
+            ExtOp::Or => "tape[p] |= storage;",
+        }
+        .into()],
+        Token::LoopStart(_) | Token::LoopEnd(_) | Token::IfStart(_) | Token::IfEnd(_) => {
+            unreachable!("blocks are emitted by the caller")
+        }
+    }
+}
+
+fn rust_token(token: Token, eof: EofBehavior) -> Vec<String> {
+    let guard = |cond: &str| Target::Rust.guard(cond);
+    let put = |byte: &str| format!("out.write_all(&[{}]).unwrap();", byte);
+    match token {
+        Token::IncrementData(x) => vec![format!("tape[p] = tape[p].wrapping_add({});", x)],
+        Token::DecrementData(x) => vec![format!("tape[p] = tape[p].wrapping_sub({});", x)],
+        Token::IncrementPointer(x) => vec![format!("p += {};", x), guard("p >= MEM")],
+        Token::DecrementPointer(x) => vec![guard(&format!("p < {}", x)), format!("p -= {};", x)],
+        Token::Input => {
+            let on_eof = match eof {
+                EofBehavior::Unchanged => "{}",
+                EofBehavior::SetZero => "tape[p] = 0,",
+                EofBehavior::SetMinusOne => "tape[p] = 255,",
+                EofBehavior::Halt => "return out.flush().unwrap(),",
+            };
+            vec![
+                "out.flush().unwrap();".into(),
+                "match input.next() {".into(),
+                "    Some(Ok(c)) => tape[p] = c,".into(),
+                format!("    _ => {}", on_eof),
+                "}".into(),
+            ]
+        }
+        Token::Output => vec![put("tape[p]")],
+        Token::OutputRepeat(n) => vec![format!("for _ in 0..{} {{ {} }}", n, put("tape[p]"))],
+        Token::Print(byte) => vec![put(&byte.to_string())],
+        Token::ClearRange { start_offset, len } => {
+            let start = start_offset as i64;
+            vec![
+                guard(&off_tape(start, len as u64)),
+                format!("tape[{}..{}].fill(0);", at(start), at(start + len as i64)),
+            ]
+        }
+        Token::MulAdd { offset, factor } => {
+            let target = at(offset as i64);
+            vec![
+                "if tape[p] != 0 {".into(),
+                format!("    {}", guard(&off_tape(offset as i64, 1))),
+                format!(
+                    "    tape[{0}] = tape[{0}].wrapping_add(tape[p].wrapping_mul({1}));",
+                    target, factor
+                ),
+                "}".into(),
+            ]
+        }
+        Token::AddAt { offset, value } => {
+            let target = at(offset as i64);
+            vec![
+                guard(&off_tape(offset as i64, 1)),
+                format!("tape[{0}] = tape[{0}].wrapping_add({1});", target, value),
+            ]
+        }
+        Token::ScanRight(x) => vec![
+            "while tape[p] != 0 {".into(),
+            format!("    p += {};", x),
+            format!("    {}", guard("p >= MEM")),
+            "}".into(),
+        ],
+        Token::ScanLeft(x) => vec![
+            "while tape[p] != 0 {".into(),
+            format!("    {}", guard(&format!("p < {}", x))),
+            format!("    p -= {};", x),
+            "}".into(),
+        ],
+        Token::Breakpoint => vec!["// #".into()],
+        Token::Ext(op) => vec![match op {
+            ExtOp::Halt => "return out.flush().unwrap();",
+            ExtOp::Store => "storage = tape[p];",
+            ExtOp::Load => "tape[p] = storage;",
+            ExtOp::ShiftLeft => "tape[p] <<= 1;",
+            ExtOp::ShiftRight => "tape[p] >>= 1;",
+            ExtOp::Not => "tape[p] = !tape[p];",
+            ExtOp::Xor => "tape[p] ^= storage;",
+            ExtOp::And => "tape[p] &= storage;",
+            ExtOp::Or => "tape[p] |= storage;",
+        }
+        .into()],
+        Token::LoopStart(_) | Token::LoopEnd(_) | Token::IfStart(_) | Token::IfEnd(_) => {
+            unreachable!("blocks are emitted by the caller")
+        }
+    }
+}
+
+/// Translate `tokens` into a standalone `target` program; `eof` decides what
+/// `,` does once stdin is exhausted.
+pub fn emit(tokens: &[Token], target: Target, eof: EofBehavior) -> String {
+    let uses = |f: fn(&Token) -> bool| tokens.iter().any(f);
+    let input = uses(|t| matches!(t, Token::Input));
+    let storage = uses(|t| {
+        matches!(
+            t,
+            Token::Ext(ExtOp::Store | ExtOp::Load | ExtOp::Xor | ExtOp::And | ExtOp::Or)
+        )
+    });
+    let moves = uses(|t| {
+        matches!(
+            t,
+            Token::IncrementPointer(_)
+                | Token::DecrementPointer(_)
+                | Token::ScanRight(_)
+                | Token::ScanLeft(_)
+        )
+    });
+    let bounded = moves
+        || uses(|t| {
+            matches!(
+                t,
+                Token::ClearRange { .. } | Token::MulAdd { .. } | Token::AddAt { .. }
+            )
+        });
+    let message = VmError::PointerOverFlow(None).to_string();
+    let status = ErrorCategory::Runtime.exit_code();
+
+    let mut out = String::new();
+    match target {
+        Target::C => {
+            writeln!(out, "/* translated from brainfuck by bfjit */").unwrap();
+            writeln!(out, "#include <stdio.h>").unwrap();
+            writeln!(out, "#include <stdlib.h>").unwrap();
+            writeln!(out, "#include <string.h>\n").unwrap();
+            writeln!(out, "#define MEM {}\n", MEMORY_SIZE).unwrap();
+            writeln!(out, "static unsigned char tape[MEM];").unwrap();
+            if storage {
+                writeln!(out, "static unsigned char storage;").unwrap();
+            }
+            if bounded {
+                writeln!(out, "\nstatic void overflow(void) {{").unwrap();
+                writeln!(out, "    fflush(stdout);").unwrap();
+                writeln!(out, "    fputs(\"{}\\n\", stderr);", message).unwrap();
+                writeln!(out, "    exit({});\n}}", status).unwrap();
+            }
+            writeln!(out, "\nint main(void) {{").unwrap();
+            writeln!(out, "    size_t p = 0;").unwrap();
+            if input {
+                writeln!(out, "    int c;").unwrap();
+            }
+        }
+        Target::Rust => {
+            writeln!(out, "//! Translated from brainfuck by bfjit.\n").unwrap();
+            // a move just before the end has nothing left to read it
+            writeln!(out, "#![allow(unused_assignments)]\n").unwrap();
+            match input {
+                true => writeln!(out, "use std::io::{{self, Read, Write}};").unwrap(),
+                false => writeln!(out, "use std::io::{{self, Write}};").unwrap(),
+            }
+            if bounded {
+                writeln!(out, "use std::process::exit;").unwrap();
+            }
+            writeln!(out).unwrap();
+            writeln!(out, "const MEM: usize = {};", MEMORY_SIZE).unwrap();
+            if bounded {
+                writeln!(out, "\nfn overflow(out: &mut impl Write) -> ! {{").unwrap();
+                writeln!(out, "    out.flush().unwrap();").unwrap();
+                writeln!(out, "    eprintln!(\"{}\");", message).unwrap();
+                writeln!(out, "    exit({});\n}}", status).unwrap();
+            }
+            writeln!(out, "\nfn main() {{").unwrap();
+            writeln!(out, "    let mut tape = vec![0_u8; MEM];").unwrap();
+            let binding = if moves { "let mut p" } else { "let p" };
+            writeln!(out, "    {} = 0_usize;", binding).unwrap();
+            if storage {
+                writeln!(out, "    let mut storage = 0_u8;").unwrap();
+            }
+            if input {
+                writeln!(out, "    let mut input = io::stdin().lock().bytes();").unwrap();
+            }
+            writeln!(
+                out,
+                "    let mut out = io::BufWriter::new(io::stdout().lock());"
+            )
+            .unwrap();
+        }
+    }
+
+    let mut depth = 1; // in levels of four spaces
+    for &token in tokens {
+        let lines = if token.is_block_start() {
+            let keyword = match token {
+                Token::LoopStart(_) => "while",
+                _ => "if",
+            };
+            let line = match target {
+                Target::C => format!("{} (tape[p]) {{", keyword),
+                Target::Rust => format!("{} tape[p] != 0 {{", keyword),
+            };
+            writeln!(out, "{}{}", "    ".repeat(depth), line).unwrap();
+            depth += 1;
+            continue;
+        } else if token.is_block_end() {
+            depth -= 1;
+            vec!["}".to_string()]
+        } else {
+            match target {
+                Target::C => c_token(token, eof),
+                Target::Rust => rust_token(token, eof),
+            }
+        };
+        for line in lines {
+            writeln!(out, "{}{}", "    ".repeat(depth), line).unwrap();
+        }
+    }
+    match target {
+        Target::C => writeln!(out, "    return 0;\n}}").unwrap(),
+        Target::Rust => writeln!(out, "    out.flush().unwrap();\n}}").unwrap(),
+    }
+    out
+}
+
+#[test]
+fn test_emit() {
+    use std::{
+        io::Write as _,
+        process::{Command, Stdio},
+    };
+
+    use crate::{
+        program::Program,
+        reference::{self, OnEof},
+    };
+
+    // every kind of O2 token, then an if and a clear reaching left
+    let mixed = ">>+++++[<++++++++>-]<+.[-]>>+<<<++[>>[-]<<-]>>>[<]>>+.+..[[-]>]<<<-[>+<-].";
+    let cases = [
+        std::fs::read_to_string("bfcode/hellow.bf").unwrap(),
+        std::fs::read_to_string("bfcode/echo.bf").unwrap(),
+        mixed.to_string(),
+        ">,[>+<[-]]>.>[-]<[-]<[-]+.".to_string(),
+        // loop targets stay absolute indices, the blocks still nest
+        format!(
+            "+{}{}+++[>++++++++++<-]>+++.",
+            "[>+".repeat(40),
+            "-]<".repeat(40)
+        ),
+    ];
+    let input = b"cat \\ me\n'\xff";
+    let dir = std::env::temp_dir().join(format!("bfjit-codegen-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    // build `source` with whichever compiler is around, or give up
+    let build = |target: Target, source: &str, name: &str| {
+        let (compiler, file) = match target {
+            Target::C => ("cc", dir.join(format!("{}.c", name))),
+            Target::Rust => ("rustc", dir.join(format!("{}.rs", name))),
+        };
+        std::fs::write(&file, source).unwrap();
+        let exe = dir.join(name);
+        let mut command = Command::new(compiler);
+        match target {
+            Target::C => command.args(["-O1", "-Wall", "-Werror", "-o"]),
+            Target::Rust => command.args(["-O", "-D", "warnings", "-o"]),
+        };
+        let built = command.arg(&exe).arg(&file).output().ok()?;
+        let errors = String::from_utf8_lossy(&built.stderr);
+        assert!(built.status.success(), "{}:\n{}\n{}", name, errors, source);
+        Some(exe)
+    };
+    for target in [Target::C, Target::Rust] {
+        for (i, src) in cases.iter().enumerate() {
+            let program = Program::compile(src).unwrap();
+            let source = emit(program.tokens(), target, EofBehavior::Unchanged);
+            let Some(exe) = build(target, &source, &format!("{:?}{}", target, i)) else {
+                break;
+            };
+            let mut child = Command::new(exe)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap();
+            // a program that never reads may be gone before the input is
+            let _ = child.stdin.take().unwrap().write_all(input);
+            let ran = child.wait_with_output().unwrap();
+            assert!(ran.status.success(), "{:?} {}", target, i);
+            let mut expected = vec![];
+            let on_eof = OnEof::Unchanged;
+            reference::run(src, MEMORY_SIZE, &mut &input[..], &mut expected, on_eof).unwrap();
+            assert_eq!(ran.stdout, expected, "{:?} {}:\n{}", target, i, source);
+        }
+        // walking off the tape fails as the VM does, after what was written
+        let program = Program::compile("+++++[>++++++++++<-]>-.<<").unwrap();
+        let source = emit(program.tokens(), target, EofBehavior::Unchanged);
+        if let Some(exe) = build(target, &source, &format!("{:?}-left", target)) {
+            let ran = Command::new(exe).output().unwrap();
+            assert_eq!(ran.status.code(), Some(3));
+            assert_eq!(ran.stdout, b"1");
+            assert_eq!(ran.stderr, b"E0403 Pointer OverFlow Error\n");
+        }
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    // without a compiler the shape is still pinned
+    let program = Program::compile(",[.,]").unwrap();
+    let c = emit(program.tokens(), Target::C, EofBehavior::Halt);
+    let expected = "\
+/* translated from brainfuck by bfjit */
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#define MEM MEMORY_SIZE
+
+static unsigned char tape[MEM];
+
+int main(void) {
+    size_t p = 0;
+    int c;
+    fflush(stdout);
+    if ((c = getchar()) != EOF) tape[p] = c;
+    else return 0;
+    while (tape[p]) {
+        putchar(tape[p]);
+        fflush(stdout);
+        if ((c = getchar()) != EOF) tape[p] = c;
+        else return 0;
+    }
+    return 0;
+}
+";
+    assert_eq!(c, expected.replace("MEMORY_SIZE", &MEMORY_SIZE.to_string()));
+    let rust = emit(program.tokens(), Target::Rust, EofBehavior::SetZero);
+    assert!(rust.contains("\n    let p = 0_usize;\n"));
+    assert!(rust.contains("\n        match input.next() {\n            Some(Ok(c)) => tape[p] = c,\n            _ => tape[p] = 0,\n        }\n"));
+    assert_eq!(Target::from_name("rust"), Some(Target::Rust));
+    assert_eq!(off_tape(-3, 5), "p < 3 || p + 2 > MEM");
+}
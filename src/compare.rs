@@ -0,0 +1,289 @@
+//! `bfjit --compare`: the JIT checked against the interpreter.
+//!
+//! Both engines run the same program on the same captured input, each on a
+//! zeroed tape of its own and into a buffer of its own, and the first place
+//! they part ways is reported: an output byte, an error one had and the
+//! other did not, the cells of the final tape, or where the pointer ended.
+//! Errors are told apart by code, since the engines word some details
+//! differently.
+
+use std::fmt;
+
+use crate::{
+    engine::{Engine, ExecContext, Interpreter, X86_64Jit},
+    program::Program,
+    tape::{DiffRegion, TapeDiff},
+    vm::{VmError, VmOptions, MEMORY_SIZE},
+};
+
+/// Where the two runs first disagree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Divergence {
+    /// The first byte the outputs differ at, `None` past the end of the
+    /// shorter one.
+    Output {
+        offset: usize,
+        interpreter: Option<u8>,
+        jit: Option<u8>,
+    },
+    /// One run failed and the other did not, or they failed differently;
+    /// `None` for a run that finished.
+    Error {
+        interpreter: Option<String>,
+        jit: Option<String>,
+    },
+    /// Every region the final tapes differ in, the interpreter's cells as
+    /// `a` and the JIT's as `b`.
+    Memory(Vec<DiffRegion>),
+    Pointer {
+        interpreter: usize,
+        jit: usize,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ComparisonResult {
+    /// Same output, same outcome, same tape.
+    Same {
+        output_bytes: usize,
+    },
+    Diverged(Divergence),
+    /// The JIT could not run the program here, so nothing was compared.
+    NoJit(String),
+}
+
+impl ComparisonResult {
+    pub fn is_same(&self) -> bool {
+        matches!(self, ComparisonResult::Same { .. })
+    }
+}
+
+// what one engine did
+struct Run {
+    output: Vec<u8>,
+    tape: Vec<u8>,
+    result: Result<usize, VmError>, // the final pointer
+}
+
+fn run_one(
+    engine: &mut dyn Engine,
+    program: &Program,
+    input: &[u8],
+    options: &VmOptions,
+    len: usize,
+) -> Run {
+    let mut tape = vec![0_u8; len];
+    let (mut input, mut output) = (input, vec![]);
+    let mut ctx =
+        ExecContext::new(&mut tape, &mut input, &mut output).with_options(options.clone());
+    let result = engine.run(program, &mut ctx).map(|outcome| outcome.pointer);
+    Run {
+        output,
+        tape,
+        result,
+    }
+}
+
+/// Run `program` on `input` under both engines with default options.
+pub fn run_compare(program: &Program, input: &[u8]) -> ComparisonResult {
+    run_compare_with(program, input, &VmOptions::default(), MEMORY_SIZE)
+}
+
+/// Run `program` on `input` under both engines with `options`, each on a
+/// tape of `tape_len` cells.
+pub fn run_compare_with(
+    program: &Program,
+    input: &[u8],
+    options: &VmOptions,
+    tape_len: usize,
+) -> ComparisonResult {
+    let jit = run_one(&mut X86_64Jit, program, input, options, tape_len);
+    if let Err(VmError::Jit(e)) = &jit.result {
+        return ComparisonResult::NoJit(e.to_string());
+    }
+    let interpreted = run_one(&mut Interpreter, program, input, options, tape_len);
+    match divergence(&interpreted, &jit) {
+        Some(divergence) => ComparisonResult::Diverged(divergence),
+        None => ComparisonResult::Same {
+            output_bytes: jit.output.len(),
+        },
+    }
+}
+
+fn divergence(interpreted: &Run, jit: &Run) -> Option<Divergence> {
+    let (a, b) = (&interpreted.output, &jit.output);
+    if a != b {
+        let offset = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+        return Some(Divergence::Output {
+            offset,
+            interpreter: a.get(offset).copied(),
+            jit: b.get(offset).copied(),
+        });
+    }
+    let code = |run: &Run| run.result.as_ref().err().map(VmError::code);
+    if code(interpreted) != code(jit) {
+        let message = |run: &Run| run.result.as_ref().err().map(VmError::to_string);
+        return Some(Divergence::Error {
+            interpreter: message(interpreted),
+            jit: message(jit),
+        });
+    }
+    let regions = TapeDiff::compare(&interpreted.tape, &jit.tape);
+    if !regions.is_empty() {
+        return Some(Divergence::Memory(regions));
+    }
+    match (&interpreted.result, &jit.result) {
+        (Ok(a), Ok(b)) if a != b => Some(Divergence::Pointer {
+            interpreter: *a,
+            jit: *b,
+        }),
+        _ => None,
+    }
+}
+
+impl fmt::Display for ComparisonResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let byte =
+            |b: &Option<u8>| b.map_or("end of output".to_string(), |b| format!("{:#04x}", b));
+        let error = |e: &Option<String>| e.clone().unwrap_or_else(|| "finished".to_string());
+        match self {
+            ComparisonResult::Same { output_bytes } => {
+                write!(f, "same: {} output bytes and the same tape", output_bytes)
+            }
+            ComparisonResult::NoJit(why) => write!(f, "not compared: {}", why),
+            ComparisonResult::Diverged(Divergence::Output {
+                offset,
+                interpreter,
+                jit,
+            }) => write!(
+                f,
+                "output differs at byte {}: interpreter {}, jit {}",
+                offset,
+                byte(interpreter),
+                byte(jit)
+            ),
+            ComparisonResult::Diverged(Divergence::Error { interpreter, jit }) => write!(
+                f,
+                "outcome differs: interpreter {}, jit {}",
+                error(interpreter),
+                error(jit)
+            ),
+            ComparisonResult::Diverged(Divergence::Memory(regions)) => write!(
+                f,
+                "tape differs, interpreter -> jit:\n{}",
+                TapeDiff::render(regions)
+            ),
+            ComparisonResult::Diverged(Divergence::Pointer { interpreter, jit }) => write!(
+                f,
+                "pointer differs: interpreter cell {}, jit cell {}",
+                interpreter, jit
+            ),
+        }
+    }
+}
+
+#[test]
+fn test_compare() {
+    use crate::{tokenizer::Dialect, vm::EofBehavior};
+
+    // every program that ships runs the same on both; halting at end of
+    // input ends both echo.bf and what dbfi.bf interprets
+    let options = VmOptions {
+        eof: EofBehavior::Halt,
+        ..Default::default()
+    };
+    let mut checked = 0;
+    for entry in std::fs::read_dir("bfcode").unwrap() {
+        let path = entry.unwrap().path();
+        let dialect = match path.extension().and_then(|e| e.to_str()) {
+            Some("bf") => Dialect::Standard,
+            Some("ebf") => Dialect::Ebf1,
+            _ => continue,
+        };
+        let src = std::fs::read_to_string(&path).unwrap();
+        let program = Program::compile_dialect(&src, Default::default(), dialect).unwrap();
+        let result = run_compare_with(&program, b",[.,]!compared\n", &options, 1 << 16);
+        // the JIT has no lowering for the extended ops
+        if !X86_64Jit::supported() || dialect == Dialect::Ebf1 {
+            assert!(matches!(result, ComparisonResult::NoJit(_)), "{}", result);
+            continue;
+        }
+        assert!(result.is_same(), "{}: {}", path.display(), result);
+        checked += 1;
+    }
+    assert_eq!(checked, if X86_64Jit::supported() { 3 } else { 0 });
+
+    // each kind of divergence, between runs made up to differ
+    let run = |output: &[u8], tape: &[u8], result: Result<usize, VmError>| Run {
+        output: output.to_vec(),
+        tape: tape.to_vec(),
+        result,
+    };
+    let base = run(b"abc", &[1, 2, 3], Ok(0));
+    assert_eq!(
+        divergence(&base, &run(b"abd", &[1, 2, 3], Ok(0))),
+        Some(Divergence::Output {
+            offset: 2,
+            interpreter: Some(b'c'),
+            jit: Some(b'd')
+        })
+    );
+    let short = divergence(&base, &run(b"ab", &[1, 2, 3], Ok(0)));
+    assert!(matches!(
+        short,
+        Some(Divergence::Output {
+            offset: 2,
+            jit: None,
+            ..
+        })
+    ));
+    let failed = run(b"abc", &[1, 2, 3], Err(VmError::PointerOverFlow(None)));
+    assert_eq!(
+        divergence(&base, &failed),
+        Some(Divergence::Error {
+            interpreter: None,
+            jit: Some("E0403 Pointer OverFlow Error".into())
+        })
+    );
+    assert_eq!(divergence(&failed, &failed), None);
+    assert_eq!(
+        divergence(&base, &run(b"abc", &[1, 2, 4], Ok(0))),
+        Some(Divergence::Memory(vec![DiffRegion {
+            start: 2,
+            a: vec![3],
+            b: vec![4]
+        }]))
+    );
+    let far = |last| run(b"abc", &[1, 2, 3, 0, 0, 0, last], Ok(0));
+    let differs = ComparisonResult::Diverged(divergence(&far(0), &far(9)).unwrap());
+    assert_eq!(
+        differs.to_string(),
+        "tape differs, interpreter -> jit:\ncells 6..7: [0] -> [9]"
+    );
+    let apart = run(b"abc", &[5, 2, 3, 0, 0, 0, 9], Ok(0));
+    let differs = ComparisonResult::Diverged(divergence(&far(0), &apart).unwrap());
+    assert_eq!(
+        differs.to_string(),
+        "tape differs, interpreter -> jit:\ncells 0..1: [1] -> [5]\ncells 6..7: [0] -> [9]"
+    );
+    let moved =
+        ComparisonResult::Diverged(divergence(&base, &run(b"abc", &[1, 2, 3], Ok(1))).unwrap());
+    assert_eq!(
+        moved.to_string(),
+        "pointer differs: interpreter cell 0, jit cell 1"
+    );
+    let diverged =
+        ComparisonResult::Diverged(divergence(&base, &run(b"ab", &[1, 2, 3], Ok(0))).unwrap());
+    assert_eq!(
+        diverged.to_string(),
+        "output differs at byte 2: interpreter 0x63, jit end of output"
+    );
+
+    // a program the JIT refuses is not compared, and breakpoints run
+    // through on both
+    let ebf = Program::compile_dialect("+~", Default::default(), Dialect::Ebf1).unwrap();
+    assert!(matches!(run_compare(&ebf, b""), ComparisonResult::NoJit(_)));
+    let debug = Program::compile_dialect("+#.", Default::default(), Dialect::Debug).unwrap();
+    let result = run_compare(&debug, b"");
+    assert!(!X86_64Jit::supported() || result.is_same(), "{}", result);
+}
@@ -0,0 +1,281 @@
+//! Program output on a terminal, the same bytes on every platform.
+//!
+//! Rust's standard streams never go through the C runtime's text mode: on
+//! Windows a `\n` is written as one byte and a 0x1A read from a pipe or file
+//! is data like any other, so redirected I/O is binary already. Only a Windows
+//! console gets different treatment. The standard library writes to one with
+//! `WriteConsoleW` and refuses anything that is not UTF-8, which a program
+//! printing arbitrary bytes hits at once.
+//!
+//! `ConsoleOutput` writes such a console with `WriteFile` instead, the way it
+//! writes a pipe, so the bytes arrive unchanged and show in the console's code
+//! page. With `--console-unicode` every complete UTF-8 sequence goes through
+//! the standard library's `WriteConsoleW` path instead, so it shows as the
+//! right characters; bytes that are not UTF-8 still go out raw. Without a
+//! Windows console both settings simply pass the bytes on.
+//!
+//! On a terminal of any kind every write is flushed at once, so a prompt
+//! shows before the program waits for input rather than sitting in the
+//! standard library's line buffer.
+
+use std::io::{self, IsTerminal, Write};
+
+/// A run of output in unicode mode: text for the console, or bytes that are
+/// not UTF-8.
+#[cfg(any(windows, test))]
+#[derive(Debug, PartialEq, Eq)]
+enum Piece<'a> {
+    Text(&'a str),
+    Raw(&'a [u8]),
+}
+
+// the first piece of `buf` and its length, or None when `buf` is empty or
+// only the start of a sequence
+#[cfg(any(windows, test))]
+fn next_piece(buf: &[u8]) -> Option<(Piece<'_>, usize)> {
+    let text = match std::str::from_utf8(buf) {
+        Ok(text) => text,
+        Err(e) if e.valid_up_to() > 0 => {
+            // just checked
+            std::str::from_utf8(&buf[..e.valid_up_to()]).unwrap()
+        }
+        Err(e) => {
+            let len = e.error_len()?;
+            return Some((Piece::Raw(&buf[..len]), len));
+        }
+    };
+    (!text.is_empty()).then_some((Piece::Text(text), text.len()))
+}
+
+pub struct ConsoleOutput<W: Write> {
+    inner: W,
+    terminal: bool, // flush after every write
+    #[cfg(windows)]
+    console: Option<windows::Console>, // stdout is a console
+    #[cfg(windows)]
+    unicode: bool,
+    #[cfg(windows)]
+    pending: Vec<u8>, // an unfinished sequence, in unicode mode
+}
+
+impl ConsoleOutput<io::Stdout> {
+    /// Standard output, with `unicode` as `--console-unicode`.
+    #[cfg_attr(not(windows), allow(unused_variables))]
+    pub fn stdout(unicode: bool) -> Self {
+        let inner = io::stdout();
+        ConsoleOutput {
+            terminal: inner.is_terminal(),
+            #[cfg(windows)]
+            console: windows::Console::of(&inner),
+            inner,
+            #[cfg(windows)]
+            unicode,
+            #[cfg(windows)]
+            pending: vec![],
+        }
+    }
+}
+
+impl<W: Write> ConsoleOutput<W> {
+    /// Output that is never a console, passing bytes on as they are.
+    pub fn new(inner: W) -> Self {
+        ConsoleOutput {
+            inner,
+            terminal: false,
+            #[cfg(windows)]
+            console: None,
+            #[cfg(windows)]
+            unicode: false,
+            #[cfg(windows)]
+            pending: vec![],
+        }
+    }
+
+    #[cfg(windows)]
+    fn write_console(&mut self, console: windows::Console, buf: &[u8]) -> io::Result<usize> {
+        if !self.unicode {
+            return console.write_raw(buf);
+        }
+        self.pending.extend_from_slice(buf);
+        let mut done = 0;
+        while let Some((piece, len)) = next_piece(&self.pending[done..]) {
+            match piece {
+                Piece::Text(text) => self.inner.write_all(text.as_bytes())?,
+                Piece::Raw(bytes) => {
+                    // keep the order with text still buffered by std
+                    self.inner.flush()?;
+                    console.write_all_raw(bytes)?;
+                }
+            }
+            done += len;
+        }
+        self.pending.drain(..done);
+        self.inner.flush()?;
+        Ok(buf.len())
+    }
+}
+
+impl<W: Write> Write for ConsoleOutput<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        #[cfg(windows)]
+        if let Some(console) = self.console {
+            return self.write_console(console, buf);
+        }
+        let n = self.inner.write(buf)?;
+        if self.terminal {
+            self.inner.flush()?;
+        }
+        Ok(n)
+    }
+
+    /// Also writes out an unfinished sequence, raw, since nothing can
+    /// complete it any more once the caller flushes at the end.
+    fn flush(&mut self) -> io::Result<()> {
+        #[cfg(windows)]
+        if let Some(console) = self.console {
+            self.inner.flush()?;
+            console.write_all_raw(&std::mem::take(&mut self.pending))?;
+        }
+        self.inner.flush()
+    }
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::{
+        ffi::c_void,
+        io,
+        os::windows::io::{AsRawHandle, RawHandle},
+        ptr,
+    };
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetConsoleMode(console: RawHandle, mode: *mut u32) -> i32;
+        fn WriteFile(
+            file: RawHandle,
+            buf: *const u8,
+            len: u32,
+            written: *mut u32,
+            overlapped: *mut c_void,
+        ) -> i32;
+    }
+
+    #[derive(Clone, Copy)]
+    pub struct Console(RawHandle);
+
+    impl Console {
+        /// The console behind `stream`, if it is one rather than a pipe or
+        /// file.
+        pub fn of(stream: &impl AsRawHandle) -> Option<Self> {
+            let handle = stream.as_raw_handle();
+            let mut mode = 0;
+            // SAFETY: the handle is the stream's for as long as it lives,
+            // and GetConsoleMode only fails for handles that are no console
+            (unsafe { GetConsoleMode(handle, &mut mode) } != 0).then_some(Console(handle))
+        }
+
+        pub fn write_raw(self, buf: &[u8]) -> io::Result<usize> {
+            let len = buf.len().min(u32::MAX as usize) as u32;
+            let mut written = 0;
+            // SAFETY: `buf` holds `len` bytes and the call is synchronous
+            let ok = unsafe { WriteFile(self.0, buf.as_ptr(), len, &mut written, ptr::null_mut()) };
+            match ok {
+                0 => Err(io::Error::last_os_error()),
+                _ => Ok(written as usize),
+            }
+        }
+
+        pub fn write_all_raw(self, mut buf: &[u8]) -> io::Result<()> {
+            while !buf.is_empty() {
+                match self.write_raw(buf)? {
+                    0 => return Err(io::ErrorKind::WriteZero.into()),
+                    n => buf = &buf[n..],
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_console_handle() {
+        // a file handle is never taken for a console
+        let path = std::env::temp_dir().join(format!("bfjit-console-{}", std::process::id()));
+        let file = std::fs::File::create(&path).unwrap();
+        assert!(Console::of(&file).is_none());
+        drop(file);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
+
+#[test]
+fn test_console_output() {
+    use crate::{
+        engine::{Engine, ExecContext, Interpreter},
+        program::Program,
+        vm::MEMORY_SIZE,
+    };
+
+    // every byte value from 255 down, then the input echoed up to a zero; a
+    // redirected stream must see exactly these bytes, with 0x0a and 0x1a
+    // neither translated nor taken for the end
+    let src = format!("{}[>{}<-]>[.-].,[.,]", "+".repeat(15), "+".repeat(17));
+    let mut expected: Vec<u8> = (0..=255u8).rev().collect();
+    let input = b"a\x1a\r\n\x1ab\n\xff\xfe";
+    expected.extend_from_slice(input);
+    let mut tape = vec![0; MEMORY_SIZE];
+    let mut output = ConsoleOutput::new(vec![]);
+    let mut reader = &[&input[..], b"\0"].concat()[..];
+    let mut ctx = ExecContext::new(&mut tape, &mut reader, &mut output);
+    Interpreter
+        .run(&Program::compile(&src).unwrap(), &mut ctx)
+        .unwrap();
+    output.flush().unwrap();
+    assert_eq!(output.inner, expected);
+
+    // on a terminal each write is out before the next one starts
+    #[derive(Default)]
+    struct Flushes(Vec<u8>, Vec<usize>);
+    impl Write for Flushes {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            self.1.push(self.0.len());
+            Ok(())
+        }
+    }
+    let mut terminal = ConsoleOutput::new(Flushes::default());
+    terminal.terminal = true;
+    let (mut tape, mut input) = (vec![0; 4], &b"\0"[..]);
+    let mut ctx = ExecContext::new(&mut tape, &mut input, &mut terminal);
+    let prompt = Program::compile("++++++[>++++++++++<-]>+++.+.,").unwrap();
+    Interpreter.run(&prompt, &mut ctx).unwrap();
+    assert_eq!(terminal.inner.0, b"?@");
+    assert_eq!(terminal.inner.1[..2], [1, 2]);
+
+    // how unicode mode splits output between the console and raw writes
+    let mut pieces = vec![];
+    let buf = "añ€😀"
+        .as_bytes()
+        .iter()
+        .chain(b"\xff\x80z\xe2\x82")
+        .copied();
+    let buf: Vec<u8> = buf.collect();
+    let mut at = 0;
+    while let Some((piece, len)) = next_piece(&buf[at..]) {
+        pieces.push(piece);
+        at += len;
+    }
+    assert_eq!(
+        pieces,
+        [
+            Piece::Text("añ€😀"),
+            Piece::Raw(b"\xff"),
+            Piece::Raw(b"\x80"),
+            Piece::Text("z")
+        ]
+    );
+    // the start of a `€` waits for the rest
+    assert_eq!(&buf[at..], b"\xe2\x82");
+}
@@ -0,0 +1,154 @@
+//! `bfjit doctor`: what works on this machine, and why not when it does not.
+//!
+//! Every line is `name: ok (detail)` or `name: unavailable (reason)`, in a
+//! fixed order, so the report can be pasted into a bug or read by a script.
+//! Engine lines come from `Engine::detect`, the same answer that decides
+//! whether `EngineRegistry::builtin` offers the engine at all.
+
+use std::{env, fs, process};
+
+use crate::{
+    engine::{Engine, Interpreter, X86_64Jit},
+    jit,
+    tape_file::TapeFile,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub name: String,
+    pub result: Result<String, String>, // what works, or why it does not
+}
+
+impl Check {
+    fn new(name: impl Into<String>, result: Result<String, String>) -> Self {
+        Check {
+            name: name.into(),
+            result,
+        }
+    }
+
+    fn engine<E: Engine>(engine: E) -> Self {
+        let result = E::detect().map(|()| "runs programs here".to_string());
+        Check::new(format!("engine {}", engine.name()), result)
+    }
+}
+
+/// The engine a user most likely wants: the JIT where there is a backend for
+/// this architecture, the interpreter elsewhere.
+pub fn preferred_engine() -> &'static str {
+    if cfg!(target_arch = "x86_64") {
+        X86_64Jit.name()
+    } else {
+        Interpreter.name()
+    }
+}
+
+// map a small tape file the way `--tape-file` does, then remove it again
+fn probe_tape_file() -> Result<String, String> {
+    let path = env::temp_dir().join(format!("bfjit-doctor-{}.tape", process::id()));
+    let result = TapeFile::open(&path, Some(4096)).and_then(|mut tape| {
+        tape[0] = 1;
+        tape.flush()
+    });
+    drop(fs::remove_file(&path));
+    result
+        .map(|()| "shared file mappings work".to_string())
+        .map_err(|e| e.to_string())
+}
+
+pub fn checks() -> Vec<Check> {
+    let exec = jit::probe().map(|()| {
+        if cfg!(target_arch = "x86_64") {
+            "mapped and ran a stub".to_string()
+        } else {
+            "mapped a stub, no backend to run it".to_string()
+        }
+    });
+    let arena = jit::CodeArena::global().stats();
+    let arena = format!(
+        "{} chunks mapped, {} programs resident",
+        arena.chunks, arena.programs
+    );
+    let w_xor_x = if cfg!(target_os = "linux") {
+        Ok("code is written through a second, non-executable mapping".to_string())
+    } else if cfg!(target_os = "macos") {
+        Err("needs MAP_JIT, which this build does not use".to_string())
+    } else {
+        Ok("code is written, then remapped read+execute".to_string())
+    };
+    let feature = |name, enabled| {
+        let result = match enabled {
+            true => Ok("built in".to_string()),
+            false => Err(format!("built without --features {}", name)),
+        };
+        Check::new(format!("feature {}", name), result)
+    };
+    vec![
+        Check::new(
+            "platform",
+            Ok(format!("{} {}", env::consts::OS, env::consts::ARCH)),
+        ),
+        Check::new("executable memory", exec),
+        Check::new("code arena", Ok(arena)),
+        Check::new("w^x", w_xor_x),
+        Check::new(
+            "guard pages",
+            Err("not used, generated code checks every pointer move".to_string()),
+        ),
+        Check::new("mmap tape", probe_tape_file()),
+        Check::new(
+            "large pages",
+            Err("tapes are mapped with normal pages".to_string()),
+        ),
+        feature("oracle", cfg!(feature = "oracle")),
+        Check::engine(Interpreter),
+        Check::engine(X86_64Jit),
+    ]
+}
+
+pub fn render(checks: &[Check]) -> String {
+    let mut out = String::new();
+    for check in checks {
+        let (status, detail) = match &check.result {
+            Ok(detail) => ("ok", detail),
+            Err(reason) => ("unavailable", reason),
+        };
+        out += &format!("{}: {} ({})\n", check.name, status, detail);
+    }
+    out
+}
+
+/// Whether `engine` is usable according to `checks`; unknown names are not.
+pub fn engine_available(checks: &[Check], engine: &str) -> bool {
+    let name = format!("engine {}", engine);
+    checks
+        .iter()
+        .any(|check| check.name == name && check.result.is_ok())
+}
+
+#[test]
+fn test_doctor() {
+    let checks = checks();
+    let report = render(&checks);
+    let mut names = vec![];
+    for line in report.lines() {
+        let (name, rest) = line.split_once(": ").expect(line);
+        let (status, detail) = rest.split_once(" (").expect(line);
+        assert!(["ok", "unavailable"].contains(&status), "{}", line);
+        assert!(detail.ends_with(')') && detail.len() > 1, "{}", line);
+        names.push(name);
+    }
+    assert_eq!(names.len(), checks.len());
+    assert_eq!(names[0], "platform");
+    assert!(report.contains("engine interpreter: ok (runs programs here)\n"));
+    assert!(engine_available(&checks, "interpreter"));
+    assert!(!engine_available(&checks, "no-such-engine"));
+
+    // the report agrees with what the registry offers
+    let registry = crate::engine::EngineRegistry::builtin();
+    assert_eq!(
+        engine_available(&checks, "jit-x86_64"),
+        registry.names().contains(&"jit-x86_64")
+    );
+    assert_eq!(render(&checks), render(&self::checks()));
+}
@@ -0,0 +1,152 @@
+//! Source text kept lexed across edits, for the LSP.
+//!
+//! An edit replaces a byte range with new text. Only the new text is lexed;
+//! ops before the edit stay as they are, ops inside it are dropped, and ops
+//! after it keep their tokens with spans shifted: by the change in lines,
+//! and for the rest of the edit's last line also by the change in columns.
+//! Linking is cheap next to lexing and runs over the whole op list when
+//! asked. The result is always exactly what `tokenizer::lex` gives for the
+//! new text.
+
+use std::ops::Range;
+
+use crate::tokenizer::{self, Dialect, RawOp, Span, Token, TokenizerError};
+
+pub struct Document {
+    text: String,
+    ops: Vec<RawOp>,
+    offsets: Vec<usize>, // byte offset of each op in `text`
+}
+
+impl Document {
+    pub fn new(text: impl Into<String>) -> Self {
+        let mut doc = Document {
+            text: String::new(),
+            ops: vec![],
+            offsets: vec![],
+        };
+        doc.edit(0..0, &text.into());
+        doc
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn ops(&self) -> &[RawOp] {
+        &self.ops
+    }
+
+    /// `tokenizer::link_recover` of the current ops.
+    pub fn link(&self) -> (Vec<Token>, Vec<TokenizerError>) {
+        tokenizer::link_recover(&self.ops)
+    }
+
+    /// The byte offset of a 0-based line and character, as LSP positions
+    /// count them; past the end of a line is its end, past the last line the
+    /// end of the text.
+    pub fn offset(&self, line: usize, character: usize) -> usize {
+        let mut start = 0;
+        for _ in 0..line {
+            match self.text[start..].find('\n') {
+                Some(i) => start += i + 1,
+                None => return self.text.len(),
+            }
+        }
+        let rest = &self.text[start..];
+        let end = rest.find('\n').unwrap_or(rest.len());
+        let at = rest[..end].char_indices().nth(character);
+        start + at.map_or(end, |(i, _)| i)
+    }
+
+    // line and column of the last character before `offset`, lexing from
+    // the nearest op in front of it
+    fn position(&self, offset: usize) -> Span {
+        let before = self.offsets.partition_point(|&o| o < offset);
+        let (from, span) = match before.checked_sub(1) {
+            // every command is a single byte
+            Some(i) => (self.offsets[i] + 1, self.ops[i].span),
+            None => (0, Span { line: 1, col: 0 }),
+        };
+        tokenizer::lex_each(&self.text[from..offset], Dialect::Standard, span, |_, _| {})
+    }
+
+    /// Replace the bytes in `range` with `new`; both ends must be character
+    /// boundaries.
+    pub fn edit(&mut self, range: Range<usize>, new: &str) {
+        assert!(self.text.is_char_boundary(range.start) && self.text.is_char_boundary(range.end));
+        let (start, end) = (self.position(range.start), self.position(range.end));
+        let mut ops = vec![];
+        let mut offsets = vec![];
+        let next = tokenizer::lex_each(new, Dialect::Standard, start, |offset, op| {
+            offsets.push(range.start + offset);
+            ops.push(op);
+        });
+
+        // shift what follows the edit
+        let lo = self.offsets.partition_point(|&o| o < range.start);
+        let hi = self.offsets.partition_point(|&o| o < range.end);
+        let grown = new.len() as isize - range.len() as isize;
+        for (op, offset) in self.ops[hi..].iter_mut().zip(&mut self.offsets[hi..]) {
+            if op.span.line == end.line {
+                op.span.col += next.col - end.col;
+            }
+            op.span.line += next.line - end.line;
+            *offset = offset.wrapping_add_signed(grown);
+        }
+        self.ops.splice(lo..hi, ops);
+        self.offsets.splice(lo..hi, offsets);
+        self.text.replace_range(range, new);
+    }
+}
+
+#[test]
+fn test_document() {
+    let mut doc = Document::new("+[->++<\n]]");
+    assert_eq!(doc.ops(), tokenizer::lex(doc.text()));
+    assert_eq!(doc.link().1.len(), 1);
+    let at = doc.offset(1, 1);
+    doc.edit(at..at + 1, "");
+    assert_eq!(doc.text(), "+[->++<\n]");
+    assert!(doc.link().1.is_empty());
+    assert_eq!(
+        (doc.offset(0, 99), doc.offset(1, 0), doc.offset(5, 0)),
+        (7, 8, 9)
+    );
+    assert_eq!(Document::new("é+\n+").offset(0, 1), 2);
+
+    // random edit sequences agree with lexing the result from scratch
+    let mut seed: u64 = 0x9e37_79b9_7f4a_7c15;
+    let mut next = |n: usize| {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        (seed % n as u64) as usize
+    };
+    let pieces = [
+        "+", "-", "[", "]", "<>", ".,", "\n", "\n\n", "x", "é", "!", "[-]\n", "",
+    ];
+    for _ in 0..200 {
+        let mut doc = Document::new("");
+        for _ in 0..30 {
+            let mut new = String::new();
+            for _ in 0..next(6) {
+                new += pieces[next(pieces.len())];
+            }
+            let boundaries: Vec<usize> = (0..=doc.text().len())
+                .filter(|&i| doc.text().is_char_boundary(i))
+                .collect();
+            let a = boundaries[next(boundaries.len())];
+            let b = boundaries[next(boundaries.len())];
+            doc.edit(a.min(b)..a.max(b), &new);
+            assert_eq!(doc.ops(), tokenizer::lex(doc.text()), "{:?}", doc.text());
+            let offsets: Vec<usize> = doc
+                .text()
+                .char_indices()
+                .filter(|&(_, c)| "+-<>,.[]".contains(c))
+                .map(|(i, _)| i)
+                .collect();
+            assert_eq!(doc.offsets, offsets);
+        }
+    }
+}
@@ -0,0 +1,299 @@
+//! One interface over every way of executing a `Program`.
+//!
+//! An `Engine` runs a program against the tape, I/O and options bundled in an
+//! `ExecContext`. The interpreter always works; each JIT backend is its own
+//! engine that only reports `supported()` on a host that can run its code,
+//! and `detect()` says why not when it cannot.
+//! `EngineRegistry::builtin()` holds every supported engine, and outside code
+//! can `register` its own next to them.
+
+use std::{
+    io::{Read, Write},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    jit::{self, Backend, JitError},
+    program::Program,
+    tape::Tape,
+    tokenizer::Span,
+    trace::Tracer,
+    vm::{TapeMode, Termination, VmError, VmOptions, VM},
+};
+
+/// Everything a run reads or writes besides the program itself.
+pub struct ExecContext<'a> {
+    pub tape: &'a mut [u8],
+    pub input: &'a mut dyn Read,
+    pub output: &'a mut dyn Write,
+    pub options: VmOptions,
+    /// Source position of the failing instruction after an error, when the
+    /// engine can tell.
+    pub error_span: Option<Span>,
+    /// Data pointer after an error, when the engine can tell.
+    pub error_pointer: Option<usize>,
+    /// Executions of each instruction after a run with `options.profile`.
+    pub profile: Option<Vec<u64>>,
+    /// Where the interpreter records each instruction it runs, handed back
+    /// after the run; other engines leave it be.
+    pub trace: Option<Tracer>,
+}
+
+impl<'a> ExecContext<'a> {
+    pub fn new(tape: &'a mut [u8], input: &'a mut dyn Read, output: &'a mut dyn Write) -> Self {
+        ExecContext {
+            tape,
+            input,
+            output,
+            options: VmOptions::default(),
+            error_span: None,
+            error_pointer: None,
+            profile: None,
+            trace: None,
+        }
+    }
+
+    pub fn with_options(mut self, options: VmOptions) -> Self {
+        self.options = options;
+        self
+    }
+}
+
+/// How a successful run ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Outcome {
+    pub termination: Termination,
+    pub pointer: usize,
+    /// Instructions executed, for engines that count them.
+    pub steps: Option<u64>,
+    /// Time spent generating code before the run, for compiling engines.
+    pub compile_time: Option<Duration>,
+}
+
+pub trait Engine {
+    /// Run `program` from cell 0 of `ctx.tape`, leaving the final cells there.
+    fn run(&mut self, program: &Program, ctx: &mut ExecContext) -> Result<Outcome, VmError>;
+
+    fn name(&self) -> &str;
+
+    /// Whether this engine can run on this machine, with the reason when it
+    /// cannot. `bfjit doctor` prints this same answer.
+    fn detect() -> Result<(), String>
+    where
+        Self: Sized;
+
+    fn supported() -> bool
+    where
+        Self: Sized,
+    {
+        Self::detect().is_ok()
+    }
+}
+
+/// The tree-walking `VM`; the only engine honouring the limits in
+/// `VmOptions` and `profile`.
+///
+/// Under `TapeMode::Grow` it runs on a copy of `ctx.tape`, which can grow,
+/// and copies back as many cells as `ctx.tape` holds. `Bidirectional` does
+/// the same around cell 0, which is the middle of `ctx.tape`; pointers are
+/// indices into `ctx.tape`, the first cell for one that went further left.
+#[derive(Debug, Default)]
+pub struct Interpreter;
+
+impl Engine for Interpreter {
+    fn run(&mut self, program: &Program, ctx: &mut ExecContext) -> Result<Outcome, VmError> {
+        let program = Arc::new(program.clone());
+        let (grow, len) = (ctx.options.tape_mode != TapeMode::Fixed, ctx.tape.len());
+        let tape = match grow {
+            true => Tape::Flat(ctx.tape.to_vec().into_boxed_slice()),
+            false => Tape::Borrowed(&mut *ctx.tape),
+        };
+        let mut vm = VM::build(program, tape)?
+            .with_options(ctx.options.clone())
+            .with_io(&mut *ctx.input, &mut *ctx.output);
+        if let Some(tracer) = ctx.trace.take() {
+            vm = vm.with_trace(tracer);
+        }
+        // breakpoints are for a caller driving the `VM` itself; a run goes on
+        let mut result = vm.run();
+        while result.is_ok() && !vm.halted() {
+            result = vm.resume();
+        }
+        ctx.profile = vm.profile().map(<[u64]>::to_vec);
+        ctx.trace = vm.take_trace();
+        // where `ctx.tape` starts on the VM's tape, once it grew at the front
+        let start = vm.origin() - ctx.options.tape_mode.origin(len);
+        let pointer = vm.pointer().saturating_sub(start);
+        let result = match result {
+            Ok(()) => Ok(Outcome {
+                termination: vm.stats().termination,
+                pointer,
+                steps: Some(vm.stats().steps),
+                compile_time: None,
+            }),
+            Err(e) => {
+                ctx.error_span = vm.current_span();
+                ctx.error_pointer = Some(pointer);
+                Err(e)
+            }
+        };
+        let grown = grow.then(|| vm.cells(start..start + len));
+        drop(vm);
+        if let Some(cells) = grown {
+            ctx.tape.copy_from_slice(&cells);
+        }
+        result
+    }
+
+    fn name(&self) -> &str {
+        "interpreter"
+    }
+
+    fn detect() -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Native code from the x86-64 backend.
+#[derive(Debug, Default)]
+pub struct X86_64Jit;
+
+impl Engine for X86_64Jit {
+    fn run(&mut self, program: &Program, ctx: &mut ExecContext) -> Result<Outcome, VmError> {
+        run_jit(Backend::X86_64, program, ctx)
+    }
+
+    fn name(&self) -> &str {
+        "jit-x86_64"
+    }
+
+    fn detect() -> Result<(), String> {
+        if Backend::host() != Some(Backend::X86_64) {
+            return Err(format!(
+                "generates x86_64 code, this is {} {}",
+                std::env::consts::OS,
+                std::env::consts::ARCH
+            ));
+        }
+        jit::probe()
+    }
+}
+
+fn run_jit(backend: Backend, program: &Program, ctx: &mut ExecContext) -> Result<Outcome, VmError> {
+    if let Some(option) = ctx.options.jit_unsupported() {
+        return Err(JitError::Unsupported(option).into());
+    }
+    let start = Instant::now();
+    let code = match ctx.options.fuel {
+        Some(fuel) => jit::compile_with_fuel(backend, program.tokens(), fuel)?,
+        None => jit::compile(backend, program.tokens())?,
+    };
+    let compile_time = start.elapsed();
+    let origin = ctx.options.tape_mode.origin(ctx.tape.len());
+    let exit = code
+        .run(ctx.tape, origin, ctx.input, ctx.output, ctx.options.eof)
+        .map_err(|e| e.located(program));
+    if let Err(VmError::PointerOverFlow(Some(fault))) = &exit {
+        ctx.error_span = fault.span;
+        ctx.error_pointer = Some(fault.pointer);
+    }
+    let exit = exit?;
+    ctx.output.flush()?;
+    Ok(Outcome {
+        termination: exit.termination,
+        pointer: exit.pointer,
+        steps: None,
+        compile_time: Some(compile_time),
+    })
+}
+
+/// The engines available to the CLI, by name.
+#[derive(Default)]
+pub struct EngineRegistry {
+    engines: Vec<Box<dyn Engine>>,
+}
+
+impl EngineRegistry {
+    /// Every built-in engine this machine supports, the interpreter first.
+    pub fn builtin() -> Self {
+        let mut registry = EngineRegistry::default();
+        registry.register(Interpreter);
+        registry.register(X86_64Jit);
+        registry
+    }
+
+    /// Add `engine`, unless it cannot run here.
+    pub fn register<E: Engine + 'static>(&mut self, engine: E) {
+        if E::supported() {
+            self.engines.push(Box::new(engine));
+        }
+    }
+
+    pub fn get(&mut self, name: &str) -> Option<&mut dyn Engine> {
+        self.engines
+            .iter_mut()
+            .find(|engine| engine.name() == name)
+            .map(|engine| &mut **engine as &mut dyn Engine)
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.engines.iter().map(|engine| engine.name()).collect()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut dyn Engine> + '_ {
+        self.engines
+            .iter_mut()
+            .map(|engine| &mut **engine as &mut dyn Engine)
+    }
+}
+
+#[test]
+fn test_every_engine() {
+    use crate::vm::EofBehavior;
+
+    let hellow = std::fs::read_to_string("bfcode/hellow.bf").unwrap();
+    let echo = std::fs::read_to_string("bfcode/echo.bf").unwrap();
+    let halt = VmOptions {
+        eof: EofBehavior::Halt,
+        ..Default::default()
+    };
+    let corpus = [
+        (hellow.as_str(), &b""[..], VmOptions::default()),
+        (echo.as_str(), &b"echo me"[..], halt),
+    ];
+
+    let mut registry = EngineRegistry::builtin();
+    assert_eq!(registry.names()[0], "interpreter");
+    for (src, input, options) in corpus {
+        let program = Program::compile(src).unwrap();
+        let mut results = vec![];
+        for engine in registry.iter_mut() {
+            let mut tape = vec![0_u8; 1024];
+            let (mut input, mut output) = (input, vec![]);
+            let mut ctx =
+                ExecContext::new(&mut tape, &mut input, &mut output).with_options(options.clone());
+            let outcome = engine.run(&program, &mut ctx).unwrap();
+            results.push((engine.name().to_string(), outcome.pointer, output, tape));
+        }
+        let (_, pointer, output, tape) = &results[0];
+        assert!(!output.is_empty());
+        for (name, p, o, t) in &results[1..] {
+            assert_eq!((p, o, t), (pointer, output, tape), "{}", name);
+        }
+    }
+
+    // the interpreter reports where it failed; the JIT refuses loop limits
+    let program = Program::compile("+[>+]").unwrap();
+    let mut tape = vec![0_u8; 8];
+    let (mut input, mut output) = (&b""[..], vec![]);
+    let mut ctx = ExecContext::new(&mut tape, &mut input, &mut output);
+    let err = Interpreter.run(&program, &mut ctx).unwrap_err();
+    assert!(matches!(err, VmError::PointerOverFlow(_)));
+    assert!(ctx.error_span.is_some());
+    if X86_64Jit::supported() {
+        ctx.options.max_loop_iterations = Some(10);
+        let err = X86_64Jit.run(&program, &mut ctx).unwrap_err();
+        assert!(matches!(err, VmError::Jit(JitError::Unsupported(_))));
+    }
+}
@@ -0,0 +1,164 @@
+//! Coarse error categories shared by every error type.
+//!
+//! Each error variant has a stable code, `E` and four digits, returned by its
+//! `code()` and printed at the start of its message. The first two digits
+//! name the component and never change meaning; codes are never reused once
+//! a variant is gone.
+//!
+//! ```text
+//! E01xx  tokenizer        E05xx  JIT
+//! E02xx  IR text          E06xx  bench
+//! E03xx  bytecode         E07xx  image
+//! E04xx  VM               E08xx  checkpoint
+//! ```
+//!
+//! Errors that wrap another one report the wrapped error's code.
+
+use crate::tokenizer::Span;
+
+/// What kind of failure an error is, for deciding how to react to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ErrorCategory {
+    /// The program could not be parsed or loaded.
+    Compile,
+    /// The program went wrong while running.
+    Runtime,
+    /// Reading input, writing output or loading a file failed.
+    Io,
+    /// A configured limit stopped the run.
+    Limit,
+}
+
+impl ErrorCategory {
+    /// Process exit status for a failure in this category; 1 is left for
+    /// usage errors.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ErrorCategory::Compile => 2,
+            ErrorCategory::Runtime => 3,
+            ErrorCategory::Io => 4,
+            ErrorCategory::Limit => 5,
+        }
+    }
+}
+
+/// `error` followed by each of its sources, separated by `: `.
+pub fn report(error: &dyn std::error::Error) -> String {
+    let mut report = error.to_string();
+    let mut source = error.source();
+    while let Some(e) = source {
+        report.push_str(": ");
+        report.push_str(&e.to_string());
+        source = e.source();
+    }
+    report
+}
+
+/// The line of `src` that `span` points into, with a caret under its column:
+///
+/// ```text
+///  3 | ++>[<-]<<
+///    |        ^
+/// ```
+pub fn snippet(src: &str, span: Span) -> Option<String> {
+    let line = src
+        .lines()
+        .nth(usize::try_from(span.line).ok()?.checked_sub(1)?)?;
+    let col = usize::try_from(span.col).ok()?.checked_sub(1)?;
+    line.chars().nth(col)?;
+    // tabs stay tabs so the caret lines up however wide they are shown
+    let pad: String = line
+        .chars()
+        .take(col)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let number = span.line.to_string();
+    let gutter = " ".repeat(number.len());
+    Some(format!(" {} | {}\n {} | {}^\n", number, line, gutter, pad))
+}
+
+#[test]
+fn test_error_codes() {
+    use crate::{
+        bench::BenchError,
+        jit::JitError,
+        program::Program,
+        tokenizer::{ExtOp, Token},
+        vm::VmError,
+    };
+    use std::error::Error;
+
+    fn depth(e: &dyn Error) -> usize {
+        1 + e.source().map_or(0, depth)
+    }
+
+    let vm_errors = [
+        (
+            VmError::from(Program::compile("[").unwrap_err()),
+            "E0102",
+            2,
+        ),
+        (VmError::from(std::io::Error::other("gone")), "E0402", 2),
+        (
+            VmError::from(Program::from_bytecode(b"nope").unwrap_err()),
+            "E0301",
+            2,
+        ),
+        (
+            VmError::from(JitError::Unsupported("max_loop_iterations")),
+            "E0503",
+            2,
+        ),
+        (
+            VmError::from(JitError::UnsupportedToken(Token::Ext(ExtOp::Halt))),
+            "E0504",
+            2,
+        ),
+        (VmError::PointerOverFlow(None), "E0403", 1),
+        (VmError::OutOfFuel(100), "E0405", 1),
+        (VmError::NativeOutOfFuel(100), "E0405", 1),
+    ];
+    let categories = [
+        ErrorCategory::Compile,
+        ErrorCategory::Io,
+        ErrorCategory::Compile,
+        ErrorCategory::Runtime,
+        ErrorCategory::Runtime,
+        ErrorCategory::Runtime,
+        ErrorCategory::Limit,
+        ErrorCategory::Limit,
+    ];
+    for ((e, code, chain), category) in vm_errors.into_iter().zip(categories) {
+        assert_eq!((e.code(), e.category()), (code, category), "{}", e);
+        assert!(e.to_string().starts_with(code), "{}", e);
+        assert_eq!(depth(&e), chain, "{}", e);
+    }
+
+    // wrapping keeps both the inner code and the whole chain
+    let inner = VmError::from(Program::from_bytecode(b"nope").unwrap_err());
+    let e = BenchError::Run("jit-x86_64".to_string(), inner);
+    assert_eq!((e.code(), e.category()), ("E0301", ErrorCategory::Compile));
+    assert!(e.to_string().starts_with("E0301"));
+    assert_eq!(depth(&e), 3);
+    assert_eq!(
+        report(&e),
+        "E0301 Engine jit-x86_64 Failed: E0301 Bytecode Error: E0301 Not a bytecode file"
+    );
+    assert_eq!(ErrorCategory::Limit.exit_code(), 5);
+}
+
+#[test]
+fn test_snippet() {
+    let src = "++\n>[<-]\t<<\n";
+    let span = |line, col| Span { line, col };
+    assert_eq!(
+        snippet(src, span(2, 8)).unwrap(),
+        " 2 | >[<-]\t<<\n   |      \t ^\n"
+    );
+    assert_eq!(snippet(src, span(1, 1)).unwrap(), " 1 | ++\n   | ^\n");
+    // nothing to point at past the text
+    assert_eq!(snippet(src, span(2, 10)), None);
+    assert_eq!(snippet(src, span(3, 1)), None);
+    assert_eq!(snippet(src, span(0, 0)), None);
+}
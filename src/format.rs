@@ -0,0 +1,74 @@
+//! Brainfuck re-indented by loop depth, as the LSP formats a document.
+//!
+//! Only whitespace at the ends of lines changes, and whitespace is comment,
+//! so the commands are exactly the ones there were. Each line is indented
+//! two spaces for every `[` still open where it starts, less any `]` the
+//! line opens with; runs of blank lines become one, and the text ends in a
+//! single newline. A `#!` first line is left as it is.
+
+use crate::tokenizer::{self, Token};
+
+const INDENT: &str = "  ";
+
+/// `src` formatted; formatting that again changes nothing.
+pub fn format(src: &str) -> String {
+    let ops = tokenizer::lex(src);
+    let mut ops = ops.iter().peekable();
+    let (mut out, mut depth, mut blank) = (String::new(), 0_usize, false);
+    for (i, line) in src.lines().enumerate() {
+        let number = i as i32 + 1;
+        let mut on_line = vec![];
+        while let Some(op) = ops.next_if(|op| op.span.line == number) {
+            on_line.push(op.token);
+        }
+        if i == 0 && line.starts_with("#!") {
+            out.push_str(line);
+            out.push('\n');
+            continue;
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            // held back until a line with text follows
+            blank = !out.is_empty();
+            continue;
+        }
+        if blank {
+            out.push('\n');
+            blank = false;
+        }
+        let closing = on_line
+            .iter()
+            .take_while(|token| matches!(token, Token::LoopEnd(_)))
+            .count();
+        out.push_str(&INDENT.repeat(depth.saturating_sub(closing)));
+        out.push_str(line);
+        out.push('\n');
+        for token in on_line {
+            match token {
+                Token::LoopStart(_) => depth += 1,
+                Token::LoopEnd(_) => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+        }
+    }
+    out
+}
+
+#[test]
+fn test_format() {
+    let src = "#!/usr/bin/env bfjit\n\n\n++[  \n>++[\n>+<-\n   ]   print it\n\n\n\n<-]>>.\r\n\n";
+    let formatted = format(src);
+    assert_eq!(
+        formatted,
+        "#!/usr/bin/env bfjit\n\n++[\n  >++[\n    >+<-\n  ]   print it\n\n  <-]>>.\n"
+    );
+    assert_eq!(format(&formatted), formatted);
+    let tokens = |src: &str| tokenizer::tokenizer(tokenizer::strip_shebang(src)).unwrap();
+    assert_eq!(tokens(&formatted), tokens(src));
+
+    // a stray `]` does not indent below the left edge, and a comment line
+    // indents like any other
+    assert_eq!(format("]\n  ]]\n[\nnote\n"), "]\n]]\n[\n  note\n");
+    assert_eq!(format(""), "");
+    assert_eq!(format("\n \n"), "");
+}
@@ -0,0 +1,206 @@
+//! Random well-formed programs for fuzzing and benchmarks.
+//!
+//! The output depends only on the seed and the settings: the generator uses
+//! its own `Rng` state and integer arithmetic, so a failing seed reproduces
+//! on every platform. Brackets are always balanced, the pointer never moves
+//! left of cell 0, and with `terminating(true)` every loop is shaped so it has
+//! to end:
+//!
+//! ```text
+//! [-  body  ]    body returns to the counter cell and never writes it
+//! ```
+//!
+//! Such loops run at most 255 times per entry, and they nest at most two deep
+//! so the running time stays small as well as finite.
+
+use crate::rng::Rng;
+
+// a loop that is open gets closed with this probability at each step
+const CLOSE_PROBABILITY: f64 = 0.2;
+
+// deepest loop nesting in terminating mode
+const TERMINATING_DEPTH: usize = 2;
+
+// keep the pointer within the first cells of the tape
+const MAX_CELL: usize = 4096;
+
+pub struct ProgramGenerator {
+    rng: Rng,
+    max_len: usize,
+    loop_probability: f64,
+    io_probability: f64,
+    terminating: bool,
+}
+
+impl ProgramGenerator {
+    pub fn new(seed: u64) -> Self {
+        ProgramGenerator {
+            rng: Rng::new(seed),
+            max_len: 100,
+            loop_probability: 0.1,
+            io_probability: 0.05,
+            terminating: false,
+        }
+    }
+
+    /// Upper bound on the length of each program, in characters.
+    pub fn max_len(mut self, n: usize) -> Self {
+        self.max_len = n;
+        self
+    }
+
+    /// Chance that an instruction opens a loop.
+    pub fn loop_probability(mut self, p: f64) -> Self {
+        self.loop_probability = p;
+        self
+    }
+
+    /// Chance that an instruction is `.` or `,`.
+    pub fn io_probability(mut self, q: f64) -> Self {
+        self.io_probability = q;
+        self
+    }
+
+    /// Only generate programs that are guaranteed to halt.
+    pub fn terminating(mut self, yes: bool) -> Self {
+        self.terminating = yes;
+        self
+    }
+
+    pub fn generate(&mut self) -> String {
+        let len = self.max_len;
+        let mut out = String::with_capacity(len);
+        let mut pos = 0; // cell the pointer is on
+        let mut loops: Vec<usize> = vec![]; // counter cell of each open loop
+
+        // characters needed to close every open loop from `at`
+        let closing = |loops: &[usize], mut at: usize| {
+            let mut cost = 0;
+            for &cell in loops.iter().rev() {
+                cost += at.abs_diff(cell) + 1;
+                at = cell;
+            }
+            cost
+        };
+
+        loop {
+            let room = len - out.len() - closing(&loops, pos);
+            if !loops.is_empty() && (room == 0 || self.rng.chance(CLOSE_PROBABILITY)) {
+                let cell = loops.pop().unwrap();
+                let moves = if cell < pos { '<' } else { '>' };
+                out.extend(std::iter::repeat_n(moves, pos.abs_diff(cell)));
+                out.push(']');
+                pos = cell;
+                continue;
+            }
+            if room == 0 {
+                break;
+            }
+
+            // in terminating mode the open loops' counters are off limits
+            let writable = !self.terminating || !loops.contains(&pos);
+            let open_cost = if self.terminating { 3 } else { 2 };
+            let nestable = !self.terminating || loops.len() < TERMINATING_DEPTH;
+            if writable && nestable && room >= open_cost && self.rng.chance(self.loop_probability) {
+                out.push_str(if self.terminating { "[-" } else { "[" });
+                loops.push(pos);
+                continue;
+            }
+            if self.rng.chance(self.io_probability) {
+                out.push(if writable && self.rng.below(2) == 0 {
+                    ','
+                } else {
+                    '.'
+                });
+                continue;
+            }
+
+            // a move away from the innermost counter costs one more to undo
+            let toward = |cell: usize| {
+                loops
+                    .last()
+                    .is_some_and(|&c| c.abs_diff(cell) < c.abs_diff(pos))
+            };
+            let left = pos > 0 && (room >= 2 || toward(pos - 1));
+            let right = pos < MAX_CELL && (room >= 2 || toward(pos + 1));
+            let op = match self.rng.below(4) {
+                0 if writable => '+',
+                1 if writable => '-',
+                2 if left => '<',
+                3 if right => '>',
+                _ => '.',
+            };
+            match op {
+                '<' => pos -= 1,
+                '>' => pos += 1,
+                _ => {}
+            }
+            out.push(op);
+        }
+        out
+    }
+}
+
+// run with wrapping cells and `,` reading zero, expecting the program to end
+#[cfg(test)]
+fn halts(tokens: &[crate::tokenizer::Token]) {
+    use crate::tokenizer::Token;
+
+    let mut mem = vec![0_u8; MAX_CELL + 1];
+    let (mut pc, mut point, mut steps) = (0, 0, 0_u64);
+    while pc < tokens.len() {
+        steps += 1;
+        assert!(steps < 100_000_000, "runaway program");
+        match tokens[pc] {
+            Token::IncrementData(x) => mem[point] = mem[point].wrapping_add(x),
+            Token::DecrementData(x) => mem[point] = mem[point].wrapping_sub(x),
+            Token::IncrementPointer(x) => point += x,
+            Token::DecrementPointer(x) => point -= x,
+            Token::Input => mem[point] = 0,
+            Token::LoopStart(x) if mem[point] == 0 => pc = x as usize,
+            Token::LoopEnd(x) if mem[point] != 0 => pc = x as usize,
+            _ => {}
+        }
+        pc += 1;
+    }
+}
+
+#[test]
+fn test_generate() {
+    use crate::tokenizer::tokenizer;
+
+    // pinned so a change to the generator, or a platform difference, shows up
+    let seven = ProgramGenerator::new(7).max_len(60).generate();
+    assert_eq!(seven, ProgramGenerator::new(7).max_len(60).generate());
+    assert_eq!(
+        seven,
+        "[.+]>>+.[]<<..+.-...+.--+..[+.-.><]+[].><[.[><,+]]-.+[><]>+."
+    );
+    assert_ne!(seven, ProgramGenerator::new(8).max_len(60).generate());
+
+    for seed in 0..300 {
+        for terminating in [false, true] {
+            let src = ProgramGenerator::new(seed)
+                .max_len(seed as usize)
+                .loop_probability(0.3)
+                .io_probability(0.1)
+                .terminating(terminating)
+                .generate();
+            assert!(src.len() <= seed as usize, "{}", src);
+            let mut depth = 0_i32;
+            for c in src.chars() {
+                depth += match c {
+                    '[' => 1,
+                    ']' => -1,
+                    _ => 0,
+                };
+                assert!(depth >= 0, "{}", src);
+            }
+            assert_eq!(depth, 0, "{}", src);
+            let tokens = tokenizer(&src).unwrap();
+            if terminating {
+                halts(&tokens);
+            }
+        }
+    }
+}
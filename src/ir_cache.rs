@@ -0,0 +1,311 @@
+//! A disk cache of optimized programs, so huge sources are not optimized
+//! again on every run.
+//!
+//! Entries are `.bfc` files named after a hash of the source together with
+//! everything else the output depends on: the optimization level, the
+//! dialect, how the tape starts and how cells overflow, the crate version and
+//! `REVISION`. A changed source or compiler simply looks up another name, so
+//! there is no staleness to check. An entry is used only when it loads and
+//! verifies and its source map carries the same source hash; anything else
+//! is compiled again and the entry rewritten.
+
+use std::{
+    cell::Cell,
+    env, fs, io,
+    path::{Path, PathBuf},
+    process,
+};
+
+use crate::{
+    bytecode,
+    program::{OptLevel, Program, SourceInfo},
+    tokenizer::{Dialect, StartTape, TokenizerError},
+    vm::CellOverflow,
+};
+
+/// Revision of the optimizer's output and of the IR it is stored as. Bump it
+/// with any change to either between releases, which the crate version alone
+/// would miss.
+pub const REVISION: u32 = 2;
+
+/// How lookups in an `IrCache` went so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,   // no entry, so compiled and stored
+    pub rejected: u64, // an entry that did not load, compiled over
+}
+
+pub struct IrCache {
+    dir: PathBuf,
+    stats: Cell<CacheStats>,
+}
+
+impl IrCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        IrCache {
+            dir: dir.into(),
+            stats: Cell::new(CacheStats::default()),
+        }
+    }
+
+    /// `$XDG_CACHE_HOME/bfjit/ir`, falling back to `~/.cache/bfjit/ir`.
+    pub fn default_dir() -> Option<PathBuf> {
+        let base = match env::var_os("XDG_CACHE_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".cache"),
+        };
+        Some(base.join("bfjit").join("ir"))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats.get()
+    }
+
+    fn entry(
+        &self,
+        src: &str,
+        level: OptLevel,
+        dialect: Dialect,
+        start: StartTape,
+        overflow: CellOverflow,
+    ) -> PathBuf {
+        let version = env!("CARGO_PKG_VERSION");
+        let key = format!(
+            "{} r{} {} {:?} {:?} {:?}\n{}",
+            version, REVISION, level, dialect, start, overflow, src
+        );
+        self.dir
+            .join(format!("{:016x}.bfc", bytecode::source_hash(&key)))
+    }
+
+    /// `Program::compile_for`, through the cache. `file` is recorded as the
+    /// program's source. Failing to write an entry is not an error; the
+    /// program is simply compiled again next time.
+    pub fn compile(
+        &self,
+        src: &str,
+        level: OptLevel,
+        dialect: Dialect,
+        start: StartTape,
+        overflow: CellOverflow,
+        file: &str,
+    ) -> Result<Program, TokenizerError> {
+        let source = SourceInfo {
+            file: file.to_string(),
+            hash: bytecode::source_hash(src),
+        };
+        let path = self.entry(src, level, dialect, start, overflow);
+        let mut stats = self.stats.get();
+        match fs::read(&path) {
+            Ok(bytes) => match Program::from_bytecode(&bytes) {
+                Ok(program) if program.source_info().map(|s| s.hash) == Some(source.hash) => {
+                    stats.hits += 1;
+                    self.stats.set(stats);
+                    return Ok(program.with_source_info(source));
+                }
+                _ => stats.rejected += 1,
+            },
+            Err(_) => stats.misses += 1,
+        }
+        self.stats.set(stats);
+        let program = Program::compile_for(src, level, dialect, start, overflow)?;
+        let program = program.with_source_info(source);
+        drop(self.store(&path, &program));
+        Ok(program)
+    }
+
+    // write through a temporary file, so a reader never sees half an entry
+    fn store(&self, path: &Path, program: &Program) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let tmp = path.with_extension(format!("tmp{}", process::id()));
+        fs::write(&tmp, program.to_bytecode(false))?;
+        fs::rename(&tmp, path).inspect_err(|_| drop(fs::remove_file(&tmp)))
+    }
+
+    /// The number of entries and the bytes they take.
+    pub fn usage(&self) -> io::Result<(usize, u64)> {
+        let mut usage = (0, 0);
+        for entry in self.entries()? {
+            usage.0 += 1;
+            usage.1 += entry.metadata()?.len();
+        }
+        Ok(usage)
+    }
+
+    /// Remove every entry, returning how many there were.
+    pub fn clear(&self) -> io::Result<usize> {
+        let entries = self.entries()?;
+        for entry in &entries {
+            fs::remove_file(entry.path())?;
+        }
+        Ok(entries.len())
+    }
+
+    fn entries(&self) -> io::Result<Vec<fs::DirEntry>> {
+        let dir = match fs::read_dir(&self.dir) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            dir => dir?,
+        };
+        let mut entries = vec![];
+        for entry in dir {
+            let entry = entry?;
+            if entry.path().extension().is_some_and(|ext| ext == "bfc") {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+}
+
+#[test]
+fn test_ir_cache() {
+    let dir = env::temp_dir().join(format!("bfjit-ir-cache-{}", process::id()));
+    drop(fs::remove_dir_all(&dir));
+    let cache = IrCache::new(&dir);
+    let src = std::fs::read_to_string("bfcode/hellow.bf").unwrap();
+    let fresh = Program::compile(&src).unwrap();
+
+    let first = cache
+        .compile(
+            &src,
+            OptLevel::O2,
+            Dialect::Standard,
+            StartTape::Unknown,
+            CellOverflow::Wrap,
+            "hellow.bf",
+        )
+        .unwrap();
+    assert_eq!(
+        cache.stats(),
+        CacheStats {
+            misses: 1,
+            ..Default::default()
+        }
+    );
+    let second = cache
+        .compile(
+            &src,
+            OptLevel::O2,
+            Dialect::Standard,
+            StartTape::Unknown,
+            CellOverflow::Wrap,
+            "hellow.bf",
+        )
+        .unwrap();
+    assert_eq!(cache.stats().hits, 1);
+    for program in [&first, &second] {
+        assert_eq!(program.tokens(), fresh.tokens());
+        assert_eq!(program.spans(), fresh.spans());
+        assert_eq!(program.source_info().unwrap().file, "hellow.bf");
+    }
+    // another level, source, dialect, start tape or overflow is another entry
+    cache
+        .compile(
+            &src,
+            OptLevel::O0,
+            Dialect::Standard,
+            StartTape::Unknown,
+            CellOverflow::Wrap,
+            "hellow.bf",
+        )
+        .unwrap();
+    cache
+        .compile(
+            "+.",
+            OptLevel::O2,
+            Dialect::Standard,
+            StartTape::Unknown,
+            CellOverflow::Wrap,
+            "plus.bf",
+        )
+        .unwrap();
+    cache
+        .compile(
+            "+.",
+            OptLevel::O2,
+            Dialect::Ebf1,
+            StartTape::Unknown,
+            CellOverflow::Wrap,
+            "plus.bf",
+        )
+        .unwrap();
+    cache
+        .compile(
+            "+.",
+            OptLevel::O2,
+            Dialect::Standard,
+            StartTape::Zeroed,
+            CellOverflow::Wrap,
+            "plus.bf",
+        )
+        .unwrap();
+    cache
+        .compile(
+            "+.",
+            OptLevel::O2,
+            Dialect::Standard,
+            StartTape::Zeroed,
+            CellOverflow::Error,
+            "plus.bf",
+        )
+        .unwrap();
+    assert_eq!(cache.stats().misses, 6);
+    assert_eq!(cache.usage().unwrap().0, 6);
+
+    // a damaged entry is compiled over and then good again
+    let entry = cache.entry(
+        &src,
+        OptLevel::O2,
+        Dialect::Standard,
+        StartTape::Unknown,
+        CellOverflow::Wrap,
+    );
+    let mut bytes = fs::read(&entry).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    bytes.truncate(last - 3);
+    fs::write(&entry, bytes).unwrap();
+    let rebuilt = cache
+        .compile(
+            &src,
+            OptLevel::O2,
+            Dialect::Standard,
+            StartTape::Unknown,
+            CellOverflow::Wrap,
+            "hellow.bf",
+        )
+        .unwrap();
+    assert_eq!(rebuilt.tokens(), fresh.tokens());
+    assert_eq!((cache.stats().rejected, cache.stats().hits), (1, 1));
+    cache
+        .compile(
+            &src,
+            OptLevel::O2,
+            Dialect::Standard,
+            StartTape::Unknown,
+            CellOverflow::Wrap,
+            "hellow.bf",
+        )
+        .unwrap();
+    assert_eq!(cache.stats().hits, 2);
+
+    // compile errors are not cached
+    assert!(cache
+        .compile(
+            "[",
+            OptLevel::O2,
+            Dialect::Standard,
+            StartTape::Unknown,
+            CellOverflow::Wrap,
+            "bad.bf"
+        )
+        .is_err());
+    assert_eq!(cache.clear().unwrap(), 6);
+    assert_eq!(cache.usage().unwrap(), (0, 0));
+    fs::remove_dir_all(&dir).unwrap();
+}
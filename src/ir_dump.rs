@@ -0,0 +1,139 @@
+//! Numbered IR listings for `--dump-ir`.
+//!
+//! `listing` prints one instruction per line after its index, indented by
+//! block nesting, in the `Display` form of `Token`:
+//!
+//! ```text
+//! 0000: ADD 2
+//! 0001: JZ -> 0004
+//! 0002:     ADD -1
+//! 0003: JNZ -> 0001
+//! ```
+//!
+//! `json_lines` has the same content as one JSON object per instruction,
+//! with the operands as fields and the source position where there is one.
+
+use std::fmt::Write;
+
+use crate::{json::Json, program::Program, tokenizer::Token};
+
+pub fn listing(program: &Program) -> String {
+    let tokens = program.tokens();
+    let width = tokens.len().saturating_sub(1).to_string().len().max(4);
+    let mut out = String::new();
+    let mut depth = 0;
+    for (pc, token) in tokens.iter().enumerate() {
+        if token.is_block_end() {
+            depth -= 1;
+        }
+        let indent = "    ".repeat(depth);
+        writeln!(out, "{:0width$}: {}{}", pc, indent, token, width = width).unwrap();
+        if token.is_block_start() {
+            depth += 1;
+        }
+    }
+    out
+}
+
+// a count negated for `-`, `<` and `[<]`; f64 holds any of them closely
+// enough, and exactly below 2^53
+fn signed(x: usize, negative: bool) -> Json {
+    Json::Number(if negative { -(x as f64) } else { x as f64 })
+}
+
+// the mnemonic of a token and its operands by name
+fn fields(token: Token) -> (&'static str, Vec<(&'static str, Json)>) {
+    match token {
+        Token::IncrementData(x) => ("add", vec![("value", Json::from(x as i32))]),
+        Token::DecrementData(x) => ("add", vec![("value", Json::from(-(x as i32)))]),
+        Token::IncrementPointer(x) => ("move", vec![("value", signed(x, false))]),
+        Token::DecrementPointer(x) => ("move", vec![("value", signed(x, true))]),
+        Token::ScanRight(x) => ("scan", vec![("value", signed(x, false))]),
+        Token::ScanLeft(x) => ("scan", vec![("value", signed(x, true))]),
+        Token::Input => ("in", vec![]),
+        Token::Output => ("out", vec![("count", Json::from(1_usize))]),
+        Token::OutputRepeat(n) => ("out", vec![("count", Json::from(n))]),
+        Token::Print(byte) => ("print", vec![("value", Json::from(byte as i32))]),
+        Token::ClearRange { start_offset, len } => (
+            "clear",
+            vec![
+                ("offset", Json::from(start_offset)),
+                ("len", Json::from(len as u64)),
+            ],
+        ),
+        Token::MulAdd { offset, factor } => (
+            "muladd",
+            vec![
+                ("offset", Json::from(offset)),
+                ("factor", Json::from(factor as i32)),
+            ],
+        ),
+        Token::AddAt { offset, value } => (
+            "addat",
+            vec![
+                ("offset", Json::from(offset)),
+                ("value", Json::from(value as i32)),
+            ],
+        ),
+        Token::Ext(op) => ("ext", vec![("ext", Json::from(op.name()))]),
+        Token::Breakpoint => ("break", vec![]),
+        Token::LoopStart(target) => ("jz", vec![("target", Json::from(target as u64))]),
+        Token::LoopEnd(target) => ("jnz", vec![("target", Json::from(target as u64))]),
+        Token::IfStart(target) => ("if", vec![("target", Json::from(target as u64))]),
+        Token::IfEnd(start) => ("endif", vec![("start", Json::from(start as u64))]),
+    }
+}
+
+/// `listing` as JSON, one object per line, each tagged with `stage`.
+pub fn json_lines(program: &Program, stage: &str) -> String {
+    let mut out = String::new();
+    let mut depth: usize = 0;
+    for (pc, (token, span)) in program.tokens().iter().zip(program.spans()).enumerate() {
+        if token.is_block_end() {
+            depth -= 1;
+        }
+        let (op, operands) = fields(*token);
+        let mut object = vec![
+            ("stage", Json::from(stage)),
+            ("pc", Json::from(pc)),
+            ("depth", Json::from(depth)),
+            ("op", Json::from(op)),
+        ];
+        object.extend(operands);
+        if span.line > 0 {
+            object.push(("line", Json::from(span.line)));
+            object.push(("col", Json::from(span.col)));
+        }
+        let object = object.into_iter().map(|(k, v)| (k.to_string(), v));
+        writeln!(out, "{}", Json::Object(object.collect())).unwrap();
+        if token.is_block_start() {
+            depth += 1;
+        }
+    }
+    out
+}
+
+#[test]
+fn test_ir_dump() {
+    use crate::program::OptLevel;
+
+    // indices widen past four digits, and stay aligned
+    let program = Program::compile_with(&"+>".repeat(6000), OptLevel::O1).unwrap();
+    let listing = listing(&program);
+    assert!(listing.starts_with("00000: ADD 1\n00001: MOVE 1\n"));
+    assert!(listing.ends_with("11999: MOVE 1\n"));
+
+    // every line parses back, in order, with the fields of its token
+    let program = Program::compile("+[<]>[-]").unwrap();
+    let lines = json_lines(&program, "optimized");
+    let lines: Vec<Json> = lines.lines().map(|l| Json::parse(l).unwrap()).collect();
+    assert_eq!(lines.len(), program.tokens().len());
+    for (pc, line) in lines.iter().enumerate() {
+        assert_eq!(line.get("pc").and_then(Json::as_u64), Some(pc as u64));
+        assert_eq!(line.get("stage").and_then(Json::as_str), Some("optimized"));
+    }
+    assert_eq!(
+        lines[1].to_string(),
+        r#"{"stage":"optimized","pc":1,"depth":0,"op":"scan","value":-1,"line":1,"col":2}"#
+    );
+}
@@ -0,0 +1,403 @@
+//! Human-readable IR assembly.
+//!
+//! One instruction per line: `add N`, `move N`, `scan N` (negative operands
+//! for `-`, `<` and `[<]`), `in`, `out`, `print BYTE`, `clear START LEN`, `muladd OFFSET
+//! FACTOR`, `addat OFFSET VALUE`, `ext OP` for the extended dialect
+//! (`ext halt`, `ext xor`, ...), `break` for a `#` breakpoint, and blocks `loop {` / `if {` ... `}`. Blank lines and `#` comments are ignored, so test cases can be
+//! written by hand.
+
+use std::fmt::{self, Write};
+
+use crate::{
+    error::ErrorCategory,
+    program::Program,
+    tokenizer::{relink, ExtOp, Span, Token, MAX_INSTRUCTIONS},
+};
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum IrErrorKind {
+    #[error("Unknown mnemonic `{0}`")]
+    UnknownMnemonic(String),
+
+    #[error("Bad operand `{0}`")]
+    BadOperand(String),
+
+    #[error("Unexpected closing brace")]
+    UnexpectedBrace,
+
+    #[error("Unclosed loop")]
+    UnclosedLoop,
+
+    #[error("Program too large, more than {} instructions", MAX_INSTRUCTIONS)]
+    ProgramTooLarge,
+}
+
+impl IrErrorKind {
+    pub fn code(&self) -> &'static str {
+        match self {
+            IrErrorKind::UnknownMnemonic(_) => "E0201",
+            IrErrorKind::BadOperand(_) => "E0202",
+            IrErrorKind::UnexpectedBrace => "E0203",
+            IrErrorKind::UnclosedLoop => "E0204",
+            IrErrorKind::ProgramTooLarge => "E0205",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct IrError {
+    line: i32,
+    col: i32,
+    kind: IrErrorKind,
+}
+
+impl IrError {
+    pub fn line(&self) -> i32 {
+        self.line
+    }
+
+    pub fn col(&self) -> i32 {
+        self.col
+    }
+
+    pub fn kind(&self) -> &IrErrorKind {
+        &self.kind
+    }
+
+    pub fn code(&self) -> &'static str {
+        self.kind.code()
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        ErrorCategory::Compile
+    }
+}
+
+impl fmt::Display for IrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} at line {}:{}",
+            self.kind.code(),
+            self.kind,
+            self.line,
+            self.col
+        )
+    }
+}
+impl std::error::Error for IrError {}
+
+// parses a signed operand, keeping the sign of `-0` apart from `0`
+fn operand(word: &str) -> Option<(bool, &str)> {
+    match word.strip_prefix('-') {
+        Some(digits) => Some((true, digits)),
+        None => Some((false, word.strip_prefix('+').unwrap_or(word))),
+    }
+    .filter(|(_, digits)| !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()))
+}
+
+// a signed cell offset, as `clear` and `muladd` take
+fn offset(word: &str) -> Option<i32> {
+    let (neg, digits) = operand(word)?;
+    let magnitude: i64 = digits.parse().ok()?;
+    i32::try_from(if neg { -magnitude } else { magnitude }).ok()
+}
+
+impl Program {
+    pub fn to_ir_text(&self) -> String {
+        let mut out = String::new();
+        let mut depth = 0;
+        for token in self.tokens() {
+            if token.is_block_end() {
+                depth -= 1;
+            }
+            for _ in 0..depth {
+                out.push_str("    ");
+            }
+            match *token {
+                Token::IncrementData(x) => writeln!(out, "add {}", x),
+                Token::DecrementData(x) => writeln!(out, "add -{}", x),
+                Token::IncrementPointer(x) => writeln!(out, "move {}", x),
+                Token::DecrementPointer(x) => writeln!(out, "move -{}", x),
+                Token::ScanRight(x) => writeln!(out, "scan {}", x),
+                Token::ScanLeft(x) => writeln!(out, "scan -{}", x),
+                Token::Input => writeln!(out, "in"),
+                Token::Output => writeln!(out, "out"),
+                Token::OutputRepeat(n) => writeln!(out, "out {}", n),
+                Token::Print(byte) => writeln!(out, "print {}", byte),
+                Token::Ext(op) => writeln!(out, "ext {}", op.name()),
+                Token::Breakpoint => writeln!(out, "break"),
+                Token::ClearRange { start_offset, len } => {
+                    writeln!(out, "clear {} {}", start_offset, len)
+                }
+                Token::MulAdd { offset, factor } => writeln!(out, "muladd {} {}", offset, factor),
+                Token::AddAt { offset, value } => writeln!(out, "addat {} {}", offset, value),
+                Token::LoopStart(_) => writeln!(out, "loop {{"),
+                Token::IfStart(_) => writeln!(out, "if {{"),
+                Token::LoopEnd(_) | Token::IfEnd(_) => writeln!(out, "}}"),
+            }
+            .unwrap();
+            if token.is_block_start() {
+                depth += 1;
+            }
+        }
+        out
+    }
+
+    /// Parse the output of `to_ir_text`. Spans point into the IR text.
+    pub fn from_ir_text(text: &str) -> Result<Self, IrError> {
+        let mut tokens: Vec<Token> = vec![];
+        let mut spans: Vec<Span> = vec![];
+        let mut stk: Vec<(Span, Token)> = vec![];
+
+        for (i, raw) in text.lines().enumerate() {
+            let code = raw.split('#').next().unwrap();
+            let col = (code.len() - code.trim_start().len()) as i32 + 1;
+            let span = Span {
+                line: i as i32 + 1,
+                col,
+            };
+            let err = |col: i32, kind: IrErrorKind| IrError {
+                line: span.line,
+                col,
+                kind,
+            };
+
+            let words: Vec<&str> = code.split_whitespace().collect();
+            let token = match words.as_slice() {
+                [] => continue,
+                ["in"] => Token::Input,
+                ["break"] => Token::Breakpoint,
+                ["out"] => Token::Output,
+                ["out", arg] => {
+                    let col = code.find(arg).unwrap() as i32 + 1;
+                    let n = arg
+                        .parse()
+                        .ok()
+                        .filter(|_| arg.bytes().all(|b| b.is_ascii_digit()));
+                    Token::OutputRepeat(
+                        n.ok_or_else(|| err(col, IrErrorKind::BadOperand(arg.to_string())))?,
+                    )
+                }
+                ["print", arg] => {
+                    let col = code.find(arg).unwrap() as i32 + 1;
+                    let byte = arg
+                        .parse()
+                        .ok()
+                        .filter(|_| arg.bytes().all(|b| b.is_ascii_digit()));
+                    Token::Print(
+                        byte.ok_or_else(|| err(col, IrErrorKind::BadOperand(arg.to_string())))?,
+                    )
+                }
+                ["ext", name] => {
+                    let col = code.find(name).unwrap() as i32 + 1;
+                    let op = ExtOp::from_name(name);
+                    Token::Ext(
+                        op.ok_or_else(|| err(col, IrErrorKind::BadOperand(name.to_string())))?,
+                    )
+                }
+                ["loop", "{"] => {
+                    stk.push((span, Token::LoopEnd(0)));
+                    Token::LoopStart(0)
+                }
+                ["if", "{"] => {
+                    stk.push((span, Token::IfEnd(0)));
+                    Token::IfStart(0)
+                }
+                ["}"] => match stk.pop() {
+                    Some((_, end)) => end,
+                    None => return Err(err(col, IrErrorKind::UnexpectedBrace)),
+                },
+                ["clear", start, len] => {
+                    let bad = |arg: &str| {
+                        let col = code.find(arg).unwrap() as i32 + 1;
+                        err(col, IrErrorKind::BadOperand(arg.to_string()))
+                    };
+                    let start_offset = offset(start).ok_or_else(|| bad(start))?;
+                    let len = len
+                        .parse()
+                        .ok()
+                        .filter(|_| len.bytes().all(|b| b.is_ascii_digit()))
+                        .ok_or_else(|| bad(len))?;
+                    Token::ClearRange { start_offset, len }
+                }
+                [op @ ("muladd" | "addat"), at, byte] => {
+                    let bad = |arg: &str| {
+                        let col = code.find(arg).unwrap() as i32 + 1;
+                        err(col, IrErrorKind::BadOperand(arg.to_string()))
+                    };
+                    let offset = offset(at).ok_or_else(|| bad(at))?;
+                    let byte = byte
+                        .parse()
+                        .ok()
+                        .filter(|_| byte.bytes().all(|b| b.is_ascii_digit()))
+                        .ok_or_else(|| bad(byte))?;
+                    match *op {
+                        "muladd" => Token::MulAdd {
+                            offset,
+                            factor: byte,
+                        },
+                        _ => Token::AddAt {
+                            offset,
+                            value: byte,
+                        },
+                    }
+                }
+                [op @ ("add" | "move" | "scan"), arg] => {
+                    let bad = || {
+                        let col = code.find(arg).unwrap() as i32 + 1;
+                        err(col, IrErrorKind::BadOperand(arg.to_string()))
+                    };
+                    let (neg, digits) = operand(arg).ok_or_else(bad)?;
+                    match (*op, neg) {
+                        ("add", false) => Token::IncrementData(digits.parse().map_err(|_| bad())?),
+                        ("add", true) => Token::DecrementData(digits.parse().map_err(|_| bad())?),
+                        ("move", false) => {
+                            Token::IncrementPointer(digits.parse().map_err(|_| bad())?)
+                        }
+                        ("move", true) => {
+                            Token::DecrementPointer(digits.parse().map_err(|_| bad())?)
+                        }
+                        (_, false) => Token::ScanRight(digits.parse().map_err(|_| bad())?),
+                        (_, true) => Token::ScanLeft(digits.parse().map_err(|_| bad())?),
+                    }
+                }
+                _ => {
+                    return Err(err(
+                        col,
+                        IrErrorKind::UnknownMnemonic(code.trim().to_string()),
+                    ));
+                }
+            };
+            if tokens.len() > MAX_INSTRUCTIONS {
+                return Err(err(col, IrErrorKind::ProgramTooLarge));
+            }
+            tokens.push(token);
+            spans.push(span);
+        }
+
+        if let Some((span, _)) = stk.pop() {
+            return Err(IrError {
+                line: span.line,
+                col: span.col,
+                kind: IrErrorKind::UnclosedLoop,
+            });
+        }
+        relink(&mut tokens);
+        Ok(Program::from_parts(tokens, spans))
+    }
+}
+
+#[test]
+fn test_ir_text() {
+    use crate::{
+        program::OptLevel,
+        tokenizer::{Dialect, StartTape},
+        vm::CellOverflow,
+    };
+
+    // peeled, so the loop body shows up twice
+    let src = "+++++[>++.<-]<-,";
+    let program = Program::compile_for(
+        src,
+        OptLevel::O2,
+        Dialect::Standard,
+        StartTape::Zeroed,
+        CellOverflow::Wrap,
+    );
+    let program = program.unwrap();
+    let text = program.to_ir_text();
+    assert_eq!(
+        text,
+        "add 5\nmove 1\nadd 2\nout\nmove -1\nadd -1\nloop {\n    move 1\n    add 2\n    out\n    \
+         move -1\n    add -1\n}\nmove -1\nadd -1\nin\n"
+    );
+    let parsed = Program::from_ir_text(&text).unwrap();
+    assert_eq!(parsed.tokens(), program.tokens());
+    assert_eq!(parsed.to_ir_text(), text);
+    assert_eq!(parsed.spans()[7], Span { line: 8, col: 5 });
+
+    // round-trip random nested programs, with operands at their limits
+    let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut next = |n: u64| {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed % n
+    };
+    for _ in 0..200 {
+        let mut tokens = vec![];
+        let mut opened = vec![];
+        for _ in 0..next(64) {
+            let x = next(300);
+            tokens.push(match next(8) {
+                0 => Token::IncrementData(x.min(255) as u8),
+                1 => Token::DecrementData(x.min(255) as u8),
+                2 if x > 280 => Token::AddAt {
+                    offset: x as i32 - 290,
+                    value: x as u8,
+                },
+                2 => Token::IncrementPointer(x as usize),
+                3 if x > 250 => Token::ScanRight(x as usize - 250),
+                3 if x > 200 => Token::ScanLeft(if x == 201 { usize::MAX } else { x as usize }),
+                3 => Token::DecrementPointer(if x == 1 { usize::MAX } else { x as usize }),
+                4 if x > 200 => Token::Ext(ExtOp::ALL[x as usize % ExtOp::ALL.len()]),
+                4 if x > 190 => Token::Breakpoint,
+                4 if x > 150 => Token::MulAdd {
+                    offset: x as i32 - 175,
+                    factor: x as u8,
+                },
+                4 => Token::Input,
+                5 if x > 255 => Token::Print(x as u8),
+                5 if x > 1 => Token::OutputRepeat(x as usize),
+                5 => Token::Output,
+                6 => {
+                    let kind = next(2) == 0;
+                    opened.push(kind);
+                    if kind {
+                        Token::LoopStart(0)
+                    } else {
+                        Token::IfStart(0)
+                    }
+                }
+                _ => match opened.pop() {
+                    Some(true) => Token::LoopEnd(0),
+                    Some(false) => Token::IfEnd(0),
+                    None => Token::Output,
+                },
+            });
+        }
+        while let Some(kind) = opened.pop() {
+            tokens.push(if kind {
+                Token::LoopEnd(0)
+            } else {
+                Token::IfEnd(0)
+            });
+        }
+        relink(&mut tokens);
+
+        let text = Program::new(tokens.clone()).to_ir_text();
+        let parsed = Program::from_ir_text(&text).unwrap();
+        assert_eq!(parsed.tokens(), &tokens[..], "{}", text);
+        assert_eq!(parsed.to_ir_text(), text);
+    }
+
+    let err = Program::from_ir_text("# comment\nadd 1\n  mul 2").unwrap_err();
+    assert!(matches!(err.kind(), IrErrorKind::UnknownMnemonic(m) if m == "mul 2"));
+    assert_eq!((err.line(), err.col()), (3, 3));
+
+    let err = Program::from_ir_text("add 1\nmove  x3").unwrap_err();
+    assert!(matches!(err.kind(), IrErrorKind::BadOperand(_)));
+    assert_eq!((err.line(), err.col()), (2, 7));
+
+    let err = Program::from_ir_text("add 256").unwrap_err();
+    assert!(matches!(err.kind(), IrErrorKind::BadOperand(_)));
+
+    let err = Program::from_ir_text("loop {\n  loop {\n  }\n").unwrap_err();
+    assert!(matches!(err.kind(), IrErrorKind::UnclosedLoop));
+    assert_eq!((err.line(), err.col()), (1, 1));
+
+    let err = Program::from_ir_text("}").unwrap_err();
+    assert!(matches!(err.kind(), IrErrorKind::UnexpectedBrace));
+}
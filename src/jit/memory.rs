@@ -0,0 +1,370 @@
+//! Executable memory for generated code.
+//!
+//! Programs are small, so rather than a page-granular mapping each they are
+//! bump-allocated out of larger chunks owned by a `CodeArena`. On Linux a
+//! chunk is one memory file mapped twice, read-write for copying code in and
+//! read-execute for running it, so no page is ever writable and executable
+//! at once and new code can go next to code that is running. Every
+//! `ExecutableBuffer` holds a reference to its chunk; once the last one is
+//! dropped the arena starts the chunk over from the beginning.
+//!
+//! Elsewhere a chunk is a single mapping, sized for and holding one program
+//! at a time; it is only made writable while no buffer refers to it.
+
+use std::{
+    ffi::{c_int, c_void},
+    io, ptr,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+const PROT_READ: c_int = 1;
+const PROT_WRITE: c_int = 2;
+const PROT_EXEC: c_int = 4;
+#[cfg(target_os = "linux")]
+const MAP_SHARED: c_int = 0x01;
+#[cfg(not(target_os = "linux"))]
+const MAP_PRIVATE: c_int = 0x02;
+#[cfg(not(target_os = "linux"))]
+const MAP_ANONYMOUS: c_int = 0x1000;
+
+const PAGE_SIZE: usize = 4096;
+
+// whether a chunk can take more code while earlier code in it may be running
+const SHAREABLE: bool = cfg!(target_os = "linux");
+
+/// Default size of an arena chunk.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+// start of every allocation, so code never shares a cache line by accident
+const ALIGN: usize = 16;
+
+#[cfg(unix)]
+extern "C" {
+    fn mmap(
+        addr: *mut c_void,
+        len: usize,
+        prot: c_int,
+        flags: c_int,
+        fd: c_int,
+        off: i64,
+    ) -> *mut c_void;
+    #[cfg(not(target_os = "linux"))]
+    fn mprotect(addr: *mut c_void, len: usize, prot: c_int) -> c_int;
+    fn munmap(addr: *mut c_void, len: usize) -> c_int;
+    #[cfg(target_os = "linux")]
+    fn memfd_create(name: *const std::ffi::c_char, flags: u32) -> c_int;
+    #[cfg(target_os = "linux")]
+    fn ftruncate(fd: c_int, len: i64) -> c_int;
+    #[cfg(target_os = "linux")]
+    fn close(fd: c_int) -> c_int;
+}
+
+// one mapping of `len` bytes, seen writable at `rw` and executable at `rx`
+struct Chunk {
+    rw: *mut u8, // the same as `rx` without a second view
+    rx: *mut u8,
+    len: usize,
+}
+
+// the chunk only hands out disjoint ranges, under the arena lock
+unsafe impl Send for Chunk {}
+unsafe impl Sync for Chunk {}
+
+impl Chunk {
+    #[cfg(not(unix))]
+    fn map(_len: usize) -> io::Result<Self> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn map(len: usize) -> io::Result<Self> {
+        // SAFETY: a fresh memory file mapped twice; both views are only
+        // unmapped on drop, and the descriptor is not needed after mapping
+        unsafe {
+            let fd = memfd_create(c"bfjit-code".as_ptr(), 0);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let map = |prot| mmap(ptr::null_mut(), len, prot, MAP_SHARED, fd, 0);
+            let (mut rw, mut rx) = (usize::MAX as *mut c_void, usize::MAX as *mut c_void);
+            let mut err = None;
+            if ftruncate(fd, len as i64) != 0 {
+                err = Some(io::Error::last_os_error());
+            } else {
+                rw = map(PROT_READ | PROT_WRITE);
+                if rw as isize != -1 {
+                    rx = map(PROT_READ | PROT_EXEC);
+                }
+                if rw as isize == -1 || rx as isize == -1 {
+                    err = Some(io::Error::last_os_error());
+                }
+            }
+            close(fd);
+            if let Some(err) = err {
+                if rw as isize != -1 {
+                    munmap(rw, len);
+                }
+                return Err(err);
+            }
+            Ok(Chunk {
+                rw: rw as *mut u8,
+                rx: rx as *mut u8,
+                len,
+            })
+        }
+    }
+
+    #[cfg(all(unix, not(target_os = "linux")))]
+    fn map(len: usize) -> io::Result<Self> {
+        // SAFETY: a fresh anonymous mapping, only unmapped on drop
+        unsafe {
+            let ptr = mmap(
+                ptr::null_mut(),
+                len,
+                PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if ptr as isize == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Chunk {
+                rw: ptr as *mut u8,
+                rx: ptr as *mut u8,
+                len,
+            })
+        }
+    }
+
+    // copy `code` in at `offset`, then make sure it can run
+    #[cfg(target_os = "linux")]
+    fn write(&self, offset: usize, code: &[u8]) -> io::Result<()> {
+        // SAFETY: the arena gave out `offset..offset + code.len()` to this
+        // call alone and it lies within the mapping
+        unsafe {
+            ptr::copy_nonoverlapping(code.as_ptr(), self.rw.add(offset), code.len());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn write(&self, offset: usize, code: &[u8]) -> io::Result<()> {
+        // SAFETY: a chunk holding a single program is only written while no
+        // buffer refers to it, so nothing runs from it meanwhile
+        unsafe {
+            let (ptr, len) = (self.rw as *mut c_void, self.len);
+            if mprotect(ptr, len, PROT_READ | PROT_WRITE) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            ptr::copy_nonoverlapping(code.as_ptr(), self.rw.add(offset), code.len());
+            if mprotect(ptr, len, PROT_READ | PROT_EXEC) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Chunk {
+    #[cfg(not(unix))]
+    fn drop(&mut self) {}
+
+    #[cfg(unix)]
+    fn drop(&mut self) {
+        // SAFETY: the views created in `map`; no buffer refers to them now
+        unsafe {
+            if self.rw != self.rx {
+                munmap(self.rw as *mut c_void, self.len);
+            }
+            munmap(self.rx as *mut c_void, self.len);
+        }
+    }
+}
+
+/// What a `CodeArena` holds right now and has done so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ArenaStats {
+    pub chunks: usize,   // chunks currently mapped
+    pub programs: usize, // buffers still alive
+    pub mapped: u64,     // chunks ever mapped
+    pub reclaimed: u64,  // times an emptied chunk was started over
+}
+
+struct Slot {
+    chunk: Arc<Chunk>,
+    used: usize, // bytes handed out from the start of the chunk
+}
+
+/// Executable chunks shared by many programs.
+pub struct CodeArena {
+    chunk_size: usize,
+    slots: Mutex<(Vec<Slot>, ArenaStats)>,
+}
+
+impl Default for CodeArena {
+    fn default() -> Self {
+        CodeArena::with_chunk_size(CHUNK_SIZE)
+    }
+}
+
+impl CodeArena {
+    /// An arena mapping `chunk_size` bytes at a time, rounded up to pages.
+    pub fn with_chunk_size(chunk_size: usize) -> Self {
+        CodeArena {
+            chunk_size: chunk_size.max(1).div_ceil(PAGE_SIZE) * PAGE_SIZE,
+            slots: Mutex::new((vec![], ArenaStats::default())),
+        }
+    }
+
+    /// The arena `ExecutableBuffer::new` allocates from.
+    pub fn global() -> &'static CodeArena {
+        static GLOBAL: OnceLock<CodeArena> = OnceLock::new();
+        GLOBAL.get_or_init(CodeArena::default)
+    }
+
+    pub fn alloc(&self, code: &[u8]) -> io::Result<ExecutableBuffer> {
+        let size = code.len().max(1).next_multiple_of(ALIGN);
+        let mut guard = self.slots.lock().unwrap();
+        let (slots, stats) = &mut *guard;
+        for slot in slots.iter_mut() {
+            if slot.used > 0 && Arc::strong_count(&slot.chunk) == 1 {
+                slot.used = 0;
+                stats.reclaimed += 1;
+            }
+        }
+        let fits =
+            |slot: &Slot| (slot.used == 0 || SHAREABLE) && slot.used + size <= slot.chunk.len;
+        let index = match slots.iter().position(fits) {
+            Some(index) => index,
+            None => {
+                let pages = size.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+                let len = if SHAREABLE {
+                    self.chunk_size.max(pages)
+                } else {
+                    pages
+                };
+                slots.push(Slot {
+                    chunk: Arc::new(Chunk::map(len)?),
+                    used: 0,
+                });
+                stats.mapped += 1;
+                slots.len() - 1
+            }
+        };
+        let slot = &mut slots[index];
+        let offset = slot.used;
+        slot.chunk.write(offset, code)?;
+        slot.used += size;
+        Ok(ExecutableBuffer {
+            chunk: slot.chunk.clone(),
+            offset,
+            code: code.len(),
+        })
+    }
+
+    pub fn stats(&self) -> ArenaStats {
+        let guard = self.slots.lock().unwrap();
+        let (slots, stats) = &*guard;
+        ArenaStats {
+            chunks: slots.len(),
+            programs: slots
+                .iter()
+                .map(|slot| Arc::strong_count(&slot.chunk) - 1)
+                .sum(),
+            ..*stats
+        }
+    }
+}
+
+/// Machine code in an arena chunk, readable and executable but never
+/// writable through the same mapping.
+pub struct ExecutableBuffer {
+    chunk: Arc<Chunk>,
+    offset: usize, // start of the code in the chunk
+    code: usize,   // bytes of code
+}
+
+impl ExecutableBuffer {
+    /// Copy `code` into the global arena.
+    pub fn new(code: &[u8]) -> io::Result<Self> {
+        CodeArena::global().alloc(code)
+    }
+
+    pub fn as_ptr(&self) -> *const u8 {
+        // SAFETY: `offset` is within the chunk
+        unsafe { self.chunk.rx.add(self.offset) }
+    }
+
+    pub fn code(&self) -> &[u8] {
+        // SAFETY: the chunk stays mapped and readable while `self` holds it
+        unsafe { std::slice::from_raw_parts(self.as_ptr(), self.code) }
+    }
+}
+
+#[test]
+fn test_code_arena() {
+    // mov eax, imm32; ret, padded so a few of them fill a page
+    let stub = |value: u32| {
+        let mut code = vec![0xb8];
+        code.extend_from_slice(&value.to_le_bytes());
+        code.push(0xc3);
+        code.resize(200, 0xcc);
+        code
+    };
+    #[cfg(target_arch = "x86_64")]
+    let call = |buffer: &ExecutableBuffer| {
+        // SAFETY: every buffer here holds one stub
+        let entry: extern "sysv64" fn() -> u32 = unsafe { std::mem::transmute(buffer.as_ptr()) };
+        entry()
+    };
+
+    let arena = CodeArena::with_chunk_size(PAGE_SIZE);
+    let first = arena.alloc(&stub(7)).unwrap();
+    let second = arena.alloc(&stub(9)).unwrap();
+    assert_eq!(first.code(), &stub(7)[..]);
+    assert_eq!(second.code(), &stub(9)[..]);
+    if cfg!(target_os = "linux") {
+        // both share one chunk, next to each other
+        assert_eq!(arena.stats().chunks, 1);
+        assert_eq!(second.as_ptr() as usize - first.as_ptr() as usize, 208);
+    }
+    #[cfg(target_arch = "x86_64")]
+    assert_eq!((call(&first), call(&second)), (7, 9));
+    drop((first, second));
+
+    let buffers: Vec<_> = (0..100).map(|i| arena.alloc(&stub(i)).unwrap()).collect();
+    let stats = arena.stats();
+    assert_eq!(stats.programs, 100);
+    if cfg!(target_os = "linux") {
+        // 19 stubs of 208 bytes fit a page, and the first chunk was reused
+        assert_eq!((stats.chunks, stats.mapped, stats.reclaimed), (6, 6, 1));
+    }
+    #[cfg(target_arch = "x86_64")]
+    for (i, buffer) in buffers.iter().enumerate() {
+        assert_eq!(call(buffer), i as u32);
+    }
+    drop(buffers);
+    assert_eq!(arena.stats().programs, 0);
+    let again: Vec<_> = (0..100).map(|i| arena.alloc(&stub(i)).unwrap()).collect();
+    let stats = arena.stats();
+    assert_eq!(stats.mapped, stats.chunks as u64);
+    drop(again);
+
+    // programs compiled on several threads at once
+    let arena = CodeArena::default();
+    std::thread::scope(|scope| {
+        for t in 0..4 {
+            let arena = &arena;
+            scope.spawn(move || {
+                for i in 0..50 {
+                    let buffer = arena.alloc(&stub(t * 1000 + i)).unwrap();
+                    #[cfg(target_arch = "x86_64")]
+                    assert_eq!(call(&buffer), t * 1000 + i);
+                    assert_eq!(buffer.code().len(), 200);
+                }
+            });
+        }
+    });
+    assert_eq!(arena.stats().programs, 0);
+}
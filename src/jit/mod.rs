@@ -0,0 +1,888 @@
+//! Native code generation for the token stream.
+
+use std::{
+    io::{self, Read, Write},
+    sync::OnceLock,
+};
+
+use crate::{
+    error::ErrorCategory,
+    progress,
+    tokenizer::{relink, Token},
+    vm::{EofBehavior, Fault, Termination, VmError},
+};
+
+mod memory;
+mod x86_64;
+
+pub use memory::{ArenaStats, CodeArena, ExecutableBuffer};
+
+// status codes returned by generated code and the I/O callbacks
+const STATUS_OK: u32 = 0;
+const STATUS_POINTER_OVERFLOW: u32 = 1;
+const STATUS_IO_ERROR: u32 = 2;
+const STATUS_EOF_HALT: u32 = 3;
+const STATUS_OUT_OF_FUEL: u32 = 4;
+const STATUS_BREAKPOINT: u32 = 5;
+
+/// A target instruction set the JIT can generate code for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    X86_64,
+}
+
+impl Backend {
+    pub const ALL: [Backend; 1] = [Backend::X86_64];
+
+    /// The backend whose code runs on this machine, if there is one.
+    pub fn host() -> Option<Backend> {
+        if cfg!(all(unix, target_arch = "x86_64")) {
+            Some(Backend::X86_64)
+        } else {
+            None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backend::X86_64 => "x86_64",
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum JitError {
+    #[error("E0501 Backend {} Cannot Run On This Machine", .0.name())]
+    UnsupportedBackend(Backend),
+
+    #[error("E0502 Executable Memory Error")]
+    Memory(#[from] io::Error),
+
+    #[error("E0503 {0} Is Not Supported By The JIT")]
+    Unsupported(&'static str),
+
+    /// A token the code generator has no lowering for, found before any
+    /// code is mapped so the caller can interpret the program instead.
+    #[error("E0504 Token {0:?} Is Not Supported By The JIT")]
+    UnsupportedToken(Token),
+}
+
+impl JitError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            JitError::UnsupportedBackend(_) => "E0501",
+            JitError::Memory(_) => "E0502",
+            JitError::Unsupported(_) => "E0503",
+            JitError::UnsupportedToken(_) => "E0504",
+        }
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            JitError::Memory(_) => ErrorCategory::Io,
+            _ => ErrorCategory::Runtime,
+        }
+    }
+}
+
+// state shared between generated code and the callbacks; the code only
+// touches `pointer`, at offset 0, `halt_pc` on an overflow, at 8, metered
+// code `budget`, at 16, and reads `progress_seen`, at 24
+#[repr(C)]
+struct JitContext<'a> {
+    pointer: usize,
+    halt_pc: usize,
+    budget: u64,
+    progress_seen: u64,
+    eof: EofBehavior,
+    input: &'a mut dyn Read,
+    output: &'a mut dyn Write,
+    progress: Option<&'a mut dyn Write>,
+    output_bytes: u64,
+    stop_at_breakpoints: bool,
+    error: Option<io::Error>,
+}
+
+#[cfg(target_arch = "x86_64")]
+extern "sysv64" fn jit_output(ctx: *mut JitContext, byte: u32, count: usize) -> u32 {
+    // SAFETY: generated code passes back the context given to `run`
+    let ctx = unsafe { &mut *ctx };
+    match crate::vm::write_repeated(ctx.output, byte as u8, count) {
+        Ok(()) => {
+            ctx.output_bytes += count as u64;
+            STATUS_OK
+        }
+        Err(e) => {
+            ctx.error = Some(e);
+            STATUS_IO_ERROR
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+extern "sysv64" fn jit_input(ctx: *mut JitContext, cell: *mut u8, pc: u32) -> u32 {
+    // SAFETY: generated code passes back the context given to `run` and a
+    // bounds-checked cell address
+    let (ctx, cell) = unsafe { (&mut *ctx, &mut *cell) };
+    let mut buf = [0_u8];
+    match ctx.input.read(&mut buf) {
+        Ok(0) => match ctx.eof {
+            EofBehavior::Unchanged => STATUS_OK,
+            EofBehavior::SetZero => {
+                *cell = 0;
+                STATUS_OK
+            }
+            EofBehavior::SetMinusOne => {
+                *cell = 255;
+                STATUS_OK
+            }
+            EofBehavior::Halt => {
+                ctx.halt_pc = pc as usize;
+                STATUS_EOF_HALT
+            }
+        },
+        Ok(_) => {
+            *cell = buf[0];
+            STATUS_OK
+        }
+        Err(e) => {
+            ctx.error = Some(e);
+            STATUS_IO_ERROR
+        }
+    }
+}
+
+// a status line for the back-edge at `pc`; native code counts no
+// instructions, so it has fewer fields than the interpreter's
+#[cfg(target_arch = "x86_64")]
+extern "sysv64" fn jit_progress(ctx: *mut JitContext, pc: u32, pointer: usize) {
+    // SAFETY: generated code passes back the context given to `run`
+    let ctx = unsafe { &mut *ctx };
+    ctx.progress_seen = progress::requests();
+    let line = format!(
+        "progress: native code, pc {}, pointer {}, {} output bytes",
+        pc, pointer, ctx.output_bytes
+    );
+    match &mut ctx.progress {
+        // a status line is best effort and never fails the run
+        Some(sink) => drop(writeln!(sink, "{}", line)),
+        None => drop(writeln!(io::stderr(), "{}", line)),
+    }
+}
+
+// a `#` that ran: what came before is written out, then the run goes on or
+// stops there with the pointer synced, as asked
+#[cfg(target_arch = "x86_64")]
+extern "sysv64" fn jit_breakpoint(ctx: *mut JitContext, pc: u32) -> u32 {
+    // SAFETY: generated code passes back the context given to `run`
+    let ctx = unsafe { &mut *ctx };
+    if let Err(e) = ctx.output.flush() {
+        ctx.error = Some(e);
+        return STATUS_IO_ERROR;
+    }
+    match ctx.stop_at_breakpoints {
+        true => {
+            ctx.halt_pc = pc as usize;
+            STATUS_BREAKPOINT
+        }
+        false => STATUS_OK,
+    }
+}
+
+/// Where a native run stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JitExit {
+    pub pointer: usize,
+    pub termination: Termination,
+    /// The pc of the breakpoint a run that stops at them stopped at, after
+    /// which `run_with` can go on; `termination` is only final when unset.
+    pub breakpoint: Option<usize>,
+}
+
+/// What `JitProgram::run_with` takes besides the tape and I/O.
+#[derive(Default)]
+pub struct RunOptions<'a> {
+    /// Where status lines go, stderr when unset.
+    pub progress: Option<&'a mut dyn Write>,
+    /// The token to start at, 0 or one just past a breakpoint.
+    pub from_pc: usize,
+    /// Stop at each breakpoint as `VM::run` does, rather than go on past
+    /// them as an engine run does.
+    pub stop_at_breakpoints: bool,
+}
+
+/// Compiled machine code for one token stream.
+pub struct JitProgram {
+    code: ExecutableBuffer,
+    fuel: Option<u64>,
+    map: Vec<(usize, usize)>,
+}
+
+pub fn compile(backend: Backend, tokens: &[Token]) -> Result<JitProgram, JitError> {
+    generate(backend, tokens, None)
+}
+
+/// `compile` for runs that end with `VmError::NativeOutOfFuel` near `fuel`
+/// instructions.
+///
+/// The count is coarse: each back-edge taken costs the instructions of its
+/// loop, and nothing else is counted, so a run stops on a `]` somewhere
+/// around the limit rather than on it.
+pub fn compile_with_fuel(
+    backend: Backend,
+    tokens: &[Token],
+    fuel: u64,
+) -> Result<JitProgram, JitError> {
+    generate(backend, tokens, Some(fuel))
+}
+
+fn generate(backend: Backend, tokens: &[Token], fuel: Option<u64>) -> Result<JitProgram, JitError> {
+    if Backend::host() != Some(backend) {
+        return Err(JitError::UnsupportedBackend(backend));
+    }
+    #[cfg(target_arch = "x86_64")]
+    let callbacks = x86_64::Callbacks {
+        output: jit_output as *const () as u64,
+        input: jit_input as *const () as u64,
+        progress: jit_progress as *const () as u64,
+        breakpoint: jit_breakpoint as *const () as u64,
+        requests: progress::counter().as_ptr() as u64,
+    };
+    #[cfg(not(target_arch = "x86_64"))]
+    let callbacks = x86_64::Callbacks::default();
+    let (code, map) = x86_64::emit(tokens, &callbacks, fuel.is_some())?;
+    Ok(JitProgram {
+        code: ExecutableBuffer::new(&code)?,
+        fuel,
+        map,
+    })
+}
+
+impl JitProgram {
+    pub fn code(&self) -> &[u8] {
+        self.code.code()
+    }
+
+    /// `(pc, offset)` for each token, the offset into `code` its lowering
+    /// starts at, in pc order, and last a pc one past the end for where the
+    /// exits start.
+    pub fn code_map(&self) -> &[(usize, usize)] {
+        &self.map
+    }
+
+    /// Run the program against `tape` starting at cell `pointer`, sending
+    /// the status lines `progress::request` asks for to stderr and going on
+    /// past breakpoints.
+    pub fn run(
+        &self,
+        tape: &mut [u8],
+        pointer: usize,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+        eof: EofBehavior,
+    ) -> Result<JitExit, VmError> {
+        self.run_with(tape, pointer, input, output, eof, RunOptions::default())
+    }
+
+    /// `run` as `options` say.
+    pub fn run_with<'a>(
+        &self,
+        tape: &mut [u8],
+        pointer: usize,
+        input: &'a mut dyn Read,
+        output: &'a mut dyn Write,
+        eof: EofBehavior,
+        options: RunOptions<'a>,
+    ) -> Result<JitExit, VmError> {
+        if pointer >= tape.len() {
+            return Err(VmError::PointerOverFlow(None));
+        }
+        let RunOptions {
+            progress,
+            from_pc,
+            stop_at_breakpoints,
+        } = options;
+        let mut ctx = JitContext {
+            pointer,
+            halt_pc: 0,
+            budget: self.fuel.unwrap_or(u64::MAX),
+            progress_seen: progress::requests(),
+            eof,
+            input,
+            output,
+            progress,
+            output_bytes: 0,
+            stop_at_breakpoints,
+            error: None,
+        };
+        let status = self.enter(&mut ctx, tape, from_pc);
+        let mut breakpoint = None;
+        let termination = match status {
+            STATUS_OK => Termination::Finished,
+            STATUS_BREAKPOINT => {
+                breakpoint = Some(ctx.halt_pc);
+                Termination::Finished
+            }
+            STATUS_POINTER_OVERFLOW => {
+                // the pc of the failed token, and the pointer from before it
+                return Err(VmError::PointerOverFlow(Some(Fault {
+                    pc: ctx.halt_pc,
+                    span: None,
+                    pointer: ctx.pointer,
+                })));
+            }
+            STATUS_IO_ERROR => return Err(VmError::IO(ctx.error.take().unwrap())),
+            STATUS_EOF_HALT => Termination::EofHalt { pc: ctx.halt_pc },
+            STATUS_OUT_OF_FUEL => {
+                return Err(VmError::NativeOutOfFuel(self.fuel.unwrap_or(u64::MAX)))
+            }
+            _ => unreachable!("unknown jit status {}", status),
+        };
+        Ok(JitExit {
+            pointer: ctx.pointer,
+            termination,
+            breakpoint,
+        })
+    }
+
+    // run from the code of token `from_pc`, which is past any prologue so
+    // the entry sets up the registers and jumps there
+    #[cfg(target_arch = "x86_64")]
+    fn enter(&self, ctx: &mut JitContext, tape: &mut [u8], from_pc: usize) -> u32 {
+        type Entry = extern "sysv64" fn(*mut JitContext, *mut u8, usize, usize, usize) -> u32;
+        // SAFETY: the buffer holds a function with exactly this signature,
+        // generated by `x86_64::emit`, which keeps every access in bounds
+        let entry: Entry = unsafe { std::mem::transmute(self.code.as_ptr()) };
+        let resume = match from_pc {
+            0 => 0,
+            pc => {
+                let (_, offset) = self.map[pc.min(self.map.len() - 1)];
+                self.code.as_ptr() as usize + offset
+            }
+        };
+        entry(ctx, tape.as_mut_ptr(), tape.len(), ctx.pointer, resume)
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn enter(&self, _ctx: &mut JitContext, _tape: &mut [u8], _from_pc: usize) -> u32 {
+        unreachable!("compile() refuses backends the host cannot run")
+    }
+}
+
+/// Check that this machine lets generated code run at all.
+///
+/// Maps a stub the way `compile` maps real code and, where there is a
+/// backend for the host, calls it and checks what it returns. Kernels and
+/// sandboxes that forbid executable memory fail here rather than on the
+/// first `--engine` run. The answer is computed once per process.
+pub fn probe() -> Result<(), String> {
+    static PROBE: OnceLock<Result<(), String>> = OnceLock::new();
+    PROBE
+        .get_or_init(|| {
+            // mov eax, 42; ret
+            let stub = ExecutableBuffer::new(&[0xb8, 42, 0, 0, 0, 0xc3])
+                .map_err(|e| format!("cannot map executable memory: {}", e))?;
+            #[cfg(target_arch = "x86_64")]
+            {
+                // SAFETY: the buffer holds exactly the function above
+                let entry: extern "sysv64" fn() -> u32 =
+                    unsafe { std::mem::transmute(stub.as_ptr()) };
+                match entry() {
+                    42 => {}
+                    got => return Err(format!("stub returned {} instead of 42", got)),
+                }
+            }
+            drop(stub);
+            Ok(())
+        })
+        .clone()
+}
+
+/// Result of `run_fragment`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FragmentOutput {
+    pub pointer: usize,
+    pub output: Vec<u8>,
+    pub termination: Termination,
+}
+
+/// Compile a short token sequence with `backend` and run it once from cell 0
+/// of `tape`, reading `input`. Block targets are relinked first, so callers
+/// can write loops with placeholder targets.
+///
+/// This exists to test code generators one encoding at a time.
+pub fn run_fragment(
+    backend: Backend,
+    tokens: &[Token],
+    tape: &mut [u8],
+    input: &[u8],
+) -> Result<FragmentOutput, VmError> {
+    let mut tokens = tokens.to_vec();
+    relink(&mut tokens);
+    let program = compile(backend, &tokens)?;
+    let mut output = vec![];
+    let exit = program.run(
+        tape,
+        0,
+        &mut &input[..],
+        &mut output,
+        EofBehavior::Unchanged,
+    )?;
+    Ok(FragmentOutput {
+        pointer: exit.pointer,
+        output,
+        termination: exit.termination,
+    })
+}
+
+#[cfg(all(unix, target_arch = "x86_64"))]
+#[test]
+fn test_x86_64_fragments() {
+    use crate::tape::TapeDiff;
+    use Token::*;
+
+    // (tokens, initial tape, input, expected tape/pointer/output or overflow)
+    type Case = (
+        Vec<Token>,
+        Vec<u8>,
+        &'static [u8],
+        Option<(Vec<u8>, usize, &'static [u8])>,
+    );
+    let cases: Vec<Case> = vec![
+        (
+            vec![IncrementData(1)],
+            vec![0],
+            b"",
+            Some((vec![1], 0, b"")),
+        ),
+        (
+            vec![IncrementData(255)],
+            vec![1],
+            b"",
+            Some((vec![0], 0, b"")),
+        ),
+        (
+            vec![IncrementData(255)],
+            vec![0],
+            b"",
+            Some((vec![255], 0, b"")),
+        ),
+        (
+            vec![DecrementData(1)],
+            vec![0],
+            b"",
+            Some((vec![255], 0, b"")),
+        ),
+        (
+            vec![DecrementData(255)],
+            vec![0],
+            b"",
+            Some((vec![1], 0, b"")),
+        ),
+        (
+            vec![IncrementPointer(1), IncrementData(7)],
+            vec![0; 4],
+            b"",
+            Some((vec![0, 7, 0, 0], 1, b"")),
+        ),
+        (
+            vec![IncrementPointer(3)],
+            vec![0; 4],
+            b"",
+            Some((vec![0; 4], 3, b"")),
+        ),
+        (vec![IncrementPointer(4)], vec![0; 4], b"", None),
+        (vec![IncrementPointer(1 << 31)], vec![0; 4], b"", None),
+        (vec![IncrementPointer(usize::MAX)], vec![0; 4], b"", None),
+        (
+            vec![IncrementPointer(3), DecrementPointer(2), IncrementData(1)],
+            vec![0; 4],
+            b"",
+            Some((vec![0, 1, 0, 0], 1, b"")),
+        ),
+        (vec![DecrementPointer(1)], vec![0; 4], b"", None),
+        (
+            vec![IncrementPointer(1), DecrementPointer(2)],
+            vec![0; 4],
+            b"",
+            None,
+        ),
+        (vec![DecrementPointer(usize::MAX)], vec![0; 4], b"", None),
+        (vec![Output], vec![b'A'], b"", Some((vec![b'A'], 0, b"A"))),
+        (
+            vec![Output, Output],
+            vec![0],
+            b"",
+            Some((vec![0], 0, b"\0\0")),
+        ),
+        (
+            vec![OutputRepeat(3)],
+            vec![b'z'],
+            b"",
+            Some((vec![b'z'], 0, b"zzz")),
+        ),
+        (
+            vec![OutputRepeat(0)],
+            vec![b'z'],
+            b"",
+            Some((vec![b'z'], 0, b"")),
+        ),
+        (
+            vec![
+                IncrementPointer(2),
+                ClearRange {
+                    start_offset: -1,
+                    len: 3,
+                },
+            ],
+            vec![1, 2, 3, 4, 5],
+            b"",
+            Some((vec![1, 0, 0, 0, 5], 2, b"")),
+        ),
+        (
+            vec![ClearRange {
+                start_offset: 0,
+                len: 5,
+            }],
+            vec![1; 5],
+            b"",
+            Some((vec![0; 5], 0, b"")),
+        ),
+        (
+            vec![ClearRange {
+                start_offset: 0,
+                len: 6,
+            }],
+            vec![1; 5],
+            b"",
+            None,
+        ),
+        (
+            vec![ClearRange {
+                start_offset: -1,
+                len: 1,
+            }],
+            vec![1; 5],
+            b"",
+            None,
+        ),
+        (
+            vec![ClearRange {
+                start_offset: i32::MIN,
+                len: u32::MAX,
+            }],
+            vec![1; 5],
+            b"",
+            None,
+        ),
+        (vec![Input], vec![0], b"x", Some((vec![b'x'], 0, b""))),
+        (
+            vec![Input, Input],
+            vec![0],
+            b"x",
+            Some((vec![b'x'], 0, b"")),
+        ),
+        (
+            vec![Input, Output],
+            vec![0],
+            b"\xff",
+            Some((vec![0xff], 0, b"\xff")),
+        ),
+        (
+            vec![LoopStart(0), DecrementData(1), LoopEnd(0)],
+            vec![200],
+            b"",
+            Some((vec![0], 0, b"")),
+        ),
+        (
+            vec![LoopStart(0), IncrementData(1), LoopEnd(0), Output],
+            vec![0],
+            b"",
+            Some((vec![0], 0, b"\0")),
+        ),
+        (
+            [
+                vec![LoopStart(0), DecrementData(1), IncrementPointer(1)],
+                vec![IncrementData(3), DecrementPointer(1), LoopEnd(0)],
+            ]
+            .concat(),
+            vec![4, 0],
+            b"",
+            Some((vec![0, 12], 0, b"")),
+        ),
+        (
+            vec![
+                MulAdd {
+                    offset: 1,
+                    factor: 255,
+                },
+                MulAdd {
+                    offset: 3,
+                    factor: 9,
+                },
+            ],
+            vec![10, 5, 200, 2],
+            b"",
+            Some((vec![10, 251, 200, 92], 0, b"")),
+        ),
+        (
+            vec![
+                IncrementPointer(1),
+                MulAdd {
+                    offset: 2,
+                    factor: 3,
+                },
+            ],
+            vec![0, 100, 0],
+            b"",
+            None,
+        ),
+        (
+            vec![MulAdd {
+                offset: -1,
+                factor: 1,
+            }],
+            vec![7],
+            b"",
+            None,
+        ),
+        // nothing to add, so nothing to check either
+        (
+            vec![MulAdd {
+                offset: -1,
+                factor: 1,
+            }],
+            vec![0],
+            b"",
+            Some((vec![0], 0, b"")),
+        ),
+        (
+            vec![
+                IncrementPointer(1),
+                AddAt {
+                    offset: 2,
+                    value: 5,
+                },
+                AddAt {
+                    offset: -1,
+                    value: 255,
+                },
+            ],
+            vec![0, 0, 0, 7],
+            b"",
+            Some((vec![255, 0, 0, 12], 1, b"")),
+        ),
+        (
+            vec![AddAt {
+                offset: 1,
+                value: 1,
+            }],
+            vec![0],
+            b"",
+            None,
+        ),
+        (
+            vec![AddAt {
+                offset: -1,
+                value: 1,
+            }],
+            vec![0; 2],
+            b"",
+            None,
+        ),
+        (
+            vec![ScanRight(2)],
+            vec![1, 0, 1, 1, 0],
+            b"",
+            Some((vec![1, 0, 1, 1, 0], 4, b"")),
+        ),
+        (
+            vec![IncrementPointer(4), ScanLeft(3)],
+            vec![1, 0, 1, 1, 1],
+            b"",
+            Some((vec![1, 0, 1, 1, 1], 1, b"")),
+        ),
+        (
+            vec![ScanRight(1)],
+            vec![0, 1],
+            b"",
+            Some((vec![0, 1], 0, b"")),
+        ),
+        // no zero cell before the end of the tape
+        (vec![ScanRight(1)], vec![1; 4], b"", None),
+        (
+            vec![IncrementPointer(3), ScanLeft(2)],
+            vec![1; 4],
+            b"",
+            None,
+        ),
+        (
+            vec![IfStart(0), IncrementPointer(1), IncrementData(1), IfEnd(0)],
+            vec![0, 0],
+            b"",
+            Some((vec![0, 0], 0, b"")),
+        ),
+        (
+            vec![IfStart(0), IncrementPointer(1), IncrementData(1), IfEnd(0)],
+            vec![9, 0],
+            b"",
+            Some((vec![9, 1], 1, b"")),
+        ),
+        // a loop body longer than any rel8 jump could reach
+        (
+            [
+                vec![LoopStart(0), IncrementPointer(1)],
+                vec![Output; 40],
+                vec![DecrementPointer(1), DecrementData(1), LoopEnd(0)],
+            ]
+            .concat(),
+            vec![2, 7],
+            b"",
+            Some((vec![0, 7], 0, &[7; 80])),
+        ),
+    ];
+
+    for backend in Backend::ALL
+        .into_iter()
+        .filter(|b| Some(*b) == Backend::host())
+    {
+        for (tokens, mut tape, input, expected) in cases.clone() {
+            let result = run_fragment(backend, &tokens, &mut tape, input);
+            match (result, expected) {
+                (Ok(out), Some((want_tape, pointer, output))) => {
+                    let diff = TapeDiff::compare(&tape, &want_tape);
+                    assert!(diff.is_empty(), "{:?}: {}", tokens, TapeDiff::render(&diff));
+                    assert_eq!(out.pointer, pointer, "{:?}", tokens);
+                    assert_eq!(out.output, output, "{:?}", tokens);
+                    assert_eq!(out.termination, Termination::Finished);
+                }
+                (Err(VmError::PointerOverFlow(_)), None) => {}
+                (result, _) => panic!("{:?}: unexpected {:?}", tokens, result.map(|o| o.pointer)),
+            }
+        }
+
+        // end of input under each convention
+        let program = compile(backend, &[Input, Output, Input, Output]).unwrap();
+        for (eof, output, termination) in [
+            (
+                EofBehavior::Unchanged,
+                b"aa".to_vec(),
+                Termination::Finished,
+            ),
+            (EofBehavior::SetZero, b"a\0".to_vec(), Termination::Finished),
+            (
+                EofBehavior::SetMinusOne,
+                b"a\xff".to_vec(),
+                Termination::Finished,
+            ),
+            (
+                EofBehavior::Halt,
+                b"a".to_vec(),
+                Termination::EofHalt { pc: 2 },
+            ),
+        ] {
+            let mut tape = [0_u8];
+            let mut out = vec![];
+            let exit = program
+                .run(&mut tape, 0, &mut &b"a"[..], &mut out, eof)
+                .unwrap();
+            assert_eq!((out, exit.termination), (output, termination));
+        }
+    }
+}
+
+#[test]
+fn test_emit_every_token() {
+    use crate::tokenizer::ExtOp;
+    use Token::*;
+
+    // one of each variant, blocks with their ends; the match has no `_` arm,
+    // so a new variant does not build until it is listed here too
+    let fragments = [
+        vec![IncrementData(3)],
+        vec![DecrementData(3)],
+        vec![IncrementPointer(3)],
+        vec![DecrementPointer(3)],
+        vec![Input],
+        vec![Output],
+        vec![OutputRepeat(3)],
+        vec![Print(b'a')],
+        vec![LoopStart(1), LoopEnd(0)],
+        vec![IfStart(1), IfEnd(0)],
+        vec![ClearRange {
+            start_offset: -1,
+            len: 3,
+        }],
+        vec![MulAdd {
+            offset: 1,
+            factor: 3,
+        }],
+        vec![AddAt {
+            offset: -1,
+            value: 3,
+        }],
+        vec![ScanRight(3)],
+        vec![ScanLeft(3)],
+        vec![Ext(ExtOp::Halt)],
+        vec![Breakpoint],
+    ];
+    let mut seen = [false; 19];
+    for token in fragments.iter().flatten() {
+        let kind = match token {
+            IncrementData(_) => 0,
+            DecrementData(_) => 1,
+            IncrementPointer(_) => 2,
+            DecrementPointer(_) => 3,
+            Input => 4,
+            Output => 5,
+            OutputRepeat(_) => 6,
+            Print(_) => 7,
+            LoopStart(_) => 8,
+            LoopEnd(_) => 9,
+            IfStart(_) => 10,
+            IfEnd(_) => 11,
+            ClearRange { .. } => 12,
+            MulAdd { .. } => 13,
+            ScanRight(_) => 14,
+            ScanLeft(_) => 15,
+            Ext(_) => 16,
+            Breakpoint => 17,
+            AddAt { .. } => 18,
+        };
+        seen[kind] = true;
+    }
+    assert!(seen.iter().all(|&seen| seen), "{:?}", seen);
+
+    for (tokens, metered) in fragments.iter().flat_map(|f| [(f, false), (f, true)]) {
+        match (
+            x86_64::emit(tokens, &Default::default(), metered),
+            tokens[0],
+        ) {
+            (Err(JitError::UnsupportedToken(token)), Ext(_)) => {
+                assert_eq!(token, tokens[0])
+            }
+            (Ok((code, map)), _) => assert!(!code.is_empty() && map.len() == tokens.len() + 1),
+            (result, _) => panic!("{:?}: unexpected {:?}", tokens, result.err()),
+        }
+    }
+    // the refusal comes before any memory is mapped, in the middle of a
+    // program as well
+    let err = compile(Backend::X86_64, &[IncrementData(1), Ext(ExtOp::Not)]).err();
+    if Backend::host().is_some() {
+        assert_eq!(err.unwrap().code(), "E0504");
+    }
+
+    // counts are immediates: any run is as long as a single step
+    let len = |token| {
+        x86_64::emit(&[token], &Default::default(), false)
+            .unwrap()
+            .0
+            .len()
+    };
+    assert_eq!(len(IncrementData(1)), len(IncrementData(200)));
+    assert_eq!(len(DecrementData(1)), len(DecrementData(255)));
+    assert_eq!(len(IncrementPointer(1)), len(IncrementPointer(1 << 20)));
+    assert_eq!(len(DecrementPointer(1)), len(DecrementPointer(1 << 20)));
+    assert_eq!(len(OutputRepeat(2)), len(OutputRepeat(1 << 20)));
+}
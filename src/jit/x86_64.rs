@@ -0,0 +1,413 @@
+//! x86-64 code generator.
+//!
+//! The generated function uses the System V calling convention:
+//! `fn(ctx, tape, tape_len, pointer, resume) -> status`, keeping its state
+//! in callee-saved registers so the I/O callbacks need no spilling. A
+//! nonzero `resume` is the address of a token's code to start at rather
+//! than the first token's.
+//!
+//!
+//! ```text
+//! r12  *mut JitContext
+//! r13  tape base
+//! r14  data pointer, an index into the tape
+//! r15  tape length
+//! ```
+
+use super::{JitError, STATUS_OK, STATUS_OUT_OF_FUEL, STATUS_POINTER_OVERFLOW};
+use crate::tokenizer::Token;
+
+// `[r13 + r14]` addressing, with `reg` in the ModRM reg field
+const CELL_REX: u8 = 0x43; // REX.X + REX.B
+const CELL_SIB: u8 = 0x35; // index r14, base r13
+const REX_W: u8 = 0x08;
+
+// (pc, code offset) pairs, as `emit` returns them
+type CodeMap = Vec<(usize, usize)>;
+
+/// Addresses the generated code calls or reads.
+#[derive(Debug, Default, Clone, Copy)]
+pub(super) struct Callbacks {
+    pub output: u64,
+    pub input: u64,
+    /// `fn(ctx, pc, pointer)`, printing a status line.
+    pub progress: u64,
+    /// The `progress::requests` counter, compared on each back-edge.
+    pub requests: u64,
+    /// `fn(ctx, pc) -> status` for a `#`.
+    pub breakpoint: u64,
+}
+
+struct Emitter {
+    code: Vec<u8>,
+    pc: usize,                         // token being lowered
+    overflow: Vec<(usize, usize)>,     // rel32 fields jumping to the overflow exit, by pc
+    exit: Vec<usize>,                  // rel32 fields jumping to the common exit
+    fuel: Option<Vec<usize>>,          // rel32 fields jumping to the fuel exit, when metered
+    polls: Vec<(usize, usize, usize)>, // (rel32 field, pc, resume) of each progress check
+    map: CodeMap,                      // where each token's code starts
+}
+
+impl Emitter {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn imm32(&mut self, x: u32) {
+        self.bytes(&x.to_le_bytes());
+    }
+
+    fn imm64(&mut self, x: u64) {
+        self.bytes(&x.to_le_bytes());
+    }
+
+    // `op [r13 + r14 + 0]` with `reg` as the ModRM reg field
+    fn cell(&mut self, rex: u8, opcode: &[u8], reg: u8) {
+        self.code.push(CELL_REX | rex);
+        self.bytes(opcode);
+        self.bytes(&[0x44 | (reg << 3), CELL_SIB, 0x00]);
+    }
+
+    // jcc with a rel32 placeholder, returning the position of the field
+    fn jcc(&mut self, cc: u8) -> usize {
+        self.bytes(&[0x0f, cc]);
+        self.imm32(0);
+        self.code.len() - 4
+    }
+
+    // jcc to the overflow exit of the current token
+    fn jcc_overflow(&mut self, cc: u8) {
+        let field = self.jcc(cc);
+        self.overflow.push((field, self.pc));
+    }
+
+    fn patch(&mut self, field: usize, target: usize) {
+        let rel = target as i64 - (field as i64 + 4);
+        self.code[field..field + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+    }
+
+    fn mov_rax(&mut self, x: u64) {
+        self.bytes(&[0x48, 0xb8]);
+        self.imm64(x);
+    }
+
+    // call the callback in rax and leave with its status unless it is 0
+    fn call_rax_checked(&mut self) {
+        self.bytes(&[0xff, 0xd0]); // call rax
+        self.bytes(&[0x85, 0xc0]); // test eax, eax
+        let field = self.jcc(0x85); // jnz exit
+        self.exit.push(field);
+    }
+
+    fn cmp_cell_zero(&mut self) {
+        self.cell(0, &[0x80], 7);
+        self.code.push(0x00);
+    }
+
+    // moves go through rax, so a failed one leaves the pointer as it was
+    fn move_right(&mut self, x: usize) {
+        self.bytes(&[0x4c, 0x89, 0xf0]); // mov rax, r14
+        if x <= i32::MAX as usize {
+            self.bytes(&[0x48, 0x05]); // add rax, imm32
+            self.imm32(x as u32);
+        } else {
+            self.bytes(&[0x48, 0xba]); // mov rdx, imm64
+            self.imm64(x as u64);
+            self.bytes(&[0x48, 0x01, 0xd0]); // add rax, rdx
+            self.jcc_overflow(0x82); // jc overflow
+        }
+        self.bytes(&[0x4c, 0x39, 0xf8]); // cmp rax, r15
+        self.jcc_overflow(0x83); // jae overflow
+        self.bytes(&[0x49, 0x89, 0xc6]); // mov r14, rax
+    }
+
+    fn move_left(&mut self, x: usize) {
+        self.bytes(&[0x4c, 0x89, 0xf0]); // mov rax, r14
+        if x <= i32::MAX as usize {
+            self.bytes(&[0x48, 0x2d]); // sub rax, imm32
+            self.imm32(x as u32);
+        } else {
+            self.bytes(&[0x48, 0xba]); // mov rdx, imm64
+            self.imm64(x as u64);
+            self.bytes(&[0x48, 0x29, 0xd0]); // sub rax, rdx
+        }
+        self.jcc_overflow(0x82); // jb overflow
+        self.bytes(&[0x49, 0x89, 0xc6]); // mov r14, rax
+    }
+
+    // bounds-check the whole range once, then `rep stosb` zeroes it
+    fn clear_range(&mut self, start_offset: i32, len: u32) {
+        self.bytes(&[0x4c, 0x89, 0xf0]); // mov rax, r14
+        self.bytes(&[0x48, 0xba]); // mov rdx, imm64
+        self.imm64(start_offset as i64 as u64);
+        self.bytes(&[0x48, 0x01, 0xd0]); // add rax, rdx
+        self.jcc_overflow(0x88); // js overflow, the start is left of cell 0
+        self.code.push(0xb9); // mov ecx, imm32
+        self.imm32(len);
+        self.bytes(&[0x48, 0x01, 0xc1]); // add rcx, rax
+        self.bytes(&[0x4c, 0x39, 0xf9]); // cmp rcx, r15
+        self.jcc_overflow(0x87); // ja overflow
+        self.bytes(&[0x49, 0x8d, 0x7c, 0x05, 0x00]); // lea rdi, [r13 + rax]
+        self.code.push(0xb9); // mov ecx, imm32
+        self.imm32(len);
+        self.bytes(&[0x31, 0xc0]); // xor eax, eax
+        self.bytes(&[0xf3, 0xaa]); // rep stosb
+    }
+
+    // skip when the cell is zero, else bounds-check the target and add the
+    // low byte of cell * factor to it
+    fn mul_add(&mut self, offset: i32, factor: u8) {
+        self.cell(0, &[0x0f, 0xb6], 0); // movzx eax, byte [cell]
+        self.bytes(&[0x85, 0xc0]); // test eax, eax
+        let skip = self.jcc(0x84); // jz past it all
+        self.bytes(&[0x4c, 0x89, 0xf2]); // mov rdx, r14
+        self.bytes(&[0x48, 0xb9]); // mov rcx, imm64
+        self.imm64(offset as i64 as u64);
+        self.bytes(&[0x48, 0x01, 0xca]); // add rdx, rcx
+        self.jcc_overflow(0x88); // js overflow, left of cell 0
+        self.bytes(&[0x4c, 0x39, 0xfa]); // cmp rdx, r15
+        self.jcc_overflow(0x83); // jae overflow
+        self.bytes(&[0x69, 0xc0]); // imul eax, eax, imm32
+        self.imm32(factor as u32);
+        self.bytes(&[0x41, 0x00, 0x44, 0x15, 0x00]); // add byte [r13 + rdx], al
+        let end = self.code.len();
+        self.patch(skip, end);
+    }
+
+    // bounds-check the cell `offset` away and add `value` to it
+    fn add_at(&mut self, offset: i32, value: u8) {
+        self.bytes(&[0x4c, 0x89, 0xf2]); // mov rdx, r14
+        self.bytes(&[0x48, 0xb9]); // mov rcx, imm64
+        self.imm64(offset as i64 as u64);
+        self.bytes(&[0x48, 0x01, 0xca]); // add rdx, rcx
+        self.jcc_overflow(0x88); // js overflow, left of cell 0
+        self.bytes(&[0x4c, 0x39, 0xfa]); // cmp rdx, r15
+        self.jcc_overflow(0x83); // jae overflow
+        self.bytes(&[0x41, 0x80, 0x44, 0x15, 0x00, value]); // add byte [r13 + rdx], imm8
+    }
+
+    // move by `stride` until the cell is zero, each move checked like `>`
+    // and `<`
+    fn scan(&mut self, stride: usize, right: bool) {
+        let top = self.code.len();
+        self.cmp_cell_zero();
+        let done = self.jcc(0x84); // jz done
+        match right {
+            true => self.move_right(stride),
+            false => self.move_left(stride),
+        }
+        self.code.push(0xe9); // jmp top
+        self.imm32(0);
+        let field = self.code.len() - 4;
+        self.patch(field, top);
+        let end = self.code.len();
+        self.patch(done, end);
+    }
+
+    // `output(ctx, cell, count)`, one callback for the whole run
+    fn output(&mut self, output: u64, count: usize) {
+        self.bytes(&[0x4c, 0x89, 0xe7]); // mov rdi, r12
+        self.cell(0, &[0x0f, 0xb6], 6); // movzx esi, byte [cell]
+        self.bytes(&[0x48, 0xba]); // mov rdx, imm64
+        self.imm64(count as u64);
+        self.mov_rax(output);
+        self.call_rax_checked();
+    }
+
+    // `output` of one constant byte rather than the cell
+    fn print(&mut self, output: u64, byte: u8) {
+        self.bytes(&[0x4c, 0x89, 0xe7]); // mov rdi, r12
+        self.code.push(0xbe); // mov esi, imm32
+        self.code.extend_from_slice(&(byte as u32).to_le_bytes());
+        self.bytes(&[0xba, 1, 0, 0, 0]); // mov edx, 1
+        self.mov_rax(output);
+        self.call_rax_checked();
+    }
+}
+
+/// Generate code for `tokens`, calling `callbacks`. Each back-edge compares
+/// the requests counter with the count at offset 24 of the context and
+/// calls the progress callback when they differ. `metered` code also takes
+/// the length of a loop from the budget at offset 16 on each back-edge, and
+/// leaves once it runs short.
+///
+/// Returns the code and where in it each token's code starts, as
+/// `(pc, offset)` pairs in pc order, then `(tokens.len(), offset)` where the
+/// exits and overflow stubs start.
+///
+/// Every token is matched by name, so a new variant does not build until it
+/// is lowered here or refused with `JitError::UnsupportedToken`.
+pub(super) fn emit(
+    tokens: &[Token],
+    callbacks: &Callbacks,
+    metered: bool,
+) -> Result<(Vec<u8>, CodeMap), JitError> {
+    let Callbacks { output, input, .. } = *callbacks;
+    let mut e = Emitter {
+        code: Vec::with_capacity(tokens.len() * 8 + 64),
+        pc: 0,
+        overflow: vec![],
+        exit: vec![],
+        fuel: metered.then(Vec::new),
+        polls: vec![],
+        map: Vec::with_capacity(tokens.len() + 1),
+    };
+
+    // push rbx, r12-r15 (leaves rsp 16-byte aligned for calls)
+    e.bytes(&[0x53, 0x41, 0x54, 0x41, 0x55, 0x41, 0x56, 0x41, 0x57]);
+    e.bytes(&[0x49, 0x89, 0xfc]); // mov r12, rdi
+    e.bytes(&[0x49, 0x89, 0xf5]); // mov r13, rsi
+    e.bytes(&[0x49, 0x89, 0xd7]); // mov r15, rdx
+    e.bytes(&[0x49, 0x89, 0xce]); // mov r14, rcx
+    e.bytes(&[0x4d, 0x85, 0xc0]); // test r8, r8
+    e.bytes(&[0x74, 0x03]); // jz over the jump
+    e.bytes(&[0x41, 0xff, 0xe0]); // jmp r8
+
+    // (jz field, body start) of each open block
+    let mut stk: Vec<(usize, usize)> = vec![];
+    for (pc, token) in tokens.iter().enumerate() {
+        e.pc = pc;
+        e.map.push((pc, e.code.len()));
+        match *token {
+            Token::IncrementData(x) => {
+                e.cell(0, &[0x80], 0); // add byte [cell], imm8
+                e.code.push(x);
+            }
+            Token::DecrementData(x) => {
+                e.cell(0, &[0x80], 5); // sub byte [cell], imm8
+                e.code.push(x);
+            }
+            Token::IncrementPointer(x) => e.move_right(x),
+            Token::DecrementPointer(x) => e.move_left(x),
+            Token::Output => e.output(output, 1),
+            Token::OutputRepeat(n) => e.output(output, n),
+            Token::Print(byte) => e.print(output, byte),
+            Token::ClearRange { start_offset, len } => e.clear_range(start_offset, len),
+            Token::MulAdd { offset, factor } => e.mul_add(offset, factor),
+            Token::AddAt { offset, value } => e.add_at(offset, value),
+            Token::ScanRight(x) => e.scan(x, true),
+            Token::ScanLeft(x) => e.scan(x, false),
+            Token::Ext(_) => return Err(JitError::UnsupportedToken(*token)),
+            // one at the very end ends the run, as in the interpreter
+            Token::Breakpoint if pc + 1 == tokens.len() => {}
+            Token::Breakpoint => {
+                e.bytes(&[0x4c, 0x89, 0xe7]); // mov rdi, r12
+                e.code.push(0xbe); // mov esi, imm32
+                e.imm32(pc as u32);
+                e.mov_rax(callbacks.breakpoint);
+                e.call_rax_checked();
+            }
+            Token::Input => {
+                e.bytes(&[0x4c, 0x89, 0xe7]); // mov rdi, r12
+                e.cell(REX_W, &[0x8d], 6); // lea rsi, [cell]
+                e.code.push(0xba); // mov edx, imm32
+                e.imm32(pc as u32);
+                e.mov_rax(input);
+                e.call_rax_checked();
+            }
+            Token::LoopStart(_) | Token::IfStart(_) => {
+                e.cmp_cell_zero();
+                let field = e.jcc(0x84); // jz past the block
+                stk.push((field, e.code.len()));
+            }
+            Token::LoopEnd(start) => {
+                let (field, body) = stk.pop().expect("unbalanced loop");
+                e.cmp_cell_zero();
+                let done = e.jcc(0x84); // jz past the loop
+                e.mov_rax(callbacks.requests);
+                e.bytes(&[0x48, 0x8b, 0x00]); // mov rax, [rax]
+                e.bytes(&[0x49, 0x3b, 0x44, 0x24, 0x18]); // cmp rax, [r12 + 24]
+                let report = e.jcc(0x85); // jne to a call of the progress callback
+                e.polls.push((report, pc, e.code.len()));
+                if e.fuel.is_some() {
+                    // the body and this `]`, what an iteration runs
+                    e.bytes(&[0x49, 0x81, 0x6c, 0x24, 0x10]); // sub qword [r12 + 16], imm32
+                    e.imm32((pc - start as usize) as u32);
+                    let short = e.jcc(0x82); // jb out of fuel
+                    e.fuel.as_mut().unwrap().push(short);
+                }
+                e.code.push(0xe9); // jmp body
+                e.imm32(0);
+                let back = e.code.len() - 4;
+                e.patch(back, body);
+                let end = e.code.len();
+                e.patch(done, end);
+                e.patch(field, end);
+            }
+            Token::IfEnd(_) => {
+                let (field, _) = stk.pop().expect("unbalanced loop");
+                let end = e.code.len();
+                e.patch(field, end);
+            }
+        }
+    }
+
+    e.map.push((tokens.len(), e.code.len()));
+    e.bytes(&[0xb8]); // mov eax, STATUS_OK
+    e.imm32(STATUS_OK);
+    let exit = e.code.len();
+    e.bytes(&[0x4d, 0x89, 0x34, 0x24]); // mov [r12], r14
+
+    // pop r15-r12, rbx; ret
+    e.bytes(&[0x41, 0x5f, 0x41, 0x5e, 0x41, 0x5d, 0x41, 0x5c, 0x5b, 0xc3]);
+
+    let overflow = e.code.len();
+    e.bytes(&[0xb8]); // mov eax, STATUS_POINTER_OVERFLOW
+    e.imm32(STATUS_POINTER_OVERFLOW);
+    e.bytes(&[0xe9]); // jmp exit
+    e.imm32(0);
+    let field = e.code.len() - 4;
+    e.patch(field, exit);
+
+    // a stub per failing token stores its pc in `halt_pc`, at offset 8,
+    // then leaves through the overflow exit
+    let mut stub: Option<(usize, usize)> = None; // (pc, start)
+    for (field, pc) in std::mem::take(&mut e.overflow) {
+        let start = match stub {
+            Some((at, start)) if at == pc => start,
+            _ => {
+                let start = e.code.len();
+                e.bytes(&[0x49, 0xc7, 0x44, 0x24, 0x08]); // mov qword [r12 + 8], imm32
+                e.imm32(pc as u32);
+                e.code.push(0xe9); // jmp overflow
+                e.imm32(0);
+                let jmp = e.code.len() - 4;
+                e.patch(jmp, overflow);
+                stub = Some((pc, start));
+                start
+            }
+        };
+        e.patch(field, start);
+    }
+    // a stub per back-edge reports and goes back to where the check was
+    for (field, pc, resume) in std::mem::take(&mut e.polls) {
+        let start = e.code.len();
+        e.bytes(&[0x4c, 0x89, 0xe7]); // mov rdi, r12
+        e.code.push(0xbe); // mov esi, imm32
+        e.imm32(pc as u32);
+        e.bytes(&[0x4c, 0x89, 0xf2]); // mov rdx, r14
+        e.mov_rax(callbacks.progress);
+        e.bytes(&[0xff, 0xd0]); // call rax
+        e.code.push(0xe9); // jmp resume
+        e.imm32(0);
+        let jmp = e.code.len() - 4;
+        e.patch(jmp, resume);
+        e.patch(field, start);
+    }
+    if let Some(fields) = e.fuel.take() {
+        let out_of_fuel = e.code.len();
+        e.bytes(&[0xb8]); // mov eax, STATUS_OUT_OF_FUEL
+        e.imm32(STATUS_OUT_OF_FUEL);
+        e.bytes(&[0xe9]); // jmp exit
+        e.imm32(0);
+        let field = e.code.len() - 4;
+        e.patch(field, exit);
+        for field in fields {
+            e.patch(field, out_of_fuel);
+        }
+    }
+    for field in std::mem::take(&mut e.exit) {
+        e.patch(field, exit);
+    }
+    Ok((e.code, e.map))
+}
@@ -0,0 +1,122 @@
+//! Generated machine code laid against the IR, for `--dump-jit`.
+//!
+//! `listing` gives each token a line with the offset its code starts at, its
+//! index, where it came from in the source and its `Display` form, indented
+//! by block nesting as in `ir_dump`, and then the bytes of its code:
+//!
+//! ```text
+//! 00000000  ----  -      prologue
+//!           53 41 54 41 55 41 56 41 57 49 89 fc 49 89 f5 49
+//!           89 d7 49 89 ce
+//! 00000015  0000  1:1    ADD 2
+//!           43 80 44 35 00 02
+//! 0000001b  0001  2:1    JZ -> 0006
+//!           43 80 7c 35 00 00 0f 84 3f 00 00 00
+//! 00000027  0002  2:2        MOVE 1
+//! ```
+//!
+//! The offsets are those of the raw code, so `--dump-jit=PATH` output run
+//! through `objdump -D -b binary -mi386:x86-64 PATH` lines up with them.
+
+use std::fmt::Write;
+
+use crate::{jit::JitProgram, program::Program};
+
+// code bytes per row
+const ROW: usize = 16;
+
+fn rows(out: &mut String, code: &[u8]) {
+    for chunk in code.chunks(ROW) {
+        out.push_str("         ");
+        for byte in chunk {
+            write!(out, " {:02x}", byte).unwrap();
+        }
+        out.push('\n');
+    }
+}
+
+/// `jit`, compiled from `program`, one token at a time with the prologue
+/// before and the shared exits after.
+pub fn listing(program: &Program, jit: &JitProgram) -> String {
+    let (tokens, spans, code) = (program.tokens(), program.spans(), jit.code());
+    let map = jit.code_map();
+    let width = tokens.len().saturating_sub(1).to_string().len().max(4);
+    let mut out = String::new();
+    let heading = |out: &mut String, offset: usize, label: &str| {
+        let none = "-".repeat(width);
+        writeln!(out, "{:08x}  {}  {:<5}  {}", offset, none, "-", label).unwrap();
+    };
+    let (_, body) = map[0];
+    heading(&mut out, 0, "prologue");
+    rows(&mut out, &code[..body]);
+    let mut depth = 0;
+    for pair in map.windows(2) {
+        let ((pc, start), (_, end)) = (pair[0], pair[1]);
+        let token = tokens[pc];
+        if token.is_block_end() {
+            depth -= 1;
+        }
+        let span = match spans.get(pc) {
+            Some(span) if span.line > 0 => span.to_string(),
+            _ => "-".to_string(),
+        };
+        let indent = "    ".repeat(depth);
+        writeln!(
+            out,
+            "{:08x}  {:0width$}  {:<5}  {}{}",
+            start,
+            pc,
+            span,
+            indent,
+            token,
+            width = width
+        )
+        .unwrap();
+        rows(&mut out, &code[start..end]);
+        if token.is_block_start() {
+            depth += 1;
+        }
+    }
+    let (_, exits) = map[map.len() - 1];
+    heading(&mut out, exits, "exits");
+    rows(&mut out, &code[exits..]);
+    out
+}
+
+#[cfg(all(unix, target_arch = "x86_64"))]
+#[test]
+fn test_jit_dump() {
+    use crate::{
+        jit::{self, Backend},
+        program::OptLevel,
+    };
+
+    let src = "++\n[>+<-]>.";
+    let program = Program::compile_with(src, OptLevel::O1).unwrap();
+    let compiled = jit::compile(Backend::X86_64, program.tokens()).unwrap();
+
+    // a start for every token, in order, and one for the exits
+    let map = compiled.code_map();
+    assert_eq!(map.len(), program.tokens().len() + 1);
+    assert!(map.iter().enumerate().all(|(i, &(pc, _))| pc == i));
+    assert!(map.windows(2).all(|w| w[0].1 < w[1].1));
+    assert!(map.last().unwrap().1 < compiled.code().len());
+
+    let text = listing(&program, &compiled);
+    let lines: Vec<_> = text.lines().collect();
+    assert_eq!(lines[0], "00000000  ----  -      prologue");
+    // the loop's code, found by its place in the source
+    let (_, start) = map[1];
+    assert_eq!(lines[5], format!("{:08x}  0001  2:1    JZ -> 0006", start));
+    assert!(text.contains("  0003  2:3        ADD 1\n"), "{}", text);
+    assert!(text.contains("  0006  2:6    JNZ -> 0001\n"), "{}", text);
+    // every byte shows up once, in order
+    let bytes: Vec<u8> = lines
+        .iter()
+        .filter(|line| line.starts_with("          "))
+        .flat_map(|line| line.split_whitespace())
+        .map(|byte| u8::from_str_radix(byte, 16).unwrap())
+        .collect();
+    assert_eq!(bytes, compiled.code());
+    assert!(lines.iter().any(|line| line.ends_with("  exits")));
+}
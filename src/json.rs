@@ -0,0 +1,397 @@
+//! Just enough JSON for the line-delimited server protocol.
+//!
+//! Numbers are kept as `f64`, objects keep their key order, and output is
+//! always compact so one value fits on one line.
+
+use std::fmt::{self, Write};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid JSON at byte {offset}")]
+pub struct JsonError {
+    pub offset: usize,
+}
+
+impl Json {
+    pub fn parse(text: &str) -> Result<Self, JsonError> {
+        let mut parser = Parser {
+            src: text.as_bytes(),
+            pos: 0,
+            depth: 0,
+        };
+        let value = parser.value()?;
+        parser.skip_ws();
+        if parser.pos != parser.src.len() {
+            return Err(parser.error());
+        }
+        Ok(value)
+    }
+
+    /// Look up `key` in an object; `None` for missing keys and non-objects.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// The value as a non-negative integer, if it is one exactly.
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Json::Number(x) if x >= 0.0 && x.fract() == 0.0 && x < u64::MAX as f64 => {
+                Some(x as u64)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Self {
+        Json::String(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Self {
+        Json::String(s)
+    }
+}
+
+impl From<u64> for Json {
+    fn from(x: u64) -> Self {
+        Json::Number(x as f64)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(x: usize) -> Self {
+        Json::Number(x as f64)
+    }
+}
+
+impl From<i32> for Json {
+    fn from(x: i32) -> Self {
+        Json::Number(x as f64)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Self {
+        Json::Bool(b)
+    }
+}
+
+/// Build a `Json::Object` from `key => value` pairs.
+macro_rules! json_object {
+    ($($key:expr => $value:expr),* $(,)?) => {
+        $crate::json::Json::Object(vec![$(($key.to_string(), $crate::json::Json::from($value))),*])
+    };
+}
+pub(crate) use json_object;
+
+fn write_str(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    f.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => f.write_str("null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(x) if x.fract() == 0.0 && x.abs() < 1e15 => write!(f, "{}", *x as i64),
+            Json::Number(x) if x.is_finite() => write!(f, "{}", x),
+            Json::Number(_) => f.write_str("null"),
+            Json::String(s) => write_str(f, s),
+            Json::Array(items) => {
+                f.write_char('[')?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                f.write_char(']')
+            }
+            Json::Object(fields) => {
+                f.write_char('{')?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write_str(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+/// How deep arrays and objects may nest, well short of the stack running
+/// out in `Parser::value`.
+const MAX_DEPTH: usize = 256;
+
+struct Parser<'a> {
+    src: &'a [u8],
+    pos: usize,
+    depth: usize, // arrays and objects open around `pos`
+}
+
+impl Parser<'_> {
+    fn error(&self) -> JsonError {
+        JsonError { offset: self.pos }
+    }
+
+    fn skip_ws(&mut self) {
+        while matches!(self.src.get(self.pos), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        self.skip_ws();
+        if self.src.get(self.pos) == Some(&byte) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn keyword(&mut self, word: &str, value: Json) -> Result<Json, JsonError> {
+        if self.src[self.pos..].starts_with(word.as_bytes()) {
+            self.pos += word.len();
+            Ok(value)
+        } else {
+            Err(self.error())
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, JsonError> {
+        self.skip_ws();
+        if matches!(self.src.get(self.pos), Some(b'[' | b'{')) {
+            if self.depth == MAX_DEPTH {
+                return Err(self.error());
+            }
+            self.depth += 1;
+            let value = self.nested();
+            self.depth -= 1;
+            return value;
+        }
+        match self.src.get(self.pos) {
+            Some(b'n') => self.keyword("null", Json::Null),
+            Some(b't') => self.keyword("true", Json::Bool(true)),
+            Some(b'f') => self.keyword("false", Json::Bool(false)),
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            _ => Err(self.error()),
+        }
+    }
+
+    /// The array or object at `pos`.
+    fn nested(&mut self) -> Result<Json, JsonError> {
+        match self.src.get(self.pos) {
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = vec![];
+                if !self.eat(b']') {
+                    loop {
+                        items.push(self.value()?);
+                        if self.eat(b']') {
+                            break;
+                        }
+                        if !self.eat(b',') {
+                            return Err(self.error());
+                        }
+                    }
+                }
+                Ok(Json::Array(items))
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let mut fields = vec![];
+                if !self.eat(b'}') {
+                    loop {
+                        self.skip_ws();
+                        let key = self.string()?;
+                        if !self.eat(b':') {
+                            return Err(self.error());
+                        }
+                        fields.push((key, self.value()?));
+                        if self.eat(b'}') {
+                            break;
+                        }
+                        if !self.eat(b',') {
+                            return Err(self.error());
+                        }
+                    }
+                }
+                Ok(Json::Object(fields))
+            }
+            _ => Err(self.error()),
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, JsonError> {
+        let start = self.pos;
+        while matches!(
+            self.src.get(self.pos),
+            Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9')
+        ) {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.src[start..self.pos])
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .map(Json::Number)
+            .ok_or(JsonError { offset: start })
+    }
+
+    fn hex4(&mut self) -> Result<u32, JsonError> {
+        let digits = self.src.get(self.pos..self.pos + 4).ok_or(self.error())?;
+        let code = std::str::from_utf8(digits)
+            .ok()
+            .and_then(|s| u32::from_str_radix(s, 16).ok())
+            .ok_or(self.error())?;
+        self.pos += 4;
+        Ok(code)
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        if self.src.get(self.pos) != Some(&b'"') {
+            return Err(self.error());
+        }
+        self.pos += 1;
+        let mut out = String::new();
+        loop {
+            let start = self.pos;
+            while !matches!(self.src.get(self.pos), None | Some(b'"' | b'\\')) {
+                self.pos += 1;
+            }
+            // the input is a &str and we only stop at ASCII bytes
+            out.push_str(std::str::from_utf8(&self.src[start..self.pos]).unwrap());
+            match self.src.get(self.pos) {
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(out);
+                }
+                Some(b'\\') => {
+                    self.pos += 1;
+                    let escape = *self.src.get(self.pos).ok_or(self.error())?;
+                    self.pos += 1;
+                    match escape {
+                        b'"' => out.push('"'),
+                        b'\\' => out.push('\\'),
+                        b'/' => out.push('/'),
+                        b'b' => out.push('\u{8}'),
+                        b'f' => out.push('\u{c}'),
+                        b'n' => out.push('\n'),
+                        b'r' => out.push('\r'),
+                        b't' => out.push('\t'),
+                        b'u' => {
+                            let mut code = self.hex4()?;
+                            // a surrogate pair spells one code point
+                            if (0xd800..0xdc00).contains(&code)
+                                && self.src[self.pos..].starts_with(b"\\u")
+                            {
+                                self.pos += 2;
+                                let low = self.hex4()?;
+                                code =
+                                    0x10000 + ((code - 0xd800) << 10) + (low.wrapping_sub(0xdc00));
+                            }
+                            out.push(char::from_u32(code).ok_or(self.error())?);
+                        }
+                        _ => return Err(self.error()),
+                    }
+                }
+                _ => return Err(self.error()),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_json() {
+    let text = r#" {"a": [1, -2.5, true, null], "b": {"c": "x\"\n\u00e9\ud83d\ude00"}, "d": []} "#;
+    let value = Json::parse(text).unwrap();
+    assert_eq!(
+        value.get("a").unwrap(),
+        &Json::Array(vec![
+            Json::Number(1.0),
+            Json::Number(-2.5),
+            Json::Bool(true),
+            Json::Null,
+        ])
+    );
+    let c = value.get("b").and_then(|b| b.get("c")).unwrap();
+    assert_eq!(c.as_str(), Some("x\"\né😀"));
+    assert_eq!(
+        value.to_string(),
+        r#"{"a":[1,-2.5,true,null],"b":{"c":"x\"\né😀"},"d":[]}"#
+    );
+    assert_eq!(Json::parse(&value.to_string()).unwrap(), value);
+    assert_eq!(
+        json_object! {"id" => 7_u64, "ok" => true}.to_string(),
+        r#"{"id":7,"ok":true}"#
+    );
+
+    assert_eq!(Json::Number(3.0).as_u64(), Some(3));
+    assert_eq!(Json::Number(-3.0).as_u64(), None);
+    assert_eq!(Json::Number(0.5).as_u64(), None);
+
+    for bad in [
+        "",
+        "{",
+        "[1,]",
+        "{\"a\" 1}",
+        "tru",
+        "\"abc",
+        "1 2",
+        "\"\\q\"",
+    ] {
+        assert!(Json::parse(bad).is_err(), "{}", bad);
+    }
+    assert_eq!(Json::parse("[1, x]").unwrap_err().offset, 4);
+
+    // nesting is capped rather than overflowing the stack
+    let deep = |n| "[".repeat(n) + &"]".repeat(n);
+    assert!(Json::parse(&deep(MAX_DEPTH)).is_ok());
+    assert_eq!(
+        Json::parse(&deep(MAX_DEPTH + 1)).unwrap_err().offset,
+        MAX_DEPTH
+    );
+    assert_eq!(
+        Json::parse(&"[".repeat(20000)).unwrap_err().offset,
+        MAX_DEPTH
+    );
+    assert!(Json::parse(&"{\"a\":".repeat(20000)).is_err());
+}
@@ -0,0 +1,69 @@
+//! A brainfuck compiler, interpreter and x86-64 JIT.
+//!
+//! `Program::compile` turns source into optimized tokens, and a `VM` or any
+//! other `Engine` runs them:
+//!
+//! ```text
+//! let mut vm = VM::new_from_str(",[.,]")?.with_io(input, output);
+//! vm.run()?;
+//! ```
+//!
+//! `jit::compile` is the native code generator behind the `X86_64Jit`
+//! engine. The `bfjit` binary is a thin command line over this crate.
+
+pub mod batch;
+pub mod bench;
+pub mod bigcell;
+#[cfg(feature = "image")]
+pub mod brainloller;
+pub mod bytecode;
+pub mod callgrind;
+pub mod checkpoint;
+pub mod codegen;
+pub mod compare;
+pub mod console;
+pub mod doctor;
+pub mod document;
+pub mod engine;
+pub mod error;
+pub mod format;
+pub mod generate;
+pub mod ir_cache;
+pub mod ir_dump;
+pub mod ir_text;
+pub mod jit;
+pub mod jit_dump;
+pub mod json;
+pub mod lint;
+pub mod lsp;
+pub mod mutate;
+pub mod pass;
+#[cfg(feature = "image")]
+pub mod png;
+pub mod profile;
+pub mod program;
+pub mod progress;
+pub mod python;
+pub mod reduce;
+#[cfg(any(test, feature = "oracle"))]
+pub mod reference;
+pub mod repl;
+mod rng;
+pub mod sandbox;
+pub mod server;
+#[cfg(test)]
+mod snapshot;
+pub mod stream;
+pub mod tape;
+pub mod tape_file;
+pub mod tokenizer;
+pub mod trace;
+pub mod utf8;
+pub mod vm;
+pub mod widecell;
+
+pub use engine::{Engine, ExecContext, Interpreter, X86_64Jit};
+pub use jit::{Backend, JitProgram};
+pub use program::{OptLevel, Program};
+pub use tokenizer::{optimize, tokenizer, Token};
+pub use vm::{VmError, VmOptions, VM};
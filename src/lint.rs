@@ -0,0 +1,83 @@
+//! Warnings about code that runs fine but is unlikely to be what was meant,
+//! as the LSP publishes them next to the bracket errors.
+//!
+//! ```text
+//! cancels      `+-`, `-+`, `<>` or `><`: the pair does nothing
+//! dead-loop    a `[` right after a `]`, whose cell is zero there
+//! empty-loop   `[]`, which never ends once entered
+//! ```
+//!
+//! A loop at the very start of a program never runs either, but that is the
+//! usual way to write a comment block, so it is left alone, empty or not.
+
+use crate::tokenizer::{RawOp, Span, Token};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lint {
+    pub span: Span,
+    pub name: &'static str,
+    pub message: String,
+}
+
+/// Every lint in `ops`, in source order.
+pub fn lint(ops: &[RawOp]) -> Vec<Lint> {
+    use Token::*;
+    let mut lints = vec![];
+    let mut i = 0;
+    while i < ops.len() {
+        let (op, next) = (ops[i], ops.get(i + 1).map(|op| op.token));
+        let cancels = matches!(
+            (op.token, next),
+            (IncrementData(_), Some(DecrementData(_)))
+                | (DecrementData(_), Some(IncrementData(_)))
+                | (IncrementPointer(_), Some(DecrementPointer(_)))
+                | (DecrementPointer(_), Some(IncrementPointer(_)))
+        );
+        let after_loop = i > 0 && matches!(ops[i - 1].token, LoopEnd(_));
+        let found = match (op.token, next) {
+            _ if cancels => Some(("cancels", "this pair of commands cancels out")),
+            (LoopStart(_), Some(LoopEnd(_))) if i > 0 => {
+                Some(("empty-loop", "`[]` never ends once it is entered"))
+            }
+            (LoopStart(_), _) if after_loop => Some((
+                "dead-loop",
+                "this loop never runs, the loop before it leaves the cell zero",
+            )),
+            _ => None,
+        };
+        if let Some((name, message)) = found {
+            lints.push(Lint {
+                span: op.span,
+                name,
+                message: message.to_string(),
+            });
+        }
+        // the second of a cancelling pair starts no pair of its own
+        i += if cancels { 2 } else { 1 };
+    }
+    lints
+}
+
+#[test]
+fn test_lint() {
+    let names = |src: &str| -> Vec<(i32, &str)> {
+        let ops = crate::tokenizer::lex(src);
+        lint(&ops).iter().map(|l| (l.span.col, l.name)).collect()
+    };
+
+    assert_eq!(names("[comment]+[->+<]>."), []);
+    assert_eq!(names("++-"), [(2, "cancels")]);
+    assert_eq!(
+        names("+-+->< <>"),
+        [
+            (1, "cancels"),
+            (3, "cancels"),
+            (5, "cancels"),
+            (8, "cancels")
+        ]
+    );
+    assert_eq!(names("+[-][-]"), [(5, "dead-loop")]);
+    assert_eq!(names("+[]"), [(2, "empty-loop")]);
+    // comment text between commands does not hide a pair
+    assert_eq!(names("> back <"), [(1, "cancels")]);
+}
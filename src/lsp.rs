@@ -0,0 +1,481 @@
+//! `bfjit lsp`: a small Language Server Protocol server over stdio.
+//!
+//! Messages are JSON-RPC 2.0 framed with a `Content-Length` header. Documents
+//! are synced incrementally and kept lexed as a `Document`, so a keystroke
+//! only lexes the text it changes; each open or change publishes the bracket
+//! errors of the recovering linker and the warnings of `lint`. Supported
+//! requests:
+//!
+//! ```text
+//! initialize                      -> capabilities
+//! textDocument/documentHighlight  -> the `[` and `]` of the pair under the cursor
+//! textDocument/hover              -> what the loop under the cursor does
+//! textDocument/formatting         -> the document as `format` leaves it
+//! shutdown                        -> null
+//! ```
+//!
+//! LSP positions are 0-based and spans 1-based; columns are counted in
+//! characters, which matches the protocol's UTF-16 offsets for ASCII text.
+
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Write},
+};
+
+use crate::{
+    document::Document,
+    format,
+    json::{json_object, Json},
+    lint,
+    program::Program,
+    tokenizer::{RawOp, Span, Token},
+};
+
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+
+// `DiagnosticSeverity.Error` and `.Warning`, `DocumentHighlightKind.Text`
+// and `TextDocumentSyncKind.Incremental`
+const SEVERITY_ERROR: u64 = 1;
+const SEVERITY_WARNING: u64 = 2;
+const HIGHLIGHT_TEXT: u64 = 1;
+const SYNC_INCREMENTAL: u64 = 2;
+
+/// Read one framed message; `None` once the input is closed.
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut len = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                len = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let len = len.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no Content-Length"))?;
+    let mut body = vec![0; len];
+    input.read_exact(&mut body)?;
+    String::from_utf8(body)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_message(output: &mut impl Write, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+/// Serve from `input` until the client sends `exit` or closes it.
+pub fn serve(mut input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+    let mut server = Lsp::default();
+    while let Some(body) = read_message(&mut input)? {
+        let Ok(message) = Json::parse(&body) else {
+            continue;
+        };
+        for reply in server.handle(&message) {
+            write_message(&mut output, &reply)?;
+        }
+        if server.exited {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+pub struct Lsp {
+    documents: HashMap<String, Document>, // each open document by uri
+    exited: bool,
+}
+
+fn position(span: Span, col_offset: i32) -> Json {
+    json_object! {
+        "line" => (span.line - 1).max(0),
+        "character" => (span.col - 1 + col_offset).max(0),
+    }
+}
+
+// the range covering the single character at `span`
+fn range(span: Span) -> Json {
+    json_object! {"start" => position(span, 0), "end" => position(span, 1)}
+}
+
+impl Lsp {
+    /// Handle one message, returning the response (for requests) followed
+    /// by any notifications it triggers.
+    pub fn handle(&mut self, message: &Json) -> Vec<Json> {
+        let Some(method) = message.get("method").and_then(Json::as_str) else {
+            return vec![]; // a response to something we never send
+        };
+        let params = message.get("params").cloned().unwrap_or(Json::Null);
+        let mut out = vec![];
+        let result = match method {
+            "initialize" => Ok(json_object! {
+                "capabilities" => json_object! {
+                    "textDocumentSync" => SYNC_INCREMENTAL,
+                    "documentHighlightProvider" => true,
+                    "hoverProvider" => true,
+                    "documentFormattingProvider" => true,
+                },
+                "serverInfo" => json_object! {"name" => "bfjit"},
+            }),
+            "shutdown" => Ok(Json::Null),
+            "exit" => {
+                self.exited = true;
+                return out;
+            }
+            "textDocument/didOpen" => {
+                let uri = document_uri(&params);
+                let text = params
+                    .get("textDocument")
+                    .and_then(|doc| doc.get("text"))
+                    .and_then(Json::as_str);
+                if let (Some(uri), Some(text)) = (uri, text) {
+                    let doc = Document::new(text);
+                    out.push(diagnostics(&uri, &doc));
+                    self.documents.insert(uri, doc);
+                }
+                return out;
+            }
+            "textDocument/didChange" => {
+                let uri = document_uri(&params);
+                let doc = uri.as_ref().and_then(|uri| self.documents.get_mut(uri));
+                if let (Some(doc), Some(Json::Array(changes))) = (doc, params.get("contentChanges"))
+                {
+                    for change in changes {
+                        apply_change(doc, change);
+                    }
+                    out.push(diagnostics(&uri.unwrap(), doc));
+                }
+                return out;
+            }
+            "textDocument/didClose" => {
+                if let Some(uri) = document_uri(&params) {
+                    self.documents.remove(&uri);
+                    out.push(publish(&uri, vec![]));
+                }
+                return out;
+            }
+            "textDocument/documentHighlight" => self.at_cursor(&params).map(|(pair, _)| {
+                let highlights = pair
+                    .iter()
+                    .map(|&span| json_object! {"range" => range(span), "kind" => HIGHLIGHT_TEXT})
+                    .collect();
+                Json::Array(highlights)
+            }),
+            "textDocument/hover" => self.at_cursor(&params).map(|(pair, doc)| match pair[..] {
+                [open, close] => json_object! {
+                    "contents" => json_object! {
+                        "kind" => "plaintext",
+                        "value" => describe_loop(doc, open, close),
+                    },
+                    "range" => json_object! {
+                        "start" => position(open, 0),
+                        "end" => position(close, 1),
+                    },
+                },
+                _ => Json::Null,
+            }),
+            "textDocument/formatting" => self.formatted(&params),
+            _ => Err((METHOD_NOT_FOUND, format!("unknown method `{}`", method))),
+        };
+        // notifications get no response, even when they fail
+        if let Some(id) = message.get("id").cloned() {
+            let body = match result {
+                Ok(result) => ("result".to_string(), result),
+                Err((code, message)) => (
+                    "error".to_string(),
+                    json_object! {"code" => code, "message" => message},
+                ),
+            };
+            out.insert(
+                0,
+                Json::Object(vec![
+                    ("jsonrpc".to_string(), "2.0".into()),
+                    ("id".to_string(), id),
+                    body,
+                ]),
+            );
+        }
+        out
+    }
+
+    // one edit replacing the whole text with its formatted self, or none
+    // when that changes nothing
+    fn formatted(&self, params: &Json) -> Result<Json, (i32, String)> {
+        let uri = document_uri(params)
+            .ok_or_else(|| (INVALID_PARAMS, "expected a textDocument".to_string()))?;
+        let doc = self
+            .documents
+            .get(&uri)
+            .ok_or_else(|| (INVALID_PARAMS, format!("document {} is not open", uri)))?;
+        let text = format::format(doc.text());
+        if text == doc.text() {
+            return Ok(Json::Array(vec![]));
+        }
+        // a position past the last line is the end of the document
+        let end = doc.text().matches('\n').count() + 1;
+        Ok(Json::Array(vec![json_object! {
+            "range" => json_object! {
+                "start" => json_object! {"line" => 0_u64, "character" => 0_u64},
+                "end" => json_object! {"line" => end, "character" => 0_u64},
+            },
+            "newText" => text,
+        }]))
+    }
+
+    // the bracket pair under the cursor (one span for an unmatched bracket,
+    // none off a bracket) and the document
+    fn at_cursor(&self, params: &Json) -> Result<(Vec<Span>, &Document), (i32, String)> {
+        let bad = || {
+            (
+                INVALID_PARAMS,
+                "expected a textDocument and position".to_string(),
+            )
+        };
+        let uri = document_uri(params).ok_or_else(bad)?;
+        let cursor = params.get("position").ok_or_else(bad)?;
+        let line = cursor.get("line").and_then(Json::as_u64).ok_or_else(bad)?;
+        let character = cursor
+            .get("character")
+            .and_then(Json::as_u64)
+            .ok_or_else(bad)?;
+        let doc = self
+            .documents
+            .get(&uri)
+            .ok_or_else(|| (INVALID_PARAMS, format!("document {} is not open", uri)))?;
+
+        let pairs = bracket_pairs(doc.ops());
+        let (line, col) = (line as i32 + 1, character as i32 + 1);
+        // the bracket at the cursor, else the one just before it
+        let hit = [col, col - 1].into_iter().find_map(|col| {
+            pairs.iter().find(|pair| {
+                pair.iter()
+                    .flatten()
+                    .any(|s| (s.line, s.col) == (line, col))
+            })
+        });
+        let spans = hit.map_or(vec![], |pair| pair.iter().flatten().copied().collect());
+        Ok((spans, doc))
+    }
+}
+
+// one entry of `contentChanges`: a range and its new text, or without a
+// range the whole new text
+fn apply_change(doc: &mut Document, change: &Json) {
+    let Some(text) = change.get("text").and_then(Json::as_str) else {
+        return;
+    };
+    let offset = |at: Option<&Json>| {
+        let line = at?.get("line")?.as_u64()?;
+        let character = at?.get("character")?.as_u64()?;
+        Some(doc.offset(line as usize, character as usize))
+    };
+    match change.get("range") {
+        Some(range) => {
+            if let (Some(start), Some(end)) = (offset(range.get("start")), offset(range.get("end")))
+            {
+                doc.edit(start..end.max(start), text);
+            }
+        }
+        None => *doc = Document::new(text),
+    }
+}
+
+fn document_uri(params: &Json) -> Option<String> {
+    let uri = params.get("textDocument")?.get("uri")?.as_str()?;
+    Some(uri.to_string())
+}
+
+fn publish(uri: &str, diagnostics: Vec<Json>) -> Json {
+    json_object! {
+        "jsonrpc" => "2.0",
+        "method" => "textDocument/publishDiagnostics",
+        "params" => json_object! {"uri" => uri, "diagnostics" => Json::Array(diagnostics)},
+    }
+}
+
+fn diagnostics(uri: &str, doc: &Document) -> Json {
+    let (_, errors) = doc.link();
+    let mut diagnostics: Vec<Json> = errors
+        .iter()
+        .map(|e| {
+            let span = Span {
+                line: e.line(),
+                col: e.col(),
+            };
+            json_object! {
+                "range" => range(span),
+                "severity" => SEVERITY_ERROR,
+                "source" => "bfjit",
+                "code" => e.code(),
+                "message" => e.kind().to_string(),
+            }
+        })
+        .collect();
+    diagnostics.extend(lint::lint(doc.ops()).into_iter().map(|lint| {
+        json_object! {
+            "range" => range(lint.span),
+            "severity" => SEVERITY_WARNING,
+            "source" => "bfjit",
+            "code" => lint.name,
+            "message" => lint.message,
+        }
+    }));
+    publish(uri, diagnostics)
+}
+
+// `[`/`]` positions matched by nesting; an unmatched bracket stands alone
+fn bracket_pairs(ops: &[RawOp]) -> Vec<[Option<Span>; 2]> {
+    let mut stk = vec![];
+    let mut pairs = vec![];
+    for op in ops {
+        match op.token {
+            Token::LoopStart(_) => stk.push(op.span),
+            Token::LoopEnd(_) => pairs.push([stk.pop(), Some(op.span)]),
+            _ => {}
+        }
+    }
+    pairs.extend(stk.into_iter().map(|open| [Some(open), None]));
+    pairs
+}
+
+/// What the loop from `open` to `close` amounts to.
+fn describe_loop(doc: &Document, open: Span, close: Span) -> String {
+    let ops = doc.ops();
+    let inside = |span: Span| (span.line, span.col) > (open.line, open.col);
+    let before = |span: Span| (span.line, span.col) < (close.line, close.col);
+    let body: Vec<Token> = ops
+        .iter()
+        .filter(|op| inside(op.span) && before(op.span))
+        .map(|op| op.token)
+        .collect();
+
+    // straight-line arithmetic on cells relative to the counter
+    let mut deltas: Vec<(isize, i32)> = vec![];
+    let mut offset = 0_isize;
+    let mut simple = true;
+    for token in &body {
+        let (at, delta) = match *token {
+            Token::IncrementData(x) => (offset, x as i32),
+            Token::DecrementData(x) => (offset, -(x as i32)),
+            Token::IncrementPointer(x) => {
+                offset += x as isize;
+                continue;
+            }
+            Token::DecrementPointer(x) => {
+                offset -= x as isize;
+                continue;
+            }
+            _ => {
+                simple = false;
+                break;
+            }
+        };
+        match deltas.iter_mut().find(|(o, _)| *o == at) {
+            Some((_, d)) => *d += delta,
+            None => deltas.push((at, delta)),
+        }
+    }
+    let counter = deltas.iter().find(|(o, _)| *o == 0).map_or(0, |&(_, d)| d);
+    if simple && offset == 0 && counter.rem_euclid(2) == 1 {
+        if deltas.len() == 1 {
+            return "Set: clears the current cell".to_string();
+        }
+        if counter == -1 {
+            let terms: Vec<String> = deltas
+                .iter()
+                .filter(|(o, d)| *o != 0 && *d != 0)
+                .map(|&(o, d)| format!("cell[{:+}] += {} * cell", o, d))
+                .collect();
+            return format!("Mul: {}, then clears the cell", terms.join(", "));
+        }
+    }
+
+    // ask the optimizer whether it proves the loop runs at most once
+    if let Ok(program) = Program::compile(doc.text()) {
+        let lowered = program
+            .tokens()
+            .iter()
+            .zip(program.spans())
+            .any(|(token, &span)| matches!(token, Token::IfStart(_)) && span == open);
+        if lowered {
+            return "If: the body runs at most once".to_string();
+        }
+    }
+    "Loop: not recognized".to_string()
+}
+
+#[test]
+fn test_lsp_session() {
+    let frame = |body: &str| format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+    let requests = [
+        r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"capabilities":{}}}"#,
+        r#"{"jsonrpc":"2.0","method":"initialized","params":{}}"#,
+        r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"file:///a.bf","languageId":"brainfuck","version":1,"text":"+[->++<\n]]"}}}"#,
+        r#"{"jsonrpc":"2.0","method":"textDocument/didChange","params":{"textDocument":{"uri":"file:///a.bf","version":2},"contentChanges":[{"text":"+[->++<]\n>[-]"}]}}"#,
+        // type a stray `]` on the second line, then take it back
+        r#"{"jsonrpc":"2.0","method":"textDocument/didChange","params":{"textDocument":{"uri":"file:///a.bf","version":3},"contentChanges":[{"range":{"start":{"line":1,"character":4},"end":{"line":1,"character":4}},"text":"]"}]}}"#,
+        r#"{"jsonrpc":"2.0","method":"textDocument/didChange","params":{"textDocument":{"uri":"file:///a.bf","version":4},"contentChanges":[{"range":{"start":{"line":1,"character":4},"end":{"line":1,"character":5}},"text":""}]}}"#,
+        r#"{"jsonrpc":"2.0","id":2,"method":"textDocument/documentHighlight","params":{"textDocument":{"uri":"file:///a.bf"},"position":{"line":0,"character":7}}}"#,
+        r#"{"jsonrpc":"2.0","id":3,"method":"textDocument/hover","params":{"textDocument":{"uri":"file:///a.bf"},"position":{"line":0,"character":1}}}"#,
+        r#"{"jsonrpc":"2.0","id":4,"method":"textDocument/hover","params":{"textDocument":{"uri":"file:///a.bf"},"position":{"line":1,"character":1}}}"#,
+        r#"{"jsonrpc":"2.0","id":5,"method":"textDocument/hover","params":{"textDocument":{"uri":"file:///a.bf"},"position":{"line":0,"character":3}}}"#,
+        r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"file:///b.bf","languageId":"brainfuck","version":1,"text":"[\n+-\n]\n"}}}"#,
+        r#"{"jsonrpc":"2.0","id":6,"method":"textDocument/formatting","params":{"textDocument":{"uri":"file:///b.bf"},"options":{"tabSize":4,"insertSpaces":true}}}"#,
+        r#"{"jsonrpc":"2.0","id":9,"method":"textDocument/formatting","params":{}}"#,
+        r#"{"jsonrpc":"2.0","id":7,"method":"shutdown"}"#,
+        r#"{"jsonrpc":"2.0","method":"exit"}"#,
+        r#"{"jsonrpc":"2.0","id":8,"method":"shutdown"}"#,
+    ];
+    let input: String = requests.iter().map(|body| frame(body)).collect();
+    let mut output = vec![];
+    serve(input.as_bytes(), &mut output).unwrap();
+
+    let expected = [
+        r#"{"jsonrpc":"2.0","id":1,"result":{"capabilities":{"textDocumentSync":2,"documentHighlightProvider":true,"hoverProvider":true,"documentFormattingProvider":true},"serverInfo":{"name":"bfjit"}}}"#,
+        r#"{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{"uri":"file:///a.bf","diagnostics":[{"range":{"start":{"line":1,"character":1},"end":{"line":1,"character":2}},"severity":1,"source":"bfjit","code":"E0101","message":"Unclose left bracket"}]}}"#,
+        r#"{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{"uri":"file:///a.bf","diagnostics":[]}}"#,
+        r#"{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{"uri":"file:///a.bf","diagnostics":[{"range":{"start":{"line":1,"character":4},"end":{"line":1,"character":5}},"severity":1,"source":"bfjit","code":"E0101","message":"Unclose left bracket"}]}}"#,
+        r#"{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{"uri":"file:///a.bf","diagnostics":[]}}"#,
+        r#"{"jsonrpc":"2.0","id":2,"result":[{"range":{"start":{"line":0,"character":1},"end":{"line":0,"character":2}},"kind":1},{"range":{"start":{"line":0,"character":7},"end":{"line":0,"character":8}},"kind":1}]}"#,
+        r#"{"jsonrpc":"2.0","id":3,"result":{"contents":{"kind":"plaintext","value":"Mul: cell[+1] += 2 * cell, then clears the cell"},"range":{"start":{"line":0,"character":1},"end":{"line":0,"character":8}}}}"#,
+        r#"{"jsonrpc":"2.0","id":4,"result":{"contents":{"kind":"plaintext","value":"Set: clears the current cell"},"range":{"start":{"line":1,"character":1},"end":{"line":1,"character":4}}}}"#,
+        r#"{"jsonrpc":"2.0","id":5,"result":null}"#,
+        r#"{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{"uri":"file:///b.bf","diagnostics":[{"range":{"start":{"line":1,"character":0},"end":{"line":1,"character":1}},"severity":2,"source":"bfjit","code":"cancels","message":"this pair of commands cancels out"}]}}"#,
+        r#"{"jsonrpc":"2.0","id":6,"result":[{"range":{"start":{"line":0,"character":0},"end":{"line":4,"character":0}},"newText":"[\n  +-\n]\n"}]}"#,
+        r#"{"jsonrpc":"2.0","id":9,"error":{"code":-32602,"message":"expected a textDocument"}}"#,
+        r#"{"jsonrpc":"2.0","id":7,"result":null}"#,
+    ];
+    let output = String::from_utf8(output).unwrap();
+    let replies: Vec<String> = output
+        .split("Content-Length: ")
+        .skip(1)
+        .map(|frame| frame.split_once("\r\n\r\n").unwrap().1.to_string())
+        .collect();
+    assert_eq!(replies, expected);
+
+    // a loop the optimizer lowers to `if`, and one it cannot tell; the
+    // input keeps the first from being peeled and dropped
+    let mut lsp = Lsp::default();
+    lsp.documents
+        .insert("a".to_string(), Document::new(",[-[>]]+[.-.]"));
+    let hover = |lsp: &Lsp, character: u64| {
+        let params = json_object! {
+            "textDocument" => json_object! {"uri" => "a"},
+            "position" => json_object! {"line" => 0_u64, "character" => character},
+        };
+        let (pair, doc) = lsp.at_cursor(&params).ok().unwrap();
+        describe_loop(doc, pair[0], pair[1])
+    };
+    assert_eq!(hover(&lsp, 1), "If: the body runs at most once");
+    assert_eq!(hover(&lsp, 8), "Loop: not recognized");
+}
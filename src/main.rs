@@ -1,18 +1,1143 @@
-use std::{env, process::exit};
+use std::{
+    env, fs,
+    io::{self, IsTerminal, Read, Write},
+    process::exit,
+    time::Instant,
+};
 
-use vm::VM;
+#[cfg(feature = "image")]
+use bfjit::brainloller;
+use bfjit::console::ConsoleOutput;
+use bfjit::engine::{Engine, EngineRegistry, ExecContext, Interpreter, X86_64Jit};
+use bfjit::generate::ProgramGenerator;
+use bfjit::ir_cache::IrCache;
+use bfjit::jit::{self, Backend, JitError};
+use bfjit::pass::PassManager;
+use bfjit::program::{OptLevel, Program, SourceInfo};
+#[cfg(feature = "oracle")]
+use bfjit::reference;
+use bfjit::tape_file::TapeFile;
+use bfjit::tokenizer::{Dialect, StartTape, TokenizerError, WordMap};
+use bfjit::trace::{TraceFormat, Tracer};
+use bfjit::utf8::{Utf8Mode, Utf8Writer};
+use bfjit::vm::{CellOverflow, EofBehavior, TapeMode, VmOptions};
+use bfjit::widecell::{self, CellWidth, IoWidth, WideTape};
+use bfjit::{
+    bench, bigcell, bytecode, callgrind, checkpoint, codegen, compare, doctor, error, ir_dump,
+    jit_dump, lsp, profile, progress, python, reduce, repl, server, tape, tape_file, tokenizer, vm,
+};
 
-pub mod jit;
-pub mod tokenizer;
-pub mod vm;
+// cells `--grow-tape` may grow to when no limit is given
+const GROW_LIMIT: usize = 1 << 30;
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("usage bfjit <file.bf>");
+// loops `--profile` lists when not told how many
+const HOT_LOOPS: usize = 10;
+
+fn usage() -> ! {
+    eprintln!(
+        "usage bfjit [run] [--jit|--interp|--engine=NAME] [--tape-file=PATH[:SIZE]] [--mem-size=N] [--grow-tape[=MAX]] [--tape=fixed|bidirectional] [--output-utf8=raw|strict|lossy] [--console-unicode] [--dump-tape[=N]] [-O0|-O1|-O2] [--pass-sizes] [--dump-ir[=text|json]] [--dump-jit[=PATH]] [--profile[=N]] [--profile-callgrind=PATH] [--max-loop-iterations=N] [--max-steps=N, rough under the JIT] [--eof=unchanged|0|-1|halt] [--cell-overflow=wrap|error] [--cells=u8|big] [--cell-width=8|16|32] [--io-width=byte|le|be] [--dialect=bf|ebf1|debug|ook] [--dialect-map=FILE] [--no-ir-cache] [--bang-input] [--compare] [--checkpoint FILE] [--resume FILE] [--trace FILE] [--trace-limit N] [--trace-format=text|binary] <file.bf|file.bfir|file.bfc|file.png>"
+    );
+    eprintln!("      bfjit [--repl] [--eof=unchanged|0|-1|halt] [--dialect=bf|ebf1|debug|ook]");
+    eprintln!(
+        "      bfjit ir [--format=text] [-O0|-O1|-O2] [--dialect=bf|ebf1|debug|ook] <file.bf>"
+    );
+    eprintln!("      bfjit bench [--input FILE] [--engines A,B] [--runs N] [--json] [--front-end] <file.bf>");
+    eprintln!("      bfjit compile [--strip] [-o <file.bfc>] <file.bf>");
+    eprintln!(
+        "      bfjit emit-py [--eof=unchanged|0|-1|halt] [--dialect=bf|ebf1|debug|ook] <file.bf>"
+    );
+    eprintln!("      bfjit --emit=c|rust [-o FILE] [--eof=unchanged|0|-1|halt] [--dialect=bf|ebf1|debug|ook] <file.bf>");
+    eprintln!("      bfjit cache dir|stats|clear");
+    eprintln!("      bfjit serve --stdio");
+    eprintln!("      bfjit lsp");
+    eprintln!("      bfjit reduce <file.bf> --check <CMD>");
+    #[cfg(feature = "oracle")]
+    eprintln!("      bfjit selftest --oracle");
+    eprintln!("      bfjit gen random [--seed N] [--len N] [--loops P] [--io Q] [--terminating]");
+    eprintln!("      bfjit doctor [--engine=NAME]");
+    exit(1);
+}
+
+// the reason `what` on `path` failed, with the I/O exit status
+fn io_failed(what: &str, path: &str, e: io::Error) -> ! {
+    eprintln!("{} {} failed: {}", what, path, e);
+    exit(error::ErrorCategory::Io.exit_code());
+}
+
+fn read_source(path: &str) -> String {
+    fs::read_to_string(path).unwrap_or_else(|e| io_failed("read", path, e))
+}
+
+fn compiled(result: Result<Program, TokenizerError>) -> Program {
+    result.unwrap_or_else(|e| {
+        eprintln!("build program failed: {}", error::report(&e));
+        exit(e.category().exit_code());
+    })
+}
+
+fn gen(args: Vec<String>) {
+    if args.first().map(String::as_str) != Some("random") {
+        usage();
+    }
+    let (mut seed, mut len, mut terminating) = (0, 100, false);
+    let (mut loops, mut io) = (0.1, 0.05);
+    let mut args = args.into_iter().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--terminating" {
+            terminating = true;
+            continue;
+        }
+        let value = args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--seed" => seed = value.parse().unwrap_or_else(|_| usage()),
+            "--len" => len = value.parse().unwrap_or_else(|_| usage()),
+            "--loops" => loops = value.parse().unwrap_or_else(|_| usage()),
+            "--io" => io = value.parse().unwrap_or_else(|_| usage()),
+            _ => usage(),
+        }
+    }
+    let program = ProgramGenerator::new(seed)
+        .max_len(len)
+        .loop_probability(loops)
+        .io_probability(io)
+        .terminating(terminating)
+        .generate();
+    println!("{}", program);
+}
+
+#[cfg(feature = "oracle")]
+fn selftest() {
+    let mut failed = false;
+    for (name, src, input, on_eof) in reference::CORPUS {
+        let mismatches = reference::differential(src, input, on_eof);
+        println!(
+            "{}: {}",
+            name,
+            if mismatches.is_empty() {
+                "ok"
+            } else {
+                "FAILED"
+            }
+        );
+        for mismatch in &mismatches {
+            println!("  {}", mismatch);
+        }
+        failed |= !mismatches.is_empty();
+    }
+    if failed {
+        exit(1);
+    }
+}
+
+// print what works here, failing when the engine asked about does not
+fn doctor(args: Vec<String>) {
+    let engine = match args.as_slice() {
+        [] => doctor::preferred_engine(),
+        [arg] => arg.strip_prefix("--engine=").unwrap_or_else(|| usage()),
+        _ => usage(),
+    };
+    let checks = doctor::checks();
+    print!("{}", doctor::render(&checks));
+    // the last line has the same shape as the checks
+    if !doctor::engine_available(&checks, engine) {
+        println!("preferred engine: unavailable ({})", engine);
+        exit(1);
+    }
+    println!("preferred engine: ok ({})", engine);
+}
+
+// `--dump-jit`: the code the JIT generates for `program`, listed on stderr
+// against the tokens, and as raw bytes to `path` for a disassembler
+fn dump_jit_code(program: &Program, options: &VmOptions, path: Option<&str>) {
+    let Some(backend) = Backend::host() else {
+        eprintln!(
+            "--dump-jit needs a JIT backend, and none generates code for {} {}",
+            std::env::consts::OS,
+            std::env::consts::ARCH
+        );
+        exit(1);
+    };
+    let compiled = match options.fuel {
+        Some(fuel) => jit::compile_with_fuel(backend, program.tokens(), fuel),
+        None => jit::compile(backend, program.tokens()),
+    };
+    let compiled = match compiled {
+        Ok(compiled) => compiled,
+        Err(e) => {
+            eprintln!("warning: no code to dump, {}", e);
+            return;
+        }
+    };
+    eprint!("{}", jit_dump::listing(program, &compiled));
+    if let Some(path) = path {
+        fs::write(path, compiled.code()).unwrap_or_else(|e| {
+            eprintln!("write {} failed: {}", path, e);
+            exit(1);
+        });
+        let machine = match backend {
+            Backend::X86_64 => "i386:x86-64",
+        };
+        eprintln!(
+            "{} bytes written to {}, objdump -D -b binary -m{} {} disassembles them",
+            compiled.code().len(),
+            path,
+            machine,
+            path
+        );
+    }
+}
+
+// `--dump-ir`: the tokens as linked and as optimized, or as loaded from a
+// file that is not source; nothing runs
+fn dump_ir_stages(
+    filepath: &str,
+    dialect: Dialect,
+    level: OptLevel,
+    start: StartTape,
+    overflow: CellOverflow,
+    json: bool,
+) {
+    let stages = if [".bfc", ".bfir", ".png"]
+        .iter()
+        .any(|ext| filepath.ends_with(ext))
+    {
+        vec![(
+            "loaded",
+            load(filepath, None, dialect, level, start, overflow),
+        )]
+    } else {
+        let bytes = fs::read(filepath).unwrap_or_else(|e| {
+            eprintln!("build vm failed: {}", e);
+            exit(error::ErrorCategory::Io.exit_code());
+        });
+        let src = tokenizer::strip_shebang_bytes(&bytes);
+        let compile = |level| {
+            Program::compile_bytes(src, level, dialect, start, overflow).unwrap_or_else(|e| {
+                eprintln!("build vm failed: {}", error::report(&e));
+                exit(e.category().exit_code());
+            })
+        };
+        vec![
+            ("tokenized", compile(OptLevel::O0)),
+            ("optimized", compile(level)),
+        ]
+    };
+    for (i, (stage, program)) in stages.iter().enumerate() {
+        if json {
+            print!("{}", ir_dump::json_lines(program, stage));
+            continue;
+        }
+        if i > 0 {
+            println!();
+        }
+        println!("# {}", stage);
+        print!("{}", ir_dump::listing(program));
+    }
+}
+
+// `--pass-sizes`: how many tokens the source has after each pass `level`
+// runs, before the program itself runs
+fn print_pass_sizes(
+    filepath: &str,
+    dialect: Dialect,
+    level: OptLevel,
+    start: StartTape,
+    overflow: CellOverflow,
+) {
+    if [".bfc", ".bfir", ".png"]
+        .iter()
+        .any(|ext| filepath.ends_with(ext))
+    {
+        eprintln!("--pass-sizes needs brainfuck source");
+        exit(1);
+    }
+    let bytes = fs::read(filepath).unwrap_or_else(|e| io_failed("read", filepath, e));
+    let ops = tokenizer::lex_reader(tokenizer::strip_shebang_bytes(&bytes), dialect);
+    let linked = ops.and_then(|ops| Ok((tokenizer::link(&ops)?, ops)));
+    let (mut tokens, ops) = linked.unwrap_or_else(|e| {
+        eprintln!("build program failed: {}", error::report(&e));
+        exit(e.category().exit_code());
+    });
+    let mut spans = ops.iter().map(|op| op.span).collect();
+    eprintln!("{:<14}{:>10}", "linked", tokens.len());
+    for (pass, len) in
+        PassManager::for_level(level, start, overflow, 1).run(&mut tokens, &mut spans)
+    {
+        eprintln!("{:<14}{:>10}", pass, len);
+    }
+}
+
+// `--dump-tape[=N]` of a `--cell-width` tape, each cell a little-endian group
+fn dump_wide(tape: &WideTape, width: CellWidth, len: Option<usize>) -> String {
+    let pointer = Some(tape.pointer);
+    let start = tape::dump_start(&tape.cells, pointer, 0);
+    let cells = &tape.cells[start..];
+    let len = len.unwrap_or_else(|| tape::dump_len(cells, Some(tape.pointer - start)));
+    let cells = &cells[..len.min(cells.len())];
+    tape::hexdump_wide(cells, width.bits() as usize / 8, start, pointer)
+}
+
+// `--cells=big` or `--cell-width`, which need the source since tokens fold
+// with byte wrapping
+fn run_unfolded(
+    filepath: &str,
+    flag: &str,
+    run: impl FnOnce(&[u8], &mut dyn Read, &mut dyn Write) -> Result<(), vm::VmError>,
+) {
+    if filepath.ends_with(".bfc") || filepath.ends_with(".bfir") {
+        eprintln!("{} needs brainfuck source", flag);
         exit(1);
     }
+    let bytes = fs::read(filepath).unwrap_or_else(|e| {
+        eprintln!("build vm failed: {}", e);
+        exit(error::ErrorCategory::Io.exit_code());
+    });
+    let (mut input, mut output) = (io::stdin(), io::stdout());
+    let src = tokenizer::strip_shebang_bytes(&bytes);
+    if let Err(e) = run(src, &mut input, &mut output) {
+        eprintln!("run vm failed: {}", error::report(&e));
+        exit(e.category().exit_code());
+    }
+}
+
+// load a program for running, exiting with its error category on failure;
+// the dialect asked for, or the one the file's extension names
+fn source_dialect(filepath: &str, given: Option<Dialect>) -> Dialect {
+    match given {
+        Some(dialect) => dialect,
+        None if filepath.ends_with(".ook") => Dialect::Ook,
+        None => Dialect::Standard,
+    }
+}
+
+// `--dialect-map`: read once and kept for the rest of the run
+fn load_word_map(path: &str) -> &'static WordMap {
+    let text = fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("read dialect map {} failed: {}", path, e);
+        exit(error::ErrorCategory::Io.exit_code());
+    });
+    let map = WordMap::parse(&text).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        exit(e.category().exit_code());
+    });
+    Box::leak(Box::new(map))
+}
 
-    let filepath = &args[1];
-    VM::new_from_file(filepath).expect("build vm failed").run();
+// source is read as `dialect` and goes through `cache` when there is one
+fn load(
+    filepath: &str,
+    cache: Option<&IrCache>,
+    dialect: Dialect,
+    level: OptLevel,
+    start: StartTape,
+    overflow: CellOverflow,
+) -> Program {
+    let fail = |e: vm::VmError| -> ! {
+        eprintln!("build vm failed: {}", error::report(&e));
+        exit(e.category().exit_code());
+    };
+    if [".bfc", ".bfir", ".png"]
+        .iter()
+        .any(|ext| filepath.ends_with(ext))
+    {
+        return vm::load_program(filepath, level, start, overflow).unwrap_or_else(|e| fail(e));
+    }
+    let bytes = fs::read(filepath).unwrap_or_else(|e| fail(e.into()));
+    if vm::is_png(&bytes) {
+        return vm::load_program(filepath, level, start, overflow).unwrap_or_else(|e| fail(e));
+    }
+    let src = tokenizer::strip_shebang_bytes(&bytes);
+    compile_source(filepath, src, cache, dialect, level, start, overflow)
+}
+
+// `load` for `--bang-input`: the program before the first `!` and the input
+// after it
+fn load_bang(
+    filepath: &str,
+    cache: Option<&IrCache>,
+    dialect: Dialect,
+    level: OptLevel,
+    start: StartTape,
+    overflow: CellOverflow,
+) -> (Program, Vec<u8>) {
+    if [".bfc", ".bfir", ".png"]
+        .iter()
+        .any(|ext| filepath.ends_with(ext))
+    {
+        eprintln!("--bang-input needs brainfuck source");
+        exit(1);
+    }
+    let bytes = fs::read(filepath).unwrap_or_else(|e| {
+        eprintln!("build vm failed: {}", e);
+        exit(error::ErrorCategory::Io.exit_code());
+    });
+    let (src, input) = tokenizer::split_bang_bytes(tokenizer::strip_shebang_bytes(&bytes));
+    let program = compile_source(filepath, src, cache, dialect, level, start, overflow);
+    (program, input.unwrap_or_default().to_vec())
+}
+
+// source that is not UTF-8 is lexed as bytes, past the cache, which keys on
+// text
+fn compile_source(
+    filepath: &str,
+    src: &[u8],
+    cache: Option<&IrCache>,
+    dialect: Dialect,
+    level: OptLevel,
+    start: StartTape,
+    overflow: CellOverflow,
+) -> Program {
+    let fail = |e: vm::VmError| -> ! {
+        eprintln!("build vm failed: {}", error::report(&e));
+        exit(e.category().exit_code());
+    };
+    let text = std::str::from_utf8(src);
+    let (Some(cache), Ok(src)) = (cache, text) else {
+        let program = match text {
+            Ok(src) => Program::compile_for(src, level, dialect, start, overflow),
+            Err(_) => Program::compile_bytes(src, level, dialect, start, overflow),
+        };
+        return program.unwrap_or_else(|e| fail(e.into()));
+    };
+    let program = cache
+        .compile(src, level, dialect, start, overflow, filepath)
+        .unwrap_or_else(|e| fail(e.into()));
+    if cache.stats().rejected > 0 {
+        eprintln!("replaced a damaged entry in {}", cache.dir().display());
+    }
+    program
+}
+
+// `bfjit cache`: where the IR cache lives, how big it is, or empty it
+fn cache(args: Vec<String>) {
+    let Some(dir) = IrCache::default_dir() else {
+        eprintln!("no cache directory, set XDG_CACHE_HOME or HOME");
+        exit(1);
+    };
+    let cache = IrCache::new(dir);
+    let result = match args.as_slice() {
+        [cmd] if cmd == "dir" => {
+            println!("{}", cache.dir().display());
+            Ok(())
+        }
+        [cmd] if cmd == "stats" => cache.usage().map(|(entries, bytes)| {
+            println!("ir: {} entries, {} bytes", entries, bytes);
+        }),
+        [cmd] if cmd == "clear" => cache.clear().map(|n| println!("ir: removed {} entries", n)),
+        _ => usage(),
+    };
+    if let Err(e) = result {
+        eprintln!("cache {} failed: {}", cache.dir().display(), e);
+        exit(error::ErrorCategory::Io.exit_code());
+    }
+}
+
+fn bench(args: Vec<String>) {
+    let (mut input, mut names, mut runs) = (vec![], None, 5);
+    let (mut json, mut front_end, mut filepath) = (false, false, None);
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--front-end" => front_end = true,
+            "--input" => {
+                let path = args.next().unwrap_or_else(|| usage());
+                input = fs::read(&path).unwrap_or_else(|e| io_failed("read", &path, e));
+            }
+            "--engines" => names = Some(args.next().unwrap_or_else(|| usage())),
+            "--runs" => {
+                let n = args.next().unwrap_or_else(|| usage());
+                runs = n.parse().unwrap_or_else(|_| usage());
+            }
+            _ if arg.starts_with('-') || filepath.is_some() => usage(),
+            _ => filepath = Some(arg),
+        }
+    }
+    let filepath = filepath.unwrap_or_else(|| usage());
+    if front_end {
+        let src = read_source(&filepath);
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        match bench::front_end(tokenizer::strip_shebang(&src), threads, runs) {
+            Ok(timing) if json => println!("{}", bench::front_end_json(&timing)),
+            Ok(timing) => print!("{}", bench::render_front_end(&timing)),
+            Err(e) => {
+                eprintln!("bench failed: {}", e);
+                exit(e.category().exit_code());
+            }
+        }
+        return;
+    }
+    let level = OptLevel::default();
+    let program = load(
+        &filepath,
+        None,
+        Dialect::Standard,
+        level,
+        StartTape::Unknown,
+        CellOverflow::Wrap,
+    );
+
+    let mut registry = EngineRegistry::builtin();
+    let names = names.unwrap_or_else(|| registry.names().join(","));
+    let known = registry.names().join(", ");
+    let mut engines = vec![];
+    for engine in registry.iter_mut() {
+        if names.split(',').any(|name| name == engine.name()) {
+            engines.push(engine);
+        }
+    }
+    if let Some(name) = names
+        .split(',')
+        .find(|name| !engines.iter().any(|engine| engine.name() == *name))
+    {
+        eprintln!("unknown engine {}, expected one of: {}", name, known);
+        exit(1);
+    }
+    match bench::bench(&mut engines, &program, &input, &VmOptions::default(), runs) {
+        Ok(timings) if json => println!("{}", bench::to_json(&timings)),
+        Ok(timings) => print!("{}", bench::render_table(&timings)),
+        Err(e) => {
+            eprintln!("bench failed: {}", e);
+            exit(e.category().exit_code());
+        }
+    }
+}
+
+fn compile(args: Vec<String>) {
+    let (mut strip, mut output, mut filepath) = (false, None, None);
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--strip" => strip = true,
+            "-o" => output = Some(args.next().unwrap_or_else(|| usage())),
+            _ if arg.starts_with('-') || filepath.is_some() => usage(),
+            _ => filepath = Some(arg),
+        }
+    }
+    let filepath = filepath.unwrap_or_else(|| usage());
+    let output = output.unwrap_or_else(|| {
+        let stem = filepath.strip_suffix(".bf").unwrap_or(&filepath);
+        format!("{}.bfc", stem)
+    });
+
+    let src = read_source(&filepath);
+    let program =
+        compiled(Program::compile(tokenizer::strip_shebang(&src))).with_source_info(SourceInfo {
+            file: filepath.clone(),
+            hash: bytecode::source_hash(&src),
+        });
+    let bytes = program.to_bytecode(strip);
+    fs::write(&output, bytes).unwrap_or_else(|e| io_failed("write", &output, e));
+}
+
+// print the program as a Python script
+fn emit_py(args: Vec<String>) {
+    let (mut eof, mut dialect, mut filepath) = (EofBehavior::default(), None, None);
+    for arg in args {
+        if let Some(name) = arg.strip_prefix("--eof=") {
+            eof = EofBehavior::from_name(name).unwrap_or_else(|| usage());
+        } else if let Some(name) = arg.strip_prefix("--dialect=") {
+            dialect = Some(Dialect::from_name(name).unwrap_or_else(|| usage()));
+        } else if arg.starts_with('-') || filepath.is_some() {
+            usage();
+        } else {
+            filepath = Some(arg);
+        }
+    }
+    let filepath = filepath.unwrap_or_else(|| usage());
+    let dialect = source_dialect(&filepath, dialect);
+    let program = load(
+        &filepath,
+        None,
+        dialect,
+        OptLevel::default(),
+        StartTape::Unknown,
+        CellOverflow::Wrap,
+    );
+    let name = std::path::Path::new(&filepath)
+        .file_name()
+        .map_or(filepath.clone(), |name| name.to_string_lossy().into_owned());
+    print!("{}", python::emit(&program, &name, eof));
+}
+
+// print or write the program as C or Rust source
+fn emit_source(args: Vec<String>) {
+    let (mut target, mut output, mut filepath) = (None, None, None);
+    let (mut eof, mut dialect) = (EofBehavior::default(), None);
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if let Some(name) = arg.strip_prefix("--emit=") {
+            target = Some(codegen::Target::from_name(name).unwrap_or_else(|| usage()));
+        } else if arg == "-o" {
+            output = Some(args.next().unwrap_or_else(|| usage()));
+        } else if let Some(name) = arg.strip_prefix("--eof=") {
+            eof = EofBehavior::from_name(name).unwrap_or_else(|| usage());
+        } else if let Some(name) = arg.strip_prefix("--dialect=") {
+            dialect = Some(Dialect::from_name(name).unwrap_or_else(|| usage()));
+        } else if arg.starts_with('-') || filepath.is_some() {
+            usage();
+        } else {
+            filepath = Some(arg);
+        }
+    }
+    let (Some(target), Some(filepath)) = (target, filepath) else {
+        usage();
+    };
+    let dialect = source_dialect(&filepath, dialect);
+    let program = load(
+        &filepath,
+        None,
+        dialect,
+        OptLevel::default(),
+        StartTape::Unknown,
+        CellOverflow::Wrap,
+    );
+    let source = codegen::emit(program.tokens(), target, eof);
+    match output {
+        Some(path) => fs::write(&path, source).unwrap_or_else(|e| {
+            eprintln!("failed to write {}: {}", path, e);
+            exit(error::ErrorCategory::Io.exit_code());
+        }),
+        None => print!("{}", source),
+    }
+}
+
+fn main() {
+    let mut args = env::args().skip(1).peekable();
+    let command = match args.peek().map(String::as_str) {
+        Some("run") | Some("ir") | Some("serve") | Some("gen") | Some("reduce")
+        | Some("compile") | Some("lsp") | Some("bench") | Some("selftest") | Some("doctor")
+        | Some("cache") | Some("emit-py") => args.next().unwrap(),
+        _ => String::from("run"),
+    };
+    let args: Vec<_> = args.collect();
+    if command == "run" && args.iter().any(|arg| arg.starts_with("--emit=")) {
+        emit_source(args);
+        return;
+    }
+    let mut args = args.into_iter();
+    if command == "compile" {
+        compile(args.collect());
+        return;
+    }
+    #[cfg(feature = "oracle")]
+    if command == "selftest" {
+        if args.collect::<Vec<_>>() != ["--oracle"] {
+            usage();
+        }
+        selftest();
+        return;
+    }
+    if command == "bench" {
+        bench(args.collect());
+        return;
+    }
+    if command == "gen" {
+        gen(args.collect());
+        return;
+    }
+    if command == "emit-py" {
+        emit_py(args.collect());
+        return;
+    }
+    if command == "cache" {
+        cache(args.collect());
+        return;
+    }
+    if command == "doctor" {
+        doctor(args.collect());
+        return;
+    }
+    if command == "reduce" {
+        let (file, check) = match args.collect::<Vec<_>>().as_slice() {
+            [file, flag, check] if flag == "--check" => (file.clone(), check.clone()),
+            _ => usage(),
+        };
+        let src = read_source(&file);
+        let reduced = reduce::reduce_with_command(&src, &check)
+            .unwrap_or_else(|e| io_failed("run", &check, e));
+        println!("{}", reduced);
+        return;
+    }
+    if command == "lsp" {
+        if args.next().is_some() {
+            usage();
+        }
+        lsp::serve(io::stdin().lock(), io::stdout().lock()).expect("lsp failed");
+        return;
+    }
+    if command == "serve" {
+        if args.collect::<Vec<_>>() != ["--stdio"] {
+            usage();
+        }
+        server::serve(io::stdin().lock(), io::stdout().lock()).expect("serve failed");
+        return;
+    }
+
+    let mut options = VmOptions::default();
+    // `--engine=` or `--interp`; left unset the JIT runs where it can
+    let mut engine: Option<String> = None;
+    let mut jit = false;
+    let mut tape_file = None;
+    let mut mem_size = None;
+    let mut utf8 = Utf8Mode::Raw;
+    let mut dump_tape = None;
+    let mut dump_ir = None; // as JSON lines or not
+    let mut level = OptLevel::default();
+    let mut pass_sizes = false;
+    let mut dump_jit = None; // and where the raw code goes, if anywhere
+    let mut callgrind = None;
+    let mut hot_loops = None; // loops `--profile` lists
+    let mut big_cells = false;
+    let mut cell_width = CellWidth::W8;
+    let mut io_width = IoWidth::Byte;
+    let mut ir_cache = true;
+    let mut dialect = None;
+    let mut console_unicode = false;
+    let mut as_repl = false;
+    let mut compare = false;
+    let mut bang_input = false;
+    let mut checkpoint = None;
+    let mut resume = None;
+    let mut trace: Option<String> = None;
+    let mut trace_limit = None;
+    let mut trace_format = TraceFormat::Text;
+    let mut filepath = None;
+    while let Some(arg) = args.next() {
+        if command == "ir" && arg == "--format=text" {
+            continue;
+        }
+        if let Some(n) = arg.strip_prefix("--max-loop-iterations=") {
+            options.max_loop_iterations = Some(n.parse().unwrap_or_else(|_| usage()));
+        } else if let Some(n) = arg.strip_prefix("--max-steps=") {
+            options.fuel = Some(n.parse().unwrap_or_else(|_| usage()));
+        } else if let Some(spec) = arg.strip_prefix("--tape-file=") {
+            let (path, size) = tape_file::parse_spec(spec).unwrap_or_else(|| usage());
+            tape_file = Some((path.to_string(), size));
+        } else if let Some(n) = arg.strip_prefix("--mem-size=") {
+            mem_size = Some(n.parse().unwrap_or_else(|_| usage()));
+        } else if arg == "--repl" {
+            as_repl = true;
+        } else if arg == "--grow-tape" {
+            options.tape_mode = TapeMode::Grow { max: GROW_LIMIT };
+        } else if let Some(n) = arg.strip_prefix("--grow-tape=") {
+            let max = n.parse().unwrap_or_else(|_| usage());
+            options.tape_mode = TapeMode::Grow { max };
+        } else if let Some(mode) = arg.strip_prefix("--tape=") {
+            options.tape_mode = match mode {
+                "fixed" => TapeMode::Fixed,
+                "bidirectional" => TapeMode::Bidirectional { max: GROW_LIMIT },
+                _ => usage(),
+            };
+        } else if arg == "--dump-ir" {
+            dump_ir = Some(false);
+        } else if let Some(format) = arg.strip_prefix("--dump-ir=") {
+            dump_ir = Some(match format {
+                "text" => false,
+                "json" => true,
+                _ => usage(),
+            });
+        } else if arg == "--dump-jit" {
+            dump_jit = Some(None);
+        } else if let Some(path) = arg.strip_prefix("--dump-jit=") {
+            dump_jit = Some(Some(path.to_string()));
+        } else if arg == "--dump-tape" {
+            dump_tape = Some(None);
+        } else if let Some(n) = arg.strip_prefix("--dump-tape=") {
+            dump_tape = Some(Some(n.parse().unwrap_or_else(|_| usage())));
+        } else if arg == "--profile" {
+            options.profile = true;
+            hot_loops = Some(HOT_LOOPS);
+        } else if let Some(n) = arg.strip_prefix("--profile=") {
+            options.profile = true;
+            hot_loops = Some(n.parse().unwrap_or_else(|_| usage()));
+        } else if let Some(path) = arg.strip_prefix("--profile-callgrind=") {
+            options.profile = true;
+            callgrind = Some(path.to_string());
+        } else if let Some(mode) = arg.strip_prefix("--output-utf8=") {
+            utf8 = Utf8Mode::from_name(mode).unwrap_or_else(|| usage());
+        } else if let Some(name) = arg.strip_prefix("--engine=") {
+            engine = Some(name.to_string());
+        } else if arg == "--jit" {
+            (engine, jit) = (None, true);
+        } else if arg == "--interp" {
+            (engine, jit) = (Some(Interpreter.name().to_string()), false);
+        } else if let Some(cells) = arg.strip_prefix("--cells=") {
+            big_cells = match cells {
+                "u8" => false,
+                "big" => true,
+                _ => usage(),
+            };
+        } else if let Some(bits) = arg.strip_prefix("--cell-width=") {
+            cell_width = CellWidth::from_name(bits).unwrap_or_else(|| usage());
+        } else if let Some(width) = arg.strip_prefix("--io-width=") {
+            io_width = IoWidth::from_name(width).unwrap_or_else(|| usage());
+        } else if let Some(name) = arg.strip_prefix("--dialect=") {
+            dialect = Some(Dialect::from_name(name).unwrap_or_else(|| usage()));
+        } else if let Some(path) = arg.strip_prefix("--dialect-map=") {
+            dialect = Some(Dialect::Custom(load_word_map(path)));
+        } else if arg == "--bang-input" {
+            bang_input = true;
+        } else if arg == "--compare" {
+            compare = true;
+        } else if arg == "--checkpoint" || arg == "--resume" {
+            let path = args.next().unwrap_or_else(|| usage()).into();
+            match arg.as_str() {
+                "--checkpoint" => checkpoint = Some(path),
+                _ => resume = Some(path),
+            }
+        } else if arg == "--trace" {
+            trace = Some(args.next().unwrap_or_else(|| usage()));
+        } else if let Some(path) = arg.strip_prefix("--trace=") {
+            trace = Some(path.to_string());
+        } else if arg == "--trace-limit" {
+            let n = args.next().unwrap_or_else(|| usage());
+            trace_limit = Some(n.parse().unwrap_or_else(|_| usage()));
+        } else if let Some(n) = arg.strip_prefix("--trace-limit=") {
+            trace_limit = Some(n.parse().unwrap_or_else(|_| usage()));
+        } else if let Some(format) = arg.strip_prefix("--trace-format=") {
+            trace_format = TraceFormat::from_name(format).unwrap_or_else(|| usage());
+        } else if let Some(path) = arg.strip_prefix("--checkpoint=") {
+            checkpoint = Some(path.into());
+        } else if let Some(path) = arg.strip_prefix("--resume=") {
+            resume = Some(path.into());
+        } else if arg == "--console-unicode" {
+            console_unicode = true;
+        } else if let Some(n) = arg.strip_prefix("-O") {
+            level = match n {
+                "0" => OptLevel::O0,
+                "1" => OptLevel::O1,
+                "2" => OptLevel::O2,
+                _ => usage(),
+            };
+        } else if arg == "--pass-sizes" {
+            pass_sizes = true;
+        } else if arg == "--no-ir-cache" {
+            ir_cache = false;
+        } else if let Some(eof) = arg.strip_prefix("--eof=") {
+            options.eof = EofBehavior::from_name(eof).unwrap_or_else(|| usage());
+        } else if let Some(mode) = arg.strip_prefix("--cell-overflow=") {
+            options.cell_overflow = CellOverflow::from_name(mode).unwrap_or_else(|| usage());
+        } else if arg.starts_with("--") || filepath.is_some() {
+            usage();
+        } else {
+            filepath = Some(arg);
+        }
+    }
+
+    // nothing to run: a prompt instead
+    if command == "run" && (as_repl || filepath.is_none()) {
+        if filepath.is_some() {
+            usage();
+        }
+        let interactive = io::stdin().is_terminal();
+        repl::Repl::new(dialect.unwrap_or_default(), options)
+            .serve(io::stdin().lock(), io::stdout().lock(), interactive)
+            .expect("repl failed");
+        return;
+    }
+    let filepath = filepath.unwrap_or_else(|| usage());
+    let dialect = source_dialect(&filepath, dialect);
+    if command == "ir" {
+        let src = read_source(&filepath);
+        let src = tokenizer::strip_shebang(&src);
+        let program = compiled(Program::compile_dialect(src, level, dialect));
+        print!("{}", program.to_ir_text());
+        return;
+    }
+    // a run on a tape nobody has written to lets the optimizer count on
+    // zero cells; a tape file or a snapshot brings its own
+    let start = match tape_file.is_some() || checkpoint.is_some() || resume.is_some() {
+        true => StartTape::Unknown,
+        false => StartTape::Zeroed,
+    };
+    if pass_sizes {
+        print_pass_sizes(&filepath, dialect, level, start, options.cell_overflow);
+    }
+    if let Some(json) = dump_ir {
+        dump_ir_stages(
+            &filepath,
+            dialect,
+            level,
+            start,
+            options.cell_overflow,
+            json,
+        );
+        return;
+    }
+    if io_width != IoWidth::Byte && (big_cells || cell_width == CellWidth::W8) {
+        eprintln!("--io-width needs --cell-width=16 or 32");
+        exit(1);
+    }
+    if big_cells || cell_width != CellWidth::W8 {
+        // `--output-utf8` and `--console-unicode` decode bytes, not wide
+        // cells, and neither run counts steps or output, so each is refused
+        // here rather than ignored
+        let other = options.max_loop_iterations.is_some() || options.profile;
+        let other = other || options.fuel.is_some() || options.max_output.is_some();
+        let other = other || utf8 != Utf8Mode::Raw || console_unicode;
+        let other = other || options.cell_overflow != CellOverflow::Wrap;
+        let other = other || options.tape_mode != TapeMode::Fixed;
+        let other = other || dialect != Dialect::Standard || bang_input;
+        let other = other || checkpoint.is_some() || resume.is_some();
+        let other = other || dump_jit.is_some() || trace.is_some();
+        if engine.as_ref().is_some_and(|e| e != Interpreter.name())
+            || jit
+            || tape_file.is_some()
+            || other
+            || (big_cells && (mem_size.is_some() || dump_tape.is_some()))
+            || (big_cells && cell_width != CellWidth::W8)
+        {
+            match big_cells {
+                true => eprintln!("--cells=big only takes --eof"),
+                false => eprintln!(
+                    "--cell-width={} only takes --eof, --io-width, --mem-size and --dump-tape",
+                    cell_width.bits()
+                ),
+            }
+            exit(1);
+        }
+        let eof = options.eof;
+        match big_cells {
+            true => run_unfolded(&filepath, "--cells=big", |src, input, output| {
+                bigcell::run(src, input, output, eof).map(drop)
+            }),
+            false => {
+                let len = mem_size.unwrap_or(vm::MEMORY_SIZE);
+                let flag = format!("--cell-width={}", cell_width.bits());
+                run_unfolded(&filepath, &flag, |src, input, output| {
+                    let mut tape = WideTape::new(len);
+                    let result =
+                        widecell::run_on(cell_width, io_width, src, &mut tape, input, output, eof);
+                    if let Some(len) = dump_tape {
+                        eprint!("{}", dump_wide(&tape, cell_width, len));
+                    }
+                    result
+                })
+            }
+        }
+        return;
+    }
+    #[cfg(unix)]
+    progress::install_sigusr1().expect("failed to install SIGUSR1 handler");
+    let mut registry = EngineRegistry::builtin();
+    let names = registry.names().join(", ");
+    let saving = checkpoint.is_some();
+    let mut checkpointing = None;
+    if saving || resume.is_some() {
+        // a snapshot holds the interpreter's state, on a tape that stays put
+        if engine.as_ref().is_some_and(|e| e != Interpreter.name())
+            || jit
+            || compare
+            || tape_file.is_some()
+            || options.tape_mode != TapeMode::Fixed
+        {
+            eprintln!("--checkpoint and --resume run the interpreter on a fixed tape");
+            exit(1);
+        }
+        if saving && options.fuel.is_none() {
+            eprintln!("--checkpoint saves where --max-steps runs out, give it one");
+            exit(1);
+        }
+        checkpointing = Some(checkpoint::Checkpointing {
+            checkpoint: checkpoint.clone(),
+            resume,
+        });
+    }
+    if trace.is_some() {
+        // only the interpreter goes an instruction at a time
+        if engine.as_ref().is_some_and(|e| e != Interpreter.name()) || jit || compare {
+            eprintln!("--trace records what the interpreter runs");
+            exit(1);
+        }
+        engine = Some(Interpreter.name().to_string());
+    } else if trace_limit.is_some() {
+        usage();
+    }
+    // without a named engine the JIT may hand over to the interpreter, saying
+    // so when it was asked for
+    let fallback = engine.is_none() && checkpointing.is_none();
+    let interpreting =
+        checkpointing.is_some() || engine.as_ref().is_some_and(|e| e != X86_64Jit.name());
+    let name = engine.unwrap_or_else(|| X86_64Jit.name().to_string());
+    let engine = match registry.get(&name) {
+        _ if checkpointing.is_some() => checkpointing.as_mut().unwrap(),
+        Some(engine) => engine,
+        None if fallback => {
+            if let (true, Err(why)) = (jit, X86_64Jit::detect()) {
+                eprintln!("warning: {}, falling back to the interpreter", why);
+            }
+            registry
+                .get(Interpreter.name())
+                .expect("the interpreter runs anywhere")
+        }
+        None => {
+            eprintln!("unknown engine {}, expected one of: {}", name, names);
+            exit(1);
+        }
+    };
+    let cache = IrCache::default_dir()
+        .filter(|_| ir_cache)
+        .map(IrCache::new);
+    if bang_input && dialect.uses('!') {
+        eprintln!("--bang-input does not go with a dialect where ! is part of a command");
+        exit(1);
+    }
+    let (program, bang) = match bang_input {
+        true => {
+            let overflow = options.cell_overflow;
+            let (program, input) =
+                load_bang(&filepath, cache.as_ref(), dialect, level, start, overflow);
+            (program, Some(input))
+        }
+        false => {
+            let overflow = options.cell_overflow;
+            let program = load(&filepath, cache.as_ref(), dialect, level, start, overflow);
+            (program, None)
+        }
+    };
+    if let Some(path) = &dump_jit {
+        if interpreting {
+            eprintln!("--dump-jit shows what the JIT generates, and this run interprets");
+            exit(1);
+        }
+        dump_jit_code(&program, &options, path.as_deref());
+    }
+    if compare {
+        if !fallback || jit || tape_file.is_some() || dump_tape.is_some() {
+            eprintln!("--compare runs both engines, each on a tape of its own");
+            exit(1);
+        }
+        let input = bang.unwrap_or_else(|| {
+            let mut input = vec![];
+            io::stdin().read_to_end(&mut input).unwrap_or_else(|e| {
+                eprintln!("read input failed: {}", e);
+                exit(error::ErrorCategory::Io.exit_code());
+            });
+            input
+        });
+        let len = mem_size.unwrap_or(vm::MEMORY_SIZE);
+        let result = compare::run_compare_with(&program, &input, &options, len);
+        println!("{}", result);
+        exit(if result.is_same() { 0 } else { 1 });
+    }
+    if mem_size.is_some() && tape_file.is_some() {
+        eprintln!("--mem-size does not apply to --tape-file, give its size there");
+        exit(1);
+    }
+    let mut mapped = tape_file.map(|(path, size)| {
+        TapeFile::open(&path, size).unwrap_or_else(|e| {
+            eprintln!("open tape file {} failed: {}", path, e);
+            exit(1);
+        })
+    });
+    let mut owned = vec![];
+    let tape: &mut [u8] = match &mut mapped {
+        Some(file) => file,
+        None => {
+            owned.resize(mem_size.unwrap_or(vm::MEMORY_SIZE), 0);
+            &mut owned
+        }
+    };
+    let output = ConsoleOutput::stdout(console_unicode);
+    let mut input: Box<dyn Read> = match bang {
+        Some(bytes) => Box::new(io::Cursor::new(bytes)),
+        None => Box::new(io::stdin()),
+    };
+    let mut output = Utf8Writer::new(output, utf8);
+    let origin = options.tape_mode.origin(tape.len());
+    let mut ctx = ExecContext::new(&mut *tape, &mut input, &mut output).with_options(options);
+    ctx.trace = trace.as_ref().map(|path| {
+        let file = fs::File::create(path).unwrap_or_else(|e| {
+            eprintln!("create trace {} failed: {}", path, e);
+            exit(1);
+        });
+        let tracer = Tracer::new(file, trace_format);
+        match trace_limit {
+            Some(n) => tracer.with_limit(n),
+            None => tracer,
+        }
+    });
+    let start = Instant::now();
+    let result = match engine.run(&program, &mut ctx) {
+        // the JIT gives up before running anything, so the interpreter can
+        // start over on the same tape and input
+        Err(vm::VmError::Jit(e)) if fallback => {
+            if jit || matches!(e, JitError::Memory(_)) {
+                eprintln!("warning: {}, falling back to the interpreter", e);
+            }
+            Interpreter.run(&program, &mut ctx)
+        }
+        result => result,
+    };
+    let (span, pointer) = match &result {
+        Ok(outcome) => (None, Some(outcome.pointer)),
+        Err(_) => (ctx.error_span, ctx.error_pointer),
+    };
+    let elapsed = start.elapsed();
+    let profile = ctx.profile.take();
+    if let (Some(top), Some(counts)) = (hot_loops, &profile) {
+        eprint!("{}", profile::report(&program, counts, top, elapsed));
+    }
+    let result = result.and_then(|_| Ok(output.finish()?));
+    if let Some(len) = dump_tape {
+        let start = tape::dump_start(tape, pointer, origin);
+        let cells = &tape[start..];
+        let len = len.unwrap_or_else(|| tape::dump_len(cells, pointer.map(|p| p - start)));
+        let cells = &cells[..len.min(cells.len())];
+        eprint!("{}", tape::hexdump_at(cells, start, pointer));
+    }
+    if let Some(file) = &mapped {
+        file.flush().expect("failed to sync tape file");
+    }
+    if let (Some(path), Some(counts)) = (callgrind, profile) {
+        let source = program.source_info().map_or(filepath.as_str(), |s| &s.file);
+        let text = callgrind::render(&program, &counts, source);
+        fs::write(&path, text).unwrap_or_else(|e| {
+            eprintln!("write profile {} failed: {}", path, e);
+            exit(1);
+        });
+    }
+    if let (Err(vm::VmError::OutOfFuel(_)), Some(path)) = (&result, &checkpoint) {
+        eprintln!("checkpoint saved to {}", path.display());
+    }
+    if let Err(e) = result {
+        match span {
+            #[cfg(feature = "image")]
+            Some(span) if filepath.ends_with(".png") => {
+                let (x, y) = brainloller::pixel(span);
+                eprintln!(
+                    "run vm failed at pixel ({}, {}): {}",
+                    x,
+                    y,
+                    error::report(&e)
+                );
+            }
+            Some(span) => {
+                // an overflow says where itself
+                match e {
+                    vm::VmError::PointerOverFlow(Some(_)) => {
+                        eprintln!("run vm failed: {}", error::report(&e))
+                    }
+                    _ => eprintln!("run vm failed at {}: {}", span, error::report(&e)),
+                }
+                let source = program.source_info().map_or(filepath.as_str(), |s| &s.file);
+                // only the text the spans were taken from, which is hashed
+                // with its shebang line or without
+                let text = fs::read_to_string(source).ok().filter(|text| {
+                    let stripped = tokenizer::strip_shebang(text);
+                    program.source_info().is_none_or(|s| {
+                        [text.as_str(), stripped]
+                            .iter()
+                            .any(|text| s.hash == bytecode::source_hash(text))
+                    })
+                });
+                if let Some(snippet) = text.and_then(|text| error::snippet(&text, span)) {
+                    eprint!("{}", snippet);
+                }
+            }
+            None => eprintln!("run vm failed: {}", error::report(&e)),
+        }
+        exit(e.category().exit_code());
+    }
 }
@@ -0,0 +1,355 @@
+//! Mutations that keep a program well formed, for genetic programming and
+//! structure-aware fuzzing.
+//!
+//! Every operation works on the linked tokens of a `Program` and treats them
+//! as a sequence of subtrees: a single token, or a block from its start to
+//! its end inclusive. Inserting or deleting a plain token, moving whole
+//! subtrees, and adding or removing both ends of a block at once cannot
+//! unbalance the brackets, so every result is relinked and passes
+//! `tokenizer::verify`. Tokens that are moved keep their spans; new ones get
+//! the default span.
+//!
+//! Like `ProgramGenerator`, a `Mutator` owns its `Rng` state, so a seed
+//! gives the same offspring on every run and platform.
+
+use std::ops::Range;
+
+use crate::{
+    program::Program,
+    rng::Rng,
+    tokenizer::{self, Span, Token},
+};
+
+/// What `Mutator::mutate` does to a program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mutation {
+    /// Put a new `+`, `-`, `<`, `>`, `.` or `,` anywhere.
+    Insert,
+    /// Remove one token that is not part of a block.
+    Delete,
+    /// Pick another value for a token's count, byte or length.
+    Operand,
+    /// Exchange two subtrees that do not overlap.
+    Swap,
+    /// Put a run of sibling subtrees inside a new loop.
+    Wrap,
+    /// Remove both ends of a block, keeping its body.
+    Unwrap,
+}
+
+impl Mutation {
+    pub const ALL: [Mutation; 6] = [
+        Mutation::Insert,
+        Mutation::Delete,
+        Mutation::Operand,
+        Mutation::Swap,
+        Mutation::Wrap,
+        Mutation::Unwrap,
+    ];
+}
+
+pub struct Mutator {
+    rng: Rng,
+    max_len: usize,
+}
+
+impl Mutator {
+    pub fn new(seed: u64) -> Self {
+        Mutator {
+            rng: Rng::new(seed),
+            max_len: 1000,
+        }
+    }
+
+    /// Upper bound on the tokens a mutation may grow a program to. A program
+    /// already longer than this only ever shrinks or keeps its length.
+    pub fn max_len(mut self, n: usize) -> Self {
+        self.max_len = n;
+        self
+    }
+
+    /// Apply one mutation chosen at random among those possible on `program`.
+    /// An empty program can only have a token inserted; when nothing at all
+    /// fits, the program comes back unchanged.
+    pub fn mutate(&mut self, program: &Program) -> Program {
+        let mut kinds = Mutation::ALL.to_vec();
+        while !kinds.is_empty() {
+            let kind = kinds.swap_remove(self.rng.below(kinds.len()));
+            if let Some(program) = self.apply(program, kind) {
+                return program;
+            }
+        }
+        program.clone()
+    }
+
+    /// Apply `kind` at a random place, or None when `program` offers no
+    /// place for it or it would grow the program past `max_len`.
+    pub fn apply(&mut self, program: &Program, kind: Mutation) -> Option<Program> {
+        let mut tokens = program.tokens().to_vec();
+        let mut spans = program.spans().to_vec();
+        let grows = matches!(kind, Mutation::Insert | Mutation::Wrap);
+        if grows && tokens.len() + (kind == Mutation::Wrap) as usize >= self.max_len {
+            return None;
+        }
+        match kind {
+            Mutation::Insert => {
+                let at = self.rng.below(tokens.len() + 1);
+                tokens.insert(at, self.simple_token());
+                spans.insert(at, Span::default());
+            }
+            Mutation::Delete => {
+                let plain = positions(&tokens, |t| !t.is_block_start() && !t.is_block_end());
+                let at = *self.pick(&plain)?;
+                tokens.remove(at);
+                spans.remove(at);
+            }
+            Mutation::Operand => {
+                let operands = positions(&tokens, |t| self::operand(t).is_some());
+                let at = *self.pick(&operands)?;
+                tokens[at] = self.change_operand(tokens[at]);
+            }
+            Mutation::Swap => {
+                let subtrees = subtrees(&tokens);
+                let a = self.pick(&subtrees)?.clone();
+                let apart: Vec<_> = subtrees
+                    .iter()
+                    .filter(|b| b.end <= a.start || b.start >= a.end)
+                    .cloned()
+                    .collect();
+                let b = self.pick(&apart)?.clone();
+                let (first, second) = if a.start < b.start { (a, b) } else { (b, a) };
+                swap(&mut tokens, first.clone(), second.clone());
+                swap(&mut spans, first, second);
+            }
+            Mutation::Wrap => {
+                let region = self.region(&tokens)?;
+                tokens.insert(region.end, Token::LoopEnd(0));
+                spans.insert(region.end, Span::default());
+                tokens.insert(region.start, Token::LoopStart(0));
+                spans.insert(region.start, Span::default());
+            }
+            Mutation::Unwrap => {
+                let blocks = positions(&tokens, Token::is_block_start);
+                let start = *self.pick(&blocks)?;
+                let end = block_end(&tokens, start);
+                for at in [end, start] {
+                    tokens.remove(at);
+                    spans.remove(at);
+                }
+            }
+        }
+        tokenizer::relink(&mut tokens);
+        debug_assert_eq!(tokenizer::verify(&tokens), Ok(()));
+        Some(Program::from_parts(tokens, spans))
+    }
+
+    fn pick<'a, T>(&mut self, items: &'a [T]) -> Option<&'a T> {
+        match items.len() {
+            0 => None,
+            n => Some(&items[self.rng.below(n)]),
+        }
+    }
+
+    fn simple_token(&mut self) -> Token {
+        let amount = 1 + self.rng.below(8);
+        match self.rng.below(6) {
+            0 => Token::IncrementData(amount as u8),
+            1 => Token::DecrementData(amount as u8),
+            2 => Token::IncrementPointer(amount),
+            3 => Token::DecrementPointer(amount),
+            4 => Token::Output,
+            _ => Token::Input,
+        }
+    }
+
+    fn change_operand(&mut self, token: Token) -> Token {
+        // a new value from 1 to twice the old one, always a change
+        let old = operand(&token).unwrap();
+        let mut new = 1 + self.rng.below(2 * old.max(1));
+        if new == old {
+            new = if old > 1 { old - 1 } else { 2 };
+        }
+        match token {
+            Token::IncrementData(_) => Token::IncrementData(new.min(255) as u8),
+            Token::DecrementData(_) => Token::DecrementData(new.min(255) as u8),
+            Token::IncrementPointer(_) => Token::IncrementPointer(new),
+            Token::DecrementPointer(_) => Token::DecrementPointer(new),
+            Token::ScanRight(_) => Token::ScanRight(new),
+            Token::ScanLeft(_) => Token::ScanLeft(new),
+            Token::OutputRepeat(_) => Token::OutputRepeat(new.max(2)),
+            Token::Print(_) => Token::Print(self.rng.next() as u8),
+            Token::ClearRange { start_offset, .. } => Token::ClearRange {
+                start_offset,
+                len: new as u32,
+            },
+            _ => unreachable!("no operand"),
+        }
+    }
+
+    // a run of one or more sibling subtrees
+    fn region(&mut self, tokens: &[Token]) -> Option<Range<usize>> {
+        let first = self.pick(&subtrees(tokens))?.clone();
+        let mut end = first.end;
+        while end < tokens.len() && !tokens[end].is_block_end() && self.rng.below(2) == 0 {
+            end = subtree_end(tokens, end);
+        }
+        Some(first.start..end)
+    }
+}
+
+/// Exchange a region of one or more sibling subtrees between `a` and `b`,
+/// returning both offspring. Regions are chosen by `seed`; an empty parent
+/// offers an empty region at its start.
+pub fn crossover(a: &Program, b: &Program, seed: u64) -> (Program, Program) {
+    let mut rng = Mutator::new(seed);
+    let ra = rng.region(a.tokens()).unwrap_or(0..0);
+    let rb = rng.region(b.tokens()).unwrap_or(0..0);
+    let child = |into: &Program, at: Range<usize>, from: &Program, take: Range<usize>| {
+        let mut tokens = into.tokens().to_vec();
+        let mut spans = into.spans().to_vec();
+        tokens.splice(at.clone(), from.tokens()[take.clone()].iter().copied());
+        spans.splice(at, from.spans()[take].iter().copied());
+        tokenizer::relink(&mut tokens);
+        debug_assert_eq!(tokenizer::verify(&tokens), Ok(()));
+        Program::from_parts(tokens, spans)
+    };
+    (child(a, ra.clone(), b, rb.clone()), child(b, rb, a, ra))
+}
+
+fn positions(tokens: &[Token], keep: impl Fn(&Token) -> bool) -> Vec<usize> {
+    (0..tokens.len()).filter(|&i| keep(&tokens[i])).collect()
+}
+
+// the count, byte or length a token carries, as a size
+fn operand(token: &Token) -> Option<usize> {
+    match *token {
+        Token::IncrementData(x) | Token::DecrementData(x) => Some(x as usize),
+        Token::IncrementPointer(x) | Token::DecrementPointer(x) => Some(x),
+        Token::ScanRight(x) | Token::ScanLeft(x) => Some(x),
+        Token::OutputRepeat(n) => Some(n),
+        Token::Print(b) => Some(b as usize),
+        Token::ClearRange { len, .. } => Some(len as usize),
+        _ => None,
+    }
+}
+
+fn block_end(tokens: &[Token], start: usize) -> usize {
+    match tokens[start] {
+        Token::LoopStart(end) | Token::IfStart(end) => end as usize,
+        _ => unreachable!("not a block start"),
+    }
+}
+
+// one past the subtree starting at `start`
+fn subtree_end(tokens: &[Token], start: usize) -> usize {
+    match tokens[start].is_block_start() {
+        true => block_end(tokens, start) + 1,
+        false => start + 1,
+    }
+}
+
+// every subtree, nested ones included
+fn subtrees(tokens: &[Token]) -> Vec<Range<usize>> {
+    (0..tokens.len())
+        .filter(|&i| !tokens[i].is_block_end())
+        .map(|i| i..subtree_end(tokens, i))
+        .collect()
+}
+
+// exchange `first` and `second`, which do not overlap and come in that order
+fn swap<T: Copy>(items: &mut Vec<T>, first: Range<usize>, second: Range<usize>) {
+    let mut out = Vec::with_capacity(items.len());
+    out.extend_from_slice(&items[..first.start]);
+    out.extend_from_slice(&items[second.clone()]);
+    out.extend_from_slice(&items[first.end..second.start]);
+    out.extend_from_slice(&items[first]);
+    out.extend_from_slice(&items[second.end..]);
+    *items = out;
+}
+
+#[test]
+fn test_mutate() {
+    use crate::{generate::ProgramGenerator, program::OptLevel};
+
+    let max_len = 120;
+    for seed in 0..40 {
+        let src = ProgramGenerator::new(seed).max_len(100).generate();
+        let level = OptLevel::ALL[seed as usize % 3];
+        let mut program = Program::compile_with(&src, level).unwrap();
+        let mut mutator = Mutator::new(seed).max_len(max_len);
+        for _ in 0..200 {
+            let before = program.tokens().len();
+            program = mutator.mutate(&program);
+            let len = program.tokens().len();
+            assert_eq!(tokenizer::verify(program.tokens()), Ok(()));
+            assert_eq!(program.spans().len(), len);
+            assert!(len <= max_len.max(before), "{} tokens", len);
+        }
+        // every kind does what it says wherever it applies
+        for kind in Mutation::ALL {
+            let Some(after) = mutator.apply(&program, kind) else {
+                continue;
+            };
+            let (old, new) = (program.tokens(), after.tokens());
+            let blocks = |t: &[Token]| t.iter().filter(|t| t.is_block_start()).count();
+            match kind {
+                Mutation::Insert => assert_eq!(new.len(), old.len() + 1),
+                Mutation::Delete => assert_eq!(new.len() + 1, old.len()),
+                Mutation::Operand | Mutation::Swap => assert_eq!(new.len(), old.len()),
+                Mutation::Wrap => assert_eq!(blocks(new), blocks(old) + 1),
+                Mutation::Unwrap => assert_eq!(blocks(new) + 1, blocks(old)),
+            }
+            assert_eq!(tokenizer::verify(new), Ok(()));
+        }
+    }
+
+    // one token, nothing to delete but something to change
+    let one = Program::compile_with(">", OptLevel::O0).unwrap();
+    let mut mutator = Mutator::new(1);
+    assert!(mutator.apply(&one, Mutation::Unwrap).is_none());
+    assert!(mutator.apply(&one, Mutation::Swap).is_none());
+    let changed = mutator.apply(&one, Mutation::Operand).unwrap();
+    assert!(matches!(changed.tokens(), [Token::IncrementPointer(n)] if *n != 1));
+    let empty = Program::new(vec![]);
+    assert_eq!(mutator.mutate(&empty).tokens().len(), 1);
+    let full = Mutator::new(1).max_len(1).mutate(&one);
+    assert!(full.tokens().len() <= 1);
+}
+
+#[test]
+fn test_crossover() {
+    use crate::program::OptLevel;
+
+    let a = Program::compile_with("++[->+<]>.[-]", OptLevel::O1).unwrap();
+    let b = Program::compile_with(",[.,]<<[[-]>]", OptLevel::O1).unwrap();
+    let offspring = |seed| {
+        let (x, y) = crossover(&a, &b, seed);
+        (x.tokens().to_vec(), y.tokens().to_vec())
+    };
+    // the same seed breeds the same pair, every time
+    assert_eq!(offspring(5), offspring(5));
+    let mut seen = std::collections::HashSet::new();
+    for seed in 0..200 {
+        let (x, y) = offspring(seed);
+        assert_eq!(tokenizer::verify(&x), Ok(()));
+        assert_eq!(tokenizer::verify(&y), Ok(()));
+        // tokens only move between the parents
+        assert_eq!(x.len() + y.len(), a.tokens().len() + b.tokens().len());
+        seen.insert(format!("{:?}", x));
+    }
+    assert!(seen.len() > 20);
+    // and a seeded chain of mutations is just as repeatable
+    let mutants: Vec<Vec<Token>> = (0..3)
+        .scan(a.clone(), |p, seed| {
+            *p = Mutator::new(seed).mutate(p);
+            Some(p.tokens().to_vec())
+        })
+        .collect();
+    let again: Vec<Vec<Token>> = (0..3)
+        .scan(a.clone(), |p, seed| {
+            *p = Mutator::new(seed).mutate(p);
+            Some(p.tokens().to_vec())
+        })
+        .collect();
+    assert_eq!(mutants, again);
+}
@@ -0,0 +1,305 @@
+//! The optimizer as a list of passes, each over freshly linked tokens.
+//!
+//! `OptLevel` picks a list through `PassManager::for_level`; a list can
+//! also be put together by hand to find which pass changes what a program
+//! does. Every pass keeps the tokens' parallel spans in step and leaves the
+//! block targets correct.
+
+use crate::{
+    program::OptLevel,
+    tokenizer::{self, Span, StartTape, Token},
+    vm::CellOverflow,
+};
+
+pub trait Pass {
+    fn name(&self) -> &'static str;
+
+    /// Rewrite `tokens`, keeping the parallel `spans` in step.
+    fn run_spanned(&self, tokens: &mut Vec<Token>, spans: &mut Vec<Span>);
+
+    /// `run_spanned` for tokens that have no source positions.
+    fn run(&self, ir: &mut Vec<Token>) {
+        let mut spans = vec![Span::default(); ir.len()];
+        self.run_spanned(ir, &mut spans);
+    }
+}
+
+/// Run-length folding of `+-`, `<>` and `.`, as `tokenizer::optimize`, on
+/// up to `threads` threads.
+#[derive(Debug, Clone, Copy)]
+pub struct Fold {
+    pub threads: usize,
+    pub overflow: CellOverflow,
+}
+
+impl Pass for Fold {
+    fn name(&self) -> &'static str {
+        "fold"
+    }
+
+    fn run_spanned(&self, tokens: &mut Vec<Token>, spans: &mut Vec<Span>) {
+        match self.threads {
+            0 | 1 => tokenizer::optimize_spanned(tokens, spans, self.overflow),
+            n => tokenizer::optimize_parallel(tokens, spans, n, self.overflow),
+        }
+    }
+}
+
+/// `[-]` runs as `ClearRange`, and other odd steps when cells wrap.
+#[derive(Debug, Clone, Copy)]
+pub struct ClearRanges {
+    pub overflow: CellOverflow,
+}
+
+impl Pass for ClearRanges {
+    fn name(&self) -> &'static str {
+        "clear-ranges"
+    }
+
+    fn run_spanned(&self, tokens: &mut Vec<Token>, spans: &mut Vec<Span>) {
+        tokenizer::clear_ranges_spanned(tokens, spans, self.overflow)
+    }
+}
+
+/// Loops whose cell is known nonzero on entry, see `tokenizer::peel_loops`.
+#[derive(Debug, Clone, Copy)]
+pub struct PeelLoops {
+    pub start: StartTape,
+}
+
+impl Pass for PeelLoops {
+    fn name(&self) -> &'static str {
+        "peel-loops"
+    }
+
+    fn run_spanned(&self, tokens: &mut Vec<Token>, spans: &mut Vec<Span>) {
+        tokenizer::peel_loops_spanned(tokens, spans, self.start)
+    }
+}
+
+/// Loops that can never be entered, see `tokenizer::dead_loops`.
+#[derive(Debug, Clone, Copy)]
+pub struct DeadLoops {
+    pub start: StartTape,
+}
+
+impl Pass for DeadLoops {
+    fn name(&self) -> &'static str {
+        "dead-loops"
+    }
+
+    fn run_spanned(&self, tokens: &mut Vec<Token>, spans: &mut Vec<Span>) {
+        tokenizer::dead_loops_spanned(tokens, spans, self.start)
+    }
+}
+
+macro_rules! passes {
+    ($($(#[$doc:meta])* $pass:ident $name:literal => $run:expr;)*) => {$(
+        $(#[$doc])*
+        #[derive(Debug, Clone, Copy)]
+        pub struct $pass;
+
+        impl Pass for $pass {
+            fn name(&self) -> &'static str {
+                $name
+            }
+
+            fn run_spanned(&self, tokens: &mut Vec<Token>, spans: &mut Vec<Span>) {
+                let run: fn(&mut Vec<Token>, &mut Vec<Span>) = $run;
+                run(tokens, spans)
+            }
+        }
+    )*};
+}
+
+passes! {
+    /// Copy and multiply loops as `MulAdd`.
+    MulLoops "mul-loops" => tokenizer::mul_loops_spanned;
+    /// `[>]` and `[<]` as scans.
+    ScanLoops "scan-loops" => tokenizer::scan_loops_spanned;
+    /// Blocks whose condition cell is known zero dropped, and `.` of a known
+    /// cell as `Print`.
+    FoldKnown "fold-known" => tokenizer::fold_known_spanned;
+    /// Loops that run at most once as `IfStart`/`IfEnd`.
+    LowerIfs "lower-ifs" => |tokens, _| tokenizer::lower_ifs(tokens);
+    /// Pointer moves folded into the offsets of `AddAt`.
+    OffsetOps "offset-ops" => tokenizer::offset_ops_spanned;
+}
+
+/// Passes run one after the other.
+#[derive(Default)]
+pub struct PassManager {
+    passes: Vec<Box<dyn Pass + Send + Sync>>,
+    relink: bool,
+}
+
+impl PassManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// What `level` optimizes with for a program starting on `start` whose
+    /// cells overflow as `overflow` says, folding on `threads` threads.
+    /// Under `CellOverflow::Error` nothing is folded in a way that would
+    /// take an overflow away, and multiply loops, whose adds wrap, stay
+    /// loops.
+    pub fn for_level(
+        level: OptLevel,
+        start: StartTape,
+        overflow: CellOverflow,
+        threads: usize,
+    ) -> Self {
+        let manager = PassManager::new();
+        let fold = Fold { threads, overflow };
+        match level {
+            OptLevel::O0 => manager,
+            OptLevel::O1 => manager.with(fold).with(DeadLoops { start }),
+            OptLevel::O2 => {
+                let manager = manager
+                    .with(fold)
+                    .with(DeadLoops { start })
+                    .with(ClearRanges { overflow });
+                let manager = match overflow {
+                    CellOverflow::Wrap => manager.with(MulLoops),
+                    CellOverflow::Error => manager,
+                };
+                manager
+                    .with(ScanLoops)
+                    .with(PeelLoops { start })
+                    .with(FoldKnown)
+                    .with(LowerIfs)
+                    .with(OffsetOps)
+            }
+        }
+    }
+
+    pub fn with(mut self, pass: impl Pass + Send + Sync + 'static) -> Self {
+        self.passes.push(Box::new(pass));
+        self
+    }
+
+    /// Recompute the block targets after every pass, for a pass that moves
+    /// tokens without fixing them.
+    pub fn relink(mut self, relink: bool) -> Self {
+        self.relink = relink;
+        self
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.passes.iter().map(|pass| pass.name()).collect()
+    }
+
+    /// Run every pass on `tokens`, giving the number of tokens after each.
+    pub fn run(
+        &self,
+        tokens: &mut Vec<Token>,
+        spans: &mut Vec<Span>,
+    ) -> Vec<(&'static str, usize)> {
+        let mut sizes = Vec::with_capacity(self.passes.len());
+        for pass in &self.passes {
+            pass.run_spanned(tokens, spans);
+            if self.relink {
+                tokenizer::relink(tokens);
+            }
+            debug_assert_eq!(tokens.len(), spans.len(), "{}", pass.name());
+            sizes.push((pass.name(), tokens.len()));
+        }
+        sizes
+    }
+}
+
+#[test]
+fn test_passes() {
+    use crate::program::Program;
+    use Token::*;
+
+    let linked = |src: &str| tokenizer::link(&tokenizer::lex(src)).unwrap();
+
+    // folding on its own is `optimize`, on one thread or several
+    let src = "++-->>><+[->+<]..,";
+    let mut expected = linked(src);
+    tokenizer::optimize(&mut expected);
+    for threads in [1, 4] {
+        let mut tokens = linked(src);
+        let overflow = CellOverflow::Wrap;
+        Fold { threads, overflow }.run(&mut tokens);
+        assert_eq!(tokens, expected);
+    }
+    // unless an overflow is an error, which folding must keep
+    let mut tokens = linked("-++>><");
+    let overflow = CellOverflow::Error;
+    Fold {
+        threads: 1,
+        overflow,
+    }
+    .run(&mut tokens);
+    assert_eq!(
+        tokens,
+        [DecrementData(1), IncrementData(2), IncrementPointer(1)]
+    );
+    assert_eq!(
+        expected[..3],
+        [IncrementPointer(2), IncrementData(1), LoopStart(7)]
+    );
+
+    // each pass on a sequence of its own
+    let mut tokens = linked("[-]");
+    ClearRanges {
+        overflow: CellOverflow::Wrap,
+    }
+    .run(&mut tokens);
+    assert!(matches!(tokens[..], [ClearRange { .. }]), "{:?}", tokens);
+    let mut tokens = linked("[+]");
+    ClearRanges { overflow }.run(&mut tokens);
+    assert_eq!(tokens, linked("[+]"));
+    let mut tokens = linked("[>]");
+    ScanLoops.run(&mut tokens);
+    assert_eq!(tokens, [ScanRight(1)]);
+    let mut tokens = linked("[-][+]+");
+    DeadLoops {
+        start: StartTape::Unknown,
+    }
+    .run(&mut tokens);
+    assert_eq!(
+        tokens,
+        [LoopStart(2), DecrementData(1), LoopEnd(0), IncrementData(1)]
+    );
+    let mut tokens = linked("[-][+]+");
+    DeadLoops {
+        start: StartTape::Zeroed,
+    }
+    .run(&mut tokens);
+    assert_eq!(tokens, [IncrementData(1)]);
+
+    // the levels are these lists, and give what compiling at them gives
+    let wrap = CellOverflow::Wrap;
+    assert!(
+        PassManager::for_level(OptLevel::O0, StartTape::Zeroed, wrap, 1)
+            .names()
+            .is_empty()
+    );
+    assert_eq!(
+        PassManager::for_level(OptLevel::O1, StartTape::Zeroed, wrap, 1).names(),
+        ["fold", "dead-loops"]
+    );
+    let strict = PassManager::for_level(OptLevel::O2, StartTape::Zeroed, overflow, 1);
+    assert!(!strict.names().contains(&"mul-loops"));
+    let hellow = std::fs::read_to_string("bfcode/hellow.bf").unwrap();
+    for level in OptLevel::ALL {
+        let ops = tokenizer::lex(&hellow);
+        let mut tokens = tokenizer::link(&ops).unwrap();
+        let mut spans: Vec<Span> = ops.iter().map(|op| op.span).collect();
+        let sizes = PassManager::for_level(level, StartTape::Unknown, wrap, 1)
+            .relink(true)
+            .run(&mut tokens, &mut spans);
+        assert_eq!(
+            sizes.last().map_or(ops.len(), |&(_, len)| len),
+            tokens.len()
+        );
+        let program = Program::compile_with(&hellow, level).unwrap();
+        assert_eq!(
+            (&tokens[..], &spans[..]),
+            (program.tokens(), program.spans())
+        );
+    }
+}
@@ -0,0 +1,536 @@
+//! Just enough PNG to read a picture's pixels, for the Brainloller front end.
+//!
+//! Every color type and bit depth decodes, to 8-bit RGB with alpha dropped;
+//! 16-bit samples keep their high byte. Interlaced images are refused. Chunk
+//! CRCs and the zlib checksum are verified, so a damaged file fails instead of
+//! turning into a different program. Inflate follows RFC 1951 directly: a
+//! canonical Huffman code is decoded one bit at a time from the counts of
+//! each code length, which is slow and small.
+
+use crate::error::ErrorCategory;
+
+const SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+
+// more pixels than this is no program anyone drew
+const MAX_PIXELS: u64 = 1 << 26;
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum PngError {
+    #[error("E0701 Not a PNG image")]
+    NotPng,
+
+    #[error("E0702 Damaged PNG chunk at byte {0}")]
+    BadChunk(usize),
+
+    #[error("E0703 Unsupported PNG: {0}")]
+    Unsupported(&'static str),
+
+    #[error("E0704 Damaged PNG image data")]
+    BadData,
+}
+
+impl PngError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            PngError::NotPng => "E0701",
+            PngError::BadChunk(_) => "E0702",
+            PngError::Unsupported(_) => "E0703",
+            PngError::BadData => "E0704",
+        }
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        ErrorCategory::Compile
+    }
+}
+
+/// Whether `bytes` start like a PNG file.
+pub fn is_png(bytes: &[u8]) -> bool {
+    bytes.starts_with(SIGNATURE)
+}
+
+pub struct Image {
+    width: u32,
+    height: u32,
+    pixels: Vec<[u8; 3]>, // row by row from the top left
+}
+
+impl Image {
+    /// An image of `pixels`, row by row from the top left.
+    pub fn new(width: u32, height: u32, pixels: Vec<[u8; 3]>) -> Self {
+        assert_eq!(pixels.len(), width as usize * height as usize);
+        Image {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 3] {
+        self.pixels[y as usize * self.width as usize + x as usize]
+    }
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0_u32;
+    for &b in bytes {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+fn be32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes[..4].try_into().unwrap())
+}
+
+struct Header {
+    width: u32,
+    height: u32,
+    depth: u8,
+    color: u8,
+}
+
+impl Header {
+    fn channels(&self) -> usize {
+        match self.color {
+            0 | 3 => 1,
+            4 => 2,
+            2 => 3,
+            _ => 4, // 6
+        }
+    }
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Image, PngError> {
+    if !is_png(bytes) {
+        return Err(PngError::NotPng);
+    }
+    let mut header = None;
+    let mut palette: Vec<[u8; 3]> = vec![];
+    let mut data = vec![];
+    let mut at = SIGNATURE.len();
+    loop {
+        let chunk = at;
+        let len = bytes.get(at..at + 4).ok_or(PngError::BadChunk(chunk))?;
+        let len = be32(len) as usize;
+        let end = at
+            .checked_add(12 + len)
+            .filter(|&end| end <= bytes.len())
+            .ok_or(PngError::BadChunk(chunk))?;
+        let (kind, body) = (&bytes[at + 4..at + 8], &bytes[at + 8..end - 4]);
+        if crc32(&bytes[at + 4..end - 4]) != be32(&bytes[end - 4..]) {
+            return Err(PngError::BadChunk(chunk));
+        }
+        at = end;
+        match (kind, &header) {
+            (b"IHDR", None) if len == 13 => {
+                if body[10] != 0 || body[11] != 0 {
+                    return Err(PngError::Unsupported("compression or filter method"));
+                }
+                if body[12] != 0 {
+                    return Err(PngError::Unsupported("interlacing"));
+                }
+                let (depth, color) = (body[8], body[9]);
+                let valid = match color {
+                    0 => matches!(depth, 1 | 2 | 4 | 8 | 16),
+                    3 => matches!(depth, 1 | 2 | 4 | 8),
+                    2 | 4 | 6 => matches!(depth, 8 | 16),
+                    _ => false,
+                };
+                let (width, height) = (be32(body), be32(&body[4..]));
+                if !valid || width == 0 || height == 0 {
+                    return Err(PngError::BadChunk(chunk));
+                }
+                if width as u64 * height as u64 > MAX_PIXELS {
+                    return Err(PngError::Unsupported("more than 2^26 pixels"));
+                }
+                header = Some(Header {
+                    width,
+                    height,
+                    depth,
+                    color,
+                });
+            }
+            (b"IHDR", _) => return Err(PngError::BadChunk(chunk)),
+            (_, None) => return Err(PngError::BadChunk(chunk)),
+            (b"PLTE", _) if len.is_multiple_of(3) && len <= 768 => {
+                palette = body.chunks(3).map(|c| [c[0], c[1], c[2]]).collect();
+            }
+            (b"IDAT", _) => data.extend_from_slice(body),
+            (b"IEND", Some(header)) => return pixels(header, &palette, &data),
+            // an unknown chunk with a lowercase first letter can be skipped
+            (kind, _) if kind[0].is_ascii_lowercase() && kind != b"PLTE" => {}
+            _ => return Err(PngError::BadChunk(chunk)),
+        }
+    }
+}
+
+fn pixels(header: &Header, palette: &[[u8; 3]], data: &[u8]) -> Result<Image, PngError> {
+    let bits = header.channels() * header.depth as usize;
+    let stride = (header.width as usize * bits).div_ceil(8);
+    let bpp = bits.div_ceil(8); // bytes back to the same channel
+    let raw = zlib(data)?;
+    if raw.len() != (stride + 1) * header.height as usize {
+        return Err(PngError::BadData);
+    }
+
+    let mut rows = vec![0_u8; stride * header.height as usize];
+    for y in 0..header.height as usize {
+        let line = &raw[y * (stride + 1)..(y + 1) * (stride + 1)];
+        let (done, row) = rows.split_at_mut(y * stride);
+        let prior = done.get(done.len().wrapping_sub(stride)..);
+        let row = &mut row[..stride];
+        for i in 0..stride {
+            let a = if i >= bpp { row[i - bpp] } else { 0 };
+            let b = prior.map_or(0, |p| p[i]);
+            let c = match (prior, i >= bpp) {
+                (Some(p), true) => p[i - bpp],
+                _ => 0,
+            };
+            let x = line[1 + i];
+            row[i] = match line[0] {
+                0 => x,
+                1 => x.wrapping_add(a),
+                2 => x.wrapping_add(b),
+                3 => x.wrapping_add(((a as u16 + b as u16) / 2) as u8),
+                4 => x.wrapping_add(paeth(a, b, c)),
+                _ => return Err(PngError::BadData),
+            };
+        }
+    }
+
+    let depth = header.depth as usize;
+    let sample = |row: &[u8], i: usize| -> u8 {
+        match depth {
+            8 => row[i],
+            16 => row[2 * i],
+            _ => {
+                let bit = i * depth;
+                let shift = 8 - depth - bit % 8;
+                (row[bit / 8] >> shift) & ((1 << depth) - 1)
+            }
+        }
+    };
+    // a gray sample of any depth stretched to 0..=255
+    let gray = |v: u8| match depth {
+        8 | 16 => v,
+        _ => (v as u16 * 255 / ((1 << depth) - 1)) as u8,
+    };
+    let mut pixels = Vec::with_capacity(header.width as usize * header.height as usize);
+    for row in rows.chunks(stride) {
+        for x in 0..header.width as usize {
+            let n = header.channels();
+            let pixel = match header.color {
+                0 | 4 => [gray(sample(row, x * n)); 3],
+                3 => *palette
+                    .get(sample(row, x) as usize)
+                    .ok_or(PngError::BadData)?,
+                _ => [
+                    sample(row, x * n),
+                    sample(row, x * n + 1),
+                    sample(row, x * n + 2),
+                ],
+            };
+            pixels.push(pixel);
+        }
+    }
+    Ok(Image {
+        width: header.width,
+        height: header.height,
+        pixels,
+    })
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = (
+        (p - a as i16).abs(),
+        (p - b as i16).abs(),
+        (p - c as i16).abs(),
+    );
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+// a zlib stream: two header bytes, deflate data, then an Adler-32 of the output
+fn zlib(data: &[u8]) -> Result<Vec<u8>, PngError> {
+    let [cmf, flg, ..] = *data else {
+        return Err(PngError::BadData);
+    };
+    if cmf & 0x0f != 8 || !(cmf as u16 * 256 + flg as u16).is_multiple_of(31) || flg & 0x20 != 0 {
+        return Err(PngError::BadData);
+    }
+    let mut bits = Bits {
+        data,
+        pos: 2,
+        buf: 0,
+        count: 0,
+    };
+    let out = inflate(&mut bits)?;
+    let sum = data.get(bits.pos..bits.pos + 4).ok_or(PngError::BadData)?;
+    let (mut a, mut b) = (1_u32, 0_u32);
+    for &byte in &out {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    match be32(sum) == (b << 16 | a) {
+        true => Ok(out),
+        false => Err(PngError::BadData),
+    }
+}
+
+// deflate reads bits from the least significant end of each byte
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize, // next byte not yet in `buf`
+    buf: u32,
+    count: u32, // bits in `buf`
+}
+
+impl Bits<'_> {
+    fn bits(&mut self, n: u32) -> Result<u32, PngError> {
+        while self.count < n {
+            let byte = *self.data.get(self.pos).ok_or(PngError::BadData)?;
+            self.buf |= (byte as u32) << self.count;
+            self.pos += 1;
+            self.count += 8;
+        }
+        let value = self.buf & ((1_u64 << n) - 1) as u32;
+        self.buf >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    // drop the rest of the current byte, for stored blocks
+    fn align(&mut self) {
+        self.buf = 0;
+        self.count = 0;
+    }
+}
+
+// a canonical Huffman code as the number of codes of each length and the
+// symbols in code order
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self, PngError> {
+        let mut counts = [0_u16; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+        // no length may have more codes than are left for it
+        let mut left = 1_i32;
+        for &count in &counts[1..] {
+            left = 2 * left - count as i32;
+            if left < 0 {
+                return Err(PngError::BadData);
+            }
+        }
+        let mut offsets = [0_u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Result<u16, PngError> {
+        let (mut code, mut first, mut index) = (0_i32, 0_i32, 0_i32);
+        for len in 1..16 {
+            code |= bits.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(PngError::BadData)
+    }
+}
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+// the order code length code lengths are sent in
+const CODE_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+fn inflate(bits: &mut Bits) -> Result<Vec<u8>, PngError> {
+    let mut out = vec![];
+    loop {
+        let last = bits.bits(1)? == 1;
+        match bits.bits(2)? {
+            0 => {
+                bits.align();
+                let at = bits.pos;
+                let header = bits.data.get(at..at + 4).ok_or(PngError::BadData)?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                if len != !u16::from_le_bytes([header[2], header[3]]) {
+                    return Err(PngError::BadData);
+                }
+                let stored = bits.data.get(at + 4..at + 4 + len as usize);
+                out.extend_from_slice(stored.ok_or(PngError::BadData)?);
+                bits.pos = at + 4 + len as usize;
+            }
+            1 => {
+                let mut lengths = [8_u8; 288];
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                let lit = Huffman::new(&lengths)?;
+                let dist = Huffman::new(&[5; 30])?;
+                codes(bits, &lit, &dist, &mut out)?;
+            }
+            2 => {
+                let (lit, dist) = dynamic(bits)?;
+                codes(bits, &lit, &dist, &mut out)?;
+            }
+            _ => return Err(PngError::BadData),
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+// the two codes of a dynamic block, sent in its header
+fn dynamic(bits: &mut Bits) -> Result<(Huffman, Huffman), PngError> {
+    let nlen = bits.bits(5)? as usize + 257;
+    let ndist = bits.bits(5)? as usize + 1;
+    let ncode = bits.bits(4)? as usize + 4;
+    let mut lengths = [0_u8; 19];
+    for &i in &CODE_ORDER[..ncode] {
+        lengths[i] = bits.bits(3)? as u8;
+    }
+    let code = Huffman::new(&lengths)?;
+
+    let mut lengths = vec![0_u8; nlen + ndist];
+    let mut i = 0;
+    while i < nlen + ndist {
+        let (value, repeat) = match code.decode(bits)? {
+            len @ 0..=15 => (len as u8, 1),
+            16 => {
+                let previous = *i
+                    .checked_sub(1)
+                    .map(|p| &lengths[p])
+                    .ok_or(PngError::BadData)?;
+                (previous, 3 + bits.bits(2)? as usize)
+            }
+            17 => (0, 3 + bits.bits(3)? as usize),
+            _ => (0, 11 + bits.bits(7)? as usize),
+        };
+        let run = lengths.get_mut(i..i + repeat).ok_or(PngError::BadData)?;
+        run.fill(value);
+        i += repeat;
+    }
+    if lengths[256] == 0 {
+        return Err(PngError::BadData);
+    }
+    Ok((
+        Huffman::new(&lengths[..nlen])?,
+        Huffman::new(&lengths[nlen..])?,
+    ))
+}
+
+fn codes(
+    bits: &mut Bits,
+    lit: &Huffman,
+    dist: &Huffman,
+    out: &mut Vec<u8>,
+) -> Result<(), PngError> {
+    loop {
+        let symbol = lit.decode(bits)? as usize;
+        match symbol {
+            0..=255 => out.push(symbol as u8),
+            256 => return Ok(()),
+            _ => {
+                let i = symbol - 257;
+                let extra = *LENGTH_EXTRA.get(i).ok_or(PngError::BadData)?;
+                let len = LENGTH_BASE[i] as usize + bits.bits(extra as u32)? as usize;
+                let i = dist.decode(bits)? as usize;
+                let extra = *DIST_EXTRA.get(i).ok_or(PngError::BadData)?;
+                let back = DIST_BASE[i] as usize + bits.bits(extra as u32)? as usize;
+                let start = out.len().checked_sub(back).ok_or(PngError::BadData)?;
+                // the copy may overlap what it writes
+                for k in 0..len {
+                    out.push(out[start + k]);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_png() {
+    // a 3x2 image of two-bit palette indices, compressed by zlib
+    let palette = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR\0\0\0\x03\0\0\0\x02\x02\x03\0\0\0\xe0\x1a\x8e\x89\0\0\0\x09PLTE\xff\0\0\0\xff\0\0\0\xff-J\xcd\x8a\0\0\0\x0cIDATx\xdac\x90`\x98\0\0\0\xdc\0\xa9R\x1a\x13\x8f\0\0\0\0IEND\xaeB`\x82";
+    let image = decode(palette).unwrap();
+    assert_eq!((image.width(), image.height()), (3, 2));
+    let rows: Vec<_> = (0..6).map(|i| image.pixel(i % 3, i / 3)).collect();
+    let (r, g, b) = ([255, 0, 0], [0, 255, 0], [0, 0, 255]);
+    assert_eq!(rows, [r, g, b, b, g, r]);
+
+    // damage is noticed, whether in a chunk or in the compressed data
+    let mut bytes = palette.to_vec();
+    bytes[64] ^= 1;
+    assert!(matches!(decode(&bytes), Err(PngError::BadChunk(54))));
+    assert!(matches!(decode(b"GIF89a"), Err(PngError::NotPng)));
+
+    // stored, fixed and dynamic blocks, with copies that overlap themselves
+    assert_eq!(zlib(b"\x78\x01\x01\x01\0\xfe\xffA\0B\0B").unwrap(), b"A");
+    assert!(matches!(
+        zlib(b"\x78\x01\x01\x01\0\xfe\xffA\0B\0C"),
+        Err(PngError::BadData)
+    ));
+    let fixed = b"x\xdaKLJNDE\0A|\x06\xe5";
+    assert_eq!(zlib(fixed).unwrap(), b"abc".repeat(6));
+    let src = "<<>--+-+-][><-++]<+>+[<++.>>>[<++-[++-<->>+<>++[+--+->>[++<>";
+    let dynamic = [
+        120, 218, 173, 139, 193, 9, 192, 64, 16, 2, 11, 26, 76, 5, 50, 141, 44, 215, 127, 27, 217,
+        235, 32, 143, 32, 34, 226, 216, 154, 176, 58, 99, 3, 167, 200, 20, 30, 245, 102, 102, 221,
+        40, 21, 134, 75, 239, 194, 214, 126, 252, 254, 205, 189, 12, 80, 39, 183,
+    ];
+    let text = format!("{}{}", src, src[..40].repeat(3));
+    assert_eq!(zlib(&dynamic).unwrap(), text.as_bytes());
+}
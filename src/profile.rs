@@ -0,0 +1,156 @@
+//! The hottest loops of a run, for `--profile`.
+//!
+//! Everything comes from the counts `VM::profile` keeps, one per instruction.
+//! A loop's `[` runs once per entry and its `]` once per iteration, since a
+//! loop skipped at the `[` never reaches the `]`. What a loop costs is what
+//! every instruction from its `[` to its `]` ran, loops inside included, so
+//! the shares of nested loops overlap.
+
+use std::{fmt::Write, time::Duration};
+
+use crate::{bench, program::Program, tokenizer::Token};
+
+/// One loop of the program as the run used it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopCount {
+    pub start: usize, // `[` index
+    pub end: usize,   // `]` index
+    pub entries: u64,
+    pub iterations: u64,
+    /// Instructions run inside the loop, its brackets and inner loops with
+    /// them.
+    pub instructions: u64,
+}
+
+/// Every loop whose `[` ran, the costliest first and in program order
+/// among equals.
+pub fn loops(program: &Program, counts: &[u64]) -> Vec<LoopCount> {
+    assert_eq!(program.tokens().len(), counts.len());
+    let mut loops: Vec<_> = program
+        .tokens()
+        .iter()
+        .enumerate()
+        .filter_map(|(start, token)| match *token {
+            Token::LoopStart(end) if counts[start] > 0 => {
+                let end = end as usize;
+                Some(LoopCount {
+                    start,
+                    end,
+                    entries: counts[start],
+                    iterations: counts[end],
+                    instructions: counts[start..=end].iter().sum(),
+                })
+            }
+            _ => None,
+        })
+        .collect();
+    loops.sort_by_key(|l| std::cmp::Reverse(l.instructions));
+    loops
+}
+
+/// The run's total and time, then the `top` costliest loops by where their
+/// brackets are in the source, or by index without a source map.
+pub fn report(program: &Program, counts: &[u64], top: usize, elapsed: Duration) -> String {
+    let total: u64 = counts.iter().sum();
+    let mut out = String::new();
+    writeln!(
+        out,
+        "profile: {} instructions in {}",
+        total,
+        bench::millis(elapsed)
+    )
+    .unwrap();
+    let loops = loops(program, counts);
+    if loops.is_empty() {
+        return out;
+    }
+    let spans = program.spans();
+    let place = |l: &LoopCount| match (spans.get(l.start), spans.get(l.end)) {
+        (Some(start), Some(end)) if start.line > 0 => format!("{}-{}", start, end),
+        _ => format!("pc {}-{}", l.start, l.end),
+    };
+    writeln!(
+        out,
+        "{:>4} {:<15} {:>7} {:>12} {:>10} {:>14}",
+        "", "loop", "share", "iterations", "entries", "instructions"
+    )
+    .unwrap();
+    for (rank, l) in loops.iter().take(top).enumerate() {
+        let share = l.instructions as f64 * 100.0 / total as f64;
+        writeln!(
+            out,
+            "{:>3}. {:<15} {:>6.2}% {:>12} {:>10} {:>14}",
+            rank + 1,
+            place(l),
+            share,
+            l.iterations,
+            l.entries,
+            l.instructions
+        )
+        .unwrap();
+    }
+    if loops.len() > top {
+        writeln!(out, "     ... {} more", loops.len() - top).unwrap();
+    }
+    out
+}
+
+#[test]
+fn test_profile() {
+    use crate::{
+        engine::{Engine, ExecContext, Interpreter},
+        program::OptLevel,
+        vm::VmOptions,
+    };
+
+    // at O1 every loop is still a loop; the last is skipped over
+    let src = "++\n[>+++\n[>+<-]<-]\n>>.[-]<[-]\n";
+    let program = Program::compile_with(src, OptLevel::O1).unwrap();
+    let mut tape = vec![0_u8; 16];
+    let (mut input, mut output) = (&b""[..], vec![]);
+    let options = VmOptions {
+        profile: true,
+        ..Default::default()
+    };
+    let mut ctx = ExecContext::new(&mut tape, &mut input, &mut output).with_options(options);
+    let outcome = Interpreter.run(&program, &mut ctx).unwrap();
+    let counts = ctx.profile.take().unwrap();
+    let total = outcome.steps.unwrap();
+
+    let found = loops(&program, &counts);
+    let summary: Vec<_> = found
+        .iter()
+        .map(|l| (program.spans()[l.start].line, l.entries, l.iterations))
+        .collect();
+    // the inner loop runs 3 times per entry, and the clear counts down 6
+    assert_eq!(summary, [(2, 1, 2), (3, 2, 6), (4, 1, 6), (4, 1, 0)]);
+    assert!(found
+        .windows(2)
+        .all(|w| w[0].instructions >= w[1].instructions));
+    // the outer loop's own 11 instructions and all of the inner loop's
+    assert_eq!(found[0].instructions, 11 + found[1].instructions);
+
+    let text = report(&program, &counts, 2, Duration::from_millis(3));
+    let lines: Vec<_> = text.lines().collect();
+    assert_eq!(
+        lines[0],
+        format!("profile: {} instructions in 3.000ms", total)
+    );
+    assert_eq!(lines.len(), 5, "{}", text);
+    assert!(lines[2].starts_with("  1. 2:1-3:9"), "{}", text);
+    assert!(lines[2].contains(&format!(
+        "{:.2}%",
+        found[0].instructions as f64 * 100.0 / total as f64
+    )));
+    assert!(lines[3].starts_with("  2. 3:1-3:6"), "{}", text);
+    assert_eq!(lines[4], "     ... 2 more");
+
+    // without spans the loops go by index
+    let stripped = Program::from_bytecode(&program.to_bytecode(true)).unwrap();
+    let text = report(&stripped, &counts, 1, Duration::ZERO);
+    assert!(text
+        .lines()
+        .nth(2)
+        .unwrap()
+        .starts_with(&format!("  1. pc {}-{}", found[0].start, found[0].end)));
+}
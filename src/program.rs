@@ -0,0 +1,178 @@
+use std::{fmt, num::NonZeroUsize, thread};
+
+use crate::{
+    pass::PassManager,
+    tokenizer::{self, Dialect, RawOp, Span, StartTape, Token, TokenizerError},
+    vm::CellOverflow,
+};
+
+// sources shorter than this are not worth splitting between threads, and
+// no thread gets a smaller piece
+const PARALLEL_MIN: usize = 1 << 20;
+
+/// How much of the optimizer runs on freshly linked tokens; the passes of
+/// each are in `PassManager::for_level`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OptLevel {
+    O0, // as written
+    O1, // run-length folding and dead loops
+    #[default]
+    O2, // everything
+}
+
+impl OptLevel {
+    pub const ALL: [OptLevel; 3] = [OptLevel::O0, OptLevel::O1, OptLevel::O2];
+}
+
+impl fmt::Display for OptLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// The file a program was compiled from, as recorded in a source map.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceInfo {
+    pub file: String,
+    pub hash: u64, // `bytecode::source_hash` of the file's contents
+}
+
+/// Optimized instructions together with the source position of each one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    tokens: Vec<Token>,         // instructions to run
+    spans: Vec<Span>,           // source span of each instruction
+    source: Option<SourceInfo>, // where the spans point
+}
+
+impl Program {
+    /// Wrap tokens that have no source text behind them.
+    pub fn new(tokens: Vec<Token>) -> Self {
+        let spans = vec![Span::default(); tokens.len()];
+        Program {
+            tokens,
+            spans,
+            source: None,
+        }
+    }
+
+    pub fn from_parts(tokens: Vec<Token>, spans: Vec<Span>) -> Self {
+        assert_eq!(tokens.len(), spans.len());
+        Program {
+            tokens,
+            spans,
+            source: None,
+        }
+    }
+
+    pub fn compile(src: &str) -> Result<Self, TokenizerError> {
+        Self::compile_with(src, OptLevel::default())
+    }
+
+    pub fn compile_with(src: &str, level: OptLevel) -> Result<Self, TokenizerError> {
+        Self::compile_dialect(src, level, Dialect::Standard)
+    }
+
+    /// `compile_with` for source written in `dialect`.
+    pub fn compile_dialect(
+        src: &str,
+        level: OptLevel,
+        dialect: Dialect,
+    ) -> Result<Self, TokenizerError> {
+        Self::compile_for(src, level, dialect, StartTape::Unknown, CellOverflow::Wrap)
+    }
+
+    /// `compile_dialect` for a program that starts on `start` and runs with
+    /// `overflow`. Only a `StartTape::Zeroed` program may count on cells it
+    /// has not written being zero, so it is wrong on a preloaded tape; only a
+    /// `CellOverflow::Error` one keeps every overflow there is to fail on.
+    pub fn compile_for(
+        src: &str,
+        level: OptLevel,
+        dialect: Dialect,
+        start: StartTape,
+        overflow: CellOverflow,
+    ) -> Result<Self, TokenizerError> {
+        let threads = match cfg!(feature = "parallel") {
+            true => thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            false => 1,
+        };
+        let threads = threads.min(src.len() / PARALLEL_MIN);
+        Self::compile_parallel(src, level, dialect, start, overflow, threads)
+    }
+
+    /// `compile_for`, lexing and folding on up to `threads` threads. The
+    /// result is the same for any number; bracket linking and the passes that
+    /// track cells across loops always run on one.
+    pub fn compile_parallel(
+        src: &str,
+        level: OptLevel,
+        dialect: Dialect,
+        start: StartTape,
+        overflow: CellOverflow,
+        threads: usize,
+    ) -> Result<Self, TokenizerError> {
+        let ops = match threads {
+            0 | 1 => tokenizer::lex_dialect(src, dialect),
+            n => tokenizer::lex_parallel(src, dialect, n),
+        };
+        Self::link_optimize(&ops, level, start, overflow, threads)
+    }
+
+    /// `compile_for` with ops read by a front end other than the lexer.
+    pub fn compile_ops(
+        ops: &[RawOp],
+        level: OptLevel,
+        start: StartTape,
+        overflow: CellOverflow,
+    ) -> Result<Self, TokenizerError> {
+        Self::link_optimize(ops, level, start, overflow, 1)
+    }
+
+    /// `compile_for` for source that need not be UTF-8, lexed the way
+    /// `tokenizer::lex_reader` lexes it.
+    pub fn compile_bytes(
+        src: &[u8],
+        level: OptLevel,
+        dialect: Dialect,
+        start: StartTape,
+        overflow: CellOverflow,
+    ) -> Result<Self, TokenizerError> {
+        let ops = tokenizer::lex_reader(src, dialect)?;
+        Self::compile_ops(&ops, level, start, overflow)
+    }
+
+    fn link_optimize(
+        ops: &[RawOp],
+        level: OptLevel,
+        start: StartTape,
+        overflow: CellOverflow,
+        threads: usize,
+    ) -> Result<Self, TokenizerError> {
+        let mut tokens = tokenizer::link(ops)?;
+        let mut spans: Vec<Span> = ops.iter().map(|op| op.span).collect();
+        PassManager::for_level(level, start, overflow, threads).run(&mut tokens, &mut spans);
+        Ok(Program::from_parts(tokens, spans))
+    }
+
+    pub fn tokens(&self) -> &[Token] {
+        &self.tokens
+    }
+
+    pub fn spans(&self) -> &[Span] {
+        &self.spans
+    }
+
+    pub fn source_info(&self) -> Option<&SourceInfo> {
+        self.source.as_ref()
+    }
+
+    pub fn with_source_info(mut self, source: SourceInfo) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    pub fn into_parts(self) -> (Vec<Token>, Vec<Span>) {
+        (self.tokens, self.spans)
+    }
+}
@@ -0,0 +1,59 @@
+//! Status lines on demand, for runs too long to watch.
+//!
+//! `request` bumps a process-wide counter. A running VM compares it with the
+//! value it last saw on every loop back-edge, and prints one status line when
+//! it changed, so every VM that is running reports once per request. Code
+//! from the JIT does the same, with no instruction counts to report. On unix,
+//! `install_sigusr1` makes `kill -USR1` a request; the handler does nothing
+//! but the atomic increment, which is async-signal-safe.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+/// Ask every running VM for a status line.
+pub fn request() {
+    REQUESTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// How many reports have been requested so far.
+#[inline]
+pub fn requests() -> u64 {
+    REQUESTS.load(Ordering::Relaxed)
+}
+
+/// The counter itself, for native code to read on its back-edges.
+pub(crate) fn counter() -> &'static AtomicU64 {
+    &REQUESTS
+}
+
+#[cfg(unix)]
+mod signal {
+    use std::{ffi::c_int, io};
+
+    #[cfg(target_os = "linux")]
+    const SIGUSR1: c_int = 10;
+    #[cfg(not(target_os = "linux"))]
+    const SIGUSR1: c_int = 30;
+    const SIG_ERR: usize = usize::MAX;
+
+    extern "C" {
+        fn signal(signum: c_int, handler: usize) -> usize;
+    }
+
+    extern "C" fn on_sigusr1(_: c_int) {
+        super::request();
+    }
+
+    pub fn install_sigusr1() -> io::Result<()> {
+        // SAFETY: the handler only touches an atomic
+        let previous = unsafe { signal(SIGUSR1, on_sigusr1 as *const () as usize) };
+        if previous == SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+pub use signal::install_sigusr1;
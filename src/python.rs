@@ -0,0 +1,329 @@
+//! `bfjit emit-py`: the optimized program as a standalone Python 3 script.
+//!
+//! The script is meant to be read. The tape is a `bytearray`, the pointer a
+//! plain `p`, loops are `while tape[p]:` and every other token is one or two
+//! lines of arithmetic mod 256. Moving off either end of the tape is only
+//! caught as far as Python's own indexing catches it.
+//!
+//! CPython refuses more than 20 statically nested blocks in one function,
+//! which real programs do exceed. A block that would open deeper than
+//! `MAX_NESTING` is emitted as a function of its own, `block_N(tape, p)`
+//! returning the new pointer, and called in its place. Generation keeps its
+//! own stack of open blocks and functions, so the Rust side never recurses.
+
+use std::fmt::Write;
+
+use crate::{
+    program::Program,
+    tokenizer::{ExtOp, Token},
+    vm::{EofBehavior, MEMORY_SIZE},
+};
+
+// blocks open at once in one Python function, well under CPython's 20
+const MAX_NESTING: usize = 16;
+
+// a function under construction; `main` or a hoisted block
+struct Frame {
+    lines: Vec<String>,
+    depth: usize, // indentation, in levels of four spaces
+}
+
+impl Frame {
+    fn new(header: String) -> Self {
+        Frame {
+            lines: vec![header],
+            depth: 1,
+        }
+    }
+
+    fn push(&mut self, line: impl AsRef<str>) {
+        let indent = " ".repeat(4 * self.depth);
+        self.lines.push(indent + line.as_ref());
+    }
+}
+
+// `p` moved by `offset`, as written in an index
+fn at(offset: i64) -> String {
+    match offset {
+        0 => "p".to_string(),
+        n if n < 0 => format!("p - {}", -n),
+        n => format!("p + {}", n),
+    }
+}
+
+// a one-byte `bytes` literal
+fn byte_literal(byte: u8) -> String {
+    match byte {
+        b'\'' | b'\\' => format!("b'\\{}'", byte as char),
+        0x20..=0x7e => format!("b'{}'", byte as char),
+        _ => format!("b'\\x{:02x}'", byte),
+    }
+}
+
+fn emit_token(frame: &mut Frame, token: Token, eof: EofBehavior) {
+    match token {
+        Token::IncrementData(x) => frame.push(format!("tape[p] = (tape[p] + {}) % 256", x)),
+        Token::DecrementData(x) => frame.push(format!("tape[p] = (tape[p] - {}) % 256", x)),
+        Token::IncrementPointer(x) => frame.push(format!("p += {}", x)),
+        Token::DecrementPointer(x) => frame.push(format!("p -= {}", x)),
+        Token::Input => {
+            frame.push("stdout.flush()");
+            frame.push("c = stdin.read(1)");
+            frame.push("if c:");
+            frame.push("    tape[p] = c[0]");
+            match eof {
+                EofBehavior::Unchanged => {}
+                EofBehavior::SetZero => {
+                    frame.push("else:");
+                    frame.push("    tape[p] = 0");
+                }
+                EofBehavior::SetMinusOne => {
+                    frame.push("else:");
+                    frame.push("    tape[p] = 255");
+                }
+                EofBehavior::Halt => {
+                    frame.push("else:");
+                    frame.push("    sys.exit()");
+                }
+            }
+        }
+        Token::Output => frame.push("stdout.write(bytes((tape[p],)))"),
+        Token::OutputRepeat(n) => frame.push(format!("stdout.write(bytes((tape[p],)) * {})", n)),
+        Token::Print(byte) => frame.push(format!("stdout.write({})", byte_literal(byte))),
+        Token::ClearRange { start_offset, len } => {
+            let start = start_offset as i64;
+            let end = at(start + len as i64);
+            frame.push(format!("tape[{}:{}] = bytes({})", at(start), end, len));
+        }
+        Token::MulAdd { offset, factor } => {
+            let target = at(offset as i64);
+            frame.push("if tape[p]:");
+            frame.push(format!(
+                "    tape[{0}] = (tape[{0}] + tape[p] * {1}) % 256",
+                target, factor
+            ));
+        }
+        Token::AddAt { offset, value } => {
+            let target = at(offset as i64);
+            frame.push(format!(
+                "tape[{0}] = (tape[{0}] + {1}) % 256",
+                target, value
+            ));
+        }
+        Token::ScanRight(x) => {
+            frame.push("while tape[p]:");
+            frame.push(format!("    p += {}", x));
+        }
+        Token::ScanLeft(x) => {
+            frame.push("while tape[p]:");
+            frame.push(format!("    p -= {}", x));
+        }
+        // stops in pdb, or wherever PYTHONBREAKPOINT points
+        Token::Breakpoint => frame.push("breakpoint()"),
+        Token::Ext(op) => match op {
+            ExtOp::Halt => {
+                frame.push("stdout.flush()");
+                frame.push("sys.exit()");
+            }
+            ExtOp::Store => frame.push("storage[0] = tape[p]"),
+            ExtOp::Load => frame.push("tape[p] = storage[0]"),
+            ExtOp::ShiftLeft => frame.push("tape[p] = (tape[p] << 1) % 256"),
+            ExtOp::ShiftRight => frame.push("tape[p] >>= 1"),
+            ExtOp::Not => frame.push("tape[p] ^= 255"),
+            ExtOp::Xor => frame.push("tape[p] ^= storage[0]"),
+            ExtOp::And => frame.push("tape[p] &= storage[0]"),
+            ExtOp::Or => frame.push("tape[p] |= storage[0]"),
+        },
+        Token::LoopStart(_) | Token::LoopEnd(_) | Token::IfStart(_) | Token::IfEnd(_) => {
+            unreachable!("blocks are emitted by the caller")
+        }
+    }
+}
+
+/// Translate `program` into a Python script; `name` goes in its docstring
+/// and `eof` decides what `,` does once stdin is exhausted.
+pub fn emit(program: &Program, name: &str, eof: EofBehavior) -> String {
+    let mut done: Vec<(usize, Vec<String>)> = vec![];
+    let mut frames = vec![Frame::new("def main():".to_string())];
+    frames[0].push(format!("tape = bytearray({})", MEMORY_SIZE));
+    frames[0].push("p = 0");
+    let mut blocks = vec![]; // (lines when opened, whether it has its own function)
+    let mut hoisted = 0;
+    for &token in program.tokens() {
+        let frame = frames.last_mut().unwrap();
+        if token.is_block_start() {
+            let own = frame.depth > MAX_NESTING;
+            if own {
+                hoisted += 1;
+                frame.push(format!("p = block_{}(tape, p)", hoisted));
+                let header = format!("def block_{}(tape, p):", hoisted);
+                frames.push(Frame::new(header));
+            }
+            let frame = frames.last_mut().unwrap();
+            let keyword = match token {
+                Token::LoopStart(_) => "while",
+                _ => "if",
+            };
+            frame.push(format!("{} tape[p]:", keyword));
+            frame.depth += 1;
+            blocks.push((frame.lines.len(), own.then_some(hoisted)));
+        } else if token.is_block_end() {
+            let (opened, own) = blocks.pop().expect("program is linked");
+            if frame.lines.len() == opened {
+                frame.push("pass");
+            }
+            frame.depth -= 1;
+            if let Some(id) = own {
+                frame.push("return p");
+                let frame = frames.pop().unwrap();
+                done.push((id, frame.lines));
+            }
+        } else {
+            emit_token(frame, token, eof);
+        }
+    }
+    frames[0].push("stdout.flush()");
+    done.sort_by_key(|&(id, _)| id);
+
+    let mut out = String::new();
+    let name = name.replace(['\\', '"'], "_");
+    writeln!(out, "#!/usr/bin/env python3").unwrap();
+    writeln!(
+        out,
+        "\"\"\"{}, translated from brainfuck by bfjit.\"\"\"",
+        name
+    )
+    .unwrap();
+    writeln!(out, "\nimport sys\n").unwrap();
+    writeln!(out, "stdin = sys.stdin.buffer").unwrap();
+    writeln!(out, "stdout = sys.stdout.buffer").unwrap();
+    if program.tokens().iter().any(|t| matches!(t, Token::Ext(_))) {
+        writeln!(
+            out,
+            "storage = bytearray(1)  # the extended dialect's register"
+        )
+        .unwrap();
+    }
+    let main = frames.pop().unwrap().lines;
+    for lines in done.into_iter().map(|(_, lines)| lines).chain([main]) {
+        out.push_str("\n\n");
+        for line in lines {
+            writeln!(out, "{}", line).unwrap();
+        }
+    }
+    writeln!(out, "\n\nif __name__ == \"__main__\":\n    main()").unwrap();
+    out
+}
+
+#[test]
+fn test_emit_python() {
+    use std::{
+        io::Write as _,
+        process::{Command, Stdio},
+    };
+
+    use crate::reference::{self, OnEof};
+
+    // "+[>+[>+ ... -]<-]<" nested 40 deep, then a letter
+    let deep = format!(
+        "+{}{}+++[>++++++++++<-]>+++.",
+        "[>+".repeat(40),
+        "-]<".repeat(40)
+    );
+    // a quote and a backslash, known at compile time
+    let quote = format!("+++++[>+++++++<-]>++++.{}.", "+".repeat(53));
+    let cases = [
+        (
+            "hellow.bf",
+            std::fs::read_to_string("bfcode/hellow.bf").unwrap(),
+        ),
+        (
+            "echo.bf",
+            std::fs::read_to_string("bfcode/echo.bf").unwrap(),
+        ),
+        ("deep.bf", deep),
+        ("quote.bf", quote),
+    ];
+    // echo.bf stops at a 255, the usual EOF of -1
+    let input = b"cat \\ me\n'\xff";
+    let python = Command::new("python3").arg("--version").output().is_ok();
+    for (name, src) in &cases {
+        let program = Program::compile(src).unwrap();
+        let script = emit(&program, name, EofBehavior::Unchanged);
+        assert!(script.starts_with("#!/usr/bin/env python3\n"), "{}", name);
+        // never more blocks open in one function than CPython allows
+        let deepest = script.lines().map(|l| l.len() - l.trim_start().len()).max();
+        assert!(deepest.unwrap() <= 4 * (MAX_NESTING + 2), "{}", name);
+        assert_eq!(
+            script.contains("def block_"),
+            *name == "deep.bf",
+            "{}",
+            name
+        );
+        if !python {
+            continue;
+        }
+        let mut child = Command::new("python3")
+            .args(["-c", &script])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(input).unwrap();
+        let ran = child.wait_with_output().unwrap();
+        assert!(ran.status.success(), "{}:\n{}", name, script);
+        let mut expected = vec![];
+        reference::run(
+            src,
+            MEMORY_SIZE,
+            &mut &input[..],
+            &mut expected,
+            OnEof::Unchanged,
+        )
+        .unwrap();
+        assert_eq!(ran.stdout, expected, "{}", name);
+    }
+
+    // without python, the shape of the script is still pinned
+    let script = emit(
+        &Program::compile(",[.,]").unwrap(),
+        "cat.bf",
+        EofBehavior::Halt,
+    );
+    let expected = "\
+#!/usr/bin/env python3
+\"\"\"cat.bf, translated from brainfuck by bfjit.\"\"\"
+
+import sys
+
+stdin = sys.stdin.buffer
+stdout = sys.stdout.buffer
+
+
+def main():
+    tape = bytearray(MEM)
+    p = 0
+    stdout.flush()
+    c = stdin.read(1)
+    if c:
+        tape[p] = c[0]
+    else:
+        sys.exit()
+    while tape[p]:
+        stdout.write(bytes((tape[p],)))
+        stdout.flush()
+        c = stdin.read(1)
+        if c:
+            tape[p] = c[0]
+        else:
+            sys.exit()
+    stdout.flush()
+
+
+if __name__ == \"__main__\":
+    main()
+";
+    assert_eq!(script, expected.replace("MEM", &MEMORY_SIZE.to_string()));
+    assert_eq!(byte_literal(b'\''), "b'\\''");
+    assert_eq!(byte_literal(b'\n'), "b'\\x0a'");
+}
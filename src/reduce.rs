@@ -0,0 +1,203 @@
+//! Shrink a program while it keeps failing.
+//!
+//! `reduce` repeatedly tries smaller variants of a program and keeps one
+//! whenever the oracle says it is still interesting (still fails), until no
+//! pass makes progress. Every candidate has balanced brackets, so the oracle
+//! only ever sees programs that compile. The passes, cheapest wins first:
+//!
+//! ```text
+//! strip     drop comment characters
+//! regions   delete balanced slices, halving their size down to single ops
+//! loops     delete a whole `[...]`
+//! unwrap    replace `[body]` with `body`
+//! runs      shorten a run of one op to half, then by one
+//! ```
+
+use std::process::{Command, Stdio};
+
+// where the `]` of each `[` is, for an already balanced program
+fn matching(src: &[u8]) -> Vec<(usize, usize)> {
+    let mut stk = vec![];
+    let mut pairs = vec![];
+    for (i, &c) in src.iter().enumerate() {
+        match c {
+            b'[' => stk.push(i),
+            b']' => pairs.push((stk.pop().unwrap(), i)),
+            _ => {}
+        }
+    }
+    pairs.sort();
+    pairs
+}
+
+fn balanced(region: &[u8]) -> bool {
+    let mut depth = 0_i32;
+    for &c in region {
+        match c {
+            b'[' => depth += 1,
+            b']' => depth -= 1,
+            _ => {}
+        }
+        if depth < 0 {
+            return false;
+        }
+    }
+    depth == 0
+}
+
+fn without(src: &[u8], start: usize, end: usize) -> Vec<u8> {
+    [&src[..start], &src[end..]].concat()
+}
+
+fn strip(src: &[u8]) -> Vec<Vec<u8>> {
+    let ops: Vec<u8> = src
+        .iter()
+        .copied()
+        .filter(|c| b"+-<>[].,".contains(c))
+        .collect();
+    if ops.len() == src.len() {
+        vec![]
+    } else {
+        vec![ops]
+    }
+}
+
+fn regions(src: &[u8]) -> Vec<Vec<u8>> {
+    let mut out = vec![];
+    let mut size = src.len() / 2;
+    while size > 0 {
+        for start in (0..src.len()).step_by(size) {
+            let end = (start + size).min(src.len());
+            if balanced(&src[start..end]) {
+                out.push(without(src, start, end));
+            }
+        }
+        size /= 2;
+    }
+    out
+}
+
+fn loops(src: &[u8]) -> Vec<Vec<u8>> {
+    let pairs = matching(src);
+    pairs.iter().map(|&(l, r)| without(src, l, r + 1)).collect()
+}
+
+fn unwrap_loops(src: &[u8]) -> Vec<Vec<u8>> {
+    let pairs = matching(src);
+    pairs
+        .iter()
+        .map(|&(l, r)| [&src[..l], &src[l + 1..r], &src[r + 1..]].concat())
+        .collect()
+}
+
+fn runs(src: &[u8]) -> Vec<Vec<u8>> {
+    let mut out = vec![];
+    let mut start = 0;
+    while start < src.len() {
+        let c = src[start];
+        let end = start + src[start..].iter().take_while(|&&x| x == c).count();
+        let len = end - start;
+        if len >= 2 && !b"[]".contains(&c) {
+            out.push(without(src, start, start + len / 2));
+            if len > 2 {
+                out.push(without(src, start, start + 1));
+            }
+        }
+        start = end;
+    }
+    out
+}
+
+// each pass lists candidates derived from the current program
+type Pass = fn(&[u8]) -> Vec<Vec<u8>>;
+
+const PASSES: [Pass; 5] = [strip, regions, loops, unwrap_loops, runs];
+
+/// Reduce with an external check: `check` runs under `sh -c` with the path
+/// of a file holding the candidate as `$1`, and a nonzero exit status means
+/// the candidate still fails.
+pub fn reduce_with_command(src: &str, check: &str) -> std::io::Result<String> {
+    let path = std::env::temp_dir().join(format!("bfjit-reduce-{}.bf", std::process::id()));
+    let mut error = None;
+    let reduced = reduce(src, |candidate| {
+        let status = std::fs::write(&path, candidate).and_then(|()| {
+            Command::new("sh")
+                .args(["-c", check, "sh"])
+                .arg(&path)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+        });
+        match status {
+            Ok(status) => !status.success(),
+            Err(e) => {
+                error.get_or_insert(e);
+                false
+            }
+        }
+    });
+    let _ = std::fs::remove_file(&path);
+    match error {
+        Some(e) => Err(e),
+        None => Ok(reduced),
+    }
+}
+
+/// Reduce `src`, which must be balanced and interesting, to a smaller program
+/// that `interesting` still accepts.
+pub fn reduce(src: &str, mut interesting: impl FnMut(&str) -> bool) -> String {
+    let mut best = src.as_bytes().to_vec();
+    loop {
+        let before = best.clone();
+        for pass in PASSES {
+            // after a success retry at the same index, the program has shifted
+            let mut i = 0;
+            loop {
+                let mut candidates = pass(&best);
+                let Some(j) = (i..candidates.len()).find(|&j| {
+                    let candidate = &candidates[j];
+                    candidate.len() < best.len()
+                        && interesting(std::str::from_utf8(candidate).unwrap())
+                }) else {
+                    break;
+                };
+                best = candidates.swap_remove(j);
+                i = j;
+            }
+        }
+        if best == before {
+            break;
+        }
+    }
+    String::from_utf8(best).unwrap()
+}
+
+#[test]
+fn test_reduce() {
+    let src = "comment ++[>+++[-]<-]>>.,+>+< [ . [->+<] ]\n.,";
+    let mut calls = 0;
+    let reduced = reduce(src, |candidate| {
+        calls += 1;
+        assert!(balanced(candidate.as_bytes()), "{}", candidate);
+        candidate.contains("[-]") && candidate.contains(".,")
+    });
+    assert_eq!(reduced, "[-].,");
+    assert!(calls < 500, "{} oracle calls", calls);
+
+    // a marker that needs the surrounding loop kept
+    let reduced = reduce("+++++[>++++<-]>[.>]<<", |c| {
+        c.contains("[>") && c.contains("-]")
+    });
+    assert_eq!(reduced, "[>-]");
+
+    // shrinking folded runs
+    let reduced = reduce("++++++++++++.", |c| c.matches('+').count() >= 3);
+    assert_eq!(reduced, "+++");
+
+    // nothing to do when everything matters
+    assert_eq!(reduce("+.", |c| c == "+."), "+.");
+
+    // the same marker through a shell check
+    let reduced = reduce_with_command("++[>+++[-]<-]>>.,", "! grep -q '\\[-\\]' \"$1\"").unwrap();
+    assert_eq!(reduced, "[-]");
+}
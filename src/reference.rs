@@ -0,0 +1,225 @@
+//! The reference interpreter: obviously correct, never optimized.
+//!
+//! It runs the linked but otherwise untouched token stream from `tokenizer`,
+//! one source character per step, and shares nothing else with the rest of
+//! the crate: not the VM, not its options, not its errors. Every engine at
+//! every optimization level is compared against it by `differential`, so when
+//! two of them disagree it is clear which one is wrong.
+//!
+//! Keep it boring. A faster oracle is a worse oracle.
+//!
+//! Only built for tests and with the `oracle` feature, for `bfjit selftest
+//! --oracle`.
+
+use std::io::{self, Read, Write};
+
+use crate::tokenizer::{self, Token, TokenizerError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnEof {
+    Unchanged,
+    Zero,
+    MinusOne,
+    Halt,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReferenceError {
+    #[error("{0}")]
+    Parse(#[from] TokenizerError),
+
+    #[error("Pointer left the tape at instruction {0}")]
+    PointerOutOfRange(usize),
+
+    #[error("I/O Error")]
+    Io(#[from] io::Error),
+}
+
+/// The machine after a run that did not fail.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Final {
+    pub tape: Vec<u8>,
+    pub pointer: usize,
+    pub halted_on_eof: bool,
+}
+
+/// Run `src` on a zeroed tape of `tape_len` cells.
+pub fn run(
+    src: &str,
+    tape_len: usize,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+    on_eof: OnEof,
+) -> Result<Final, ReferenceError> {
+    let program = tokenizer::tokenizer(src)?;
+    let mut tape = vec![0_u8; tape_len];
+    let mut pointer = 0;
+    let mut pc = 0;
+    while pc < program.len() {
+        match program[pc] {
+            Token::IncrementData(n) => tape[pointer] = tape[pointer].wrapping_add(n),
+            Token::DecrementData(n) => tape[pointer] = tape[pointer].wrapping_sub(n),
+            Token::IncrementPointer(n) => {
+                if pointer + n >= tape_len {
+                    return Err(ReferenceError::PointerOutOfRange(pc));
+                }
+                pointer += n;
+            }
+            Token::DecrementPointer(n) => {
+                if n > pointer {
+                    return Err(ReferenceError::PointerOutOfRange(pc));
+                }
+                pointer -= n;
+            }
+            Token::Output => output.write_all(&[tape[pointer]])?,
+            Token::Input => {
+                let mut byte = [0];
+                if input.read(&mut byte)? == 1 {
+                    tape[pointer] = byte[0];
+                } else if on_eof == OnEof::Zero {
+                    tape[pointer] = 0;
+                } else if on_eof == OnEof::MinusOne {
+                    tape[pointer] = 255;
+                } else if on_eof == OnEof::Halt {
+                    output.flush()?;
+                    return Ok(Final {
+                        tape,
+                        pointer,
+                        halted_on_eof: true,
+                    });
+                }
+            }
+            Token::LoopStart(end) => {
+                if tape[pointer] == 0 {
+                    pc = end as usize;
+                }
+            }
+            Token::LoopEnd(start) => {
+                if tape[pointer] != 0 {
+                    pc = start as usize;
+                }
+            }
+            // the tokenizer never produces anything else
+            token => unreachable!("{:?} in an unoptimized program", token),
+        }
+        pc += 1;
+    }
+    output.flush()?;
+    Ok(Final {
+        tape,
+        pointer,
+        halted_on_eof: false,
+    })
+}
+
+/// Run `src` under every supported engine at every optimization level and
+/// describe each run that does not match the reference.
+pub fn differential(src: &str, input: &[u8], on_eof: OnEof) -> Vec<String> {
+    use crate::{
+        engine::{EngineRegistry, ExecContext},
+        program::{OptLevel, Program},
+        vm::{EofBehavior, Termination, VmOptions},
+    };
+
+    const TAPE_LEN: usize = 64 * 1024;
+    let mut expected_output = vec![];
+    let expected = run(src, TAPE_LEN, &mut &input[..], &mut expected_output, on_eof);
+    let expected = match expected {
+        Ok(expected) => expected,
+        Err(e) => return vec![format!("reference: {}", e)],
+    };
+    let eof = match on_eof {
+        OnEof::Unchanged => EofBehavior::Unchanged,
+        OnEof::Zero => EofBehavior::SetZero,
+        OnEof::MinusOne => EofBehavior::SetMinusOne,
+        OnEof::Halt => EofBehavior::Halt,
+    };
+    let options = VmOptions {
+        eof,
+        ..Default::default()
+    };
+
+    let mut mismatches = vec![];
+    let mut registry = EngineRegistry::builtin();
+    for level in OptLevel::ALL {
+        let program = Program::compile_with(src, level).expect("reference tokenized it");
+        for engine in registry.iter_mut() {
+            let name = format!("{} at {}", engine.name(), level);
+            let mut tape = vec![0_u8; TAPE_LEN];
+            let (mut input, mut output) = (input, vec![]);
+            let mut ctx =
+                ExecContext::new(&mut tape, &mut input, &mut output).with_options(options.clone());
+            let outcome = match engine.run(&program, &mut ctx) {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    mismatches.push(format!("{}: failed with {}", name, e));
+                    continue;
+                }
+            };
+            let halted = matches!(outcome.termination, Termination::EofHalt { .. });
+            if output != expected_output {
+                mismatches.push(format!("{}: output differs", name));
+            } else if tape != expected.tape {
+                mismatches.push(format!("{}: tape differs", name));
+            } else if (outcome.pointer, halted) != (expected.pointer, expected.halted_on_eof) {
+                mismatches.push(format!(
+                    "{}: stopped at cell {} (halted {}), expected {} (halted {})",
+                    name, outcome.pointer, halted, expected.pointer, expected.halted_on_eof
+                ));
+            }
+        }
+    }
+    mismatches
+}
+
+/// The programs `bfjit selftest` checks, with their input and EOF handling.
+pub const CORPUS: [(&str, &str, &[u8], OnEof); 3] = [
+    (
+        "hellow.bf",
+        include_str!("../bfcode/hellow.bf"),
+        b"",
+        OnEof::Unchanged,
+    ),
+    (
+        "echo.bf",
+        include_str!("../bfcode/echo.bf"),
+        b"echo me",
+        OnEof::Halt,
+    ),
+    (
+        "dbfi.bf",
+        include_str!("../bfcode/dbfi.bf"),
+        b",[.,]!interpreted",
+        OnEof::Zero,
+    ),
+];
+
+#[test]
+fn test_reference() {
+    let run = |src: &str, input: &[u8], on_eof| {
+        let mut output = vec![];
+        let result = run(src, 4, &mut &input[..], &mut output, on_eof);
+        result.map(|end| (end.tape, end.pointer, end.halted_on_eof, output))
+    };
+    let (tape, pointer, halted, output) = run("-->+++[<.>-]", b"", OnEof::Unchanged).unwrap();
+    assert_eq!((tape, pointer, halted), (vec![254, 0, 0, 0], 1, false));
+    assert_eq!(output, [254; 3]);
+    let (tape, _, halted, _) = run(",>,>,", b"a", OnEof::Zero).unwrap();
+    assert_eq!((tape, halted), (vec![b'a', 0, 0, 0], false));
+    let (tape, _, _, _) = run(",>,", b"a", OnEof::MinusOne).unwrap();
+    assert_eq!(tape, [b'a', 255, 0, 0]);
+    assert!(run("+,+", b"", OnEof::Halt).unwrap().2);
+    assert!(matches!(
+        run("<", b"", OnEof::Unchanged),
+        Err(ReferenceError::PointerOutOfRange(0))
+    ));
+    assert!(matches!(
+        run(">>>>", b"", OnEof::Unchanged),
+        Err(ReferenceError::PointerOutOfRange(3))
+    ));
+
+    for (name, src, input, on_eof) in CORPUS {
+        let mismatches = differential(src, input, on_eof);
+        assert!(mismatches.is_empty(), "{}: {:?}", name, mismatches);
+    }
+}
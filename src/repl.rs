@@ -0,0 +1,313 @@
+//! `bfjit` without a file, or `bfjit --repl`: brainfuck a line at a time.
+//!
+//! Each line is optimized and run on one tape kept for the whole session,
+//! from wherever the last line left the pointer, and what it prints comes
+//! out before the next prompt, on a terminal, or the next line. A line that
+//! leaves a `[` open is held back until the lines after it close it, so a
+//! pasted program runs loop by loop.
+//! `,` reads end of input; the session's input is the lines themselves.
+//!
+//! ```text
+//! :mem A..B       the cells from A up to B, the pointer marked
+//! :mem            the cells up to the pointer or the last nonzero one
+//! :save NAME      the tape as it is now, kept under NAME
+//! :compare NAME   the cells that differ from the tape saved as NAME
+//! :reset          a zeroed tape with the pointer on cell 0, saved tapes kept
+//! :quit           the end of the session, like the end of input
+//! ```
+
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Write},
+    ops::Range,
+};
+
+use crate::{
+    program::{OptLevel, Program},
+    tape::{self, TapeDiff},
+    tokenizer::{self, Dialect, Span, Token},
+    vm::{SharedOutput, VmOptions, VM},
+};
+
+const PROMPT: &str = "bf> ";
+// in front of the lines of a loop still open
+const CONTINUE: &str = "... ";
+
+pub struct Repl {
+    vm: VM<'static>,
+    output: SharedOutput,
+    dialect: Dialect,
+    options: VmOptions,
+    pending: String,                 // lines held back until their brackets balance
+    brackets: Brackets,              // of `pending`, counted a line at a time
+    line_open: bool,                 // the program's output so far does not end a line
+    saved: HashMap<String, Vec<u8>>, // tapes by the name `:save` gave them
+}
+
+impl Repl {
+    pub fn new(dialect: Dialect, options: VmOptions) -> Self {
+        let output = SharedOutput::default();
+        Repl {
+            vm: fresh(&options, &output),
+            output,
+            dialect,
+            options,
+            pending: String::new(),
+            brackets: Brackets::default(),
+            line_open: false,
+            saved: HashMap::new(),
+        }
+    }
+
+    /// What to print before reading the next line.
+    pub fn prompt(&self) -> &'static str {
+        match self.pending.is_empty() {
+            true => PROMPT,
+            false => CONTINUE,
+        }
+    }
+
+    /// Read lines from `input` and answer each on `output` until it ends or
+    /// `:quit`, with a prompt before each line when `interactive`.
+    pub fn serve(
+        &mut self,
+        input: impl BufRead,
+        mut output: impl Write,
+        interactive: bool,
+    ) -> io::Result<()> {
+        let mut lines = input.lines();
+        loop {
+            if interactive {
+                let prompt = self.own_line(self.prompt().to_string());
+                output.write_all(&prompt)?;
+                output.flush()?;
+            }
+            let Some(line) = lines.next() else {
+                // leave the shell on a fresh line after end of input
+                if interactive {
+                    writeln!(output)?;
+                }
+                break;
+            };
+            let Some(reply) = self.handle_line(&line?) else {
+                break;
+            };
+            output.write_all(&reply)?;
+            output.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Take one line of input, returning what to print for it, or `None`
+    /// once the session is over.
+    pub fn handle_line(&mut self, line: &str) -> Option<Vec<u8>> {
+        if self.pending.is_empty() {
+            if let Some(command) = line.trim().strip_prefix(':') {
+                return self.command(command);
+            }
+        }
+        self.pending.push_str(line);
+        self.pending.push('\n');
+        if self.brackets.open_after(line, self.dialect) {
+            return Some(vec![]);
+        }
+        self.brackets = Brackets::default();
+        let src = std::mem::take(&mut self.pending);
+        let program = match Program::compile_dialect(&src, OptLevel::default(), self.dialect) {
+            Ok(program) => program,
+            Err(e) => return Some(self.own_line(format!("{}\n", e))),
+        };
+        let mut result = self.vm.execute(program);
+        // breakpoints have nobody to stop for here
+        while result.is_ok() && !self.vm.halted() {
+            result = self.vm.resume();
+        }
+        let mut out = self.output.take();
+        if let Some(&last) = out.last() {
+            self.line_open = last != b'\n';
+        }
+        if let Err(e) = result {
+            out.extend(self.own_line(format!("{}\n", e)));
+        }
+        Some(out)
+    }
+
+    fn command(&mut self, command: &str) -> Option<Vec<u8>> {
+        let reply = match command.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["quit"] => return None,
+            ["reset"] => {
+                self.vm = fresh(&self.options, &self.output);
+                String::new()
+            }
+            ["mem"] => {
+                let cells = self.vm.memory(0..self.vm.tape_len());
+                let len = tape::dump_len(&cells, Some(self.vm.pointer()));
+                tape::hexdump(&cells[..len], Some(self.vm.pointer()))
+            }
+            ["mem", range] => match cell_range(range) {
+                Some(range) => {
+                    let end = range.end.min(self.vm.tape_len());
+                    let start = range.start.min(end);
+                    let cells = self.vm.memory(start..end);
+                    tape::hexdump_at(&cells, start, Some(self.vm.pointer()))
+                }
+                None => format!("expected :mem START..END, got {}\n", range),
+            },
+            ["save", name] => {
+                let cells = self.vm.memory(0..self.vm.tape_len()).into_owned();
+                self.saved.insert(name.to_string(), cells);
+                String::new()
+            }
+            ["compare", name] => match self.saved.get(*name) {
+                Some(saved) => {
+                    let cells = self.vm.memory(0..self.vm.tape_len());
+                    format!("{}\n", TapeDiff::render(&TapeDiff::compare(saved, &cells)))
+                }
+                None => format!("no tape saved as {}, expected :save {} first\n", name, name),
+            },
+            _ => format!(
+                "unknown command :{}, expected :mem, :save, :compare, :reset or :quit\n",
+                command.trim()
+            ),
+        };
+        Some(self.own_line(reply))
+    }
+
+    // `text` on a line of its own, after whatever the program left open
+    fn own_line(&mut self, text: String) -> Vec<u8> {
+        let mut out = vec![];
+        if self.line_open && !text.is_empty() {
+            out.push(b'\n');
+            self.line_open = false;
+        }
+        out.extend(text.into_bytes());
+        out
+    }
+}
+
+fn fresh(options: &VmOptions, output: &SharedOutput) -> VM<'static> {
+    let program = Program::new(vec![]);
+    VM::builder(program)
+        .options(options.clone())
+        .build()
+        .expect("an empty program loads")
+        .with_io(io::empty(), output.clone())
+}
+
+// the loops the lines so far leave open, so each line is lexed once
+#[derive(Default)]
+struct Brackets {
+    depth: usize,
+    stray: bool,  // a `]` closed nothing, which is left for the compiler
+    half: String, // an `Ook` word at the end of a line, still to be paired
+}
+
+impl Brackets {
+    // whether, with `line` as well, a `[` is still open
+    fn open_after(&mut self, line: &str, dialect: Dialect) -> bool {
+        let text = format!("{}{}\n", std::mem::take(&mut self.half), line);
+        let start = Span { line: 1, col: 0 };
+        tokenizer::lex_each(&text, dialect, start, |_, op| match op.token {
+            Token::LoopStart(_) => self.depth += 1,
+            Token::LoopEnd(_) if self.depth == 0 => self.stray = true,
+            Token::LoopEnd(_) => self.depth -= 1,
+            _ => {}
+        });
+        if dialect == Dialect::Ook {
+            // a pair of words can span two lines
+            let words: Vec<usize> = ["Ook.", "Ook?", "Ook!"]
+                .iter()
+                .flat_map(|word| text.match_indices(word).map(|(at, _)| at))
+                .collect();
+            if words.len() % 2 == 1 {
+                let last = words.iter().max().unwrap();
+                self.half = text[*last..].to_string();
+            }
+        }
+        !self.stray && self.depth > 0
+    }
+}
+
+// `A..B`, with `A <= B`
+fn cell_range(text: &str) -> Option<Range<usize>> {
+    let (start, end) = text.split_once("..")?;
+    let range = start.parse().ok()?..end.parse().ok()?;
+    (range.start <= range.end).then_some(range)
+}
+
+#[test]
+fn test_repl() {
+    let session = |input: &str, interactive: bool| {
+        let mut output = vec![];
+        let mut repl = Repl::new(Dialect::Standard, VmOptions::default());
+        repl.serve(input.as_bytes(), &mut output, interactive)
+            .unwrap();
+        String::from_utf8(output).unwrap()
+    };
+
+    // a pasted program prints its greeting and leaves its cells behind
+    let hellow = std::fs::read_to_string("bfcode/hellow.bf").unwrap();
+    let out = session(&format!("{}\n:mem 0..8\n", hellow), false);
+    assert_eq!(
+        out,
+        "Hello World!\n00000000: 00 00 48 64 57 21>0a 00                          ..HdW!..\n"
+    );
+    // its comment loop is held back until it closes
+    let out = session(&hellow, true);
+    assert!(out.starts_with(&format!("{}{}", PROMPT, CONTINUE.repeat(9))));
+    assert!(out.ends_with("bf> \n"));
+
+    // the tape and pointer carry over from line to line, until `:reset`
+    let out = session("+++>++\n<[->+<]>\n:mem 0..4\n:reset\n:mem\n", false);
+    assert_eq!(
+        out,
+        "00000000: 00>05 00 00                                      ....\n\
+         00000000:>00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00  ................\n"
+    );
+
+    // a loop across lines runs once it closes, a dump starts anywhere, and
+    // neither a prompt nor a reply goes on a line the program left open
+    let out = session("++++++[\n>++++++++\n<-]>+.\n:mem 1..2\n", true);
+    assert_eq!(
+        out,
+        "bf> ... ... 1\nbf> 00000001:>31                                               1\nbf> \n"
+    );
+
+    let out = session("+++++++[>+++++++<-]>.\n:mem 1..2\n", false);
+    assert_eq!(
+        out,
+        "1\n00000001:>31                                               1\n"
+    );
+
+    // a saved tape is compared with the tape now, across `:reset` too
+    let out = session(
+        "++>+\n:save before\n:compare before\n<[->+<]>>+++\n:compare before\n\
+         :reset\n:compare before\n:compare after\n",
+        false,
+    );
+    assert_eq!(
+        out,
+        "identical\n\
+         cells 0..3: [2, 1, 0] -> [0, 3, 3]\n\
+         cells 0..2: [2, 1] -> [0, 0]\n\
+         no tape saved as after, expected :save after first\n"
+    );
+
+    // errors are reported and the session goes on, up to `:quit`
+    let out = session("+]\n<\n:mem 2..1\n:what\n:quit\n+.\n", false);
+    assert_eq!(
+        out,
+        "E0101 Unclose left bracket at line 1:2\n\
+         E0403 Pointer OverFlow Error at 1:1 (pc 0, pointer 0)\n\
+         expected :mem START..END, got 2..1\n\
+         unknown command :what, expected :mem, :save, :compare, :reset or :quit\n"
+    );
+
+    // in Ook! the `]` that closes a loop can be split across two lines
+    let mut output = vec![];
+    let mut repl = Repl::new(Dialect::Ook, VmOptions::default());
+    let src = "Ook. Ook. Ook! Ook?\nOok! Ook! Ook?\nOok!\n:mem 0..1\n";
+    repl.serve(src.as_bytes(), &mut output, true).unwrap();
+    let out = String::from_utf8(output).unwrap();
+    assert!(out.starts_with("bf> ... ... bf> 00000000:>00 "), "{}", out);
+}
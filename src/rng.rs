@@ -0,0 +1,38 @@
+//! The seeded xorshift generator behind `ProgramGenerator` and `Mutator`.
+//!
+//! Only integer arithmetic goes into the sequence, so a seed gives the same
+//! numbers on every platform.
+
+pub(crate) struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Self {
+        // splitmix64 spreads nearby seeds apart; xorshift needs a nonzero state
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        Rng {
+            state: (z ^ (z >> 31)).max(1),
+        }
+    }
+
+    pub(crate) fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    /// A number in `0..n`.
+    pub(crate) fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    /// True with probability `p`.
+    pub(crate) fn chance(&mut self, p: f64) -> bool {
+        // 53 random bits convert to f64 exactly
+        ((self.next() >> 11) as f64 / (1_u64 << 53) as f64) < p
+    }
+}
@@ -0,0 +1,321 @@
+//! Running untrusted programs under one bundle of limits.
+//!
+//! `run_sandboxed` never fails and never touches anything but the input and
+//! output it is given: every run ends in an `Outcome` saying why it stopped,
+//! what it wrote, and what it used. The limits are the VM's own fuel and
+//! output cap, a tape allocated at exactly `max_tape` cells, input cut off at
+//! `max_input` bytes, and a wall clock checked every few thousand
+//! instructions, which no single instruction can outlast since each is
+//! bounded by the tape or the output cap.
+
+use std::{
+    io::{self, Read},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    engine::{Engine, ExecContext, X86_64Jit},
+    program::Program,
+    vm::{StepResult, Termination, VmError, VmOptions, VM},
+};
+
+// instructions between two looks at the clock
+const CLOCK_INTERVAL: u64 = 4096;
+
+/// Which engines a sandboxed run may use.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EnginePolicy {
+    /// Only the interpreter, which keeps every limit.
+    #[default]
+    InterpreterOnly,
+    /// Native code when the host has a JIT and nothing asks for the limits
+    /// native code cannot keep exactly: fuel, an output cap or a timeout. The
+    /// interpreter otherwise.
+    AllowJit,
+}
+
+/// The limits of a sandboxed run; `None` leaves that resource unbounded.
+#[derive(Debug, Clone)]
+pub struct SandboxConfig {
+    /// Instructions the program may execute.
+    pub fuel: Option<u64>,
+    pub wall_timeout: Option<Duration>,
+    /// Cells in the tape, allocated up front; moving past the last one is a
+    /// runtime error.
+    pub max_tape: usize,
+    /// Bytes the program may write.
+    pub max_output: Option<u64>,
+    /// Bytes of the input the program sees; it reads end of input after.
+    pub max_input: Option<usize>,
+    pub engine_policy: EnginePolicy,
+}
+
+impl SandboxConfig {
+    /// Limits for code nobody has looked at: ten million instructions in at
+    /// most a second, the classic 30000 cells, a MiB either way and no JIT.
+    pub fn strict() -> Self {
+        SandboxConfig {
+            fuel: Some(10_000_000),
+            wall_timeout: Some(Duration::from_secs(1)),
+            max_tape: 30_000,
+            max_output: Some(1 << 20),
+            max_input: Some(1 << 20),
+            engine_policy: EnginePolicy::InterpreterOnly,
+        }
+    }
+}
+
+impl Default for SandboxConfig {
+    fn default() -> Self {
+        SandboxConfig::strict()
+    }
+}
+
+/// Why a sandboxed run stopped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reason {
+    /// The program ended by itself.
+    Halted(Termination),
+    OutOfFuel,
+    Timeout,
+    OutputLimit,
+    /// Any other error, by its stable code.
+    RuntimeError {
+        code: &'static str,
+        message: String,
+    },
+}
+
+/// What a sandboxed run consumed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// Instructions executed, unless native code ran.
+    pub steps: Option<u64>,
+    pub output_bytes: u64,
+    /// Input bytes `,` read.
+    pub input_bytes: usize,
+    /// Cells up to the furthest the pointer reached, unless native code ran.
+    pub tape_cells: Option<usize>,
+    pub elapsed: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    pub reason: Reason,
+    /// Everything written before the run stopped.
+    pub output: Vec<u8>,
+    pub usage: Usage,
+}
+
+// hands out one byte per read, so what the VM has taken is exactly what `,`
+// has read
+struct Metered<'a> {
+    rest: &'a [u8],
+    taken: usize,
+}
+
+impl Read for Metered<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match (self.rest.split_first(), buf.first_mut()) {
+            (Some((&byte, rest)), Some(slot)) => {
+                *slot = byte;
+                self.rest = rest;
+                self.taken += 1;
+                Ok(1)
+            }
+            _ => Ok(0),
+        }
+    }
+}
+
+/// Run `program` on `input` within `config`.
+pub fn run_sandboxed(program: &Program, input: &[u8], config: &SandboxConfig) -> Outcome {
+    let start = Instant::now();
+    let input = &input[..input.len().min(config.max_input.unwrap_or(usize::MAX))];
+    let mut input = Metered {
+        rest: input,
+        taken: 0,
+    };
+    let mut output = vec![];
+    let mut tape = vec![0_u8; config.max_tape];
+    let native = config.engine_policy == EnginePolicy::AllowJit
+        && config.fuel.is_none()
+        && config.max_output.is_none()
+        && config.wall_timeout.is_none()
+        && X86_64Jit::supported();
+    let (result, steps, tape_cells) = match native {
+        true => {
+            let mut ctx = ExecContext::new(&mut tape, &mut input, &mut output);
+            let result = X86_64Jit.run(program, &mut ctx);
+            let result = result.map(|outcome| outcome.termination);
+            (result.map_err(Stop::Error), None, None)
+        }
+        false => interpret(program, &mut input, &mut output, &mut tape, config, start),
+    };
+    let reason = match result {
+        Ok(termination) => Reason::Halted(termination),
+        Err(Stop::Timeout) => Reason::Timeout,
+        Err(Stop::Error(VmError::OutOfFuel(_) | VmError::NativeOutOfFuel(_))) => Reason::OutOfFuel,
+        Err(Stop::Error(VmError::OutputLimit(_))) => Reason::OutputLimit,
+        Err(Stop::Error(e)) => Reason::RuntimeError {
+            code: e.code(),
+            message: e.to_string(),
+        },
+    };
+    Outcome {
+        reason,
+        usage: Usage {
+            steps,
+            output_bytes: output.len() as u64,
+            input_bytes: input.taken,
+            tape_cells,
+            elapsed: start.elapsed(),
+        },
+        output,
+    }
+}
+
+enum Stop {
+    Timeout,
+    Error(VmError),
+}
+
+impl From<VmError> for Stop {
+    fn from(e: VmError) -> Self {
+        Stop::Error(e)
+    }
+}
+
+type Interpreted = (Result<Termination, Stop>, Option<u64>, Option<usize>);
+
+// the VM a step at a time, with a look at the clock in between
+fn interpret(
+    program: &Program,
+    input: &mut Metered,
+    output: &mut Vec<u8>,
+    tape: &mut [u8],
+    config: &SandboxConfig,
+    start: Instant,
+) -> Interpreted {
+    let options = VmOptions {
+        fuel: config.fuel,
+        max_output: config.max_output,
+        ..Default::default()
+    };
+    let vm = VM::with_tape(Arc::new(program.clone()), tape);
+    let mut vm = match vm {
+        Ok(vm) => vm.with_options(options).with_io(input, output),
+        Err(e) => return (Err(e.into()), Some(0), Some(0)),
+    };
+    let result = loop {
+        match vm.step() {
+            Ok(StepResult::Halted) => break Ok(vm.stats().termination),
+            Ok(_) => {}
+            Err(e) => break Err(e.into()),
+        }
+        let due = vm.stats().steps.is_multiple_of(CLOCK_INTERVAL);
+        if due
+            && config
+                .wall_timeout
+                .is_some_and(|limit| start.elapsed() >= limit)
+        {
+            break Err(Stop::Timeout);
+        }
+    };
+    (result, Some(vm.stats().steps), Some(vm.high_water() + 1))
+}
+
+#[test]
+fn test_run_sandboxed() {
+    let run = |src: &str, input: &[u8], config: &SandboxConfig| {
+        let outcome = run_sandboxed(&Program::compile(src).unwrap(), input, config);
+        // whatever happened, nothing went past a cap
+        let usage = outcome.usage;
+        assert!(usage.steps.unwrap() <= config.fuel.unwrap_or(u64::MAX));
+        assert!(usage.output_bytes <= config.max_output.unwrap_or(u64::MAX));
+        assert!(usage.input_bytes <= config.max_input.unwrap_or(usize::MAX));
+        assert!(usage.tape_cells.unwrap() <= config.max_tape);
+        assert_eq!(usage.output_bytes, outcome.output.len() as u64);
+        outcome
+    };
+    let strict = SandboxConfig::strict();
+
+    let echo = run(",[.,]", b"sandbox\0", &strict);
+    assert_eq!(echo.reason, Reason::Halted(Termination::Finished));
+    assert_eq!(echo.output, b"sandbox");
+    assert_eq!(echo.usage.input_bytes, 8);
+    // input past the cap reads as end of input
+    let capped = SandboxConfig {
+        max_input: Some(3),
+        ..SandboxConfig::strict()
+    };
+    let echo = run(",.,.,.,.,.", b"abcdef", &capped);
+    assert_eq!(echo.output, b"abccc");
+    assert_eq!(echo.usage.input_bytes, 3);
+
+    // an endless loop runs out of fuel, or of time without fuel
+    let spin = run("+[]", b"", &strict);
+    assert_eq!(spin.reason, Reason::OutOfFuel);
+    assert_eq!(spin.usage.steps, strict.fuel);
+    let clock = SandboxConfig {
+        fuel: None,
+        wall_timeout: Some(Duration::from_millis(50)),
+        ..SandboxConfig::strict()
+    };
+    let spin = run("+[]", b"", &clock);
+    assert_eq!(spin.reason, Reason::Timeout);
+    assert!(spin.usage.elapsed >= Duration::from_millis(50));
+    assert!(spin.usage.elapsed < Duration::from_secs(5));
+
+    // a flood is cut off at the cap with everything before it kept
+    let flood = run("+[.]", b"", &strict);
+    assert_eq!(flood.reason, Reason::OutputLimit);
+    assert_eq!(flood.output, vec![1; 1 << 20]);
+    let unfueled = SandboxConfig {
+        fuel: None,
+        ..SandboxConfig::strict()
+    };
+    let flood = run("+++[.]", b"", &unfueled);
+    assert_eq!(flood.reason, Reason::OutputLimit);
+
+    // walking off the end of the tape is an error, found at the last cell
+    let walk = run("+[>+]", b"", &strict);
+    assert!(matches!(
+        walk.reason,
+        Reason::RuntimeError { code: "E0403", .. }
+    ));
+    assert_eq!(walk.usage.tape_cells, Some(strict.max_tape));
+    let left = run("+<", b"", &strict);
+    assert!(matches!(
+        left.reason,
+        Reason::RuntimeError { code: "E0403", .. }
+    ));
+    let tiny = SandboxConfig {
+        max_tape: 1,
+        ..SandboxConfig::strict()
+    };
+    let fail = run(">.", b"", &tiny);
+    assert!(matches!(
+        fail.reason,
+        Reason::RuntimeError { code: "E0403", .. }
+    ));
+    assert_eq!(fail.usage.steps, Some(0));
+    assert!(fail.output.is_empty());
+
+    // the JIT only takes over when no limit it cannot keep is asked for
+    let open = SandboxConfig {
+        fuel: None,
+        wall_timeout: None,
+        max_output: None,
+        engine_policy: EnginePolicy::AllowJit,
+        ..SandboxConfig::strict()
+    };
+    let hellow = std::fs::read_to_string("bfcode/hellow.bf").unwrap();
+    let program = Program::compile(&hellow).unwrap();
+    let jit = run_sandboxed(&program, b"", &open);
+    let interpreted = run_sandboxed(&program, b"", &strict);
+    assert_eq!(jit.output, interpreted.output);
+    assert_eq!(jit.reason, Reason::Halted(Termination::Finished));
+    assert_eq!(jit.usage.steps.is_none(), X86_64Jit::supported());
+}
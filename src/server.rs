@@ -0,0 +1,539 @@
+//! `bfjit serve --stdio`: a long-lived execution server.
+//!
+//! Requests and responses are JSON-RPC 2.0 objects, one per line. Compiled
+//! programs live in a session table under the id `compile` hands out, and a
+//! program that is being stepped keeps its paused VM there too:
+//!
+//! ```text
+//! compile {source, options: {optLevel}}               -> {programId} | {diagnostics}
+//! run     {programId, input, limits, encoding}        -> {output, stats, error?, errorCode?}
+//! step    {programId, count, input, limits, encoding} -> {pc, pointer, halted, output, stats, error?, errorCode?}
+//! tape    {programId, start, len}                     -> {pointer, start, cells}
+//! release {programId}                                 -> {released}
+//! ```
+//!
+//! `limits` takes `maxSteps`, `maxLoopIterations` and `eof`; without
+//! `maxSteps` a program stops after `DEFAULT_MAX_STEPS`, so one that never
+//! ends cannot hold the server. For `step` the input and limits only apply
+//! to the call that starts the paused VM.
+//!
+//! `input` is a string, sent as its UTF-8 bytes, or an array of bytes.
+//! `output` is a string unless `encoding` is `"bytes"`, when it is an array
+//! of bytes exactly as written; as a string, bytes that are not UTF-8 show
+//! as U+FFFD.
+
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Write},
+    sync::Arc,
+};
+
+use crate::{
+    json::{json_object, Json},
+    program::{OptLevel, Program},
+    tokenizer,
+    vm::{EofBehavior, RunStats, SharedOutput, StepResult, Termination, VmOptions, VM},
+};
+
+const PARSE_ERROR: i32 = -32700;
+const INVALID_REQUEST: i32 = -32600;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+const UNKNOWN_PROGRAM: i32 = -32001;
+const NOT_PAUSED: i32 = -32002;
+
+// cells returned by `tape` when no `len` is given, and the most it returns
+const TAPE_DEFAULT_LEN: usize = 16;
+const TAPE_MAX_LEN: usize = 4096;
+
+/// Steps a `run` or `step` takes when its limits give no `maxSteps`.
+pub const DEFAULT_MAX_STEPS: u64 = 10_000_000;
+
+struct RpcError {
+    code: i32,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i32, message: impl Into<String>) -> Self {
+        RpcError {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn params(message: impl Into<String>) -> Self {
+        Self::new(INVALID_PARAMS, message)
+    }
+}
+
+struct Paused {
+    vm: VM<'static>,
+    output: SharedOutput,
+    max_steps: u64,
+}
+
+impl Paused {
+    // `None` for an empty program, which has nothing to run
+    fn start(program: &Arc<Program>, params: &Json) -> Result<Option<Self>, RpcError> {
+        let (options, max_steps) = limits(params)?;
+        let input = input(params)?;
+        let output = SharedOutput::default();
+        let Ok(vm) = VM::new(Arc::clone(program)) else {
+            return Ok(None);
+        };
+        Ok(Some(Paused {
+            vm: vm.with_options(options).with_io(input, output.clone()),
+            output,
+            max_steps,
+        }))
+    }
+}
+
+struct Session {
+    program: Arc<Program>, // shared with the paused VM
+    paused: Option<Paused>,
+}
+
+#[derive(Default)]
+pub struct Server {
+    next_id: u64,
+    sessions: HashMap<u64, Session>,
+}
+
+/// Serve requests from `input` until it is closed.
+pub fn serve(input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+    let mut server = Server::default();
+    for line in input.lines() {
+        if let Some(response) = server.handle_line(&line?) {
+            writeln!(output, "{}", response)?;
+            output.flush()?;
+        }
+    }
+    Ok(())
+}
+
+fn limits(params: &Json) -> Result<(VmOptions, u64), RpcError> {
+    let mut options = VmOptions::default();
+    let Some(limits) = params.get("limits") else {
+        return Ok((options, DEFAULT_MAX_STEPS));
+    };
+    options.max_loop_iterations = integer(limits, "maxLoopIterations")?;
+    if let Some(eof) = limits.get("eof") {
+        options.eof = eof
+            .as_str()
+            .and_then(EofBehavior::from_name)
+            .ok_or_else(|| {
+                RpcError::params("`eof` must be \"unchanged\", \"0\", \"-1\" or \"halt\"")
+            })?;
+    }
+    let max_steps = integer(limits, "maxSteps")?.unwrap_or(DEFAULT_MAX_STEPS);
+    Ok((options, max_steps))
+}
+
+fn stats(stats: &RunStats) -> Json {
+    let termination = match stats.termination {
+        Termination::Finished => json_object! {"kind" => "finished"},
+        Termination::EofHalt { pc } => json_object! {"kind" => "eofHalt", "pc" => pc},
+        Termination::Halted { pc } => json_object! {"kind" => "halted", "pc" => pc},
+    };
+    Json::Object(vec![
+        ("steps".to_string(), stats.steps.into()),
+        ("termination".to_string(), termination),
+    ])
+}
+
+// `output` in the `encoding` asked for
+fn encoded(bytes: &[u8], params: &Json) -> Result<Json, RpcError> {
+    match params.get("encoding").map(Json::as_str) {
+        None | Some(Some("text")) => Ok(String::from_utf8_lossy(bytes).into_owned().into()),
+        Some(Some("bytes")) => Ok(Json::Array(
+            bytes.iter().map(|&b| Json::Number(b as f64)).collect(),
+        )),
+        Some(_) => Err(RpcError::params("`encoding` must be \"text\" or \"bytes\"")),
+    }
+}
+
+impl Server {
+    /// Answer one request line; notifications (no `id`) get no response.
+    pub fn handle_line(&mut self, line: &str) -> Option<String> {
+        if line.trim().is_empty() {
+            return None;
+        }
+        let request = match Json::parse(line) {
+            Ok(request) => request,
+            Err(e) => {
+                return Some(response(
+                    Json::Null,
+                    Err(RpcError::new(PARSE_ERROR, e.to_string())),
+                ))
+            }
+        };
+        let id = request.get("id").cloned();
+        let result = match (request.get("method").and_then(Json::as_str), &request) {
+            (Some(method), Json::Object(_)) => {
+                let params = request
+                    .get("params")
+                    .cloned()
+                    .unwrap_or(Json::Object(vec![]));
+                self.dispatch(method, &params)
+            }
+            _ => Err(RpcError::new(
+                INVALID_REQUEST,
+                "expected an object with a `method`",
+            )),
+        };
+        id.map(|id| response(id, result))
+    }
+
+    fn dispatch(&mut self, method: &str, params: &Json) -> Result<Json, RpcError> {
+        match method {
+            "compile" => self.compile(params),
+            "run" => self.run(params),
+            "step" => self.step(params),
+            "tape" => self.tape(params),
+            "release" => {
+                let id = program_id(params)?;
+                self.sessions
+                    .remove(&id)
+                    .ok_or_else(|| unknown_program(id))?;
+                Ok(json_object! {"released" => true})
+            }
+            _ => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("unknown method `{}`", method),
+            )),
+        }
+    }
+
+    fn session(&mut self, params: &Json) -> Result<&mut Session, RpcError> {
+        let id = program_id(params)?;
+        self.sessions
+            .get_mut(&id)
+            .ok_or_else(|| unknown_program(id))
+    }
+
+    fn compile(&mut self, params: &Json) -> Result<Json, RpcError> {
+        let source = params
+            .get("source")
+            .and_then(Json::as_str)
+            .ok_or_else(|| RpcError::params("`source` must be a string"))?;
+        let level = match params
+            .get("options")
+            .and_then(|options| options.get("optLevel"))
+        {
+            None => OptLevel::default(),
+            Some(level) => OptLevel::ALL
+                .into_iter()
+                .find(|l| level.as_str() == Some(&l.to_string()))
+                .ok_or_else(|| RpcError::params("`optLevel` must be \"O0\", \"O1\" or \"O2\""))?,
+        };
+
+        let program = match Program::compile_with(source, level) {
+            Ok(program) => program,
+            Err(_) => {
+                // report every bracket problem, not just the first
+                let (_, errors) = tokenizer::tokenizer_recover(source);
+                let diagnostics = errors
+                    .iter()
+                    .map(|e| {
+                        json_object! {
+                            "line" => e.line(),
+                            "col" => e.col(),
+                            "code" => e.code(),
+                            "message" => e.kind().to_string(),
+                        }
+                    })
+                    .collect();
+                return Ok(Json::Object(vec![(
+                    "diagnostics".to_string(),
+                    Json::Array(diagnostics),
+                )]));
+            }
+        };
+
+        self.next_id += 1;
+        let id = self.next_id;
+        let instructions = program.tokens().len();
+        self.sessions.insert(
+            id,
+            Session {
+                program: Arc::new(program),
+                paused: None,
+            },
+        );
+        Ok(json_object! {"programId" => id, "instructions" => instructions})
+    }
+
+    fn run(&mut self, params: &Json) -> Result<Json, RpcError> {
+        let session = self.session(params)?;
+        let Some(mut paused) = Paused::start(&session.program, params)? else {
+            return Ok(json_object! {
+                "output" => encoded(&[], params)?,
+                "stats" => stats(&RunStats::default()),
+            });
+        };
+        let mut result = advance(&mut paused, u64::MAX);
+        push_output(&mut result, &paused, params)?;
+        Ok(result)
+    }
+
+    fn step(&mut self, params: &Json) -> Result<Json, RpcError> {
+        let count = integer(params, "count")?.unwrap_or(1);
+        let empty = encoded(&[], params)?;
+        let session = self.session(params)?;
+        if session.paused.is_none() {
+            session.paused = Paused::start(&session.program, params)?;
+        }
+        let Some(paused) = session.paused.as_mut() else {
+            return Ok(json_object! {"halted" => true, "output" => empty});
+        };
+
+        let mut result = advance(paused, count);
+        push_output(&mut result, paused, params)?;
+        if let Json::Object(fields) = &mut result {
+            let vm = &paused.vm;
+            fields.splice(
+                0..0,
+                [
+                    ("pc".to_string(), vm.pc().into()),
+                    ("pointer".to_string(), vm.pointer().into()),
+                    ("halted".to_string(), vm.halted().into()),
+                ],
+            );
+        }
+        // a failed VM cannot continue; the next `step` starts over
+        if result.get("error").is_some() {
+            session.paused = None;
+        }
+        Ok(result)
+    }
+
+    fn tape(&mut self, params: &Json) -> Result<Json, RpcError> {
+        let start = integer(params, "start")?.map(|n| n.min(usize::MAX as u64) as usize);
+        let len = integer(params, "len")?
+            .map_or(TAPE_DEFAULT_LEN, |n| n.min(TAPE_MAX_LEN as u64) as usize);
+        let id = program_id(params)?;
+        let paused = self.session(params)?.paused.as_ref().ok_or_else(|| {
+            RpcError::new(NOT_PAUSED, format!("program {} is not being stepped", id))
+        })?;
+
+        let vm = &paused.vm;
+        let pointer = vm.pointer();
+        let start = start
+            .unwrap_or(pointer.saturating_sub(len / 2))
+            .min(vm.tape_len());
+        let end = start.saturating_add(len).min(vm.tape_len());
+        let cells = vm
+            .cells(start..end)
+            .iter()
+            .map(|&c| Json::Number(c as f64))
+            .collect();
+        Ok(Json::Object(vec![
+            ("pointer".to_string(), pointer.into()),
+            ("start".to_string(), start.into()),
+            ("cells".to_string(), Json::Array(cells)),
+        ]))
+    }
+}
+
+// run up to `count` more instructions, stopping early at the end or an error
+fn advance(paused: &mut Paused, count: u64) -> Json {
+    let (mut error, mut code) = (None, None);
+    for _ in 0..count {
+        if paused.vm.stats().steps >= paused.max_steps && !paused.vm.halted() {
+            error = Some(format!("step limit of {} exceeded", paused.max_steps));
+            break;
+        }
+        match paused.vm.step() {
+            Ok(StepResult::Halted) => break,
+            Ok(_) => {}
+            Err(e) => {
+                code = Some(e.code());
+                error = Some(e.to_string());
+                break;
+            }
+        }
+    }
+    let mut fields = vec![("stats".to_string(), stats(paused.vm.stats()))];
+    if let Some(error) = error {
+        fields.push(("error".to_string(), error.into()));
+    }
+    if let Some(code) = code {
+        fields.push(("errorCode".to_string(), code.into()));
+    }
+    Json::Object(fields)
+}
+
+fn push_output(result: &mut Json, paused: &Paused, params: &Json) -> Result<(), RpcError> {
+    let output = encoded(&paused.output.take(), params)?;
+    if let Json::Object(fields) = result {
+        fields.insert(0, ("output".to_string(), output));
+    }
+    Ok(())
+}
+
+// an optional non-negative integer parameter
+fn integer(params: &Json, key: &str) -> Result<Option<u64>, RpcError> {
+    match params.get(key) {
+        None | Some(Json::Null) => Ok(None),
+        Some(n) => n
+            .as_u64()
+            .map(Some)
+            .ok_or_else(|| RpcError::params(format!("`{}` must be a non-negative integer", key))),
+    }
+}
+
+fn program_id(params: &Json) -> Result<u64, RpcError> {
+    params
+        .get("programId")
+        .and_then(Json::as_u64)
+        .ok_or_else(|| RpcError::params("`programId` must be a non-negative integer"))
+}
+
+fn unknown_program(id: u64) -> RpcError {
+    RpcError::new(UNKNOWN_PROGRAM, format!("unknown programId {}", id))
+}
+
+fn input(params: &Json) -> Result<io::Cursor<Vec<u8>>, RpcError> {
+    let invalid = || RpcError::params("`input` must be a string or an array of bytes");
+    let bytes = match params.get("input") {
+        None => vec![],
+        Some(Json::String(s)) => s.as_bytes().to_vec(),
+        Some(Json::Array(items)) => items
+            .iter()
+            .map(|item| item.as_u64().and_then(|b| u8::try_from(b).ok()))
+            .collect::<Option<_>>()
+            .ok_or_else(invalid)?,
+        Some(_) => return Err(invalid()),
+    };
+    Ok(io::Cursor::new(bytes))
+}
+
+fn response(id: Json, result: Result<Json, RpcError>) -> String {
+    let body = match result {
+        Ok(result) => ("result".to_string(), result),
+        Err(e) => (
+            "error".to_string(),
+            json_object! {"code" => e.code, "message" => e.message},
+        ),
+    };
+    Json::Object(vec![
+        ("jsonrpc".to_string(), "2.0".into()),
+        ("id".to_string(), id),
+        body,
+    ])
+    .to_string()
+}
+
+#[test]
+fn test_serve_stdio() {
+    let transcript = [
+        (
+            r#"{"jsonrpc":"2.0","id":1,"method":"compile","params":{"source":"++[>+++<-]>."}}"#,
+            r#"{"jsonrpc":"2.0","id":1,"result":{"programId":1,"instructions":5}}"#,
+        ),
+        (
+            r#"{"jsonrpc":"2.0","id":2,"method":"compile","params":{"source":"[\n]]"}}"#,
+            r#"{"jsonrpc":"2.0","id":2,"result":{"diagnostics":[{"line":2,"col":2,"code":"E0101","message":"Unclose left bracket"}]}}"#,
+        ),
+        (
+            r#"{"jsonrpc":"2.0","id":3,"method":"compile","params":{"source":",[.,]","options":{"optLevel":"O0"}}}"#,
+            r#"{"jsonrpc":"2.0","id":3,"result":{"programId":2,"instructions":5}}"#,
+        ),
+        (
+            r#"{"jsonrpc":"2.0","id":4,"method":"run","params":{"programId":2,"input":"hi","limits":{"eof":"halt"}}}"#,
+            r#"{"jsonrpc":"2.0","id":4,"result":{"output":"hi","stats":{"steps":7,"termination":{"kind":"eofHalt","pc":3}}}}"#,
+        ),
+        (
+            r#"{"jsonrpc":"2.0","id":5,"method":"run","params":{"programId":2,"input":"hi","limits":{"eof":"0","maxSteps":4}}}"#,
+            r#"{"jsonrpc":"2.0","id":5,"result":{"output":"h","stats":{"steps":4,"termination":{"kind":"finished"}},"error":"step limit of 4 exceeded"}}"#,
+        ),
+        // no id: a notification, answered with nothing
+        (
+            r#"{"jsonrpc":"2.0","method":"step","params":{"programId":1,"count":3}}"#,
+            "",
+        ),
+        (
+            r#"{"jsonrpc":"2.0","id":"t","method":"tape","params":{"programId":1,"start":0,"len":3}}"#,
+            r#"{"jsonrpc":"2.0","id":"t","result":{"pointer":0,"start":0,"cells":[0,6,0]}}"#,
+        ),
+        (
+            r#"{"jsonrpc":"2.0","id":6,"method":"step","params":{"programId":1,"count":100}}"#,
+            r#"{"jsonrpc":"2.0","id":6,"result":{"pc":5,"pointer":1,"halted":true,"output":"\u0006","stats":{"steps":5,"termination":{"kind":"finished"}}}}"#,
+        ),
+        (
+            r#"{"jsonrpc":"2.0","id":7,"method":"release","params":{"programId":1}}"#,
+            r#"{"jsonrpc":"2.0","id":7,"result":{"released":true}}"#,
+        ),
+        (
+            r#"{"jsonrpc":"2.0","id":8,"method":"run","params":{"programId":1}}"#,
+            r#"{"jsonrpc":"2.0","id":8,"error":{"code":-32001,"message":"unknown programId 1"}}"#,
+        ),
+        (
+            r#"{"jsonrpc":"2.0","id":9,"method":"tape","params":{"programId":2}}"#,
+            r#"{"jsonrpc":"2.0","id":9,"error":{"code":-32002,"message":"program 2 is not being stepped"}}"#,
+        ),
+        (
+            r#"{"jsonrpc":"2.0","id":10,"method":"fork","params":{}}"#,
+            r#"{"jsonrpc":"2.0","id":10,"error":{"code":-32601,"message":"unknown method `fork`"}}"#,
+        ),
+        (
+            r#"{"jsonrpc":"2.0","id":11,"method":"run","params":{"programId":"2"}}"#,
+            r#"{"jsonrpc":"2.0","id":11,"error":{"code":-32602,"message":"`programId` must be a non-negative integer"}}"#,
+        ),
+        // bytes in and out exactly, where text would lose the 0xff
+        (
+            r#"{"jsonrpc":"2.0","id":13,"method":"compile","params":{"source":",+.","options":{"optLevel":"O0"}}}"#,
+            r#"{"jsonrpc":"2.0","id":13,"result":{"programId":3,"instructions":3}}"#,
+        ),
+        (
+            r#"{"jsonrpc":"2.0","id":14,"method":"run","params":{"programId":3,"input":[254],"encoding":"bytes"}}"#,
+            r#"{"jsonrpc":"2.0","id":14,"result":{"output":[255],"stats":{"steps":3,"termination":{"kind":"finished"}}}}"#,
+        ),
+        (
+            r#"{"jsonrpc":"2.0","id":15,"method":"run","params":{"programId":3,"input":[254]}}"#,
+            r#"{"jsonrpc":"2.0","id":15,"result":{"output":"�","stats":{"steps":3,"termination":{"kind":"finished"}}}}"#,
+        ),
+        (
+            r#"{"jsonrpc":"2.0","id":16,"method":"run","params":{"programId":3,"input":[256]}}"#,
+            r#"{"jsonrpc":"2.0","id":16,"error":{"code":-32602,"message":"`input` must be a string or an array of bytes"}}"#,
+        ),
+        (
+            r#"{"jsonrpc":"2.0","id":17,"method":"step","params":{"programId":3,"encoding":"hex"}}"#,
+            r#"{"jsonrpc":"2.0","id":17,"error":{"code":-32602,"message":"`encoding` must be \"text\" or \"bytes\""}}"#,
+        ),
+        (
+            r#"{"jsonrpc":"2.0","id":12,"#,
+            r#"{"jsonrpc":"2.0","id":null,"error":{"code":-32700,"message":"Invalid JSON at byte 25"}}"#,
+        ),
+    ];
+
+    let requests: String = transcript
+        .iter()
+        .map(|(req, _)| format!("{}\n", req))
+        .collect();
+    let mut out = vec![];
+    serve(io::Cursor::new(requests), &mut out).unwrap();
+    let expected: Vec<&str> = transcript
+        .iter()
+        .map(|(_, resp)| *resp)
+        .filter(|resp| !resp.is_empty())
+        .collect();
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(out.lines().collect::<Vec<_>>(), expected);
+
+    // a run given no step limit still has one
+    let (_, max_steps) = limits(&Json::Object(vec![])).ok().unwrap();
+    assert_eq!(max_steps, DEFAULT_MAX_STEPS);
+    let (_, max_steps) = limits(&Json::parse(r#"{"limits":{"eof":"0"}}"#).unwrap())
+        .ok()
+        .unwrap();
+    assert_eq!(max_steps, DEFAULT_MAX_STEPS);
+
+    // a request nested too deep is a parse error, not a crash
+    assert_eq!(
+        Server::default().handle_line(&"[".repeat(20000)).unwrap(),
+        r#"{"jsonrpc":"2.0","id":null,"error":{"code":-32700,"message":"Invalid JSON at byte 256"}}"#
+    );
+}
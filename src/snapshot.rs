@@ -0,0 +1,195 @@
+//! Golden-file snapshots of the optimizer output for the `bfcode/` corpus.
+//!
+//! Every program is compiled at each `OptLevel`, for a zeroed tape as `bfjit`
+//! runs it, and its stats summary plus IR text are compared against
+//! `tests/snapshots/<name>.<level>.txt`, so pass changes show up as
+//! reviewable diffs. Tape dumps of a few runs are kept
+//! the same way, as `tests/snapshots/<name>.tape.txt`. After an intended
+//! change regenerate them with:
+//!
+//! ```text
+//! BFJIT_BLESS=1 cargo test snapshot
+//! ```
+
+use std::{collections::BTreeMap, env, fmt::Write, fs, path::Path};
+
+use crate::{
+    program::{OptLevel, Program},
+    tokenizer::{Dialect, StartTape, Token},
+    vm::CellOverflow,
+};
+
+fn mnemonic(token: &Token) -> &'static str {
+    match token {
+        Token::IncrementData(_) | Token::DecrementData(_) => "add",
+        Token::IncrementPointer(_) | Token::DecrementPointer(_) => "move",
+        Token::Input => "in",
+        Token::Output | Token::OutputRepeat(_) | Token::Print(_) => "out",
+        Token::ClearRange { .. } => "clear",
+        Token::MulAdd { .. } => "muladd",
+        Token::AddAt { .. } => "addat",
+        Token::ScanRight(_) | Token::ScanLeft(_) => "scan",
+        Token::Ext(_) => "ext",
+        Token::Breakpoint => "break",
+        Token::LoopStart(_) | Token::LoopEnd(_) => "loop",
+        Token::IfStart(_) | Token::IfEnd(_) => "if",
+    }
+}
+
+fn render(program: &Program) -> String {
+    let tokens = program.tokens();
+    let mut kinds: BTreeMap<&str, usize> = BTreeMap::new();
+    let (mut depth, mut max_depth) = (0, 0);
+    for token in tokens {
+        if token.is_block_start() {
+            depth += 1;
+            max_depth = max_depth.max(depth);
+        } else if token.is_block_end() {
+            depth -= 1;
+            continue;
+        }
+        *kinds.entry(mnemonic(token)).or_default() += 1;
+    }
+
+    let mut out = String::new();
+    writeln!(out, "# instructions: {}", tokens.len()).unwrap();
+    writeln!(out, "# loop depth: {}", max_depth).unwrap();
+    for (kind, count) in &kinds {
+        writeln!(out, "# {}: {}", kind, count).unwrap();
+    }
+    out.push('\n');
+    out.push_str(&program.to_ir_text());
+    out
+}
+
+// compare `actual` with the snapshot file, or overwrite it when blessing
+fn check(snapshot: &Path, actual: &str, bless: bool) -> Option<String> {
+    if bless {
+        fs::write(snapshot, actual).unwrap();
+        return None;
+    }
+    let expected = fs::read_to_string(snapshot).unwrap_or_default();
+    if expected == actual {
+        return None;
+    }
+    let line = expected
+        .lines()
+        .zip(actual.lines())
+        .position(|(a, b)| a != b)
+        .unwrap_or_else(|| expected.lines().count().min(actual.lines().count()));
+    Some(format!(
+        "{} differs from line {}",
+        snapshot.display(),
+        line + 1
+    ))
+}
+
+#[test]
+fn snapshot_optimizer_output() {
+    let bless = env::var_os("BFJIT_BLESS").is_some();
+    let dir = Path::new("tests/snapshots");
+    if bless {
+        fs::create_dir_all(dir).unwrap();
+    }
+
+    let mut corpus: Vec<_> = fs::read_dir("bfcode")
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "bf"))
+        .collect();
+    corpus.sort();
+    assert!(!corpus.is_empty());
+
+    let mut failures = vec![];
+    for path in &corpus {
+        let src = fs::read_to_string(path).unwrap();
+        let name = path.file_stem().unwrap().to_str().unwrap();
+        for level in OptLevel::ALL {
+            let program = Program::compile_for(
+                &src,
+                level,
+                Dialect::Standard,
+                StartTape::Zeroed,
+                CellOverflow::Wrap,
+            );
+            let actual = render(&program.unwrap());
+            let snapshot = dir.join(format!("{}.{}.txt", name, level));
+            failures.extend(check(&snapshot, &actual, bless));
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "optimizer output changed, rerun with BFJIT_BLESS=1 if intended:\n{}",
+        failures.join("\n")
+    );
+}
+
+// tape dumps after running programs that leave a known pattern behind
+#[test]
+fn snapshot_tape_dump() {
+    use crate::{
+        engine::{Engine, ExecContext, Interpreter},
+        tape,
+    };
+
+    let bless = env::var_os("BFJIT_BLESS").is_some();
+    // "brain" in row 0, zero rows to elide, the pointer on a zero in row 3
+    let mut pattern: String = b"brain"
+        .iter()
+        .map(|&c| "+".repeat(c as usize) + ">")
+        .collect();
+    pattern += &">".repeat(50);
+    pattern += "+++<<";
+    let cases = [
+        ("pattern", pattern.as_str(), None),
+        // an explicit length ending partway through a row
+        ("short", "+>++>+++>++++", Some(5)),
+    ];
+    let mut failures = vec![];
+    for (name, src, len) in cases {
+        let program = Program::compile(src).unwrap();
+        let mut cells = vec![0_u8; 1024];
+        let (mut input, mut output) = (&b""[..], vec![]);
+        let mut ctx = ExecContext::new(&mut cells, &mut input, &mut output);
+        let pointer = Some(Interpreter.run(&program, &mut ctx).unwrap().pointer);
+        let len = len.unwrap_or_else(|| tape::dump_len(&cells, pointer));
+        let actual = tape::hexdump(&cells[..len], pointer);
+        let snapshot = Path::new("tests/snapshots").join(format!("{}.tape.txt", name));
+        failures.extend(check(&snapshot, &actual, bless));
+    }
+    assert!(
+        failures.is_empty(),
+        "tape dump changed, rerun with BFJIT_BLESS=1 if intended:\n{}",
+        failures.join("\n")
+    );
+}
+
+// the `--dump-ir` listings of a program with one of most things in it
+#[test]
+fn snapshot_ir_dump() {
+    use crate::ir_dump;
+
+    let bless = env::var_os("BFJIT_BLESS").is_some();
+    let src = "+[>,.<-]\n++[->+<]>[<]+[-]+>+++++[-[>]<]>[>+<[-]].@";
+    let mut actual = String::new();
+    for level in OptLevel::ALL {
+        let program = Program::compile_for(
+            src,
+            level,
+            Dialect::Ebf1,
+            StartTape::Zeroed,
+            CellOverflow::Wrap,
+        )
+        .unwrap();
+        writeln!(actual, "# {}", level).unwrap();
+        actual.push_str(&ir_dump::listing(&program));
+        actual.push_str(&ir_dump::json_lines(&program, &level.to_string()));
+    }
+    let snapshot = Path::new("tests/snapshots/dump.txt");
+    let failure = check(snapshot, &actual, bless);
+    assert!(
+        failure.is_none(),
+        "IR dump changed, rerun with BFJIT_BLESS=1 if intended:\n{}",
+        failure.unwrap()
+    );
+}
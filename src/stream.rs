@@ -0,0 +1,208 @@
+//! Drive a VM through `Read`/`Write` on the caller's own thread.
+//!
+//! `VmStream` owns a VM and advances it with `step()` only from inside the
+//! calls made on it, so it works where spawning a thread is not an option.
+//! Writing queues input and runs the program until it needs more input than
+//! has been written; reading runs it until it produces output.
+//!
+//! Neither side ever blocks waiting for the other. A read returns pending
+//! output if there is any, otherwise runs the program, and comes back with
+//! `WouldBlock` as soon as the program waits on a `,` that has no input yet.
+//! It returns `Ok(0)` only once the program has ended and all its output has
+//! been read. The one way a call can fail to return is a program that runs
+//! forever without reading input or writing output.
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    io::{self, Read, Write},
+    rc::Rc,
+};
+
+use crate::vm::{SharedOutput, VmError, VM};
+
+#[derive(Default)]
+struct Pending {
+    bytes: VecDeque<u8>,
+    closed: bool, // no more input will arrive
+}
+
+// the VM's input, fed by `VmStream::write`
+#[derive(Clone, Default)]
+struct StreamInput(Rc<RefCell<Pending>>);
+
+impl Read for StreamInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut pending = self.0.borrow_mut();
+        if pending.bytes.is_empty() && !pending.closed {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        pending.bytes.read(buf)
+    }
+}
+
+// why `run` stopped
+enum Blocked {
+    Input,
+    Output,
+    Halted,
+}
+
+pub struct VmStream {
+    vm: VM<'static>,
+    input: StreamInput,
+    output: SharedOutput,
+    unread: VecDeque<u8>, // output taken from the VM but not read yet
+    error: Option<VmError>,
+}
+
+impl VmStream {
+    /// Take over `vm`'s input and output; it continues from its current state.
+    pub fn new(vm: VM<'static>) -> Self {
+        let input = StreamInput::default();
+        let output = SharedOutput::default();
+        VmStream {
+            vm: vm.with_io(input.clone(), output.clone()),
+            input,
+            output,
+            unread: VecDeque::new(),
+            error: None,
+        }
+    }
+
+    /// Signal end of input; from now on `,` sees EOF once the queue is drained.
+    pub fn close_input(&mut self) {
+        self.input.0.borrow_mut().closed = true;
+    }
+
+    /// Whether the program has ended, normally or with an error.
+    pub fn halted(&self) -> bool {
+        self.vm.halted() || self.error.is_some()
+    }
+
+    /// The error that stopped the program, if one did.
+    pub fn error(&self) -> Option<&VmError> {
+        self.error.as_ref()
+    }
+
+    pub fn into_vm(self) -> VM<'static> {
+        self.vm
+    }
+
+    // step until the program needs input, has produced output or has ended
+    fn run(&mut self, stop_on_output: bool) -> Blocked {
+        loop {
+            if self.halted() {
+                return Blocked::Halted;
+            }
+            match self.vm.step() {
+                Ok(_) => {}
+                Err(VmError::IO(e)) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Blocked::Input;
+                }
+                Err(e) => self.error = Some(e),
+            }
+            let output = self.output.take();
+            if !output.is_empty() {
+                self.unread.extend(output);
+                if stop_on_output {
+                    return Blocked::Output;
+                }
+            }
+        }
+    }
+}
+
+impl Read for VmStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.unread.is_empty() {
+            if let Blocked::Input = self.run(true) {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+        }
+        self.unread.read(buf)
+    }
+}
+
+impl Write for VmStream {
+    /// Queue `buf` as input and run the program until it waits for more.
+    /// Fails with `BrokenPipe` once the program has ended.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.halted() {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        self.input.0.borrow_mut().bytes.extend(buf);
+        self.run(false);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.run(false);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_vm_stream() {
+    use crate::{
+        program::Program,
+        tokenizer::Token,
+        vm::{EofBehavior, VmOptions},
+    };
+
+    let vm = |src: &str| {
+        VM::from_program(Program::compile(src).unwrap())
+            .unwrap()
+            .with_options(VmOptions {
+                eof: EofBehavior::SetZero,
+                ..Default::default()
+            })
+    };
+    let read_all = |stream: &mut VmStream| {
+        let mut out = vec![];
+        let mut buf = [0; 2];
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) => return (out, true),
+                Ok(n) => out.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return (out, false),
+                Err(e) => panic!("{}", e),
+            }
+        }
+    };
+
+    // echo, one line at a time
+    let mut stream = VmStream::new(vm(",[.,]"));
+    assert_eq!(read_all(&mut stream), (vec![], false));
+    stream.write_all(b"hello\n").unwrap();
+    assert_eq!(read_all(&mut stream), (b"hello\n".to_vec(), false));
+    stream.write_all(b"bye").unwrap();
+    stream.close_input();
+    assert_eq!(read_all(&mut stream), (b"bye".to_vec(), true));
+    assert!(stream.halted() && stream.error().is_none());
+
+    // reads make progress without any input when the program needs none
+    let mut stream = VmStream::new(vm("++++++++[>++++++++<-]>+.+.+."));
+    assert_eq!(read_all(&mut stream), (b"ABC".to_vec(), true));
+
+    // the program takes two bytes and stops listening
+    let mut stream = VmStream::new(vm(",.,."));
+    stream.write_all(b"x").unwrap();
+    assert!(!stream.halted());
+    assert_eq!(stream.write(b"yz").unwrap(), 2);
+    assert!(stream.halted());
+    let err = stream.write(b"more").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    assert_eq!(read_all(&mut stream), (b"xy".to_vec(), true));
+
+    // runtime errors end the stream and are kept for the caller
+    let tokens = vec![
+        Token::Input,
+        Token::Output,
+        Token::IncrementPointer(1 << 22),
+    ];
+    let mut stream = VmStream::new(VM::new(Program::new(tokens)).unwrap());
+    stream.write_all(b"!").unwrap();
+    assert_eq!(read_all(&mut stream), (b"!".to_vec(), true));
+    assert!(matches!(stream.error(), Some(VmError::PointerOverFlow(_))));
+}
@@ -0,0 +1,624 @@
+//! Tape storage for the VM.
+//!
+//! A VM starts out owning a flat buffer. Forking a VM with a large tape moves
+//! it to a `CowTape`, whose fixed-size chunks are reference-counted and shared
+//! between a VM and its forks; the first write to a shared chunk clones just
+//! that chunk. The chunk table is shared the same way, so a fork costs the
+//! same however large the tape is. Either kind can grow with zero cells at
+//! its end under `TapeMode::Grow`, and at either end under
+//! `TapeMode::Bidirectional`. A VM built with `VM::with_tape` works in
+//! the caller's buffer instead and never owns its cells; a `TapeFile` is one
+//! such buffer, mapped from a file so the cells persist between runs.
+
+use std::{
+    borrow,
+    fmt::{self, Write},
+    ops::Range,
+    rc::Rc,
+};
+
+pub const CHUNK_SIZE: usize = 4096;
+
+// tapes at least this long are shared rather than copied by `fork`
+pub(crate) const COW_THRESHOLD: usize = 64 * 1024;
+
+type Chunk = [u8; CHUNK_SIZE];
+
+#[derive(Clone)]
+pub struct CowTape {
+    table: Rc<Vec<Rc<Chunk>>>,
+    len: usize,
+}
+
+impl CowTape {
+    /// A tape of `len` zero cells, all backed by one shared chunk.
+    pub fn zeroed(len: usize) -> Self {
+        let zero = Rc::new([0; CHUNK_SIZE]);
+        CowTape {
+            table: Rc::new(vec![zero; len.div_ceil(CHUNK_SIZE)]),
+            len,
+        }
+    }
+
+    pub fn from_slice(cells: &[u8]) -> Self {
+        // untouched parts of the tape keep sharing a single zero chunk
+        let zero = Rc::new([0; CHUNK_SIZE]);
+        let table = cells
+            .chunks(CHUNK_SIZE)
+            .map(|cells| {
+                if cells.iter().all(|&cell| cell == 0) {
+                    return zero.clone();
+                }
+                let mut chunk = [0; CHUNK_SIZE];
+                chunk[..cells.len()].copy_from_slice(cells);
+                Rc::new(chunk)
+            })
+            .collect();
+        CowTape {
+            table: Rc::new(table),
+            len: cells.len(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    #[inline]
+    pub fn get(&self, i: usize) -> u8 {
+        debug_assert!(i < self.len);
+        self.table[i / CHUNK_SIZE][i % CHUNK_SIZE]
+    }
+
+    #[inline]
+    pub fn set(&mut self, i: usize, value: u8) {
+        debug_assert!(i < self.len);
+        // both are no-ops unless something else holds a reference
+        let table = Rc::make_mut(&mut self.table);
+        Rc::make_mut(&mut table[i / CHUNK_SIZE])[i % CHUNK_SIZE] = value;
+    }
+
+    /// Zero the cells in `range`, copying only the shared chunks it touches.
+    pub fn clear(&mut self, range: Range<usize>) {
+        debug_assert!(range.end <= self.len);
+        let table = Rc::make_mut(&mut self.table);
+        let mut i = range.start;
+        while i < range.end {
+            let (chunk, at) = (i / CHUNK_SIZE, i % CHUNK_SIZE);
+            let end = (at + range.end - i).min(CHUNK_SIZE);
+            if table[chunk][at..end].iter().any(|&cell| cell != 0) {
+                Rc::make_mut(&mut table[chunk])[at..end].fill(0);
+            }
+            i += end - at;
+        }
+    }
+
+    /// Lengthen the tape to `len` cells with zero chunks, which are shared.
+    pub fn grow(&mut self, len: usize) {
+        debug_assert!(len >= self.len);
+        let zero = Rc::new([0; CHUNK_SIZE]);
+        Rc::make_mut(&mut self.table).resize(len.div_ceil(CHUNK_SIZE), zero);
+        self.len = len;
+    }
+
+    /// A tape sharing every chunk with this one until either is written.
+    pub fn fork(&self) -> Self {
+        self.clone()
+    }
+
+    pub fn cells(&self, range: Range<usize>) -> Vec<u8> {
+        range.map(|i| self.get(i)).collect()
+    }
+
+    // number of chunks backed by the same memory in both tapes
+    #[cfg(test)]
+    fn shared_with(&self, other: &CowTape) -> usize {
+        let pairs = self.table.iter().zip(other.table.iter());
+        pairs.filter(|(a, b)| Rc::ptr_eq(a, b)).count()
+    }
+}
+
+/// The VM's cells, flat when exclusively owned and chunked once forked.
+pub(crate) enum Tape<'t> {
+    Flat(Box<[u8]>),
+    Cow(CowTape),
+    Borrowed(&'t mut [u8]),
+}
+
+impl Tape<'_> {
+    pub(crate) fn zeroed(len: usize) -> Self {
+        Tape::Flat(vec![0; len].into_boxed_slice())
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Tape::Flat(mem) => mem.len(),
+            Tape::Cow(tape) => tape.len(),
+            Tape::Borrowed(mem) => mem.len(),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn get(&self, i: usize) -> u8 {
+        match self {
+            Tape::Flat(mem) => mem[i],
+            Tape::Cow(tape) => tape.get(i),
+            Tape::Borrowed(mem) => mem[i],
+        }
+    }
+
+    #[inline]
+    pub(crate) fn set(&mut self, i: usize, value: u8) {
+        match self {
+            Tape::Flat(mem) => mem[i] = value,
+            Tape::Cow(tape) => tape.set(i, value),
+            Tape::Borrowed(mem) => mem[i] = value,
+        }
+    }
+
+    pub(crate) fn clear(&mut self, range: Range<usize>) {
+        match self {
+            Tape::Flat(mem) => mem[range].fill(0),
+            Tape::Cow(tape) => tape.clear(range),
+            Tape::Borrowed(mem) => mem[range].fill(0),
+        }
+    }
+
+    /// All the cells in one slice, moving a shared tape back to a flat one.
+    pub(crate) fn flat(&mut self) -> &mut [u8] {
+        if let Tape::Cow(tape) = self {
+            *self = Tape::Flat(tape.cells(0..tape.len()).into_boxed_slice());
+        }
+        match self {
+            Tape::Flat(mem) => mem,
+            Tape::Borrowed(mem) => mem,
+            Tape::Cow(_) => unreachable!("just flattened"),
+        }
+    }
+
+    /// Lengthen an owned tape to `len` zeroed cells; a borrowed one cannot.
+    pub(crate) fn grow(&mut self, len: usize) -> bool {
+        match self {
+            Tape::Flat(mem) => {
+                let mut cells = std::mem::take(mem).into_vec();
+                cells.resize(len, 0);
+                *mem = cells.into_boxed_slice();
+            }
+            Tape::Cow(tape) => tape.grow(len),
+            Tape::Borrowed(_) => return false,
+        }
+        true
+    }
+
+    /// Put `extra` zeroed cells in front of an owned tape, moving every cell
+    /// up by as many; a borrowed one cannot.
+    pub(crate) fn grow_front(&mut self, extra: usize) -> bool {
+        let len = self.len();
+        let old = match self {
+            Tape::Borrowed(_) => return false,
+            Tape::Flat(mem) => std::mem::take(mem).into_vec(),
+            Tape::Cow(tape) => tape.cells(0..len),
+        };
+        let mut cells = vec![0; extra + len];
+        cells[extra..].copy_from_slice(&old);
+        *self = Tape::Flat(cells.into_boxed_slice());
+        true
+    }
+
+    /// The first zero cell from `start`, stepping `stride` cells right or
+    /// left, or `None` if the walk leaves the tape first.
+    pub(crate) fn find_zero(&self, start: usize, stride: usize, right: bool) -> Option<usize> {
+        let slice = match self {
+            Tape::Flat(mem) => &mem[..],
+            Tape::Borrowed(mem) => &mem[..],
+            Tape::Cow(tape) => {
+                let mut at = start;
+                while tape.get(at) != 0 {
+                    at = match right {
+                        true => at.checked_add(stride).filter(|&at| at < tape.len())?,
+                        false => at.checked_sub(stride)?,
+                    };
+                }
+                return Some(at);
+            }
+        };
+        match right {
+            true => slice[start..]
+                .iter()
+                .step_by(stride)
+                .position(|&cell| cell == 0)
+                .map(|i| start + i * stride),
+            false => slice[..=start]
+                .iter()
+                .rev()
+                .step_by(stride)
+                .position(|&cell| cell == 0)
+                .map(|i| start - i * stride),
+        }
+    }
+
+    pub(crate) fn cells(&self, range: Range<usize>) -> Vec<u8> {
+        match self {
+            Tape::Flat(mem) => mem[range].to_vec(),
+            Tape::Cow(tape) => tape.cells(range),
+            Tape::Borrowed(mem) => mem[range].to_vec(),
+        }
+    }
+
+    /// The cells in `range`, borrowed unless they sit in shared chunks.
+    pub(crate) fn memory(&self, range: Range<usize>) -> borrow::Cow<'_, [u8]> {
+        match self {
+            Tape::Flat(mem) => borrow::Cow::Borrowed(&mem[range]),
+            Tape::Cow(tape) => borrow::Cow::Owned(tape.cells(range)),
+            Tape::Borrowed(mem) => borrow::Cow::Borrowed(&mem[range]),
+        }
+    }
+
+    /// Copy a small tape; share a large one, switching `self` over to chunks.
+    /// A borrowed tape stays in the caller's buffer and is always copied.
+    pub(crate) fn fork(&mut self) -> Tape<'static> {
+        if let Tape::Borrowed(mem) = self {
+            return match mem.len() < COW_THRESHOLD {
+                true => Tape::Flat(mem.to_vec().into_boxed_slice()),
+                false => Tape::Cow(CowTape::from_slice(mem)),
+            };
+        }
+        if let Tape::Flat(mem) = self {
+            if mem.len() < COW_THRESHOLD {
+                return Tape::Flat(mem.clone());
+            }
+            *self = Tape::Cow(CowTape::from_slice(mem));
+        }
+        match self {
+            Tape::Cow(tape) => Tape::Cow(tape.fork()),
+            Tape::Flat(_) | Tape::Borrowed(_) => unreachable!(),
+        }
+    }
+}
+
+// cells compared at a time when looking for the next difference
+const DIFF_CHUNK: usize = 64;
+
+// identical cells between two differences that still read as one region
+const DIFF_GAP: usize = 2;
+
+/// A run of cells that differ between two tapes, with short identical gaps
+/// folded in so a report reads as a few spans rather than single cells.
+///
+/// Past the end of the shorter tape its side simply has fewer values.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffRegion {
+    pub start: usize,
+    pub a: Vec<u8>,
+    pub b: Vec<u8>,
+}
+
+impl DiffRegion {
+    pub fn end(&self) -> usize {
+        self.start + self.a.len().max(self.b.len())
+    }
+}
+
+impl fmt::Display for DiffRegion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "cells {}..{}: {:?} -> {:?}",
+            self.start,
+            self.end(),
+            self.a,
+            self.b
+        )
+    }
+}
+
+pub struct TapeDiff;
+
+impl TapeDiff {
+    /// Every region where `a` and `b` differ, in order.
+    pub fn compare(a: &[u8], b: &[u8]) -> Vec<DiffRegion> {
+        let common = a.len().min(b.len());
+        let mut regions: Vec<DiffRegion> = vec![];
+        let mut i = 0;
+        while i < common {
+            // skip identical chunks with a slice compare before going cell by cell
+            let chunk = (i + DIFF_CHUNK).min(common);
+            if a[i..chunk] == b[i..chunk] {
+                i = chunk;
+                continue;
+            }
+            while i < chunk && a[i] == b[i] {
+                i += 1;
+            }
+            let mut start = i;
+            while i < common && a[i] != b[i] {
+                i += 1;
+            }
+            if let Some(last) = regions.pop_if(|last| last.end() + DIFF_GAP >= start) {
+                start = last.start;
+            }
+            regions.push(DiffRegion {
+                start,
+                a: a[start..i].to_vec(),
+                b: b[start..i].to_vec(),
+            });
+        }
+        if a.len() != b.len() {
+            // the tail only one tape has joins a region that runs up to it
+            let tail = match regions.last() {
+                Some(last) if last.end() == common => regions.pop().unwrap(),
+                _ => DiffRegion {
+                    start: common,
+                    a: vec![],
+                    b: vec![],
+                },
+            };
+            regions.push(DiffRegion {
+                start: tail.start,
+                a: a[tail.start..].to_vec(),
+                b: b[tail.start..].to_vec(),
+            });
+        }
+        regions
+    }
+
+    /// One line per region, or `identical`.
+    pub fn render(regions: &[DiffRegion]) -> String {
+        if regions.is_empty() {
+            return "identical".to_string();
+        }
+        let lines: Vec<String> = regions.iter().map(DiffRegion::to_string).collect();
+        lines.join("\n")
+    }
+}
+
+// cells per row of a dump
+const DUMP_ROW: usize = 16;
+
+// most cells a dump shows unless asked for more
+const DUMP_DEFAULT_CAP: usize = 4096;
+
+/// How many cells a dump shows by default: whole rows up to the last nonzero
+/// cell or the pointer, whichever is further, at most `DUMP_DEFAULT_CAP`.
+pub fn dump_len<T: Copy + Default + PartialEq>(cells: &[T], pointer: Option<usize>) -> usize {
+    let last = cells.iter().rposition(|&cell| cell != T::default());
+    let end = last.max(pointer).map_or(0, |i| i + 1);
+    let len = end.div_ceil(DUMP_ROW) * DUMP_ROW;
+    len.min(DUMP_DEFAULT_CAP).min(cells.len())
+}
+
+/// Where a dump starts by default: the row of the first nonzero cell, the
+/// pointer or `origin`, whichever comes first, so on a tape whose cell 0 is
+/// mid-way it starts near the cells in use.
+pub fn dump_start<T: Copy + Default + PartialEq>(
+    cells: &[T],
+    pointer: Option<usize>,
+    origin: usize,
+) -> usize {
+    let first = cells.iter().position(|&cell| cell != T::default());
+    let start = [first, pointer, Some(origin)].into_iter().flatten().min();
+    start.unwrap_or(0).min(cells.len()) / DUMP_ROW * DUMP_ROW
+}
+
+/// An xxd-style dump: offset, hex cells, then the cells as ASCII.
+///
+/// The cell under `pointer` has `>` in front of its hex pair instead of a
+/// space. A run of zero rows is cut down to its first row and a `*` line,
+/// unless it holds the pointer or ends the dump.
+pub fn hexdump(cells: &[u8], pointer: Option<usize>) -> String {
+    hexdump_at(cells, 0, pointer)
+}
+
+/// `hexdump` of cells that start at `origin` on the tape, with offsets and
+/// `pointer` on the tape too.
+pub fn hexdump_at(cells: &[u8], origin: usize, pointer: Option<usize>) -> String {
+    hexdump_groups(cells, 1, origin, pointer)
+}
+
+/// `hexdump_at` of cells `width` bytes wide, each given as its bytes least
+/// significant first and printed as one group; offsets are in bytes, while
+/// `origin` and `pointer` count cells.
+pub fn hexdump_wide(cells: &[u32], width: usize, origin: usize, pointer: Option<usize>) -> String {
+    let bytes: Vec<u8> = cells
+        .iter()
+        .flat_map(|cell| cell.to_le_bytes()[..width].to_vec())
+        .collect();
+    hexdump_groups(&bytes, width, origin, pointer)
+}
+
+fn hexdump_groups(bytes: &[u8], width: usize, origin: usize, pointer: Option<usize>) -> String {
+    let mut out = String::new();
+    let rows = bytes.len().div_ceil(DUMP_ROW);
+    let mut zero_run = false;
+    for (row, chunk) in bytes.chunks(DUMP_ROW).enumerate() {
+        // in cells, like `pointer`
+        let start = origin + row * DUMP_ROW / width;
+        let cells = chunk.len() / width;
+        let has_pointer = pointer.is_some_and(|p| (start..start + cells).contains(&p));
+        let zero = chunk.iter().all(|&byte| byte == 0) && !has_pointer;
+        if zero && zero_run && row + 1 < rows {
+            if !out.ends_with("*\n") {
+                out.push_str("*\n");
+            }
+            continue;
+        }
+        zero_run = zero;
+
+        write!(out, "{:08x}:", start * width).unwrap();
+        for (i, group) in chunk.chunks(width).enumerate() {
+            let mark = if pointer == Some(start + i) { '>' } else { ' ' };
+            out.push(mark);
+            for byte in group {
+                write!(out, "{:02x}", byte).unwrap();
+            }
+        }
+        let missing = (DUMP_ROW - chunk.len()) / width;
+        out.push_str(&" ".repeat(missing * (1 + 2 * width)));
+        out.push_str("  ");
+        out.extend(chunk.iter().map(|&byte| match byte {
+            0x20..=0x7e => byte as char,
+            _ => '.',
+        }));
+        out.push('\n');
+    }
+    out
+}
+
+#[test]
+fn test_cow_tape() {
+    let mut parent = CowTape::zeroed(3 * CHUNK_SIZE + 10);
+    parent.set(5, 1);
+    parent.set(3 * CHUNK_SIZE + 9, 2);
+
+    let mut child = parent.fork();
+    assert_eq!(child.shared_with(&parent), 4);
+    child.set(5, 10);
+    child.set(CHUNK_SIZE, 11);
+    assert_eq!((parent.get(5), parent.get(CHUNK_SIZE)), (1, 0));
+    assert_eq!((child.get(5), child.get(CHUNK_SIZE)), (10, 11));
+    assert_eq!(child.get(3 * CHUNK_SIZE + 9), 2);
+    // only the two written chunks were copied
+    assert_eq!(child.shared_with(&parent), 2);
+
+    // many forks written in an interleaved order against plain vectors
+    let len = 5 * CHUNK_SIZE;
+    let mut tapes = vec![CowTape::zeroed(len)];
+    let mut models = vec![vec![0_u8; len]];
+    let mut seed: u64 = 0x9e37_79b9_7f4a_7c15;
+    let mut next = |n: usize| {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed as usize % n
+    };
+    for round in 0..20_000 {
+        let which = next(tapes.len());
+        if round % 500 == 0 && tapes.len() < 16 {
+            tapes.push(tapes[which].fork());
+            models.push(models[which].clone());
+            continue;
+        }
+        let (i, value) = (next(len), next(256) as u8);
+        tapes[which].set(i, value);
+        models[which][i] = value;
+    }
+    for (tape, model) in tapes.iter().zip(&models) {
+        assert_eq!(&tape.cells(0..len), model);
+    }
+
+    // flat tapes below the threshold are copied, above it shared
+    let mut small = Tape::zeroed(COW_THRESHOLD - 1);
+    assert!(matches!(small.fork(), Tape::Flat(_)));
+    let mut big = Tape::zeroed(COW_THRESHOLD);
+    big.set(7, 7);
+    let mut fork = big.fork();
+    fork.set(7, 8);
+    assert!(matches!((&big, &fork), (Tape::Cow(_), Tape::Cow(_))));
+    assert_eq!((big.get(7), fork.get(7)), (7, 8));
+}
+
+#[test]
+fn test_fork_cost() {
+    // a fork is one reference count bump, whatever the tape length, and a
+    // write copies one chunk of it
+    for len in [1 << 16, 1 << 30] {
+        let parent = CowTape::zeroed(len);
+        let mut child = parent.fork();
+        assert!(Rc::ptr_eq(&parent.table, &child.table));
+        assert_eq!(Rc::strong_count(&parent.table), 2);
+        let chunks = parent.table.len();
+        assert_eq!(chunks, len.div_ceil(CHUNK_SIZE));
+        child.set(len - 1, 1);
+        assert_eq!(child.shared_with(&parent), chunks - 1);
+    }
+}
+
+#[test]
+fn test_tape_diff() {
+    let region = |start, a: &[u8], b: &[u8]| DiffRegion {
+        start,
+        a: a.to_vec(),
+        b: b.to_vec(),
+    };
+
+    assert_eq!(TapeDiff::compare(&[], &[]), vec![]);
+    assert_eq!(
+        TapeDiff::render(&TapeDiff::compare(&[1, 2], &[1, 2])),
+        "identical"
+    );
+    assert_eq!(
+        TapeDiff::compare(&[1, 2, 3], &[1, 9, 3]),
+        vec![region(1, &[2], &[9])]
+    );
+
+    // far apart, across chunk boundaries, and at the very end
+    let mut a = vec![0_u8; 1000];
+    let mut b = a.clone();
+    a[12..15].copy_from_slice(&[3, 0, 7]);
+    b[12..15].copy_from_slice(&[0, 0, 8]);
+    b[63] = 1;
+    b[64] = 1;
+    b[999] = 5;
+    let diff = TapeDiff::compare(&a, &b);
+    b[68] = 2;
+    assert_eq!(
+        diff,
+        vec![
+            region(12, &[3, 0, 7], &[0, 0, 8]),
+            region(63, &[0, 0], &[1, 1]),
+            region(999, &[0], &[5]),
+        ]
+    );
+    assert_eq!(
+        TapeDiff::render(&diff),
+        "cells 12..15: [3, 0, 7] -> [0, 0, 8]\n\
+         cells 63..65: [0, 0] -> [1, 1]\n\
+         cells 999..1000: [0] -> [5]"
+    );
+    // a gap of three identical cells splits regions
+    assert_eq!(TapeDiff::compare(&b[60..70], &a[60..70]).len(), 2);
+
+    // a shorter tape: the extra cells are a region of their own, or extend
+    // one that runs into them
+    assert_eq!(
+        TapeDiff::compare(&[1, 2], &[1, 2, 0, 5]),
+        vec![region(2, &[], &[0, 5])]
+    );
+    assert_eq!(
+        TapeDiff::compare(&[1, 2, 3], &[1, 7]),
+        vec![region(1, &[2, 3], &[7])]
+    );
+    assert_eq!(
+        TapeDiff::render(&TapeDiff::compare(&[4], &[])),
+        "cells 0..1: [4] -> []"
+    );
+}
+
+#[test]
+fn test_hexdump_wide() {
+    // each cell one group of its bytes, low first, with the pointer's marked
+    let cells = [0x4142, 0, 0xffff, 7];
+    assert_eq!(
+        hexdump_wide(&cells, 2, 0, Some(2)),
+        "00000000: 4241 0000>ffff 0700                      BA......\n"
+    );
+    // offsets count bytes: eight 16-bit cells or four 32-bit ones to a row,
+    // and zero rows are cut as in a byte dump
+    let mut cells = vec![0_u32; 20];
+    cells[18] = 0x0102_0304;
+    assert_eq!(
+        hexdump_wide(&cells, 4, 16, None),
+        "00000040: 00000000 00000000 00000000 00000000  ................\n\
+         *\n\
+         00000080: 00000000 00000000 04030201 00000000  ................\n"
+    );
+    // one byte to a group is the plain dump
+    assert_eq!(
+        hexdump_wide(&[1, 2], 1, 0, Some(0)),
+        hexdump(&[1, 2], Some(0))
+    );
+}
@@ -0,0 +1,237 @@
+//! A tape kept in a file, so cells survive from one run to the next.
+//!
+//! The file is mapped shared and read-write, and derefs to the cells, so it
+//! can be handed to `VM::with_tape` or an `ExecContext` like any other
+//! buffer. Its length is the tape length. Changes are synced back with
+//! `flush`, and again when the `TapeFile` is dropped.
+//!
+//! A second user of the same file is kept out by `<file>.lock`, created next
+//! to it while the tape is open. A process that dies without cleaning up
+//! leaves the lock behind; delete it by hand once nothing has the tape open.
+
+use std::{
+    ffi::{c_int, c_void},
+    fs::{self, File, OpenOptions},
+    io,
+    ops::{Deref, DerefMut},
+    path::{Path, PathBuf},
+    ptr,
+};
+
+const PROT_READ: c_int = 1;
+const PROT_WRITE: c_int = 2;
+const MAP_SHARED: c_int = 0x01;
+#[cfg(target_os = "linux")]
+const MS_SYNC: c_int = 4;
+#[cfg(not(target_os = "linux"))]
+const MS_SYNC: c_int = 0x10;
+
+#[cfg(unix)]
+extern "C" {
+    fn mmap(
+        addr: *mut c_void,
+        len: usize,
+        prot: c_int,
+        flags: c_int,
+        fd: c_int,
+        off: i64,
+    ) -> *mut c_void;
+    fn msync(addr: *mut c_void, len: usize, flags: c_int) -> c_int;
+    fn munmap(addr: *mut c_void, len: usize) -> c_int;
+}
+
+/// Split `--tape-file` syntax, `path[:size]`, into its parts.
+pub fn parse_spec(spec: &str) -> Option<(&str, Option<usize>)> {
+    let (path, size) = match spec.rsplit_once(':') {
+        Some((path, size)) if size.bytes().all(|b| b.is_ascii_digit()) => {
+            (path, Some(size.parse().ok()?))
+        }
+        _ => (spec, None),
+    };
+    (!path.is_empty()).then_some((path, size))
+}
+
+pub struct TapeFile {
+    ptr: *mut u8,
+    len: usize,
+    lock: PathBuf,
+    // keeps the descriptor the mapping came from open
+    _file: File,
+}
+
+impl TapeFile {
+    /// Map the tape at `path`. A missing file is created `size` cells long;
+    /// an existing one keeps its length, which `size` must then match.
+    pub fn open(path: impl AsRef<Path>, size: Option<usize>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut lock = path.as_os_str().to_owned();
+        lock.push(".lock");
+        let lock = PathBuf::from(lock);
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::AlreadyExists => io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("tape is in use, lock file {} exists", lock.display()),
+                ),
+                _ => e,
+            })?;
+        Self::map(path, size)
+            .inspect_err(|_| {
+                let _ = fs::remove_file(&lock);
+            })
+            .map(|(file, ptr, len)| TapeFile {
+                ptr,
+                len,
+                lock,
+                _file: file,
+            })
+    }
+
+    #[cfg(not(unix))]
+    fn map(_path: &Path, _size: Option<usize>) -> io::Result<(File, *mut u8, usize)> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    #[cfg(unix)]
+    fn map(path: &Path, size: Option<usize>) -> io::Result<(File, *mut u8, usize)> {
+        use std::os::fd::AsRawFd;
+
+        // only a size makes a new file, so a missing one is left missing
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(size.is_some())
+            .truncate(false)
+            .open(path)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound if size.is_none() => io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "tape file does not exist, give a size to create it",
+                ),
+                _ => e,
+            })?;
+        let existing = file.metadata()?.len() as usize;
+        let len = match size {
+            Some(size) if existing == 0 => {
+                file.set_len(size as u64)?;
+                size
+            }
+            Some(size) if size != existing => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("tape file holds {} cells, not {}", existing, size),
+                ))
+            }
+            _ => existing,
+        };
+        if len == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "tape file is empty, give a size to create it",
+            ));
+        }
+        // SAFETY: a shared mapping of the whole file, only unmapped on drop;
+        // the lock file keeps other bfjit processes from mapping it too
+        let ptr = unsafe {
+            mmap(
+                ptr::null_mut(),
+                len,
+                PROT_READ | PROT_WRITE,
+                MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr as isize == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((file, ptr as *mut u8, len))
+    }
+
+    /// Write every changed cell back to the file before returning.
+    #[cfg(unix)]
+    pub fn flush(&self) -> io::Result<()> {
+        // SAFETY: `ptr`/`len` describe the mapping created in `open`
+        if unsafe { msync(self.ptr as *mut c_void, self.len, MS_SYNC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Deref for TapeFile {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: the mapping stays valid and exclusive for the lifetime of `self`
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl DerefMut for TapeFile {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: as for `deref`, and `&mut self` makes the borrow unique
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl Drop for TapeFile {
+    fn drop(&mut self) {
+        let _ = self.flush();
+        #[cfg(unix)]
+        // SAFETY: `ptr`/`len` describe the mapping created in `open`
+        unsafe {
+            munmap(self.ptr as *mut c_void, self.len);
+        }
+        let _ = fs::remove_file(&self.lock);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_tape_file() {
+    use crate::{
+        engine::{Engine, ExecContext, Interpreter},
+        program::Program,
+    };
+
+    let path = std::env::temp_dir().join(format!("bfjit-test-{}.tape", std::process::id()));
+    let _ = fs::remove_file(&path);
+    assert_eq!(parse_spec("a:b.tape:64"), Some(("a:b.tape", Some(64))));
+    assert_eq!(parse_spec("a:b.tape"), Some(("a:b.tape", None)));
+    assert_eq!(parse_spec(":64"), None);
+    let err = TapeFile::open(&path, None).err().unwrap();
+    assert_eq!(
+        err.kind(),
+        io::ErrorKind::NotFound,
+        "no size for a new file"
+    );
+    assert!(!path.exists(), "nor is an empty one left behind");
+
+    // each run bumps the counter in cell 1 and leaves it there
+    let program = Program::compile(">+").unwrap();
+    for expected in 1..=3 {
+        let mut tape = TapeFile::open(&path, Some(16)).unwrap();
+        assert!(TapeFile::open(&path, Some(16)).is_err(), "tape is locked");
+        let (mut input, mut output) = (&b""[..], vec![]);
+        let mut ctx = ExecContext::new(&mut tape, &mut input, &mut output);
+        Interpreter.run(&program, &mut ctx).unwrap();
+        assert_eq!(tape[1], expected);
+    }
+    assert_eq!(fs::read(&path).unwrap()[..2], [0, 3]);
+
+    let err = TapeFile::open(&path, Some(8)).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    let tape = TapeFile::open(&path, None).unwrap();
+    assert_eq!(tape.len(), 16);
+    drop(tape);
+    fs::remove_file(&path).unwrap();
+}
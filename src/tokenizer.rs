@@ -1,210 +1,2934 @@
-use std::fmt;
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum Token {
-    IncrementData(u8),       // +
-    DecrementData(u8),       // -
-    IncrementPointer(usize), // >
-    DecrementPointer(usize), // <
-    Input,                   // ,
-    Output,                  // .
-    LoopStart(u32),          // [
-    LoopEnd(u32),            // ]
-}
-
-#[derive(Debug, thiserror::Error)]
-pub enum TokenizerErrorKind {
-    #[error("Unclose left bracket")]
-    UncloseLeftBracket,
-
-    #[error("Unclose right bracket")]
-    UncloseRightBracket,
-}
-
-#[derive(Debug)]
-pub struct TokenizerError {
-    line: i32,
-    col: i32,
-    kind: TokenizerErrorKind,
-}
-
-impl fmt::Display for TokenizerError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} at line {}:{}", self.kind, self.line, self.col)
-    }
-}
-impl std::error::Error for TokenizerError {}
-
-pub fn tokenizer(src: &str) -> Result<Vec<Token>, TokenizerError> {
-    let mut ir: Vec<Token> = vec![];
-    let mut stk: Vec<(u32, i32, i32)> = vec![];
-    let mut line: i32 = 1;
-    let mut col: i32 = 0;
-    let mut pc = 0;
-
-    for chr in src.chars() {
-        match chr {
-            '\n' => {
-                // new line
-                line += 1;
-                col = 0;
-            }
-            '+' => ir.push(Token::IncrementData(1)),
-            '-' => ir.push(Token::DecrementData(1)),
-            '>' => ir.push(Token::IncrementPointer(1)),
-            '<' => ir.push(Token::DecrementPointer(1)),
-            ',' => ir.push(Token::Input),
-            '.' => ir.push(Token::Output),
-            '[' => {
-                stk.push((pc, line, col));
-                ir.push(Token::LoopStart(0));
-            }
-            ']' => {
-                let (org, _, _) = stk.pop().ok_or(TokenizerError {
-                    line,
-                    col,
-                    kind: TokenizerErrorKind::UncloseLeftBracket,
-                })?;
-                ir.push(Token::LoopEnd(org));
-                if ir.get(org as usize) == Some(&Token::LoopStart(0)) {
-                    ir[org as usize] = Token::LoopStart(pc);
-                }
-            }
-
-            _ => {}
-        }
-        pc = ir.len() as u32;
-    }
-
-    if let Some((_, line, col)) = stk.pop() {
-        return Err(TokenizerError {
-            line,
-            col,
-            kind: TokenizerErrorKind::UncloseRightBracket,
-        });
-    }
-    Ok(ir)
-}
-
-pub fn optimize(tokens: &mut Vec<Token>) {
-    let mut observer = 0;
-    let mut writer = 0;
-    let len = tokens.len();
-
-    let mut stk: Vec<usize> = vec![];
-
-    macro_rules! _flod_ir {
-        ($var:ident, $x:ident) => {{
-            let mut j = observer + 1;
-            while j < len {
-                if let $var(d) = tokens[j] {
-                    $x = $x.wrapping_add(d);
-                } else {
-                    break;
-                }
-                j += 1;
-            }
-            observer = j;
-            tokens[writer] = $var($x);
-            writer += 1;
-        }};
-    }
-
-    macro_rules! _normal_ir {
-        () => {{
-            tokens[writer] = tokens[observer];
-            writer += 1;
-            observer += 1;
-        }};
-    }
-
-    macro_rules! _loop_start_ir {
-        () => {{
-            stk.push(writer);
-            tokens[writer] = Token::LoopStart(0);
-            writer += 1;
-            observer += 1;
-        }};
-    }
-
-    macro_rules! _loop_end_ir {
-        () => {{
-            let org: usize = stk.pop().unwrap();
-            if tokens.get(org) == Some(&Token::LoopStart(0)) {
-                tokens[org] = Token::LoopStart(writer as u32);
-            }
-            tokens[writer] = Token::LoopEnd(org as u32);
-            writer += 1;
-            observer += 1;
-        }};
-    }
-
-    use Token::*;
-    while observer < len {
-        match tokens[observer] {
-            IncrementData(mut x) => _flod_ir!(IncrementData, x),
-            DecrementData(mut x) => _flod_ir!(DecrementData, x),
-            IncrementPointer(mut x) => _flod_ir!(IncrementPointer, x),
-            DecrementPointer(mut x) => _flod_ir!(DecrementPointer, x),
-            Input => _normal_ir!(),
-            Output => _normal_ir!(),
-            LoopStart(_) => _loop_start_ir!(),
-            LoopEnd(_) => _loop_end_ir!(),
-        }
-    }
-    tokens.truncate(writer);
-    tokens.shrink_to_fit();
-}
-
-#[test]
-fn test_compile() {
-    assert_eq!(
-        tokenizer("+[,.]").unwrap(),
-        vec![
-            Token::IncrementData(1),
-            Token::LoopStart(4),
-            Token::Input,
-            Token::Output,
-            Token::LoopEnd(1),
-        ]
-    );
-
-    assert_eq!(
-        tokenizer(
-            "[arst]+[,.  
-        +]"
-        )
-        .unwrap(),
-        vec![
-            Token::LoopStart(1),
-            Token::LoopEnd(0),
-            Token::IncrementData(1),
-            Token::LoopStart(7),
-            Token::Input,
-            Token::Output,
-            Token::IncrementData(1),
-            Token::LoopEnd(3),
-        ]
-    );
-
-    match tokenizer("]").unwrap_err().kind {
-        TokenizerErrorKind::UncloseLeftBracket => {}
-        _ => panic!(),
-    }
-
-    match tokenizer("[").unwrap_err().kind {
-        TokenizerErrorKind::UncloseRightBracket => {}
-        _ => panic!(),
-    }
-
-    let mut token = tokenizer("[++++++]").unwrap();
-    optimize(&mut token);
-    assert_eq!(
-        token,
-        vec![
-            Token::LoopStart(2),
-            Token::IncrementData(6),
-            Token::LoopEnd(0),
-        ]
-    )
-}
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, BufRead, Read},
+    thread,
+};
+
+use crate::{error::ErrorCategory, vm::CellOverflow};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Token {
+    IncrementData(u8),       // +
+    DecrementData(u8),       // -
+    IncrementPointer(usize), // >
+    DecrementPointer(usize), // <
+    Input,                   // ,
+    Output,                  // .
+    OutputRepeat(usize),     // a run of . with nothing in between
+    Print(u8),               // . of a cell known to hold this value
+    LoopStart(u32),          // [
+    LoopEnd(u32),            // ]
+    IfStart(u32),            // [ of a loop that runs at most once
+    IfEnd(u32),              // ] of the same, without a back-edge
+    // zero `len` cells from `start_offset` relative to the pointer
+    ClearRange { start_offset: i32, len: u32 },
+    // add the current cell times `factor` to the cell `offset` away, the
+    // body of a copy or multiply loop; nothing when the current cell is zero
+    MulAdd { offset: i32, factor: u8 },
+    // add `value` to the cell `offset` away without moving there, a run of
+    // `+` or `-` between moves; above 127 it subtracts, as with `MulAdd`
+    AddAt { offset: i32, value: u8 },
+    // move by this many cells until the pointer rests on a zero cell, the
+    // whole of a scan loop like `[>]` or `[<<]`
+    ScanRight(usize),
+    ScanLeft(usize),
+    Ext(ExtOp), // a command of `Dialect::Ebf1`
+    Breakpoint, // # of `Dialect::Debug`, hands control back to the caller
+}
+
+/// Which characters are commands.
+///
+/// `Ook` and `Custom` spell the eight commands with words instead, and are
+/// lexed a word at a time into the same ops, each at the position of its
+/// first character.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Dialect {
+    #[default]
+    Standard, // the eight commands
+    Ebf1,  // Extended Brainfuck Type I, the eight plus `ExtOp`
+    Debug, // the eight plus `#`, a breakpoint for `VM::run` to stop at
+    Ook,   // Ook!, each command a pair of `Ook.`, `Ook?` and `Ook!`
+    // each command a word of the map; a map usually lives for the whole run,
+    // and borrowing it keeps `Dialect` `Copy`
+    Custom(&'static WordMap),
+}
+
+impl Dialect {
+    /// Parse the spelling used by `--dialect=`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "bf" => Some(Dialect::Standard),
+            "ebf1" => Some(Dialect::Ebf1),
+            "debug" => Some(Dialect::Debug),
+            "ook" => Some(Dialect::Ook),
+            _ => None,
+        }
+    }
+
+    /// Whether `chr` can be part of a command; `!` of `Ook!`, say.
+    pub fn uses(self, chr: char) -> bool {
+        match self {
+            Dialect::Ook => "Ook.?!".contains(chr),
+            Dialect::Custom(map) => map.words.iter().any(|word| word.contains(chr)),
+            _ => command(chr, self).is_some(),
+        }
+    }
+
+    fn words(self) -> bool {
+        matches!(self, Dialect::Ook | Dialect::Custom(_))
+    }
+}
+
+// the eight commands in the order `WordMap` keeps their words
+const COMMANDS: [char; 8] = ['+', '-', '>', '<', ',', '.', '[', ']'];
+
+// the Ook! pair for each of `COMMANDS`
+const OOK: [(u8, u8); 8] = [
+    (b'.', b'.'),
+    (b'!', b'!'),
+    (b'.', b'?'),
+    (b'?', b'.'),
+    (b'.', b'!'),
+    (b'!', b'.'),
+    (b'!', b'?'),
+    (b'?', b'!'),
+];
+
+/// A word for each of the eight commands, for `Dialect::Custom`.
+///
+/// Its file form has a line per command, the command and then its word;
+/// blank lines and lines starting with `#` are skipped:
+///
+/// ```text
+/// + plus
+/// - minus
+/// ```
+///
+/// Text between words is comment. Where two words could start at the same
+/// place the longer one is taken.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WordMap {
+    words: [String; 8],
+}
+
+impl WordMap {
+    pub fn parse(text: &str) -> Result<Self, TokenizerError> {
+        let mut words: [Option<String>; 8] = Default::default();
+        let mut last = 0;
+        for (i, line) in text.lines().enumerate() {
+            let line_no = i as i32 + 1;
+            let fail = |col: usize, why| TokenizerError {
+                line: line_no,
+                col: col as i32 + 1,
+                kind: TokenizerErrorKind::BadWordMap(why),
+                hint: None,
+            };
+            let trimmed = line.trim_start();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+            let col = line.len() - trimmed.len();
+            let mut chars = trimmed.chars();
+            let chr = chars.next().unwrap();
+            let Some(at) = COMMANDS.iter().position(|&c| c == chr) else {
+                return Err(fail(col, "not one of the eight commands"));
+            };
+            let word = chars.as_str().trim();
+            if word.is_empty() {
+                return Err(fail(col, "no word"));
+            }
+            if words[at].is_some() {
+                return Err(fail(col, "command given twice"));
+            }
+            if words.iter().flatten().any(|w| w == word) {
+                return Err(fail(col, "word given twice"));
+            }
+            words[at] = Some(word.to_string());
+            last = line_no;
+        }
+        if words.iter().any(Option::is_none) {
+            return Err(TokenizerError {
+                line: last,
+                col: 0,
+                kind: TokenizerErrorKind::BadWordMap("a command has no word"),
+                hint: None,
+            });
+        }
+        Ok(WordMap {
+            words: words.map(Option::unwrap),
+        })
+    }
+
+    /// The word for `command`, one of the eight.
+    pub fn word(&self, command: char) -> Option<&str> {
+        let at = COMMANDS.iter().position(|&c| c == command)?;
+        Some(&self.words[at])
+    }
+}
+
+/// The extra commands of Extended Brainfuck Type I.
+///
+/// They work on the current cell and a single storage register, which
+/// starts at zero. Shifts are logical and drop the bits shifted out. The
+/// optimizer knows nothing about them beyond where they are: every pass
+/// keeps them in order and forgets all it knew about the tape at each one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtOp {
+    Halt,       // @ end the program
+    Store,      // $ copy the cell into storage
+    Load,       // ! copy storage into the cell
+    ShiftLeft,  // {
+    ShiftRight, // }
+    Not,        // ~
+    Xor,        // ^ with storage
+    And,        // & with storage
+    Or,         // | with storage
+}
+
+impl ExtOp {
+    pub const ALL: [ExtOp; 9] = [
+        ExtOp::Halt,
+        ExtOp::Store,
+        ExtOp::Load,
+        ExtOp::ShiftLeft,
+        ExtOp::ShiftRight,
+        ExtOp::Not,
+        ExtOp::Xor,
+        ExtOp::And,
+        ExtOp::Or,
+    ];
+
+    pub fn command(self) -> char {
+        match self {
+            ExtOp::Halt => '@',
+            ExtOp::Store => '$',
+            ExtOp::Load => '!',
+            ExtOp::ShiftLeft => '{',
+            ExtOp::ShiftRight => '}',
+            ExtOp::Not => '~',
+            ExtOp::Xor => '^',
+            ExtOp::And => '&',
+            ExtOp::Or => '|',
+        }
+    }
+
+    /// The mnemonic used in IR text.
+    pub fn name(self) -> &'static str {
+        match self {
+            ExtOp::Halt => "halt",
+            ExtOp::Store => "store",
+            ExtOp::Load => "load",
+            ExtOp::ShiftLeft => "shl",
+            ExtOp::ShiftRight => "shr",
+            ExtOp::Not => "not",
+            ExtOp::Xor => "xor",
+            ExtOp::And => "and",
+            ExtOp::Or => "or",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        ExtOp::ALL.into_iter().find(|op| op.name() == name)
+    }
+
+    /// The new value of a cell holding `cell`, or `None` for `Halt`.
+    pub fn apply(self, cell: u8, storage: &mut u8) -> Option<u8> {
+        Some(match self {
+            ExtOp::Halt => return None,
+            ExtOp::Store => {
+                *storage = cell;
+                cell
+            }
+            ExtOp::Load => *storage,
+            ExtOp::ShiftLeft => cell << 1,
+            ExtOp::ShiftRight => cell >> 1,
+            ExtOp::Not => !cell,
+            ExtOp::Xor => cell ^ *storage,
+            ExtOp::And => cell & *storage,
+            ExtOp::Or => cell | *storage,
+        })
+    }
+}
+
+impl Token {
+    pub fn is_block_start(&self) -> bool {
+        matches!(self, Token::LoopStart(_) | Token::IfStart(_))
+    }
+
+    pub fn is_block_end(&self) -> bool {
+        matches!(self, Token::LoopEnd(_) | Token::IfEnd(_))
+    }
+
+    /// The same block token jumping to `target`; other tokens are unchanged.
+    pub fn with_target(self, target: u32) -> Token {
+        match self {
+            Token::LoopStart(_) => Token::LoopStart(target),
+            Token::LoopEnd(_) => Token::LoopEnd(target),
+            Token::IfStart(_) => Token::IfStart(target),
+            Token::IfEnd(_) => Token::IfEnd(target),
+            token => token,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum TokenizerErrorKind {
+    #[error("Unclose left bracket")]
+    UncloseLeftBracket,
+
+    #[error("Unclose right bracket")]
+    UncloseRightBracket,
+
+    #[error("Program too large, jump targets past {} instructions", u32::MAX)]
+    ProgramTooLarge,
+
+    #[error("Read failed: {0}")]
+    Read(io::Error),
+
+    #[error("Bad word map, {0}")]
+    BadWordMap(&'static str),
+}
+
+impl TokenizerErrorKind {
+    pub fn code(&self) -> &'static str {
+        match self {
+            TokenizerErrorKind::UncloseLeftBracket => "E0101",
+            TokenizerErrorKind::UncloseRightBracket => "E0102",
+            TokenizerErrorKind::ProgramTooLarge => "E0103",
+            TokenizerErrorKind::Read(_) => "E0104",
+            TokenizerErrorKind::BadWordMap(_) => "E0105",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TokenizerError {
+    line: i32,
+    col: i32,
+    kind: TokenizerErrorKind,
+    hint: Option<Span>, // bracket that is more likely the real mistake
+}
+
+/// The form `--dump-ir` lists: a mnemonic and its operands, block tokens
+/// with the index they jump to, like `ADD -3` or `JZ -> 0007`.
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Token::IncrementData(x) => write!(f, "ADD {}", x),
+            Token::DecrementData(x) => write!(f, "ADD -{}", x),
+            Token::IncrementPointer(x) => write!(f, "MOVE {}", x),
+            Token::DecrementPointer(x) => write!(f, "MOVE -{}", x),
+            Token::ScanRight(x) => write!(f, "SCAN {}", x),
+            Token::ScanLeft(x) => write!(f, "SCAN -{}", x),
+            Token::Input => write!(f, "IN"),
+            Token::Output => write!(f, "OUT"),
+            Token::OutputRepeat(n) => write!(f, "OUT {}", n),
+            Token::Print(byte) => write!(f, "PRINT {}", byte),
+            Token::ClearRange { start_offset, len } => write!(f, "CLEAR {} {}", start_offset, len),
+            Token::MulAdd { offset, factor } => write!(f, "MULADD {} {}", offset, factor),
+            Token::AddAt { offset, value } => write!(f, "ADDAT {} {}", offset, value),
+            Token::Ext(op) => write!(f, "EXT {}", op.name().to_ascii_uppercase()),
+            Token::Breakpoint => write!(f, "BREAK"),
+            Token::LoopStart(target) => write!(f, "JZ -> {:04}", target),
+            Token::LoopEnd(target) => write!(f, "JNZ -> {:04}", target),
+            Token::IfStart(target) => write!(f, "IF -> {:04}", target),
+            // nothing jumps back, the start is named for reading
+            Token::IfEnd(start) => write!(f, "ENDIF <- {:04}", start),
+        }
+    }
+}
+
+impl fmt::Display for TokenizerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} at line {}:{}",
+            self.kind.code(),
+            self.kind,
+            self.line,
+            self.col
+        )?;
+        match self.hint {
+            Some(hint) => write!(f, " (hint: check the bracket at line {})", hint),
+            None => Ok(()),
+        }
+    }
+}
+impl std::error::Error for TokenizerError {}
+
+impl TokenizerError {
+    pub fn line(&self) -> i32 {
+        self.line
+    }
+
+    pub fn col(&self) -> i32 {
+        self.col
+    }
+
+    pub fn kind(&self) -> &TokenizerErrorKind {
+        &self.kind
+    }
+
+    /// An advisory guess at the bracket that is actually wrong, when it is
+    /// not the one reported; see `bracket_hint`.
+    pub fn hint(&self) -> Option<Span> {
+        self.hint
+    }
+
+    pub fn code(&self) -> &'static str {
+        self.kind.code()
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        match self.kind {
+            TokenizerErrorKind::Read(_) => ErrorCategory::Io,
+            _ => ErrorCategory::Compile,
+        }
+    }
+}
+
+/// Source position of a token, 1-based.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    pub line: i32,
+    pub col: i32,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+/// A command character and the position it was read from, before brackets
+/// are linked. Loop tokens carry a zero target at this stage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawOp {
+    pub token: Token,
+    pub span: Span,
+}
+
+/// A file without its `#!` first line, so it can be run as a script. The
+/// line's newline is kept, which leaves every later op on its own line.
+pub fn strip_shebang(src: &str) -> &str {
+    // the cut is at a newline or the end, both char boundaries
+    &src[src.len() - strip_shebang_bytes(src.as_bytes()).len()..]
+}
+
+/// `strip_shebang` for source that need not be UTF-8.
+pub fn strip_shebang_bytes(src: &[u8]) -> &[u8] {
+    match src.strip_prefix(b"#!") {
+        Some(rest) => &rest[rest.iter().position(|&b| b == b'\n').unwrap_or(rest.len())..],
+        None => src,
+    }
+}
+
+/// Source that carries its own input, for `--bang-input`: the program before
+/// the first `!` outside any brackets and the bytes after it, `None` without
+/// one. A `!` inside a loop, a leading comment loop included, is comment:
+/// splitting there would leave a `[` the program never closes.
+pub fn split_bang(src: &str) -> (&str, Option<&[u8]>) {
+    let (program, input) = split_bang_bytes(src.as_bytes());
+    (&src[..program.len()], input)
+}
+
+/// `split_bang` for source that need not be UTF-8.
+pub fn split_bang_bytes(src: &[u8]) -> (&[u8], Option<&[u8]>) {
+    let mut depth = 0_usize;
+    for (i, &byte) in src.iter().enumerate() {
+        match byte {
+            b'[' => depth += 1,
+            b']' => depth = depth.saturating_sub(1),
+            b'!' if depth == 0 => return (&src[..i], Some(&src[i + 1..])),
+            _ => {}
+        }
+    }
+    (src, None)
+}
+
+pub fn lex(src: &str) -> Vec<RawOp> {
+    lex_dialect(src, Dialect::Standard)
+}
+
+/// `lex`, also taking the extra commands of `dialect`.
+pub fn lex_dialect(src: &str, dialect: Dialect) -> Vec<RawOp> {
+    let mut ops: Vec<RawOp> = vec![];
+    lex_each(src, dialect, Span { line: 1, col: 0 }, |_, op| ops.push(op));
+    ops
+}
+
+/// Lex `src` as if it continued a text whose last character was at `from`,
+/// calling `f` with the byte offset of each op. Returns the position of the
+/// last character, so a text can be lexed piecewise; a text starts from
+/// line 1, column 0.
+pub fn lex_each(src: &str, dialect: Dialect, from: Span, mut f: impl FnMut(usize, RawOp)) -> Span {
+    if dialect.words() {
+        return lex_words(src, dialect, from, f);
+    }
+    let Span { mut line, mut col } = from;
+
+    for (offset, chr) in src.char_indices() {
+        if chr == '\n' {
+            // new line
+            line += 1;
+            col = 0;
+            continue;
+        }
+        col += 1;
+        if let Some(token) = command(chr, dialect) {
+            let span = Span { line, col };
+            f(offset, RawOp { token, span });
+        }
+    }
+    Span { line, col }
+}
+
+// `lex_each` for the dialects spelled in words. A piece lexed on its own
+// must not end inside a word.
+fn lex_words(src: &str, dialect: Dialect, from: Span, mut f: impl FnMut(usize, RawOp)) -> Span {
+    let Span { mut line, mut col } = from;
+    let mut order: Vec<usize> = (0..COMMANDS.len()).collect();
+    if let Dialect::Custom(map) = dialect {
+        order.sort_by_key(|&at| std::cmp::Reverse(map.words[at].len()));
+    }
+    let mut skip = 0; // end of the word last matched
+    let mut first: Option<(usize, Span, u8)> = None; // half of an Ook! pair
+    for (offset, chr) in src.char_indices() {
+        if chr == '\n' {
+            line += 1;
+            col = 0;
+            continue;
+        }
+        col += 1;
+        if offset < skip {
+            continue;
+        }
+        let rest = &src[offset..];
+        let span = Span { line, col };
+        let matched = match dialect {
+            Dialect::Custom(map) => order
+                .iter()
+                .find(|&&at| rest.starts_with(map.words[at].as_str()))
+                .map(|&at| (at, map.words[at].len())),
+            _ => match rest.as_bytes() {
+                [b'O', b'o', b'k', mark @ (b'.' | b'?' | b'!'), ..] => {
+                    skip = offset + 4;
+                    match first.take() {
+                        None => first = Some((offset, span, *mark)),
+                        // `Ook? Ook?` is no command, and comment
+                        Some((start, span, half)) => {
+                            if let Some(at) = OOK.iter().position(|&pair| pair == (half, *mark)) {
+                                let token = command(COMMANDS[at], Dialect::Standard).unwrap();
+                                f(start, RawOp { token, span });
+                            }
+                        }
+                    }
+                    None
+                }
+                _ => None,
+            },
+        };
+        if let Some((at, len)) = matched {
+            skip = offset + len;
+            let token = command(COMMANDS[at], Dialect::Standard).unwrap();
+            f(offset, RawOp { token, span });
+        }
+    }
+    Span { line, col }
+}
+
+// the token `chr` stands for in `dialect`, if it is a command
+fn command(chr: char, dialect: Dialect) -> Option<Token> {
+    let token = match chr {
+        '+' => Token::IncrementData(1),
+        '-' => Token::DecrementData(1),
+        '>' => Token::IncrementPointer(1),
+        '<' => Token::DecrementPointer(1),
+        ',' => Token::Input,
+        '.' => Token::Output,
+        '[' => Token::LoopStart(0),
+        ']' => Token::LoopEnd(0),
+        '#' if dialect == Dialect::Debug => Token::Breakpoint,
+        _ if dialect == Dialect::Ebf1 => {
+            Token::Ext(ExtOp::ALL.into_iter().find(|op| op.command() == chr)?)
+        }
+        _ => return None,
+    };
+    Some(token)
+}
+
+// bytes `lex_reader` takes from its reader at a time
+const READ_CHUNK: usize = 64 * 1024;
+
+/// `lex_dialect` over whatever `reader` yields, a buffer at a time, so the
+/// source is never held whole. Every command is ASCII, so any other byte is
+/// comment, UTF-8 or not. Columns still count characters: a byte that
+/// continues a UTF-8 sequence does not start a new one.
+pub fn lex_reader(reader: impl Read, dialect: Dialect) -> Result<Vec<RawOp>, TokenizerError> {
+    let mut reader = io::BufReader::with_capacity(READ_CHUNK, reader);
+    if dialect.words() {
+        // a word can straddle two buffers, so these are read whole
+        let mut bytes = vec![];
+        if let Err(e) = reader.read_to_end(&mut bytes) {
+            return Err(TokenizerError {
+                line: 1,
+                col: 0,
+                kind: TokenizerErrorKind::Read(e),
+                hint: None,
+            });
+        }
+        return Ok(lex_dialect(&String::from_utf8_lossy(&bytes), dialect));
+    }
+    let mut ops = vec![];
+    let (mut line, mut col) = (1, 0);
+    loop {
+        let chunk = match reader.fill_buf() {
+            Ok([]) => return Ok(ops),
+            Ok(chunk) => chunk,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                return Err(TokenizerError {
+                    line,
+                    col,
+                    kind: TokenizerErrorKind::Read(e),
+                    hint: None,
+                })
+            }
+        };
+        for &byte in chunk {
+            match byte {
+                b'\n' => (line, col) = (line + 1, 0),
+                0x80..=0xbf => {}
+                _ => {
+                    col += 1;
+                    let token = byte.is_ascii().then(|| command(byte as char, dialect));
+                    if let Some(token) = token.flatten() {
+                        let span = Span { line, col };
+                        ops.push(RawOp { token, span });
+                    }
+                }
+            }
+        }
+        let len = chunk.len();
+        reader.consume(len);
+    }
+}
+
+/// `lex_dialect` over `threads` pieces of `src` at once.
+///
+/// Each worker lexes its piece as if it started a text of its own, on line
+/// 0; the merge then moves every span to where its piece really starts. Ops
+/// on a piece's first line continue the column of the piece before it, the
+/// rest only move down. Brackets are left to `link`, which runs on the whole
+/// result, so the ops are exactly those of `lex_dialect`.
+pub fn lex_parallel(src: &str, dialect: Dialect, threads: usize) -> Vec<RawOp> {
+    // a cut could fall inside a word
+    if dialect.words() {
+        return lex_dialect(src, dialect);
+    }
+    let mut cuts = vec![0];
+    for i in 1..threads.max(1) {
+        let mut at = (src.len() * i / threads).max(cuts[i - 1]);
+        while !src.is_char_boundary(at) {
+            at += 1;
+        }
+        cuts.push(at);
+    }
+    cuts.push(src.len());
+    let pieces: Vec<(Vec<RawOp>, Span)> = thread::scope(|scope| {
+        let handles: Vec<_> = cuts
+            .windows(2)
+            .map(|w| {
+                let piece = &src[w[0]..w[1]];
+                scope.spawn(move || {
+                    let mut ops = vec![];
+                    let end = lex_each(piece, dialect, Span { line: 0, col: 0 }, |_, op| {
+                        ops.push(op)
+                    });
+                    (ops, end)
+                })
+            })
+            .collect();
+        let pieces = handles
+            .into_iter()
+            .map(|h| h.join().expect("lexer panicked"));
+        pieces.collect()
+    });
+
+    let mut ops = Vec::with_capacity(pieces.iter().map(|(ops, _)| ops.len()).sum());
+    let mut at = Span { line: 1, col: 0 };
+    for (piece, end) in pieces {
+        ops.extend(piece.into_iter().map(|mut op| {
+            if op.span.line == 0 {
+                op.span.col += at.col;
+            }
+            op.span.line += at.line;
+            op
+        }));
+        at = match end.line {
+            0 => Span {
+                line: at.line,
+                col: at.col + end.col,
+            },
+            n => Span {
+                line: at.line + n,
+                col: end.col,
+            },
+        };
+    }
+    ops
+}
+
+/// Block targets are stored as `u32` to keep tokens small, so no block token
+/// may sit past this index. Linking reports `ProgramTooLarge` instead.
+pub const MAX_INSTRUCTIONS: usize = u32::MAX as usize;
+
+// the target for a block token at `pc`, if it fits
+fn jump_target(pc: usize) -> Option<u32> {
+    u32::try_from(pc).ok()
+}
+
+// shared by `link` and `link_recover`; `errors` being `None` means strict
+fn link_ops(
+    ops: &[RawOp],
+    mut errors: Option<&mut Vec<TokenizerError>>,
+) -> Result<Vec<Token>, TokenizerError> {
+    let mut ir: Vec<Token> = Vec::with_capacity(ops.len());
+    let mut stk: Vec<(u32, i32, i32)> = vec![];
+
+    let too_large = |span: Span| TokenizerError {
+        line: span.line,
+        col: span.col,
+        kind: TokenizerErrorKind::ProgramTooLarge,
+        hint: None,
+    };
+
+    for op in ops {
+        let Some(pc) = jump_target(ir.len()) else {
+            return Err(too_large(op.span));
+        };
+        match op.token {
+            Token::LoopStart(_) => {
+                stk.push((pc, op.span.line, op.span.col));
+                ir.push(Token::LoopStart(0));
+            }
+            Token::LoopEnd(_) => {
+                let Some((org, _, _)) = stk.pop() else {
+                    let err = TokenizerError {
+                        line: op.span.line,
+                        col: op.span.col,
+                        kind: TokenizerErrorKind::UncloseLeftBracket,
+                        hint: None,
+                    };
+                    match errors.as_mut() {
+                        // drop the stray bracket and keep going
+                        Some(errors) => errors.push(err),
+                        None => return Err(err),
+                    }
+                    continue;
+                };
+                ir.push(Token::LoopEnd(org));
+                ir[org as usize] = Token::LoopStart(pc);
+            }
+            token => ir.push(token),
+        }
+    }
+
+    while let Some((org, line, col)) = stk.pop() {
+        let err = TokenizerError {
+            line,
+            col,
+            kind: TokenizerErrorKind::UncloseRightBracket,
+            hint: None,
+        };
+        match errors.as_mut() {
+            // close the loop virtually at the end of the program
+            Some(errors) => errors.push(err),
+            None => return Err(err),
+        }
+        let Some(pc) = jump_target(ir.len()) else {
+            return Err(too_large(Span { line, col }));
+        };
+        ir.push(Token::LoopEnd(org));
+        ir[org as usize] = Token::LoopStart(pc);
+    }
+    Ok(ir)
+}
+
+pub fn link(ops: &[RawOp]) -> Result<Vec<Token>, TokenizerError> {
+    link_ops(ops, None).map_err(|mut err| {
+        if !matches!(err.kind, TokenizerErrorKind::ProgramTooLarge) {
+            let reported = Span {
+                line: err.line,
+                col: err.col,
+            };
+            err.hint = bracket_hint(ops).filter(|&hint| hint != reported);
+        }
+        err
+    })
+}
+
+/// Guess which bracket is really wrong in an unbalanced program.
+///
+/// Strict matching blames whichever bracket is left over, often far from
+/// the mistake. This pass matches by indentation instead, taking the column
+/// of a line's first command as its indent: a block opened on a line ends
+/// at the next line indented no deeper, which should start with its `]`.
+///
+/// ```text
+/// +[
+///     ++[        <- hint: this block ends below without a `]`
+///         -
+///     >+
+/// ]              <- closes the inner loop, so strict matching blames `+[`
+/// ```
+///
+/// A `]` that finds nothing to close is blamed itself, unless it lines up
+/// with a block that an earlier `]` at the end of a line closed early.
+fn bracket_hint(ops: &[RawOp]) -> Option<Span> {
+    // (indent of the opening line, position of the `[`)
+    let mut open: Vec<(i32, Span)> = vec![];
+    // the last block closed by a `]` that did not start its line
+    let mut closed_inline: Option<(i32, Span)> = None;
+
+    for line in ops.chunk_by(|a, b| a.span.line == b.span.line) {
+        let indent = line[0].span.col;
+        let leads_close = matches!(line[0].token, Token::LoopEnd(_));
+        if let Some(&(top, span)) = open.last() {
+            if indent < top || (indent == top && !leads_close) {
+                return Some(span);
+            }
+        }
+
+        let mut local = 0;
+        for (i, op) in line.iter().enumerate() {
+            match op.token {
+                Token::LoopStart(_) => {
+                    local += 1;
+                    open.push((indent, op.span));
+                }
+                Token::LoopEnd(_) if local > 0 => {
+                    local -= 1;
+                    open.pop();
+                }
+                Token::LoopEnd(_) => match open.pop() {
+                    Some(block) if i > 0 => closed_inline = Some((block.0, op.span)),
+                    Some(_) => {}
+                    None => {
+                        return match closed_inline {
+                            Some((at, span)) if i == 0 && at == indent => Some(span),
+                            _ => Some(op.span),
+                        };
+                    }
+                },
+                _ => {}
+            }
+        }
+    }
+    open.last().map(|&(_, span)| span)
+}
+
+/// Link brackets without failing, for tooling that works on code mid-edit.
+///
+/// A `]` with no open loop is dropped and every `[` still open at the end is
+/// closed there; each repair is reported in the returned diagnostics. The
+/// VM's own loading paths always link strictly, so recovered IR only runs
+/// when a caller hands it to `VM::new` explicitly. A program too large to
+/// link at all comes back empty with `ProgramTooLarge` as the last error.
+pub fn link_recover(ops: &[RawOp]) -> (Vec<Token>, Vec<TokenizerError>) {
+    let mut errors = vec![];
+    match link_ops(ops, Some(&mut errors)) {
+        Ok(ir) => (ir, errors),
+        Err(err) => {
+            errors.push(err);
+            (vec![], errors)
+        }
+    }
+}
+
+pub fn tokenizer(src: &str) -> Result<Vec<Token>, TokenizerError> {
+    tokenizer_from_reader(src.as_bytes())
+}
+
+/// `tokenizer` for source read from `reader`; see `lex_reader`.
+pub fn tokenizer_from_reader<R: Read>(reader: R) -> Result<Vec<Token>, TokenizerError> {
+    link(&lex_reader(reader, Dialect::Standard)?)
+}
+
+pub fn tokenizer_recover(src: &str) -> (Vec<Token>, Vec<TokenizerError>) {
+    link_recover(&lex(src))
+}
+
+/// Check that every block token points at its matching partner.
+///
+/// Returns the index of the first instruction that breaks the structure.
+pub fn verify(tokens: &[Token]) -> Result<(), usize> {
+    let mut stk: Vec<usize> = vec![];
+    for (pc, token) in tokens.iter().enumerate() {
+        let org = match *token {
+            Token::LoopStart(_) | Token::IfStart(_) => {
+                stk.push(pc);
+                continue;
+            }
+            Token::LoopEnd(org) => (org, Token::LoopStart(pc as u32)),
+            Token::IfEnd(org) => (org, Token::IfStart(pc as u32)),
+            _ => continue,
+        };
+        if stk.pop() != Some(org.0 as usize) {
+            return Err(pc);
+        }
+        if tokens[org.0 as usize] != org.1 {
+            return Err(org.0 as usize);
+        }
+    }
+    match stk.pop() {
+        Some(pc) => Err(pc),
+        None => Ok(()),
+    }
+}
+
+pub fn optimize(tokens: &mut Vec<Token>) {
+    let mut spans = vec![Span::default(); tokens.len()];
+    optimize_spanned(tokens, &mut spans, CellOverflow::Wrap);
+}
+
+/// `optimize`, keeping the parallel `spans` in step with the tokens.
+///
+/// Under `CellOverflow::Error` a run of `+` and `-` only folds while it goes
+/// one way, into adds of at most 255, so each overflow still fails where it
+/// would unfolded.
+pub fn optimize_spanned(tokens: &mut Vec<Token>, spans: &mut Vec<Span>, overflow: CellOverflow) {
+    let mut observer = 0;
+    let mut writer = 0;
+    let len = tokens.len();
+
+    let mut stk: Vec<usize> = vec![];
+
+    // a run of `+` and `-`, or of `>` and `<`, as one token for its net
+    // effect, taken together with the token written last when that is one
+    // of the pair too; nothing when it nets out, so `+-` and `>+-<` vanish
+    macro_rules! _flod_ir {
+        ($up:ident, $down:ident, $narrow:expr) => {{
+            let mut net: i128 = 0;
+            let mut span = spans[observer];
+            if let Some(&last) = tokens[..writer].last() {
+                let merged = match last {
+                    $up(d) => Some(d as i128),
+                    $down(d) => Some(-(d as i128)),
+                    _ => None,
+                };
+                if let Some(d) = merged {
+                    net = d;
+                    writer -= 1;
+                    span = spans[writer];
+                }
+            }
+            let mut j = observer;
+            while j < len {
+                match tokens[j] {
+                    $up(d) => net += d as i128,
+                    $down(d) => net -= d as i128,
+                    _ => break,
+                }
+                j += 1;
+            }
+            // a net that wraps to nothing, like 256 `+`, is no command at all
+            let d = $narrow(net.unsigned_abs());
+            if d != 0 {
+                tokens[writer] = if net > 0 { $up(d) } else { $down(d) };
+                spans[writer] = span;
+                writer += 1;
+            }
+            observer = j;
+        }};
+    }
+
+    // `_flod_ir` for one direction of `+` or `-` and no further
+    macro_rules! _flod_checked_ir {
+        () => {{
+            let up = matches!(tokens[observer], IncrementData(_));
+            let (mut total, span) = (0_u64, spans[observer]);
+            let mut j = observer;
+            while j < len {
+                match tokens[j] {
+                    IncrementData(d) if up => total += d as u64,
+                    DecrementData(d) if !up => total += d as u64,
+                    _ => break,
+                }
+                j += 1;
+            }
+            while total > 0 {
+                let d = total.min(u8::MAX as u64) as u8;
+                tokens[writer] = if up {
+                    IncrementData(d)
+                } else {
+                    DecrementData(d)
+                };
+                spans[writer] = span;
+                writer += 1;
+                total -= d as u64;
+            }
+            observer = j;
+        }};
+    }
+
+    macro_rules! _normal_ir {
+        () => {{
+            tokens[writer] = tokens[observer];
+            spans[writer] = spans[observer];
+            writer += 1;
+            observer += 1;
+        }};
+    }
+
+    macro_rules! _loop_start_ir {
+        () => {{
+            stk.push(writer);
+            tokens[writer] = tokens[observer].with_target(0);
+            spans[writer] = spans[observer];
+            writer += 1;
+            observer += 1;
+        }};
+    }
+
+    macro_rules! _loop_end_ir {
+        () => {{
+            let org: usize = stk.pop().unwrap();
+            tokens[org] = tokens[org].with_target(writer as u32);
+            tokens[writer] = tokens[observer].with_target(org as u32);
+            spans[writer] = spans[observer];
+            writer += 1;
+            observer += 1;
+        }};
+    }
+
+    use Token::*;
+    while observer < len {
+        match tokens[observer] {
+            IncrementData(_) | DecrementData(_) if overflow == CellOverflow::Error => {
+                _flod_checked_ir!()
+            }
+            // cells wrap, and no move past the ends of the address space
+            // can be made
+            IncrementData(_) | DecrementData(_) => {
+                _flod_ir!(IncrementData, DecrementData, |d: u128| d as u8)
+            }
+            IncrementPointer(_) | DecrementPointer(_) => {
+                _flod_ir!(IncrementPointer, DecrementPointer, |d: u128| {
+                    usize::try_from(d).unwrap_or(usize::MAX)
+                })
+            }
+            Input => _normal_ir!(),
+            Output | OutputRepeat(_) => {
+                // nothing between the dots can change the cell
+                let mut n = 0;
+                let mut j = observer;
+                while j < len {
+                    match tokens[j] {
+                        Output => n += 1,
+                        OutputRepeat(k) => n += k,
+                        _ => break,
+                    }
+                    j += 1;
+                }
+                tokens[writer] = if n == 1 { Output } else { OutputRepeat(n) };
+                spans[writer] = spans[observer];
+                observer = j;
+                writer += 1;
+            }
+            LoopStart(_) | IfStart(_) => _loop_start_ir!(),
+            LoopEnd(_) | IfEnd(_) => _loop_end_ir!(),
+            ClearRange { .. }
+            | MulAdd { .. }
+            | AddAt { .. }
+            | ScanRight(_)
+            | ScanLeft(_)
+            | Print(_)
+            | Ext(_)
+            | Breakpoint => _normal_ir!(),
+        }
+    }
+    tokens.truncate(writer);
+    tokens.shrink_to_fit();
+    spans.truncate(writer);
+    spans.shrink_to_fit();
+}
+
+/// `optimize_spanned` over `threads` runs of top-level code at once.
+///
+/// Folding never reaches across a bracket, so the tokens can be cut in
+/// front of any `[` outside all loops and each piece optimized on its own;
+/// targets are then fixed by one `relink` over the whole. The brackets must
+/// already be balanced.
+pub fn optimize_parallel(
+    tokens: &mut Vec<Token>,
+    spans: &mut Vec<Span>,
+    threads: usize,
+    overflow: CellOverflow,
+) {
+    let len = tokens.len();
+    let mut cuts = vec![0];
+    let mut depth = 0;
+    for (pc, token) in tokens.iter().enumerate() {
+        if token.is_block_start() {
+            let next = len * cuts.len() / threads.max(1);
+            if depth == 0 && pc >= next && pc > cuts[cuts.len() - 1] {
+                cuts.push(pc);
+            }
+            depth += 1;
+        } else if token.is_block_end() {
+            depth -= 1;
+        }
+    }
+    cuts.push(len);
+    if cuts.len() <= 2 {
+        return optimize_spanned(tokens, spans, overflow);
+    }
+    let pieces: Vec<(Vec<Token>, Vec<Span>)> = thread::scope(|scope| {
+        let handles: Vec<_> = cuts
+            .windows(2)
+            .map(|w| {
+                let (tokens, spans) = (&tokens[w[0]..w[1]], &spans[w[0]..w[1]]);
+                scope.spawn(move || {
+                    let (mut tokens, mut spans) = (tokens.to_vec(), spans.to_vec());
+                    optimize_spanned(&mut tokens, &mut spans, overflow);
+                    (tokens, spans)
+                })
+            })
+            .collect();
+        let pieces = handles
+            .into_iter()
+            .map(|h| h.join().expect("optimizer panicked"));
+        pieces.collect()
+    });
+
+    tokens.clear();
+    spans.clear();
+    for (piece, piece_spans) in pieces {
+        tokens.extend(piece);
+        spans.extend(piece_spans);
+    }
+    relink(tokens);
+    tokens.shrink_to_fit();
+    spans.shrink_to_fit();
+}
+
+/// Recompute every block target from bracket nesting.
+///
+/// Passes that insert or remove tokens call this instead of fixing the
+/// absolute indices by hand. The brackets must already be balanced.
+pub fn relink(tokens: &mut [Token]) {
+    let mut stk: Vec<usize> = vec![];
+    for pc in 0..tokens.len() {
+        if tokens[pc].is_block_start() {
+            stk.push(pc);
+        } else if tokens[pc].is_block_end() {
+            let org = stk.pop().expect("unbalanced loop");
+            tokens[org] = tokens[org].with_target(pc as u32);
+            tokens[pc] = tokens[pc].with_target(org as u32);
+        }
+    }
+}
+
+// longest loop body that gets duplicated by `peel_loops`
+const PEEL_LIMIT: usize = 32;
+
+/// What the passes may assume about the tape a program starts on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StartTape {
+    /// Anything: `VM::with_tape`, `VM::memory_mut`, `--tape-file` and a
+    /// resumed run all start on cells already written.
+    #[default]
+    Unknown,
+    /// All zero, as on a fresh VM whose cells nothing has written yet.
+    Zeroed,
+}
+
+/// Cell values known at compile time, relative to where tracking started.
+///
+/// Cells missing from `cells` hold `default`: zero at the start of a
+/// program on a `StartTape::Zeroed` tape, otherwise unknown.
+struct KnownCells {
+    cells: HashMap<isize, Option<u8>>,
+    default: Option<u8>,
+    pos: isize,
+}
+
+impl KnownCells {
+    fn zeroed() -> Self {
+        KnownCells {
+            cells: HashMap::new(),
+            default: Some(0),
+            pos: 0,
+        }
+    }
+
+    fn start(start: StartTape) -> Self {
+        let mut known = KnownCells::zeroed();
+        if start == StartTape::Unknown {
+            known.forget();
+        }
+        known
+    }
+
+    fn current(&self) -> Option<u8> {
+        *self.cells.get(&self.pos).unwrap_or(&self.default)
+    }
+
+    fn forget(&mut self) {
+        self.cells.clear();
+        self.default = None;
+        self.pos = 0;
+    }
+
+    fn apply(&mut self, token: Token) {
+        use Token::*;
+        let cur = self.current();
+        match token {
+            IncrementData(x) => {
+                self.cells.insert(self.pos, cur.map(|v| v.wrapping_add(x)));
+            }
+            DecrementData(x) => {
+                self.cells.insert(self.pos, cur.map(|v| v.wrapping_sub(x)));
+            }
+            IncrementPointer(x) => self.pos += x as isize,
+            DecrementPointer(x) => self.pos -= x as isize,
+            Input => {
+                self.cells.insert(self.pos, None);
+            }
+            Output | OutputRepeat(_) | Print(_) => {}
+            ClearRange { start_offset, len } => {
+                for i in 0..len as isize {
+                    self.cells
+                        .insert(self.pos + start_offset as isize + i, Some(0));
+                }
+            }
+            MulAdd { offset, factor } => {
+                let at = self.pos + offset as isize;
+                let target = *self.cells.get(&at).unwrap_or(&self.default);
+                let sum = match (cur, target) {
+                    (Some(0), target) => target,
+                    (Some(c), Some(t)) => Some(t.wrapping_add(c.wrapping_mul(factor))),
+                    _ => None,
+                };
+                self.cells.insert(at, sum);
+            }
+            AddAt { offset, value } => {
+                let at = self.pos + offset as isize;
+                let target = *self.cells.get(&at).unwrap_or(&self.default);
+                self.cells.insert(at, target.map(|t| t.wrapping_add(value)));
+            }
+            // nothing is known across an op the passes do not model
+            LoopStart(_) | IfStart(_) | Ext(_) | Breakpoint => self.forget(),
+            // a loop or a scan leaves the pointer on a zero cell
+            LoopEnd(_) | IfEnd(_) | ScanRight(_) | ScanLeft(_) => {
+                self.forget();
+                self.cells.insert(0, Some(0));
+            }
+        }
+    }
+}
+
+/// Peel the first iteration of loops that are provably entered.
+///
+/// When the current cell is statically nonzero at a `[` (e.g. right after `+`
+/// on a cell that `start` says is still zero, or one an earlier loop left
+/// zero) the entry test can never skip the loop, so a straight-line body is
+/// copied in front of it. The copy runs without the test and branch, and the
+/// remaining loop keeps its normal form.
+pub fn peel_loops(tokens: &mut Vec<Token>, start: StartTape) {
+    let mut spans = vec![Span::default(); tokens.len()];
+    peel_loops_spanned(tokens, &mut spans, start);
+}
+
+/// `peel_loops`, keeping the parallel `spans` in step with the tokens.
+pub fn peel_loops_spanned(tokens: &mut Vec<Token>, spans: &mut Vec<Span>, start: StartTape) {
+    let mut out: Vec<Token> = Vec::with_capacity(tokens.len());
+    let mut out_spans: Vec<Span> = Vec::with_capacity(spans.len());
+    let mut known = KnownCells::start(start);
+    // state to restore at `]` when the loop is known to be skipped
+    let mut stk: Vec<Option<KnownCells>> = vec![];
+
+    for pc in 0..tokens.len() {
+        match tokens[pc] {
+            Token::LoopStart(end) => {
+                let body = &tokens[pc + 1..end as usize];
+                let straight = body
+                    .iter()
+                    .all(|t| !t.is_block_start() && !t.is_block_end());
+                let cur = known.current();
+                if straight && body.len() <= PEEL_LIMIT && matches!(cur, Some(v) if v != 0) {
+                    for (i, &t) in body.iter().enumerate() {
+                        known.apply(t);
+                        out.push(t);
+                        out_spans.push(spans[pc + 1 + i]);
+                    }
+                }
+                out.push(Token::LoopStart(0));
+                out_spans.push(spans[pc]);
+                if known.current() == Some(0) {
+                    stk.push(Some(std::mem::replace(&mut known, KnownCells::zeroed())));
+                } else {
+                    stk.push(None);
+                }
+                known.forget();
+            }
+            Token::LoopEnd(_) => {
+                out.push(Token::LoopEnd(0));
+                out_spans.push(spans[pc]);
+                match stk.pop().expect("unbalanced loop") {
+                    Some(skipped) => known = skipped,
+                    None => known.apply(Token::LoopEnd(0)),
+                }
+            }
+            t => {
+                known.apply(t);
+                out.push(t);
+                out_spans.push(spans[pc]);
+            }
+        }
+    }
+
+    relink(&mut out);
+    *tokens = out;
+    *spans = out_spans;
+}
+
+#[test]
+fn test_compile() {
+    assert_eq!(
+        tokenizer("+[,.]").unwrap(),
+        vec![
+            Token::IncrementData(1),
+            Token::LoopStart(4),
+            Token::Input,
+            Token::Output,
+            Token::LoopEnd(1),
+        ]
+    );
+
+    assert_eq!(
+        tokenizer(
+            "[arst]+[,.  
+        +]"
+        )
+        .unwrap(),
+        vec![
+            Token::LoopStart(1),
+            Token::LoopEnd(0),
+            Token::IncrementData(1),
+            Token::LoopStart(7),
+            Token::Input,
+            Token::Output,
+            Token::IncrementData(1),
+            Token::LoopEnd(3),
+        ]
+    );
+
+    match tokenizer("]").unwrap_err().kind {
+        TokenizerErrorKind::UncloseLeftBracket => {}
+        _ => panic!(),
+    }
+
+    match tokenizer("[").unwrap_err().kind {
+        TokenizerErrorKind::UncloseRightBracket => {}
+        _ => panic!(),
+    }
+
+    let err = tokenizer("+\n +]").unwrap_err();
+    assert_eq!((err.line(), err.col()), (2, 3));
+
+    let mut token = tokenizer("[++++++]").unwrap();
+    optimize(&mut token);
+    assert_eq!(
+        token,
+        vec![
+            Token::LoopStart(2),
+            Token::IncrementData(6),
+            Token::LoopEnd(0),
+        ]
+    )
+}
+
+// whether a clear loop like `[-]` starts at `pc`; any odd step reaches zero,
+// but only `[-]` does without wrapping
+fn is_clear_loop(tokens: &[Token], pc: usize, overflow: CellOverflow) -> bool {
+    match overflow {
+        CellOverflow::Wrap => matches!(
+            tokens[pc..],
+            [Token::LoopStart(_), Token::IncrementData(x) | Token::DecrementData(x), Token::LoopEnd(_), ..]
+                if x % 2 == 1
+        ),
+        CellOverflow::Error => matches!(
+            tokens[pc..],
+            [
+                Token::LoopStart(_),
+                Token::DecrementData(1),
+                Token::LoopEnd(_),
+                ..
+            ]
+        ),
+    }
+}
+
+/// Collapse runs of clear loops on adjacent cells into `ClearRange`.
+///
+/// `[-]>[-]>[-]` zeroes three cells walking right and leaves the pointer two
+/// cells on; it becomes a `ClearRange` over the cells followed by the same
+/// move, merged with a move in the same direction right after the run. Runs
+/// walking left work the same way with a negative start offset. A single
+/// `[-]` becomes a range of one.
+pub fn clear_ranges(tokens: &mut Vec<Token>) {
+    let mut spans = vec![Span::default(); tokens.len()];
+    clear_ranges_spanned(tokens, &mut spans, CellOverflow::Wrap);
+}
+
+/// `clear_ranges`, keeping the parallel `spans` in step with the tokens.
+/// Under `CellOverflow::Error` only `[-]` counts as a clear loop, the others
+/// wrapping on the way to zero.
+pub fn clear_ranges_spanned(
+    tokens: &mut Vec<Token>,
+    spans: &mut Vec<Span>,
+    overflow: CellOverflow,
+) {
+    let mut out: Vec<Token> = Vec::with_capacity(tokens.len());
+    let mut out_spans: Vec<Span> = Vec::with_capacity(spans.len());
+
+    let mut pc = 0;
+    while pc < tokens.len() {
+        if !is_clear_loop(tokens, pc, overflow) {
+            out.push(tokens[pc]);
+            out_spans.push(spans[pc]);
+            pc += 1;
+            continue;
+        }
+        let start = pc;
+        let mut len = 1;
+        pc += 3;
+        // the first step fixes the direction of the run
+        let right = matches!(tokens.get(pc), Some(Token::IncrementPointer(1)));
+        let step = if right {
+            Token::IncrementPointer(1)
+        } else {
+            Token::DecrementPointer(1)
+        };
+        while pc < tokens.len()
+            && tokens[pc] == step
+            && is_clear_loop(tokens, pc + 1, overflow)
+            && len < i32::MAX as u32
+        {
+            len += 1;
+            pc += 4;
+        }
+
+        let start_offset = if right { 0 } else { 1 - len as i32 };
+        out.push(Token::ClearRange { start_offset, len });
+        out_spans.push(spans[start]);
+        // the last step of the run, then any move right after it
+        let mut moved = len as usize - 1;
+        let mut move_span = spans[pc.saturating_sub(4)];
+        let trailing = match tokens.get(pc) {
+            Some(&Token::IncrementPointer(x)) if right => Some(x),
+            Some(&Token::DecrementPointer(x)) if !right => Some(x),
+            _ => None,
+        };
+        if let Some(x) = trailing {
+            moved += x;
+            move_span = spans[pc];
+            pc += 1;
+        }
+        if moved > 0 {
+            out.push(if right {
+                Token::IncrementPointer(moved)
+            } else {
+                Token::DecrementPointer(moved)
+            });
+            out_spans.push(move_span);
+        }
+    }
+    relink(&mut out);
+    *tokens = out;
+    *spans = out_spans;
+}
+
+// what a copy or multiply loop starting at `pc` adds to each other cell, by
+// offset with the index of the first op there, or None for any other loop
+fn mul_loop(tokens: &[Token], pc: usize) -> Option<Vec<(i32, u8, usize)>> {
+    let Token::LoopStart(end) = tokens[pc] else {
+        return None;
+    };
+    let mut deltas: Vec<(i32, u8, usize)> = vec![];
+    let (mut pos, mut extent) = (0_i64, (0_i64, 0_i64));
+    let mut step = 0_u8; // change to the current cell per iteration
+    for (i, &t) in tokens.iter().enumerate().take(end as usize).skip(pc + 1) {
+        let add = match t {
+            Token::IncrementData(x) => x,
+            Token::DecrementData(x) => x.wrapping_neg(),
+            Token::IncrementPointer(x) => {
+                pos = pos.checked_add(i64::try_from(x).ok()?)?;
+                extent.1 = extent.1.max(pos);
+                continue;
+            }
+            Token::DecrementPointer(x) => {
+                pos = pos.checked_sub(i64::try_from(x).ok()?)?;
+                extent.0 = extent.0.min(pos);
+                continue;
+            }
+            _ => return None,
+        };
+        if pos == 0 {
+            step = step.wrapping_add(add);
+            continue;
+        }
+        let offset = i32::try_from(pos).ok()?;
+        match deltas.iter_mut().find(|d| d.0 == offset) {
+            Some(d) => d.1 = d.1.wrapping_add(add),
+            None => deltas.push((offset, add, i)),
+        }
+    }
+    // a run of `-1` steps takes the cell's value in iterations and `+1`
+    // steps its negation
+    let scale = match step {
+        255 => 1,
+        1 => 255,
+        _ => return None,
+    };
+    deltas.retain(|d| d.1 != 0);
+    for d in &mut deltas {
+        d.1 = d.1.wrapping_mul(scale);
+    }
+    // the pointer must come back, and go nowhere a write does not, so that a
+    // move off the tape fails the same way in both forms
+    let touched = deltas.iter().map(|d| d.0 as i64);
+    let reach = touched.fold((0, 0), |(lo, hi), at| (at.min(lo), at.max(hi)));
+    (pos == 0 && reach == extent && !deltas.is_empty()).then_some(deltas)
+}
+
+/// Replace copy and multiply loops by one `MulAdd` per cell they change.
+///
+/// A loop like `[->+++>+<<]` only moves the current cell into nearby ones:
+/// its body is straight moves and adds with no net movement, and changes the
+/// current cell by one a pass. It becomes a `MulAdd` for each other cell it
+/// changes, then a `ClearRange` of the current cell. Loops with anything else
+/// inside, I/O or another loop, are left alone.
+pub fn mul_loops(tokens: &mut Vec<Token>) {
+    let mut spans = vec![Span::default(); tokens.len()];
+    mul_loops_spanned(tokens, &mut spans);
+}
+
+/// `mul_loops`, keeping the parallel `spans` in step with the tokens.
+pub fn mul_loops_spanned(tokens: &mut Vec<Token>, spans: &mut Vec<Span>) {
+    let mut out: Vec<Token> = Vec::with_capacity(tokens.len());
+    let mut out_spans: Vec<Span> = Vec::with_capacity(spans.len());
+
+    let mut pc = 0;
+    while pc < tokens.len() {
+        let Some(deltas) = mul_loop(tokens, pc) else {
+            out.push(tokens[pc]);
+            out_spans.push(spans[pc]);
+            pc += 1;
+            continue;
+        };
+        for (offset, factor, at) in deltas {
+            out.push(Token::MulAdd { offset, factor });
+            out_spans.push(spans[at]);
+        }
+        let Token::LoopStart(end) = tokens[pc] else {
+            unreachable!("mul_loop starts at a loop");
+        };
+        out.push(Token::ClearRange {
+            start_offset: 0,
+            len: 1,
+        });
+        out_spans.push(spans[pc]);
+        pc = end as usize + 1;
+    }
+    relink(&mut out);
+    *tokens = out;
+    *spans = out_spans;
+}
+
+// the scan a loop like `[>]` or `[<<]` starting at `pc` is, if it is one
+fn scan_loop(tokens: &[Token], pc: usize) -> Option<Token> {
+    match tokens[pc..] {
+        [Token::LoopStart(_), Token::IncrementPointer(x), Token::LoopEnd(_), ..] if x > 0 => {
+            Some(Token::ScanRight(x))
+        }
+        [Token::LoopStart(_), Token::DecrementPointer(x), Token::LoopEnd(_), ..] if x > 0 => {
+            Some(Token::ScanLeft(x))
+        }
+        _ => None,
+    }
+}
+
+/// Replace scan loops by `ScanRight` and `ScanLeft`.
+///
+/// `[>]` walks right to the first zero cell and `[<<]` left to the first
+/// zero cell an even number of cells away. Each becomes one instruction that
+/// searches the tape rather than dispatching a move and a test per cell.
+pub fn scan_loops(tokens: &mut Vec<Token>) {
+    let mut spans = vec![Span::default(); tokens.len()];
+    scan_loops_spanned(tokens, &mut spans);
+}
+
+/// `scan_loops`, keeping the parallel `spans` in step with the tokens.
+pub fn scan_loops_spanned(tokens: &mut Vec<Token>, spans: &mut Vec<Span>) {
+    let mut out: Vec<Token> = Vec::with_capacity(tokens.len());
+    let mut out_spans: Vec<Span> = Vec::with_capacity(spans.len());
+
+    let mut pc = 0;
+    while pc < tokens.len() {
+        match scan_loop(tokens, pc) {
+            Some(scan) => {
+                out.push(scan);
+                out_spans.push(spans[pc]);
+                pc += 3;
+            }
+            None => {
+                out.push(tokens[pc]);
+                out_spans.push(spans[pc]);
+                pc += 1;
+            }
+        }
+    }
+    relink(&mut out);
+    *tokens = out;
+    *spans = out_spans;
+}
+
+/// Drop loops that can never run, bodies and all.
+///
+/// `]` only falls through on a zero cell, so a loop right after another is
+/// skipped without fail, as is one right after a dropped one: the comment
+/// blocks written between loops. On a `StartTape::Zeroed` tape so is a loop
+/// that only pointer moves come before, such as the comment header at the
+/// start of a program; on any other it is kept, since the cells may be
+/// preloaded. Unlike `fold_known` this looks at nothing else, so it is cheap
+/// enough for `O1`.
+pub fn dead_loops(tokens: &mut Vec<Token>, start: StartTape) {
+    let mut spans = vec![Span::default(); tokens.len()];
+    dead_loops_spanned(tokens, &mut spans, start);
+}
+
+/// `dead_loops`, keeping the parallel `spans` in step with the tokens.
+pub fn dead_loops_spanned(tokens: &mut Vec<Token>, spans: &mut Vec<Span>, start: StartTape) {
+    let mut out: Vec<Token> = Vec::with_capacity(tokens.len());
+    let mut out_spans: Vec<Span> = Vec::with_capacity(spans.len());
+    // whether the cell under the pointer is still as the tape started
+    let mut untouched = true;
+
+    let mut pc = 0;
+    while pc < tokens.len() {
+        let zeroed = untouched && start == StartTape::Zeroed;
+        match tokens[pc] {
+            Token::LoopStart(end) if zeroed || matches!(out.last(), Some(Token::LoopEnd(_))) => {
+                pc = end as usize + 1;
+            }
+            t => {
+                untouched &= matches!(t, Token::IncrementPointer(_) | Token::DecrementPointer(_));
+                out.push(t);
+                out_spans.push(spans[pc]);
+                pc += 1;
+            }
+        }
+    }
+    relink(&mut out);
+    *tokens = out;
+    *spans = out_spans;
+}
+
+/// Turn loops whose body provably leaves the current cell zero into ifs.
+///
+/// Such a loop runs at most once: the test at its `]` can never jump back,
+/// so it becomes an `IfStart`/`IfEnd` pair without the back-edge. The body
+/// clearing the cell is seen through the value tracking of `peel_loops`,
+/// typically an inner loop like `[-]` or `[->+<]` ending on it.
+pub fn lower_ifs(tokens: &mut [Token]) {
+    for pc in 0..tokens.len() {
+        let Token::LoopStart(end) = tokens[pc] else {
+            continue;
+        };
+        let mut known = KnownCells::start(StartTape::Unknown);
+        for &t in &tokens[pc + 1..end as usize] {
+            known.apply(t);
+        }
+        if known.current() == Some(0) {
+            tokens[pc] = Token::IfStart(end);
+            tokens[end as usize] = Token::IfEnd(pc as u32);
+        }
+    }
+}
+
+/// The value of the current cell before each token, where it is known.
+///
+/// Nothing is assumed about the tape a program starts on, which may come
+/// preloaded from `VM::with_tape` or `--tape-file`; values become known
+/// through clears and block ends and are followed through straight-line code.
+/// A block start forgets everything, since the body may run any number of
+/// times; a block known to be skipped leaves the values as they were before
+/// it, and any other block ends with only its condition cell known to be zero.
+pub fn known_values(tokens: &[Token]) -> Vec<Option<u8>> {
+    let mut values = Vec::with_capacity(tokens.len());
+    let mut known = KnownCells::start(StartTape::Unknown);
+    // state to restore at the block end when the block is known to be skipped
+    let mut stk: Vec<Option<KnownCells>> = vec![];
+    for &t in tokens {
+        values.push(known.current());
+        if t.is_block_start() {
+            if known.current() == Some(0) {
+                stk.push(Some(std::mem::replace(&mut known, KnownCells::zeroed())));
+            } else {
+                stk.push(None);
+            }
+            known.forget();
+        } else if t.is_block_end() {
+            match stk.pop().expect("unbalanced loop") {
+                Some(skipped) => known = skipped,
+                None => known.apply(t),
+            }
+        } else {
+            known.apply(t);
+        }
+    }
+    values
+}
+
+/// Use `known_values` to drop dead blocks and print constants.
+///
+/// A block whose condition cell is known to be zero at its start never runs
+/// and goes away with its body. A `.` of a known cell becomes `Print`, which
+/// needs no load from the tape.
+pub fn fold_known(tokens: &mut Vec<Token>) {
+    let mut spans = vec![Span::default(); tokens.len()];
+    fold_known_spanned(tokens, &mut spans);
+}
+
+/// `fold_known`, keeping the parallel `spans` in step with the tokens.
+pub fn fold_known_spanned(tokens: &mut Vec<Token>, spans: &mut Vec<Span>) {
+    let values = known_values(tokens);
+    let mut out: Vec<Token> = Vec::with_capacity(tokens.len());
+    let mut out_spans: Vec<Span> = Vec::with_capacity(spans.len());
+
+    let mut pc = 0;
+    while pc < tokens.len() {
+        match (tokens[pc], values[pc]) {
+            (Token::LoopStart(end) | Token::IfStart(end), Some(0)) => {
+                pc = end as usize + 1;
+                continue;
+            }
+            (Token::Output, Some(byte)) => out.push(Token::Print(byte)),
+            (t, _) => out.push(t),
+        }
+        out_spans.push(spans[pc]);
+        pc += 1;
+    }
+
+    relink(&mut out);
+    *tokens = out;
+    *spans = out_spans;
+}
+
+/// Fold pointer moves into the data ops between them.
+///
+/// In a stretch of adds, moves and clears nothing depends on where the
+/// pointer is in between, so each op can work at an offset from where the
+/// stretch began: `>>+++<<-` becomes `AddAt { offset: 2, value: 3 }` and a
+/// `-`, and `>+>+>` two `AddAt`s and one `>>>`. A stretch ends at a block,
+/// I/O or any other op, where the net move is made once, and is only
+/// rewritten when that leaves fewer instructions.
+///
+/// A run of more than 127 `+` or 128 `-`, which `AddAt` would take the wrong
+/// way round for `--cell-overflow=error`, makes the moves before it, as does
+/// an offset past `i32`.
+pub fn offset_ops(tokens: &mut Vec<Token>) {
+    let mut spans = vec![Span::default(); tokens.len()];
+    offset_ops_spanned(tokens, &mut spans);
+}
+
+/// `offset_ops`, keeping the parallel `spans` in step with the tokens.
+pub fn offset_ops_spanned(tokens: &mut Vec<Token>, spans: &mut Vec<Span>) {
+    use Token::*;
+    let mut out: Vec<Token> = Vec::with_capacity(tokens.len());
+    let mut out_spans: Vec<Span> = Vec::with_capacity(spans.len());
+
+    let mut pc = 0;
+    while pc < tokens.len() {
+        let len = tokens[pc..]
+            .iter()
+            .take_while(|t| {
+                matches!(
+                    t,
+                    IncrementData(_)
+                        | DecrementData(_)
+                        | IncrementPointer(_)
+                        | DecrementPointer(_)
+                        | ClearRange { .. }
+                        | AddAt { .. }
+                )
+            })
+            .count()
+            .max(1);
+        let (stretch, stretch_spans) = (&tokens[pc..pc + len], &spans[pc..pc + len]);
+        let (folded, folded_spans) = fold_offsets(stretch, stretch_spans);
+        if folded.len() < len {
+            out.extend(folded);
+            out_spans.extend(folded_spans);
+        } else {
+            out.extend_from_slice(stretch);
+            out_spans.extend_from_slice(stretch_spans);
+        }
+        pc += len;
+    }
+    relink(&mut out);
+    *tokens = out;
+    *spans = out_spans;
+}
+
+// `stretch` with its moves taken into offsets, as `offset_ops` describes
+fn fold_offsets(stretch: &[Token], spans: &[Span]) -> (Vec<Token>, Vec<Span>) {
+    use Token::*;
+    let mut out: Vec<Token> = Vec::with_capacity(stretch.len());
+    let mut out_spans: Vec<Span> = Vec::with_capacity(stretch.len());
+    // the move not made yet, and the span of its last `>` or `<`
+    let (mut shift, mut shift_span) = (0_i32, Span::default());
+
+    for (&token, &span) in stretch.iter().zip(spans) {
+        let at = |offset: i32| offset.checked_add(shift);
+        let moved = match token {
+            IncrementPointer(x) => i32::try_from(x).ok().and_then(at),
+            DecrementPointer(x) => i32::try_from(x).ok().and_then(|x| shift.checked_sub(x)),
+            _ => None,
+        };
+        if let Some(to) = moved {
+            (shift, shift_span) = (to, span);
+            continue;
+        }
+        let folded = match token {
+            _ if shift == 0 => None,
+            IncrementData(value @ 0..=127) => Some(AddAt {
+                offset: shift,
+                value,
+            }),
+            DecrementData(x @ 0..=128) => Some(AddAt {
+                offset: shift,
+                value: x.wrapping_neg(),
+            }),
+            AddAt { offset, value } => at(offset).map(|offset| AddAt { offset, value }),
+            ClearRange { start_offset, len } => {
+                at(start_offset).map(|start_offset| ClearRange { start_offset, len })
+            }
+            _ => None,
+        };
+        if folded.is_none() && shift != 0 {
+            out.push(shift_token(shift));
+            out_spans.push(shift_span);
+            shift = 0;
+        }
+        out.push(folded.unwrap_or(token));
+        out_spans.push(span);
+    }
+    if shift != 0 {
+        out.push(shift_token(shift));
+        out_spans.push(shift_span);
+    }
+    (out, out_spans)
+}
+
+// the move by `shift` cells
+fn shift_token(shift: i32) -> Token {
+    match shift {
+        0.. => Token::IncrementPointer(shift as usize),
+        _ => Token::DecrementPointer(shift.unsigned_abs() as usize),
+    }
+}
+
+// executes `tokens` without input, returning the output and the number of
+// instructions dispatched
+#[cfg(test)]
+fn eval(tokens: &[Token]) -> (Vec<u8>, usize) {
+    let mut mem = [0_u8; 64];
+    let (mut pc, mut point, mut steps) = (0, 0, 0);
+    let mut out = vec![];
+    while pc < tokens.len() {
+        steps += 1;
+        match tokens[pc] {
+            Token::IncrementData(x) => mem[point] = mem[point].wrapping_add(x),
+            Token::DecrementData(x) => mem[point] = mem[point].wrapping_sub(x),
+            Token::IncrementPointer(x) => point += x,
+            Token::DecrementPointer(x) => point -= x,
+            Token::Input => mem[point] = 0,
+            Token::Output => out.push(mem[point]),
+            Token::OutputRepeat(n) => out.extend(std::iter::repeat_n(mem[point], n)),
+            Token::Print(byte) => out.push(byte),
+            Token::ClearRange { start_offset, len } => {
+                let start = point.checked_add_signed(start_offset as isize).unwrap();
+                mem[start..start + len as usize].fill(0);
+            }
+            Token::MulAdd { offset, factor } => {
+                let at = point.checked_add_signed(offset as isize).unwrap();
+                mem[at] = mem[at].wrapping_add(mem[point].wrapping_mul(factor));
+            }
+            Token::AddAt { offset, value } => {
+                let at = point.checked_add_signed(offset as isize).unwrap();
+                mem[at] = mem[at].wrapping_add(value);
+            }
+            Token::ScanRight(x) => {
+                while mem[point] != 0 {
+                    point += x;
+                }
+            }
+            Token::ScanLeft(x) => {
+                while mem[point] != 0 {
+                    point -= x;
+                }
+            }
+            Token::LoopStart(x) | Token::IfStart(x) if mem[point] == 0 => pc = x as usize,
+            Token::LoopEnd(x) if mem[point] != 0 => pc = x as usize,
+            _ => {}
+        }
+        pc += 1;
+    }
+    (out, steps)
+}
+
+#[test]
+fn test_peel_loops() {
+    let compile = |src: &str| {
+        let mut token = tokenizer(src).unwrap();
+        optimize(&mut token);
+        token
+    };
+
+    // counter initialised on a zero cell, then counted down
+    let plain = compile("+++++[>++.<-]");
+    let mut peeled = plain.clone();
+    peel_loops(&mut peeled, StartTape::Zeroed);
+    assert_eq!(
+        peeled,
+        vec![
+            Token::IncrementData(5),
+            Token::IncrementPointer(1),
+            Token::IncrementData(2),
+            Token::Output,
+            Token::DecrementPointer(1),
+            Token::DecrementData(1),
+            Token::LoopStart(12),
+            Token::IncrementPointer(1),
+            Token::IncrementData(2),
+            Token::Output,
+            Token::DecrementPointer(1),
+            Token::DecrementData(1),
+            Token::LoopEnd(6),
+        ]
+    );
+    let (out, steps) = eval(&plain);
+    assert_eq!(eval(&peeled), (out, steps - 1));
+
+    // loops starting on a cell zeroed by an earlier loop, then set again
+    let plain = compile("++[-]+++[>+.<-]>[-]++[.-]");
+    let mut peeled = plain.clone();
+    peel_loops(&mut peeled, StartTape::Zeroed);
+    let (out, steps) = eval(&plain);
+    assert_eq!(eval(&peeled), (out, steps - 3));
+
+    // unknown or wrapped-to-zero conditions stay untouched
+    for src in [",[.-]", "+[>,[.-]<-]", &"+".repeat(256)] {
+        let plain = compile(&format!("{}[.-]", src));
+        let mut peeled = plain.clone();
+        peel_loops(&mut peeled, StartTape::Zeroed);
+        assert_eq!(peeled.len(), plain.len(), "{}", src);
+    }
+
+    // a tape that may be preloaded gives nothing to go on at the start: with
+    // 255 in cell 0 these loops never run, peeled they would run once
+    for src in ["+[>+.<-]", "+[>+<--]"] {
+        let plain = compile(src);
+        let mut peeled = plain.clone();
+        peel_loops(&mut peeled, StartTape::Unknown);
+        assert_eq!(peeled, plain, "{}", src);
+    }
+    // what an earlier loop leaves behind is known on any tape
+    let plain = compile(",[-]+[>+<-]");
+    let mut peeled = plain.clone();
+    peel_loops(&mut peeled, StartTape::Unknown);
+    assert!(peeled.len() > plain.len());
+}
+
+#[test]
+fn test_link_recover() {
+    let (ir, errors) = tokenizer_recover("+]>[-\n[.]");
+    assert_eq!(verify(&ir), Ok(()));
+    assert_eq!(
+        ir,
+        vec![
+            Token::IncrementData(1),
+            Token::IncrementPointer(1),
+            Token::LoopStart(7),
+            Token::DecrementData(1),
+            Token::LoopStart(6),
+            Token::Output,
+            Token::LoopEnd(4),
+            Token::LoopEnd(2),
+        ]
+    );
+    assert_eq!(errors.len(), 2);
+    assert!(matches!(
+        errors[0].kind(),
+        TokenizerErrorKind::UncloseLeftBracket
+    ));
+    assert_eq!((errors[0].line(), errors[0].col()), (1, 2));
+    assert!(matches!(
+        errors[1].kind(),
+        TokenizerErrorKind::UncloseRightBracket
+    ));
+    assert_eq!((errors[1].line(), errors[1].col()), (1, 4));
+
+    let (ir, errors) = tokenizer_recover("+[,.]");
+    assert!(errors.is_empty());
+    assert_eq!(ir, tokenizer("+[,.]").unwrap());
+
+    assert_eq!(verify(&[Token::LoopStart(1), Token::LoopEnd(1)]), Err(1));
+    assert_eq!(verify(&[Token::LoopStart(0)]), Err(0));
+}
+
+#[test]
+fn test_lower_ifs() {
+    let lower = |src: &str| {
+        let mut token = tokenizer(src).unwrap();
+        lower_ifs(&mut token);
+        token
+    };
+    assert_eq!(
+        lower("[>+<[-]]"),
+        vec![
+            Token::IfStart(7),
+            Token::IncrementPointer(1),
+            Token::IncrementData(1),
+            Token::DecrementPointer(1),
+            Token::LoopStart(6),
+            Token::DecrementData(1),
+            Token::LoopEnd(4),
+            Token::IfEnd(0),
+        ]
+    );
+    // nested ifs, and a body whose last move leaves a cleared cell current
+    assert_eq!(verify(&lower("[[[-]]>[-]]")), Ok(()));
+    assert!(matches!(
+        lower("[[[-]]>[-]]")[..2],
+        [Token::IfStart(_), Token::IfStart(_)]
+    ));
+
+    // the condition cell is not provably zero at `]`
+    for src in ["[-]", "[->+<]", "[[-]>]", "[[-],]", "[[-]+]"] {
+        assert_eq!(lower(src), tokenizer(src).unwrap(), "{}", src);
+    }
+
+    // every initial condition value behaves the same before and after lowering
+    for body in [
+        "[>+<[-]]",
+        "[[-]>+<]",
+        "[>+.<[->+<]]",
+        "[.[-]]",
+        "[>[-]]>+.",
+    ] {
+        let src = format!("{}.>.>.", body);
+        let plain = tokenizer(&src).unwrap();
+        let lowered = lower(&src);
+        assert!(
+            lowered.iter().any(|t| matches!(t, Token::IfEnd(_))),
+            "{}",
+            body
+        );
+        for v in 0..=255_u8 {
+            let mut seeded = vec![Token::IncrementData(v)];
+            seeded.extend_from_slice(&plain);
+            relink(&mut seeded);
+            let mut seeded_lowered = seeded.clone();
+            lower_ifs(&mut seeded_lowered);
+            assert_ne!(seeded, seeded_lowered);
+            assert_eq!(
+                eval(&seeded).0,
+                eval(&seeded_lowered).0,
+                "{} at {}",
+                body,
+                v
+            );
+        }
+    }
+}
+
+#[test]
+fn test_program_too_large() {
+    // the boundary itself, without materializing four billion tokens
+    assert_eq!(jump_target(0), Some(0));
+    assert_eq!(jump_target(MAX_INSTRUCTIONS), Some(u32::MAX));
+    assert_eq!(jump_target(MAX_INSTRUCTIONS + 1), None);
+    assert_eq!(jump_target(usize::MAX), None);
+
+    let err = TokenizerError {
+        line: 3,
+        col: 9,
+        kind: TokenizerErrorKind::ProgramTooLarge,
+        hint: None,
+    };
+    assert_eq!(
+        err.to_string(),
+        "E0103 Program too large, jump targets past 4294967295 instructions at line 3:9"
+    );
+}
+
+#[test]
+fn test_fold_output() {
+    use Token::*;
+
+    let folded = |src: &str| {
+        let mut tokens = tokenizer(src).unwrap();
+        optimize(&mut tokens);
+        tokens
+    };
+    // comments between the dots do not get in the way
+    assert_eq!(folded("+... . ."), vec![IncrementData(1), OutputRepeat(5)]);
+    assert_eq!(folded("+."), vec![IncrementData(1), Output]);
+
+    // a write, a move or a loop boundary in between ends the run
+    assert_eq!(
+        folded("..+.."),
+        vec![OutputRepeat(2), IncrementData(1), OutputRepeat(2)]
+    );
+    assert_eq!(
+        folded("..>.."),
+        vec![OutputRepeat(2), IncrementPointer(1), OutputRepeat(2)]
+    );
+    assert_eq!(
+        folded("..[..].."),
+        vec![
+            OutputRepeat(2),
+            LoopStart(3),
+            OutputRepeat(2),
+            LoopEnd(1),
+            OutputRepeat(2)
+        ]
+    );
+    assert_eq!(folded(".,."), vec![Output, Input, Output]);
+
+    // folding again keeps the count
+    let mut tokens = folded("+...");
+    tokens.push(Output);
+    optimize(&mut tokens);
+    assert_eq!(tokens, vec![IncrementData(1), OutputRepeat(4)]);
+
+    let src = "++++++++[>++++++++<-]>+....>++++++++++..<..";
+    let mut tokens = tokenizer(src).unwrap();
+    let (plain, plain_steps) = eval(&tokens);
+    optimize(&mut tokens);
+    let (fused, fused_steps) = eval(&tokens);
+    assert_eq!(plain, fused);
+    assert_eq!(fused, b"AAAA\n\nAA");
+    assert!(fused_steps < plain_steps);
+}
+
+#[test]
+fn test_bracket_hint() {
+    let hint = |src: &str| {
+        let err = tokenizer(src).unwrap_err();
+        ((err.line, err.col), err.hint().map(|s| (s.line, s.col)))
+    };
+
+    // a missing `]` in a nested block: the outer `[` is left over
+    let src = "+[\n    ++[\n        -\n    >+\n]\n";
+    assert_eq!(hint(src), ((1, 2), Some((2, 7))));
+    let err = tokenizer(src).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "E0102 Unclose right bracket at line 1:2 (hint: check the bracket at line 2:7)"
+    );
+
+    // the same mistake far earlier than the end of the program
+    let body = "    >+<-\n".repeat(200);
+    let src = format!("+[\n    >[\n{}    <-\n]\n++[\n    -\n]\n.", body);
+    assert_eq!(hint(&src), ((1, 2), Some((2, 6))));
+
+    // a stray `]` at the end of a line closes the loop too early
+    let src = "++[\n    ->+<]\n    >>+\n]\n";
+    assert_eq!(hint(src), ((4, 1), Some((2, 9))));
+
+    // nothing better to offer on a single line
+    assert_eq!(hint("+[[-]"), ((1, 2), None));
+    assert_eq!(hint("+[-]]"), ((1, 5), None));
+}
+
+#[test]
+fn test_scan_loops() {
+    use Token::*;
+
+    let scanned = |src: &str| {
+        let mut tokens = tokenizer(src).unwrap();
+        optimize(&mut tokens);
+        scan_loops(&mut tokens);
+        assert!(verify(&tokens).is_ok());
+        tokens
+    };
+    assert_eq!(scanned("[>]"), vec![ScanRight(1)]);
+    assert_eq!(scanned("[<<<]."), vec![ScanLeft(3), Output]);
+    assert_eq!(
+        scanned("+[[>>]<]"),
+        vec![
+            IncrementData(1),
+            LoopStart(4),
+            ScanRight(2),
+            DecrementPointer(1),
+            LoopEnd(1)
+        ]
+    );
+    // anything else in the body keeps the loop
+    for src in ["[>+]", "[><]", "[>.]", "[]"] {
+        let mut plain = tokenizer(src).unwrap();
+        optimize(&mut plain);
+        assert_eq!(scanned(src), plain, "{}", src);
+    }
+    // a scan ends on a zero cell, like any loop
+    let program = crate::program::Program::compile("+[>].").unwrap();
+    assert_eq!(program.tokens(), [IncrementData(1), ScanRight(1), Print(0)]);
+}
+
+#[test]
+fn test_clear_ranges() {
+    use Token::*;
+
+    let cleared = |src: &str| {
+        let mut tokens = tokenizer(src).unwrap();
+        optimize(&mut tokens);
+        clear_ranges(&mut tokens);
+        assert!(verify(&tokens).is_ok());
+        tokens
+    };
+    let range = |start_offset, len| ClearRange { start_offset, len };
+
+    assert_eq!(cleared("[-]"), vec![range(0, 1)]);
+    // a clear costs one instruction, however large the cell
+    let program = crate::program::Program::compile("++[-]+++").unwrap();
+    assert_eq!(program.to_ir_text(), "add 2\nclear 0 1\nadd 3\n");
+    let mut tape = [200];
+    let mut vm = crate::vm::VM::with_tape(program, &mut tape).unwrap();
+    vm.run().unwrap();
+    assert_eq!(vm.stats().steps, 3);
+    drop(vm);
+    assert_eq!(tape, [3]);
+    assert_eq!(
+        cleared("[-]>[+]>[---]"),
+        vec![range(0, 3), IncrementPointer(2)]
+    );
+    // the move after the run is merged, whichever way it walks
+    assert_eq!(
+        cleared("[-]>[-]>>+"),
+        vec![range(0, 2), IncrementPointer(3), IncrementData(1)]
+    );
+    assert_eq!(
+        cleared("[-]<[-]<[-]<<"),
+        vec![range(-2, 3), DecrementPointer(4)]
+    );
+    assert_eq!(
+        cleared("[-]<."),
+        vec![range(0, 1), DecrementPointer(1), Output]
+    );
+
+    // I/O, wider steps and other loops end the run
+    assert_eq!(
+        cleared("[-]>.[-]"),
+        vec![range(0, 1), IncrementPointer(1), Output, range(0, 1)]
+    );
+    assert_eq!(
+        cleared("[-]>>[-]"),
+        vec![range(0, 1), IncrementPointer(2), range(0, 1)]
+    );
+    assert_eq!(
+        cleared("+[[-]>[-]<-]"),
+        vec![
+            IncrementData(1),
+            LoopStart(6),
+            range(0, 2),
+            IncrementPointer(1),
+            DecrementPointer(1),
+            DecrementData(1),
+            LoopEnd(1),
+        ]
+    );
+    // an even step never reaches zero from an odd value
+    assert_eq!(cleared("[--]").len(), 3);
+    assert_eq!(cleared("[->+<]").len(), 6);
+}
+
+#[test]
+fn test_mul_loops() {
+    use crate::{
+        generate::ProgramGenerator,
+        program::{OptLevel, Program},
+        vm::VM,
+    };
+    use Token::*;
+
+    let folded = |src: &str| {
+        let mut tokens = tokenizer(src).unwrap();
+        optimize(&mut tokens);
+        mul_loops(&mut tokens);
+        assert!(verify(&tokens).is_ok());
+        tokens
+    };
+    let mul = |offset, factor| MulAdd { offset, factor };
+    let clear = ClearRange {
+        start_offset: 0,
+        len: 1,
+    };
+
+    assert_eq!(folded("[->+++>+<<]"), vec![mul(1, 3), mul(2, 1), clear]);
+    // the step may come anywhere, and a subtracting loop has a factor to wrap
+    assert_eq!(folded("[<<->>>+<-]"), vec![mul(-2, 255), mul(1, 1), clear]);
+    assert_eq!(
+        folded("+[<+>-]>[>++<+]"),
+        vec![
+            IncrementData(1),
+            mul(-1, 1),
+            clear,
+            IncrementPointer(1),
+            mul(1, 254),
+            clear
+        ]
+    );
+    // other loops stay as they are: no net move, a step other than one,
+    // I/O, nesting, or nothing moved
+    for src in ["[->+<<]", "[-->+<]", "[->.<]", "[->[-]<]", "[-]"] {
+        let mut tokens = tokenizer(src).unwrap();
+        optimize(&mut tokens);
+        assert_eq!(folded(src), tokens, "{}", src);
+    }
+    // a detour to a cell left as it was is gone before loops are looked at
+    assert_eq!(folded("[->>+-<+<]"), folded("[->+<]"));
+
+    // optimized and unoptimized runs agree byte for byte, on the tape too
+    let run = |src: &str, level: OptLevel, cells: usize| {
+        let program = Program::compile_with(src, level).unwrap();
+        let mut tape = vec![0_u8; cells];
+        let mut output = vec![];
+        let mut vm = VM::with_tape(program, &mut tape)
+            .unwrap()
+            .with_io(&b"\x07\x03"[..], &mut output);
+        let ok = vm.run().is_ok();
+        drop(vm);
+        (ok, output, tape)
+    };
+    let hellow = std::fs::read_to_string("bfcode/hellow.bf").unwrap();
+    let mut programs = vec![
+        hellow,
+        "++++[>+++++<-]>[<+++>>++<-]<.>>.".to_string(),
+        ",>,<[>[>+>+<<-]>>[<<+>>-]<<<-]>>.".to_string(),
+        "+++++[>+++++++<-]>[<+>>+++<-]>[->+<<->]<<.>>>.".to_string(),
+        ">+[>+<-]".to_string(),
+    ];
+    for seed in 0..60 {
+        let src = ProgramGenerator::new(seed)
+            .max_len(200)
+            .loop_probability(0.2)
+            .terminating(true)
+            .generate();
+        programs.push(src);
+    }
+    let mut fused = 0;
+    for src in &programs {
+        let o2 = Program::compile_with(src, OptLevel::O2).unwrap();
+        fused += o2
+            .tokens()
+            .iter()
+            .filter(|t| matches!(t, MulAdd { .. }))
+            .count();
+        let cells = if src == ">+[>+<-]" { 2 } else { 4097 };
+        let plain = run(src, OptLevel::O0, cells);
+        assert_eq!(run(src, OptLevel::O2, cells), plain, "{}", src);
+        assert_eq!(plain.0, cells != 2, "{}", src);
+    }
+    assert!(fused > 20, "{} fused", fused);
+}
+
+#[test]
+fn test_fold_known() {
+    use Token::*;
+
+    let compile = |src: &str| {
+        let mut tokens = tokenizer(src).unwrap();
+        optimize(&mut tokens);
+        clear_ranges(&mut tokens);
+        tokens
+    };
+    let range = |start_offset, len| ClearRange { start_offset, len };
+    assert_eq!(
+        known_values(&compile("+[-]++>,<.")),
+        vec![None, None, Some(0), Some(2), None, None, Some(2)]
+    );
+    // a skipped loop leaves what was known in place
+    assert_eq!(
+        known_values(&compile("[-]+>[-][,]<.")),
+        vec![
+            None,
+            Some(0),
+            Some(1),
+            None,
+            Some(0),
+            None,
+            None,
+            Some(0),
+            Some(1)
+        ]
+    );
+
+    let fold = |src: &str| {
+        let mut tokens = compile(src);
+        fold_known(&mut tokens);
+        assert!(verify(&tokens).is_ok());
+        tokens
+    };
+    assert_eq!(
+        fold("[-][.]++."),
+        vec![range(0, 1), IncrementData(2), Print(2)]
+    );
+    assert_eq!(
+        fold("[-]+[-]>[-]<[+>[-]]."),
+        vec![
+            range(0, 1),
+            IncrementData(1),
+            range(0, 2),
+            IncrementPointer(1),
+            DecrementPointer(1),
+            Print(0),
+        ]
+    );
+    // the starting tape, unknown cells and repeated dots stay as they are
+    assert_eq!(fold("[.]"), compile("[.]"));
+    assert_eq!(
+        fold("[-],.+."),
+        vec![range(0, 1), Input, Output, IncrementData(1), Output]
+    );
+    assert_eq!(
+        fold("[-]+.."),
+        vec![range(0, 1), IncrementData(1), OutputRepeat(2)]
+    );
+
+    // every consumer agrees with the reference interpreter
+    for src in [
+        "++++++++[>++++++++<-]>+.+.[-]<[>.<-]++.",
+        "[->+<]+++.>.<[.-]>>+++[<.>-]<.",
+        "++[-]>[-]+.[<+.>-]<.>>[-][,.]<<.",
+        ",[.[-]]+.",
+    ] {
+        let mut expected = vec![];
+        crate::reference::run(
+            src,
+            64,
+            &mut &b""[..],
+            &mut expected,
+            crate::reference::OnEof::Zero,
+        )
+        .unwrap();
+        let folded = fold(src);
+        assert!(folded.iter().any(|t| matches!(t, Print(_))), "{}", src);
+        assert_eq!(eval(&folded).0, expected, "{}", src);
+        // and so does every engine running it at O2
+        let mismatches = crate::reference::differential(src, b"", crate::reference::OnEof::Zero);
+        assert!(mismatches.is_empty(), "{}: {:?}", src, mismatches);
+    }
+}
+
+#[test]
+fn test_parallel_front_end() {
+    use crate::{
+        generate::ProgramGenerator,
+        program::{OptLevel, Program},
+    };
+
+    // seams in the middle of lines, of multi-byte characters and of runs
+    let mut big = String::new();
+    let mut gen = ProgramGenerator::new(245).max_len(4000);
+    for i in 0..40 {
+        big += &gen.generate();
+        big += ["\n", "é", "\n\n", " ", "+++", "日本"][i % 6];
+    }
+    let one_line = "+>".repeat(5000) + &"[-<]".repeat(100);
+    let mut cases: Vec<(String, Dialect)> = ["hellow.bf", "echo.bf"]
+        .iter()
+        .map(|f| {
+            (
+                std::fs::read_to_string(format!("bfcode/{}", f)).unwrap(),
+                Dialect::Standard,
+            )
+        })
+        .collect();
+    cases.push((
+        std::fs::read_to_string("bfcode/ops.ebf").unwrap(),
+        Dialect::Ebf1,
+    ));
+    cases.push((big, Dialect::Standard));
+    cases.push((one_line, Dialect::Standard));
+    cases.push((String::new(), Dialect::Standard));
+
+    let wrap = CellOverflow::Wrap;
+    for (src, dialect) in &cases {
+        let ops = lex_dialect(src, *dialect);
+        for level in OptLevel::ALL {
+            let expected = Program::compile_dialect(src, level, *dialect).unwrap();
+            for threads in [2, 3, 8, 64] {
+                assert_eq!(lex_parallel(src, *dialect, threads), ops);
+                let program = Program::compile_parallel(
+                    src,
+                    level,
+                    *dialect,
+                    StartTape::Unknown,
+                    wrap,
+                    threads,
+                )
+                .unwrap();
+                assert_eq!(program.to_bytecode(false), expected.to_bytecode(false));
+            }
+        }
+    }
+    // unbalanced source fails the same way on any number of threads
+    let err = Program::compile_parallel(
+        "+[\n]]",
+        OptLevel::O2,
+        Dialect::Standard,
+        StartTape::Unknown,
+        CellOverflow::Wrap,
+        4,
+    );
+    assert_eq!(
+        (err.as_ref().unwrap_err().line(), err.unwrap_err().col()),
+        (2, 2)
+    );
+}
+
+#[test]
+fn test_shebang() {
+    let script = "#!/usr/bin/env -S bfjit --eof=0 [+-<>.,]\n+[]]\n[]\n";
+    let err = tokenizer(strip_shebang(script)).unwrap_err();
+    assert_eq!((err.line(), err.col()), (2, 4));
+    let script = "#![.\n\n+.";
+    let ops = lex(strip_shebang(script));
+    assert_eq!(ops.len(), 2);
+    assert_eq!(ops[0].span, Span { line: 3, col: 1 });
+    assert_eq!(strip_shebang("#![]"), "");
+    // only a first line that starts with the two characters
+    assert_eq!(strip_shebang(" #!+\n"), " #!+\n");
+    assert_eq!(strip_shebang("+\n#!+"), "+\n#!+");
+
+    let path = std::env::temp_dir().join(format!("bfjit-shebang-{}.bf", std::process::id()));
+    std::fs::write(&path, "#!/usr/bin/env bfjit\n+++[>+<-]").unwrap();
+    let program = crate::vm::load_program(
+        &path,
+        crate::program::OptLevel::default(),
+        StartTape::Unknown,
+        CellOverflow::Wrap,
+    )
+    .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(program.spans()[0], Span { line: 2, col: 1 });
+}
+
+#[test]
+fn test_split_bang() {
+    use crate::vm::VM;
+
+    assert_eq!(split_bang(",[.,]!in!put"), (",[.,]", Some(&b"in!put"[..])));
+    assert_eq!(split_bang(",[.,]"), (",[.,]", None));
+    // a `!` in a loop is comment, even in the comment loop up front
+    assert_eq!(
+        split_bang("[ no! ],[-!-]!.!"),
+        ("[ no! ],[-!-]", Some(&b".!"[..]))
+    );
+    assert_eq!(split_bang("[!]"), ("[!]", None));
+
+    let path = std::env::temp_dir().join(format!("bfjit-bang-{}.bf", std::process::id()));
+    // neither half need be UTF-8
+    std::fs::write(&path, b"#!/usr/bin/env bfjit\n,\xff[.,]!ba\xffng\0").unwrap();
+    let output = crate::vm::SharedOutput::default();
+    let vm = VM::new_from_file_bang_input(&path);
+    std::fs::remove_file(&path).unwrap();
+    let mut vm = vm.unwrap().with_output(output.clone());
+    vm.run().unwrap();
+    assert_eq!(output.take(), b"ba\xffng");
+}
+
+#[test]
+fn test_cancel_runs() {
+    use Token::*;
+
+    let folded = |src: &str| {
+        let mut tokens = tokenizer(src).unwrap();
+        optimize(&mut tokens);
+        tokens
+    };
+
+    assert_eq!(folded("+++--"), [IncrementData(1)]);
+    assert_eq!(folded("+--"), [DecrementData(1)]);
+    assert_eq!(folded("><"), []);
+    assert_eq!(folded("<>>"), [IncrementPointer(1)]);
+    // what is left once a run between them nets out folds as well
+    assert_eq!(folded(">+-<<"), [DecrementPointer(1)]);
+    assert_eq!(folded("+<>-."), [Output]);
+    assert_eq!(
+        folded("+>-<"),
+        [
+            IncrementData(1),
+            IncrementPointer(1),
+            DecrementData(1),
+            DecrementPointer(1)
+        ]
+    );
+    // a cell's worth of `+` wraps to nothing, and is dropped like `+-`
+    assert_eq!(folded(&"+".repeat(256)), []);
+    assert_eq!(folded(&format!(">{}<", "-".repeat(512))), []);
+    assert_eq!(folded(&"+".repeat(257)), [IncrementData(1)]);
+    // the span is that of the first command folded in
+    let (mut tokens, mut spans): (Vec<_>, Vec<_>) =
+        lex(" >+-\n<<").iter().map(|op| (op.token, op.span)).unzip();
+    optimize_spanned(&mut tokens, &mut spans, CellOverflow::Wrap);
+    assert_eq!(tokens, [DecrementPointer(1)]);
+    assert_eq!(spans, [Span { line: 1, col: 2 }]);
+
+    // brackets around and after the cancelled runs still find each other
+    let tokens = folded("+[>+-<[<>]-]<>[+-.]");
+    assert_eq!(
+        tokens,
+        [
+            IncrementData(1),
+            LoopStart(5),
+            LoopStart(3),
+            LoopEnd(2),
+            DecrementData(1),
+            LoopEnd(1),
+            LoopStart(8),
+            Output,
+            LoopEnd(6),
+        ]
+    );
+    assert_eq!(verify(&tokens), Ok(()));
+}
+
+#[test]
+fn test_dead_loops() {
+    use Token::*;
+
+    let folded_on = |src: &str, start: StartTape| {
+        let mut tokens = tokenizer(src).unwrap();
+        optimize(&mut tokens);
+        dead_loops(&mut tokens, start);
+        assert_eq!(verify(&tokens), Ok(()), "{}", src);
+        tokens
+    };
+    let folded = |src: &str| folded_on(src, StartTape::Zeroed);
+    // comment blocks after a loop go, nested brackets and all, as does a
+    // loop after one of them
+    assert_eq!(
+        folded("+[-][a [+] [.,]][>]."),
+        [
+            IncrementData(1),
+            LoopStart(3),
+            DecrementData(1),
+            LoopEnd(1),
+            Output
+        ]
+    );
+    assert_eq!(
+        folded("+[[-][.]>[<]]"),
+        [
+            IncrementData(1),
+            LoopStart(9),
+            LoopStart(4),
+            DecrementData(1),
+            LoopEnd(2),
+            IncrementPointer(1),
+            LoopStart(8),
+            DecrementPointer(1),
+            LoopEnd(6),
+            LoopEnd(1),
+        ]
+    );
+    // a loop at the start goes on a zeroed tape, after moves too, but is
+    // kept when the tape may be preloaded; moves that net out leave the
+    // loops adjacent
+    assert_eq!(folded("[a][-]"), []);
+    assert_eq!(
+        folded(">>[-]<[.]+"),
+        [IncrementPointer(2), DecrementPointer(1), IncrementData(1)]
+    );
+    assert_eq!(
+        folded_on("[a][-]", StartTape::Unknown),
+        [LoopStart(1), LoopEnd(0)]
+    );
+    assert_eq!(
+        folded_on("[-]<>[.]", StartTape::Unknown),
+        [LoopStart(2), DecrementData(1), LoopEnd(0)]
+    );
+    // a program without commands compiles to nothing
+    assert_eq!(folded("no code here"), []);
+}
+
+#[test]
+fn test_tokenizer_from_reader() {
+    use crate::program::{OptLevel, Program};
+
+    // a reader that hands over a few bytes at a time, so characters and
+    // CRLFs straddle the chunks
+    struct Trickle<'a>(&'a [u8], usize);
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.1 = self.1 % 7 + 1;
+            let n = self.1.min(buf.len()).min(self.0.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    let line = "++[>+++<-] é→ cell 🙂\r\n>[-]<.,\n\t[->+<]\n";
+    let big = line.repeat(4 * 1024 * 1024 / line.len() + 1);
+    assert!(big.len() > 4 * 1024 * 1024);
+    let ops = lex_reader(Trickle(big.as_bytes(), 0), Dialect::Standard).unwrap();
+    assert_eq!(ops, lex(&big));
+    assert_eq!(
+        tokenizer_from_reader(big.as_bytes()).unwrap(),
+        tokenizer(&big).unwrap()
+    );
+    let ebf1 = std::fs::read_to_string("bfcode/ops.ebf").unwrap();
+    let ops = lex_reader(Trickle(ebf1.as_bytes(), 0), Dialect::Ebf1).unwrap();
+    assert_eq!(ops, lex_dialect(&ebf1, Dialect::Ebf1));
+
+    // bytes that are not UTF-8 are comment, a column each
+    let raw = b"+\xff+\xff\xfe.\n\xff>\xc0[-\xff]";
+    let ops = lex_reader(&raw[..], Dialect::Standard).unwrap();
+    let spans: Vec<_> = ops.iter().map(|op| (op.span.line, op.span.col)).collect();
+    assert_eq!(
+        spans,
+        [(1, 1), (1, 3), (1, 6), (2, 2), (2, 4), (2, 5), (2, 7)]
+    );
+    let tokens = tokenizer_from_reader(&raw[..]).unwrap();
+    assert_eq!(tokens, tokenizer("++.\n>[-]").unwrap());
+    let err = tokenizer_from_reader(&b"\xff\n\xff]"[..]).unwrap_err();
+    assert_eq!((err.code(), err.line(), err.col()), ("E0101", 2, 2));
+
+    // a file no longer has to be UTF-8
+    let path = std::env::temp_dir().join(format!("bfjit-stream-{}.bf", std::process::id()));
+    std::fs::write(&path, b"#!\xff+\n\xff+++[>++<-]>.").unwrap();
+    let program = crate::vm::load_program(
+        &path,
+        OptLevel::default(),
+        StartTape::Unknown,
+        CellOverflow::Wrap,
+    );
+    let program = program.unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let expected = Program::compile_with("\n+++[>++<-]>.", OptLevel::default()).unwrap();
+    assert_eq!(program.to_bytecode(false), expected.to_bytecode(false));
+
+    // a failed read says where it got to
+    struct Broken(usize);
+    impl Read for Broken {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match std::mem::take(&mut self.0) {
+                0 => Err(io::Error::other("gone")),
+                n => {
+                    buf[..n].fill(b'+');
+                    Ok(n)
+                }
+            }
+        }
+    }
+    let err = tokenizer_from_reader(Broken(3)).unwrap_err();
+    assert_eq!((err.code(), err.line(), err.col()), ("E0104", 1, 3));
+    assert_eq!(err.category(), ErrorCategory::Io);
+}
+
+#[test]
+fn test_offset_ops() {
+    use crate::{
+        generate::ProgramGenerator,
+        program::Program,
+        reference::{self, OnEof},
+        vm::{CellOverflow, VmError, VmOptions, VM},
+    };
+    use Token::*;
+
+    let offset = |src: &str| {
+        let mut tokens = tokenizer(src).unwrap();
+        optimize(&mut tokens);
+        clear_ranges(&mut tokens);
+        offset_ops(&mut tokens);
+        assert_eq!(verify(&tokens), Ok(()), "{}", src);
+        tokens
+    };
+    assert_eq!(
+        offset(">>+++<<-"),
+        [
+            AddAt {
+                offset: 2,
+                value: 3
+            },
+            DecrementData(1)
+        ]
+    );
+    assert_eq!(
+        offset(">+>-->"),
+        [
+            AddAt {
+                offset: 1,
+                value: 1
+            },
+            AddAt {
+                offset: 2,
+                value: 254
+            },
+            IncrementPointer(3)
+        ]
+    );
+    // a clear moves with the rest, and a block ends the stretch
+    assert_eq!(
+        offset("+[>>[-]<+<-.]"),
+        [
+            IncrementData(1),
+            LoopStart(6),
+            ClearRange {
+                start_offset: 2,
+                len: 1
+            },
+            AddAt {
+                offset: 1,
+                value: 1
+            },
+            DecrementData(1),
+            Output,
+            LoopEnd(1)
+        ]
+    );
+    // nothing saved, nothing changed
+    assert_eq!(offset(">+."), tokenizer(">+.").unwrap());
+    // a run too long for `AddAt` makes the move before it
+    let long = format!(">{}>+<<", "+".repeat(200));
+    assert_eq!(
+        offset(&long),
+        [
+            IncrementPointer(1),
+            IncrementData(200),
+            AddAt {
+                offset: 1,
+                value: 1
+            },
+            DecrementPointer(1)
+        ]
+    );
+
+    // each access is checked where it lands, and subtracts like `-`
+    let folded = Program::compile(">+<<-").unwrap();
+    assert!(matches!(folded.tokens()[1], AddAt { offset: -1, .. }));
+    let mut vm = VM::new(folded).unwrap();
+    assert!(matches!(vm.run(), Err(VmError::PointerOverFlow(_))));
+    assert_eq!((vm.pc(), vm.pointer(), vm.cells(1..2)), (1, 0, vec![1]));
+    let strict = VmOptions {
+        cell_overflow: CellOverflow::Error,
+        ..Default::default()
+    };
+    let program = Program::compile(">->+<<").unwrap();
+    let mut vm = VM::new(program).unwrap().with_options(strict);
+    assert!(matches!(vm.run(), Err(VmError::CellOverflow(1))));
+    // what was written past the pointer counts as reached
+    let mut vm = VM::new(Program::compile(">>>+<<").unwrap()).unwrap();
+    vm.run().unwrap();
+    assert_eq!((vm.pointer(), vm.high_water()), (1, 3));
+
+    // generated programs run the same folded as written, on every engine
+    let mut checked = 0;
+    for seed in 0..200 {
+        let src = ProgramGenerator::new(seed)
+            .max_len(80)
+            .loop_probability(0.2)
+            .io_probability(0.1)
+            .terminating(true)
+            .generate();
+        let mismatches = reference::differential(&src, b"input", OnEof::Zero);
+        if mismatches.len() == 1 && mismatches[0].starts_with("reference: ") {
+            continue;
+        }
+        assert!(mismatches.is_empty(), "{}: {:?}", src, mismatches);
+        checked += 1;
+    }
+    assert!(checked > 100, "{}", checked);
+}
+
+#[test]
+fn test_word_dialects() {
+    use crate::{
+        program::{OptLevel, Program},
+        vm::{SharedOutput, VM},
+    };
+
+    let run = |src: &str, dialect| {
+        let program = Program::compile_dialect(src, OptLevel::O2, dialect).unwrap();
+        let output = SharedOutput::default();
+        let mut vm = VM::new(program)
+            .unwrap()
+            .with_io(io::empty(), output.clone());
+        vm.run().unwrap();
+        output.take()
+    };
+    let hellow = std::fs::read_to_string("bfcode/hellow.ook").unwrap();
+    assert_eq!(run(&hellow, Dialect::Ook), b"Hello World!\n");
+
+    // the same ops as the brainfuck it spells, at the words it was read from
+    let ops = lex_dialect(&hellow, Dialect::Ook);
+    let commands: String = ops
+        .iter()
+        .map(|op| {
+            let at = COMMANDS
+                .iter()
+                .position(|&c| command(c, Dialect::Standard) == Some(op.token));
+            COMMANDS[at.unwrap()]
+        })
+        .collect();
+    assert!(commands.starts_with("++++++++[>++++[>++>"), "{}", commands);
+    assert_eq!(ops[8].span, Span { line: 2, col: 1 });
+    assert_eq!(ops[9].span, Span { line: 2, col: 11 });
+    assert_eq!(lex_reader(hellow.as_bytes(), Dialect::Ook).unwrap(), ops);
+    assert_eq!(lex_parallel(&hellow, Dialect::Ook, 4), ops);
+    // pairs may be split across lines or run together; `Ook? Ook?` and
+    // anything else is comment
+    let ops = lex_dialect("Ook.\n Ook. Ook?Ook? x Ook!Ook.", Dialect::Ook);
+    let tokens: Vec<_> = ops.iter().map(|op| (op.token, op.span)).collect();
+    assert_eq!(
+        tokens,
+        [
+            (Token::IncrementData(1), Span { line: 1, col: 1 }),
+            (Token::Output, Span { line: 2, col: 18 })
+        ]
+    );
+    // errors point at the original text
+    let err = link(&lex_dialect("Ook. Ook.\n  Ook! Ook?", Dialect::Ook)).unwrap_err();
+    assert_eq!((err.code(), err.line(), err.col()), ("E0102", 2, 3));
+
+    // a substitution dialect, the longer of two words taken
+    let text = "# words\n+ plus\n- minus\n> right\n< left\n, in\n. out\n[ loop\n] loopend\n";
+    let map: &'static WordMap = Box::leak(Box::new(WordMap::parse(text).unwrap()));
+    assert_eq!(map.word(']'), Some("loopend"));
+    let src = "plus plus loop right plus plus left minus loopend right out";
+    assert_eq!(run(src, Dialect::Custom(map)), [4]);
+    assert!(Dialect::Custom(map).uses('p') && !Dialect::Custom(map).uses('!'));
+    let bad = |text: &str| WordMap::parse(text).map(drop).unwrap_err();
+    let err = bad("+ plus\n  * times\n");
+    assert_eq!((err.code(), err.line(), err.col()), ("E0105", 2, 3));
+    assert_eq!(
+        bad("+ a\n- a\n").to_string(),
+        "E0105 Bad word map, word given twice at line 2:1"
+    );
+    assert_eq!(bad("+ a\n+ b\n").line(), 2);
+    assert_eq!(bad("+\n").line(), 1);
+    assert_eq!(
+        bad("+ a\n").to_string(),
+        "E0105 Bad word map, a command has no word at line 1:0"
+    );
+}
@@ -0,0 +1,200 @@
+//! A record per instruction the interpreter runs, for `--trace`.
+//!
+//! Each record has the instruction's index, the data pointer, and the cell
+//! under the pointer before and after the instruction ran. As text that is
+//! one line per record, the token last:
+//!
+//! ```text
+//! 0 0 0 1 ADD 1
+//! 1 0 1 2 ADD 1
+//! ```
+//!
+//! The binary form leaves the token out, since the program has it at that
+//! index:
+//!
+//! ```text
+//! magic    "BFT\0"
+//! version  u8
+//! records  varint pc, varint pointer, u8 cell before, u8 cell after
+//! ```
+//!
+//! An instruction that fails has no record, nor does one the run never got
+//! to. Records go through one buffer reused for all of them, so a traced run
+//! allocates nothing per step.
+
+use std::io::{self, BufWriter, Write};
+
+use crate::{bytecode, tokenizer::Token};
+
+const MAGIC: &[u8; 4] = b"BFT\0";
+const VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TraceFormat {
+    #[default]
+    Text,
+    Binary,
+}
+
+impl TraceFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(TraceFormat::Text),
+            "binary" => Some(TraceFormat::Binary),
+            _ => None,
+        }
+    }
+}
+
+// `x` in decimal, without the formatting machinery `write!` goes through
+fn decimal(out: &mut Vec<u8>, mut x: usize) {
+    let mut digits = [0_u8; 20];
+    let mut i = digits.len();
+    loop {
+        i -= 1;
+        digits[i] = b'0' + (x % 10) as u8;
+        x /= 10;
+        if x == 0 {
+            break;
+        }
+    }
+    out.extend_from_slice(&digits[i..]);
+}
+
+/// Where a run's records go, up to `limit` of them.
+pub struct Tracer {
+    out: BufWriter<Box<dyn Write>>,
+    format: TraceFormat,
+    limit: Option<u64>,
+    records: u64,
+    started: bool,   // whether the binary header is out
+    record: Vec<u8>, // a record as it is put together
+}
+
+impl Tracer {
+    pub fn new(sink: impl Write + 'static, format: TraceFormat) -> Self {
+        Tracer {
+            out: BufWriter::new(Box::new(sink)),
+            format,
+            limit: None,
+            records: 0,
+            started: false,
+            record: Vec::with_capacity(24),
+        }
+    }
+
+    /// Stop writing after `records` of them; the run goes on.
+    pub fn with_limit(mut self, records: u64) -> Self {
+        self.limit = Some(records);
+        self
+    }
+
+    /// Records written so far.
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    /// Whether the limit is reached, so further records are dropped.
+    pub fn full(&self) -> bool {
+        self.limit.is_some_and(|limit| self.records >= limit)
+    }
+
+    pub fn record(
+        &mut self,
+        pc: usize,
+        token: Token,
+        pointer: usize,
+        before: u8,
+        after: u8,
+    ) -> io::Result<()> {
+        if self.full() {
+            return Ok(());
+        }
+        self.records += 1;
+        self.record.clear();
+        match self.format {
+            TraceFormat::Text => {
+                for x in [pc, pointer, before as usize, after as usize] {
+                    decimal(&mut self.record, x);
+                    self.record.push(b' ');
+                }
+                write!(self.record, "{}", token)?;
+                self.record.push(b'\n');
+            }
+            TraceFormat::Binary => {
+                self.start()?;
+                bytecode::put_varint(&mut self.record, pc as u64);
+                bytecode::put_varint(&mut self.record, pointer as u64);
+                self.record.extend_from_slice(&[before, after]);
+            }
+        }
+        self.out.write_all(&self.record)
+    }
+
+    /// Push out what is buffered; a binary trace without records still gets
+    /// its header.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.format == TraceFormat::Binary {
+            self.start()?;
+        }
+        self.out.flush()
+    }
+
+    fn start(&mut self) -> io::Result<()> {
+        if !self.started {
+            self.started = true;
+            self.out.write_all(MAGIC)?;
+            self.out.write_all(&[VERSION])?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_trace() {
+    use crate::{
+        bytecode::Reader,
+        engine::{Engine, ExecContext, Interpreter},
+        program::{OptLevel, Program},
+        vm::SharedOutput,
+    };
+
+    let traced = |src: &str, tracer: Tracer| {
+        let program = Program::compile_with(src, OptLevel::O0).unwrap();
+        let mut tape = vec![0_u8; 16];
+        let (mut input, mut output) = (&b""[..], vec![]);
+        let mut ctx = ExecContext::new(&mut tape, &mut input, &mut output);
+        ctx.trace = Some(tracer);
+        let result = Interpreter.run(&program, &mut ctx).map(drop);
+        (result, ctx.trace.take().unwrap())
+    };
+
+    let sink = SharedOutput::default();
+    let (result, tracer) = traced("+++.", Tracer::new(sink.clone(), TraceFormat::Text));
+    result.unwrap();
+    assert_eq!(tracer.records(), 4);
+    assert_eq!(
+        String::from_utf8(sink.take()).unwrap(),
+        "0 0 0 1 ADD 1\n1 0 1 2 ADD 1\n2 0 2 3 ADD 1\n3 0 3 3 OUT\n"
+    );
+
+    // the binary form has the same records; past the limit the run goes on
+    // unrecorded, and a failing instruction has none
+    let tracer = Tracer::new(sink.clone(), TraceFormat::Binary).with_limit(3);
+    let (result, tracer) = traced("->+<<", tracer);
+    assert_eq!(result.unwrap_err().code(), "E0403");
+    assert!(tracer.full());
+    let bytes = sink.take();
+    assert_eq!(&bytes[..5], b"BFT\0\x01");
+    let mut r = Reader::new(&bytes[5..]);
+    let mut records = vec![];
+    while r.remaining() > 0 {
+        let (pc, pointer) = (r.varint().unwrap(), r.varint().unwrap());
+        records.push((pc, pointer, r.byte().unwrap(), r.byte().unwrap()));
+    }
+    assert_eq!(records, [(0, 0, 0, 255), (1, 0, 255, 255), (2, 1, 0, 1)]);
+    let (result, tracer) = traced("<", Tracer::new(sink.clone(), TraceFormat::Binary));
+    assert!(result.is_err());
+    assert_eq!(tracer.records(), 0);
+    assert_eq!(sink.take(), b"BFT\0\x01");
+}
@@ -0,0 +1,191 @@
+//! Checking that program output is UTF-8, for `--output-utf8`.
+//!
+//! Programs write one byte per `.`, so a multi-byte character is split over
+//! several writes. `Utf8Writer` keeps the bytes of an unfinished sequence
+//! between writes and only passes them on once the sequence is complete.
+//! `Strict` fails with the offset of the first bad sequence, after writing
+//! everything before it; `Lossy` writes U+FFFD in its place and carries on.
+//! A sequence still unfinished at the end only shows up in `finish`.
+
+use std::io::{self, Write};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Utf8Mode {
+    /// Bytes go out as written.
+    #[default]
+    Raw,
+    Strict,
+    Lossy,
+}
+
+impl Utf8Mode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "raw" => Some(Utf8Mode::Raw),
+            "strict" => Some(Utf8Mode::Strict),
+            "lossy" => Some(Utf8Mode::Lossy),
+            _ => None,
+        }
+    }
+}
+
+const REPLACEMENT: &[u8] = "\u{fffd}".as_bytes();
+
+// sequence length for a lead byte, and the range its second byte must be in
+// to rule out overlong forms, surrogates and code points past U+10FFFF
+fn lead(byte: u8) -> Option<(usize, u8, u8)> {
+    match byte {
+        0xc2..=0xdf => Some((2, 0x80, 0xbf)),
+        0xe0 => Some((3, 0xa0, 0xbf)),
+        0xed => Some((3, 0x80, 0x9f)),
+        0xe1..=0xef => Some((3, 0x80, 0xbf)),
+        0xf0 => Some((4, 0x90, 0xbf)),
+        0xf1..=0xf3 => Some((4, 0x80, 0xbf)),
+        0xf4 => Some((4, 0x80, 0x8f)),
+        _ => None,
+    }
+}
+
+pub struct Utf8Writer<W: Write> {
+    inner: W,
+    mode: Utf8Mode,
+    pending: [u8; 4], // start of an unfinished sequence
+    have: usize,      // bytes of it in `pending`
+    need: usize,      // its full length, 0 between sequences
+    offset: u64,      // stream offset of `pending[0]`, or of the next byte
+}
+
+impl<W: Write> Utf8Writer<W> {
+    pub fn new(inner: W, mode: Utf8Mode) -> Self {
+        Utf8Writer {
+            inner,
+            mode,
+            pending: [0; 4],
+            have: 0,
+            need: 0,
+            offset: 0,
+        }
+    }
+
+    fn invalid(&self) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid UTF-8 in output at byte {}", self.offset),
+        )
+    }
+
+    // drop the bad sequence at `offset`, replacing it or failing
+    fn reject(&mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        if self.mode == Utf8Mode::Strict {
+            self.inner.write_all(out)?;
+            return Err(self.invalid());
+        }
+        out.extend_from_slice(REPLACEMENT);
+        self.offset += self.have.max(1) as u64;
+        (self.have, self.need) = (0, 0);
+        Ok(())
+    }
+
+    /// Check that no sequence was left unfinished, then flush.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.need > 0 {
+            let mut out = vec![];
+            self.reject(&mut out)?;
+            self.inner.write_all(&out)?;
+        }
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for Utf8Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.mode == Utf8Mode::Raw {
+            return self.inner.write(buf);
+        }
+        let mut out = Vec::with_capacity(buf.len());
+        let mut i = 0;
+        while i < buf.len() {
+            let byte = buf[i];
+            if self.need == 0 {
+                if byte < 0x80 {
+                    out.push(byte);
+                    self.offset += 1;
+                } else if let Some((need, _, _)) = lead(byte) {
+                    (self.pending[0], self.have, self.need) = (byte, 1, need);
+                } else {
+                    self.reject(&mut out)?;
+                }
+                i += 1;
+                continue;
+            }
+            let (lo, hi) = match self.have {
+                1 => lead(self.pending[0]).map(|(_, lo, hi)| (lo, hi)).unwrap(),
+                _ => (0x80, 0xbf),
+            };
+            if !(lo..=hi).contains(&byte) {
+                // the byte may well start the next sequence, so look again
+                self.reject(&mut out)?;
+                continue;
+            }
+            self.pending[self.have] = byte;
+            self.have += 1;
+            i += 1;
+            if self.have == self.need {
+                out.extend_from_slice(&self.pending[..self.have]);
+                self.offset += self.have as u64;
+                (self.have, self.need) = (0, 0);
+            }
+        }
+        self.inner.write_all(&out)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn test_utf8_writer() {
+    fn feed(mode: Utf8Mode, writes: &[&[u8]]) -> (Vec<u8>, Result<(), String>) {
+        let mut out = vec![];
+        let mut writer = Utf8Writer::new(&mut out, mode);
+        let result = writes
+            .iter()
+            .try_for_each(|bytes| writer.write_all(bytes))
+            .and_then(|()| writer.finish())
+            .map_err(|e| e.to_string());
+        (out, result)
+    }
+
+    // "é€😀" one byte per write, as `.` produces it
+    let text = "aé€😀".as_bytes();
+    let bytes: Vec<&[u8]> = text.chunks(1).collect();
+    for mode in [Utf8Mode::Raw, Utf8Mode::Strict, Utf8Mode::Lossy] {
+        assert_eq!(feed(mode, &bytes), (text.to_vec(), Ok(())));
+    }
+    let split: &[&[u8]] = &[b"a\xe2", b"\x82", b"\xacb"];
+    assert_eq!(feed(Utf8Mode::Strict, split), ("a€b".into(), Ok(())));
+
+    let bad: &[&[u8]] = &[b"ok\xc3", b"(\xff"];
+    let err = "invalid UTF-8 in output at byte 2".to_string();
+    assert_eq!(feed(Utf8Mode::Strict, bad), (b"ok".to_vec(), Err(err)));
+    assert_eq!(
+        feed(Utf8Mode::Lossy, bad),
+        ("ok\u{fffd}(\u{fffd}".into(), Ok(()))
+    );
+    assert_eq!(feed(Utf8Mode::Raw, bad), (b"ok\xc3(\xff".to_vec(), Ok(())));
+
+    // overlong and surrogate forms, and a sequence cut off at the end
+    let cases: [(&[u8], &str); 3] = [
+        (b"\xc0\xafx", "\u{fffd}\u{fffd}x"),
+        (b"\xed\xa0\x80", "\u{fffd}\u{fffd}\u{fffd}"),
+        (b"x\xf0\x9f\x98", "x\u{fffd}"),
+    ];
+    for (bytes, lossy) in cases {
+        assert_eq!(feed(Utf8Mode::Lossy, &[bytes]).0, lossy.as_bytes());
+        assert!(feed(Utf8Mode::Strict, &[bytes]).1.is_err());
+    }
+    let err = feed(Utf8Mode::Strict, &[b"x\xf0\x9f\x98"]).1.unwrap_err();
+    assert_eq!(err, "invalid UTF-8 in output at byte 1");
+}
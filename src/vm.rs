@@ -1,128 +1,2411 @@
-use crate::tokenizer::{self, optimize, Token};
-
-use std::{
-    fs::File,
-    io::{Read, Write},
-    mem::size_of,
-    str::FromStr,
-};
-
-const MEMORY_SIZE: usize = 4 * 1024 * 1024;
-
-#[derive(Debug, thiserror::Error)]
-pub enum VmError {
-    #[error("Instruction Is Null")]
-    InstructionIsNull,
-
-    #[error("Read File Error")]
-    IO(#[from] std::io::Error),
-
-    #[error("Token Error")]
-    Token(#[from] crate::tokenizer::TokenizerError),
-
-    #[error("Pointer OverFlow Error")]
-    PointerOverFlow,
-}
-
-pub struct VM {
-    inst_len: usize,  // instruction length
-    inst: Vec<Token>, // instruction to run
-    mem_len: usize,   // memory length
-    mem: Box<[u8]>,   // memory buffer
-}
-
-impl VM {
-    pub fn new(inst: Vec<Token>) -> Result<Self, VmError> {
-        if inst.len() == 0 {
-            return Err(VmError::InstructionIsNull);
-        }
-
-        let mem = vec![0 as u8; MEMORY_SIZE].into_boxed_slice();
-        Ok(VM {
-            mem_len: mem.len(),
-            mem,
-            inst_len: inst.len(),
-            inst,
-        })
-    }
-
-    pub fn new_from_file(path: &String) -> Result<Self, VmError> {
-        let mut file = File::open(path).expect("file not found");
-        let mut src = String::new();
-        file.read_to_string(&mut src).expect("failed to read file");
-        let mut tokens = tokenizer::tokenizer(&src)?;
-        optimize(&mut tokens);
-        Self::new(tokens)
-    }
-
-    pub fn run(&mut self) -> Result<(), VmError> {
-        let mut pc = 0;
-        let mut point = 0;
-
-        use crate::tokenizer::Token::*;
-        while pc < self.inst_len {
-            match self.inst[pc] {
-                IncrementData(x) => {
-                    self.mem[point] += x;
-                }
-                DecrementData(x) => {
-                    self.mem[point] -= x;
-                }
-                IncrementPointer(x) => {
-                    if point + x >= self.mem_len {
-                        return Err(VmError::PointerOverFlow);
-                    }
-                    point += x;
-                }
-                DecrementPointer(x) => {
-                    if ((point + x) >> (size_of::<usize>() - 1)) == 0xf {
-                        return Err(VmError::PointerOverFlow);
-                    }
-                    point -= x;
-                }
-                Output => {
-                    let mut buf = [0_u8];
-                    buf[0] = self.mem[point];
-                    match std::io::stdout().write_all(&buf) {
-                        Ok(()) => {}
-                        Err(e) => return Err(VmError::IO(e)),
-                    }
-                }
-                Input => {
-                    let mut buf = [0_u8];
-                    match std::io::stdin().read(&mut buf) {
-                        Ok(0) => {}
-                        Ok(1) => {
-                            self.mem[point] = buf[0];
-                        }
-                        Err(e) => return Err(VmError::IO(e)),
-                        _ => unreachable!(),
-                    }
-                }
-                LoopStart(x) => {
-                    if self.mem[point] == 0 {
-                        if x as usize <= self.inst_len {
-                            pc = x as usize;
-                        }
-                    }
-                }
-                LoopEnd(x) => {
-                    if self.mem[point] != 0 {
-                        if x as usize <= self.inst_len {
-                            pc = x as usize;
-                        }
-                    }
-                }
-            }
-            pc += 1;
-        }
-        Ok(())
-    }
-}
-
-#[test]
-fn test_vm_run() {
-    let file = String::from_str("bfcode\\hellow.bf").unwrap();
-    let vm = VM::new_from_file(&file);
-    vm.unwrap().run();
-}
+use crate::{
+    checkpoint::{self, Snapshot, SnapshotError},
+    console::ConsoleOutput,
+    error::ErrorCategory,
+    jit::{self, Backend, JitError},
+    program::{OptLevel, Program},
+    progress,
+    tape::Tape,
+    tokenizer::{self, Span, StartTape, Token},
+    trace::Tracer,
+};
+
+use std::{
+    borrow::Cow,
+    ffi::OsStr,
+    fmt, fs,
+    io::{self, BufRead, Read, Write},
+    ops::Range,
+    path::Path,
+    sync::Arc,
+    time::Instant,
+};
+
+/// Cells in the tape of a VM that owns its memory.
+pub const MEMORY_SIZE: usize = 4 * 1024 * 1024;
+
+// cells shown on each side of the pointer in error reports
+const WINDOW_RADIUS: usize = 8;
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum VmError {
+    #[error("E0402 Read File Error")]
+    IO(#[from] std::io::Error),
+
+    #[error("{} Token Error", .0.code())]
+    Token(#[from] crate::tokenizer::TokenizerError),
+
+    #[error("{} IR Text Error", .0.code())]
+    Ir(#[from] crate::ir_text::IrError),
+
+    #[error("{} Bytecode Error", .0.code())]
+    Load(#[from] crate::bytecode::LoadError),
+
+    #[error("{} JIT Error", .0.code())]
+    Jit(#[from] crate::jit::JitError),
+
+    #[cfg(feature = "image")]
+    #[error("{} Image Error", .0.code())]
+    Image(#[from] crate::brainloller::ImageError),
+
+    #[error("{} Snapshot Error", .0.code())]
+    Snapshot(#[from] crate::checkpoint::SnapshotError),
+
+    #[error("E0403 Pointer OverFlow Error{}", .0.map_or(String::new(), |at| format!(" {}", at)))]
+    PointerOverFlow(Option<Fault>), // where, when the VM was the one running
+
+    #[error(
+        "E0404 Loop Iteration Limit at {start}..{end} after {iterations} iterations, {window}"
+    )]
+    LoopIterationLimit {
+        start: Span, // position of the loop's `[`
+        end: Span,   // position of the loop's `]`
+        iterations: u64,
+        window: TapeWindow,
+    },
+
+    #[error("E0405 Out Of Fuel after {0} instructions")]
+    OutOfFuel(u64),
+
+    /// `OutOfFuel` from native code, which counts only at loop back-edges, a
+    /// loop's length each, and stops near the limit with the limit as the
+    /// count.
+    #[error("E0405 Out Of Fuel near {0} instructions, as the JIT counts them only roughly")]
+    NativeOutOfFuel(u64),
+
+    #[error("E0406 Output Limit of {0} bytes reached")]
+    OutputLimit(u64),
+
+    #[error("E0407 Cell Overflow at cell {0}")]
+    CellOverflow(usize),
+}
+
+impl VmError {
+    /// The stable code of this error, or of the error it wraps.
+    pub fn code(&self) -> &'static str {
+        match self {
+            VmError::IO(_) => "E0402",
+            VmError::Token(e) => e.code(),
+            VmError::Ir(e) => e.code(),
+            VmError::Load(e) => e.code(),
+            VmError::Jit(e) => e.code(),
+            #[cfg(feature = "image")]
+            VmError::Image(e) => e.code(),
+            VmError::Snapshot(e) => e.code(),
+            VmError::PointerOverFlow(_) => "E0403",
+            VmError::LoopIterationLimit { .. } => "E0404",
+            VmError::OutOfFuel(_) | VmError::NativeOutOfFuel(_) => "E0405",
+            VmError::OutputLimit(_) => "E0406",
+            VmError::CellOverflow(_) => "E0407",
+        }
+    }
+
+    /// This error with the source position of its fault looked up in
+    /// `program`, for engines that only know the pc.
+    pub fn located(self, program: &Program) -> Self {
+        match self {
+            VmError::PointerOverFlow(Some(fault)) if fault.span.is_none() => {
+                let span = program.spans().get(fault.pc).copied();
+                let span = span.filter(|span| span.line > 0);
+                VmError::PointerOverFlow(Some(Fault { span, ..fault }))
+            }
+            e => e,
+        }
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            VmError::IO(_) => ErrorCategory::Io,
+            VmError::Token(e) => e.category(),
+            VmError::Ir(e) => e.category(),
+            VmError::Load(e) => e.category(),
+            VmError::Jit(e) => e.category(),
+            #[cfg(feature = "image")]
+            VmError::Image(e) => e.category(),
+            VmError::Snapshot(e) => e.category(),
+            VmError::PointerOverFlow(_) | VmError::CellOverflow(_) => ErrorCategory::Runtime,
+            VmError::LoopIterationLimit { .. }
+            | VmError::OutOfFuel(_)
+            | VmError::NativeOutOfFuel(_)
+            | VmError::OutputLimit(_) => ErrorCategory::Limit,
+        }
+    }
+}
+
+/// The instruction that failed and the pointer it failed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    pub pc: usize,
+    pub span: Option<Span>, // none for a program without source positions
+    pub pointer: usize,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.span {
+            Some(span) => write!(f, "at {} (pc {}, pointer {})", span, self.pc, self.pointer),
+            None => write!(f, "at pc {}, pointer {}", self.pc, self.pointer),
+        }
+    }
+}
+
+/// A few cells around the data pointer, captured when a run is aborted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TapeWindow {
+    pub start: usize,   // index of the first cell in `cells`
+    pub pointer: usize, // data pointer at capture time
+    pub cells: Vec<u8>,
+}
+
+impl TapeWindow {
+    fn capture(mem: &Tape, pointer: usize) -> Self {
+        let start = pointer.saturating_sub(WINDOW_RADIUS);
+        let end = (pointer + WINDOW_RADIUS + 1).min(mem.len());
+        TapeWindow {
+            start,
+            pointer,
+            cells: mem.cells(start..end),
+        }
+    }
+}
+
+impl fmt::Display for TapeWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "tape[{}..]:", self.start)?;
+        for (i, cell) in self.cells.iter().enumerate() {
+            if self.start + i == self.pointer {
+                write!(f, " <{}>", cell)?;
+            } else {
+                write!(f, " {}", cell)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What `,` does once the input is exhausted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EofBehavior {
+    #[default]
+    Unchanged, // leave the cell as it was
+    SetZero,     // store 0
+    SetMinusOne, // store 255, -1 in a wrapping cell
+    Halt,        // end the program cleanly
+}
+
+impl EofBehavior {
+    /// Parse the spelling used by `--eof=`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "unchanged" => Some(EofBehavior::Unchanged),
+            "0" => Some(EofBehavior::SetZero),
+            "-1" => Some(EofBehavior::SetMinusOne),
+            "halt" => Some(EofBehavior::Halt),
+            _ => None,
+        }
+    }
+}
+
+/// What `+` past 255 and `-` below 0 do.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CellOverflow {
+    #[default]
+    Wrap, // around to 0 or 255, as nearly every program expects
+    Error, // fail with `CellOverflow`
+}
+
+impl CellOverflow {
+    /// Parse the spelling used by `--cell-overflow=`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "wrap" => Some(CellOverflow::Wrap),
+            "error" => Some(CellOverflow::Error),
+            _ => None,
+        }
+    }
+}
+
+/// What moving past either end of the tape does.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TapeMode {
+    #[default]
+    Fixed, // fail with `PointerOverFlow`
+    /// Double the tape, to at most `max` cells, and fail only past those.
+    /// Only a tape the VM owns can grow; one lent by `with_tape` stays fixed.
+    Grow { max: usize },
+    /// Cell 0 is in the middle of the tape, `VM::origin`, with cells on both
+    /// sides of it. Moving past either end grows the tape there as `Grow`
+    /// does; growing to the left moves every cell up, the origin with them.
+    /// Native code, which cannot grow a tape, refuses this as it does
+    /// `Grow`.
+    Bidirectional { max: usize },
+}
+
+impl TapeMode {
+    /// Where the pointer starts on a tape of `len` cells.
+    pub fn origin(self, len: usize) -> usize {
+        match self {
+            TapeMode::Bidirectional { .. } => len / 2,
+            _ => 0,
+        }
+    }
+}
+
+/// Debugging and safety knobs for a run; everything is off by default.
+#[derive(Debug, Clone, Default)]
+pub struct VmOptions {
+    /// Abort once a single entry into a loop takes more back-edges than this.
+    pub max_loop_iterations: Option<u64>,
+    pub eof: EofBehavior,
+    /// Checked on each instruction as optimized, so `+` folded into a
+    /// `[-]` clear, say, cannot overflow.
+    pub cell_overflow: CellOverflow,
+    pub tape_mode: TapeMode,
+    /// Abort once this many instructions have run; native code keeps this
+    /// only roughly, see `jit::compile_with_fuel`, and fails with
+    /// `VmError::NativeOutOfFuel`.
+    pub fuel: Option<u64>,
+    /// Abort rather than write more than this many bytes.
+    pub max_output: Option<u64>,
+    /// Count how often each instruction runs, for `VM::profile`.
+    pub profile: bool,
+}
+
+impl VmOptions {
+    /// The first option set that native code cannot keep, by field name.
+    pub fn jit_unsupported(&self) -> Option<&'static str> {
+        if self.max_loop_iterations.is_some() {
+            Some("max_loop_iterations")
+        } else if self.profile {
+            Some("profile")
+        } else if self.max_output.is_some() {
+            Some("max_output")
+        } else if self.cell_overflow != CellOverflow::Wrap {
+            Some("cell_overflow")
+        } else if self.tape_mode != TapeMode::Fixed {
+            Some("tape_mode")
+        } else {
+            None
+        }
+    }
+}
+
+/// How the last `run()` ended.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Termination {
+    #[default]
+    Finished,
+    /// `,` hit end of input under `EofBehavior::Halt`.
+    EofHalt { pc: usize },
+    /// `@` of the extended dialect ran.
+    Halted { pc: usize },
+}
+
+/// Where a `step()` left the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepResult {
+    Running,
+    /// A breakpoint just ran; the next step runs what follows it.
+    Breakpoint,
+    /// The program has ended, and stepping again does nothing.
+    Halted,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RunStats {
+    pub termination: Termination,
+    pub steps: u64,        // instructions executed
+    pub output_bytes: u64, // bytes written to the output
+}
+
+// bytes fetched from the input per underlying read
+const INPUT_CHUNK: usize = 8 * 1024;
+
+/// The VM's input, read a chunk at a time.
+///
+/// A refill is a single `read`, which returns whatever the source has ready:
+/// on a terminal that is the current line, so `,` never waits for more than
+/// the user has typed. A zero-length read is end of input. The buffer is
+/// allocated on the first refill, so a VM that never reads never has one.
+struct InputBuffer<'a> {
+    source: Box<dyn Read + 'a>,
+    buf: Vec<u8>,
+    pos: usize,
+    len: usize,
+}
+
+impl<'a> InputBuffer<'a> {
+    fn new(source: impl Read + 'a) -> Self {
+        InputBuffer {
+            source: Box::new(source),
+            buf: Vec::new(),
+            pos: 0,
+            len: 0,
+        }
+    }
+
+    /// The next byte, or `None` at end of input.
+    fn next_byte(&mut self) -> std::io::Result<Option<u8>> {
+        if self.pos == self.len {
+            self.buf.resize(INPUT_CHUNK, 0);
+            let n = self.source.read(&mut self.buf)?;
+            if n == 0 {
+                return Ok(None);
+            }
+            (self.pos, self.len) = (0, n);
+        }
+        self.pos += 1;
+        Ok(Some(self.buf[self.pos - 1]))
+    }
+}
+
+// for native code, which reads a byte at a time
+impl Read for InputBuffer<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(slot) = buf.first_mut() else {
+            return Ok(0);
+        };
+        match self.next_byte()? {
+            Some(byte) => {
+                *slot = byte;
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    }
+}
+
+pub struct VM<'t> {
+    inst_len: usize,             // instruction length
+    program: Arc<Program>,       // instructions to run, shared with other VMs
+    mem_len: usize,              // memory length
+    mem: Tape<'t>,               // memory buffer
+    options: VmOptions,          // run configuration
+    input: InputBuffer<'t>,      // source of `,`
+    output: Box<dyn Write + 't>, // sink of `.`
+    stats: RunStats,             // summary of the last run
+    pc: usize,                   // next instruction to execute
+    point: usize,                // data pointer
+    origin: usize,               // cell the pointer starts at
+    high_water: usize,           // furthest cell the pointer reached
+    storage: u8,                 // register of the extended dialect
+    // back-edges taken since each loop was last entered, by `[` index
+    loop_counts: Option<Vec<u64>>,
+    // executions of each instruction, when `options.profile` is set
+    profile: Option<Vec<u64>>,
+    // a record per instruction run goes here, when set
+    trace: Option<Tracer>,
+    // where status lines go, stderr when unset
+    progress: Option<Box<dyn Write + 't>>,
+    // `progress::requests()` when last checked, and time and steps of the
+    // last status line
+    progress_seen: u64,
+    last_report: (Instant, u64),
+}
+
+/// A VM on a tape of its own, configured before the tape is allocated.
+pub struct VmBuilder {
+    program: Arc<Program>,
+    memory_size: usize,
+    options: VmOptions,
+}
+
+impl VmBuilder {
+    /// Cells in the tape, `MEMORY_SIZE` unless set.
+    pub fn memory_size(mut self, cells: usize) -> Self {
+        self.memory_size = cells;
+        self
+    }
+
+    pub fn options(mut self, options: VmOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn build(self) -> Result<VM<'static>, VmError> {
+        let vm = VM::build(self.program, Tape::zeroed(self.memory_size))?;
+        Ok(vm.with_options(self.options))
+    }
+}
+
+impl VM<'static> {
+    /// A VM on a zeroed tape of its own. The program is only read, so one
+    /// `Arc<Program>` can back any number of VMs on any number of threads.
+    pub fn new(program: impl Into<Arc<Program>>) -> Result<Self, VmError> {
+        VM::builder(program).build()
+    }
+
+    pub fn builder(program: impl Into<Arc<Program>>) -> VmBuilder {
+        VmBuilder {
+            program: program.into(),
+            memory_size: MEMORY_SIZE,
+            options: VmOptions::default(),
+        }
+    }
+
+    pub fn from_program(program: Program) -> Result<Self, VmError> {
+        Self::new(program)
+    }
+
+    /// A VM for a file `load_program` reads, source in it optimized at
+    /// `level`.
+    pub fn new_from_file(path: impl AsRef<Path>, level: OptLevel) -> Result<Self, VmError> {
+        Self::from_program(load_program(
+            path,
+            level,
+            StartTape::Unknown,
+            CellOverflow::Wrap,
+        )?)
+    }
+
+    /// A VM for a brainfuck file that carries its input after the first `!`,
+    /// which `,` reads in place of stdin; see `tokenizer::split_bang`.
+    pub fn new_from_file_bang_input(path: impl AsRef<Path>) -> Result<Self, VmError> {
+        let bytes = fs::read(path)?;
+        let (src, input) = tokenizer::split_bang_bytes(tokenizer::strip_shebang_bytes(&bytes));
+        let program = Program::compile_bytes(
+            src,
+            OptLevel::default(),
+            tokenizer::Dialect::Standard,
+            StartTape::Unknown,
+            CellOverflow::Wrap,
+        )?;
+        let mut vm = Self::from_program(program)?;
+        vm.input = InputBuffer::new(io::Cursor::new(input.unwrap_or_default().to_vec()));
+        Ok(vm)
+    }
+
+    /// A VM for brainfuck source, without a file to load it from.
+    pub fn new_from_str(src: &str) -> Result<Self, VmError> {
+        Self::from_program(Program::compile(tokenizer::strip_shebang(src))?)
+    }
+}
+
+/// Whether a file starts with the PNG signature, whose 0x89 no UTF-8 text
+/// starts with.
+pub fn is_png(bytes: &[u8]) -> bool {
+    bytes.starts_with(b"\x89PNG\r\n\x1a\n")
+}
+
+#[cfg(feature = "image")]
+fn load_image(bytes: &[u8], level: OptLevel, overflow: CellOverflow) -> Result<Program, VmError> {
+    Ok(crate::brainloller::compile(bytes, level, overflow)?)
+}
+
+#[cfg(not(feature = "image"))]
+fn load_image(_: &[u8], _: OptLevel, _: CellOverflow) -> Result<Program, VmError> {
+    let e = "running a PNG needs bfjit built with the image feature";
+    Err(io::Error::new(io::ErrorKind::Unsupported, e).into())
+}
+
+/// Load `.bfc` bytecode, `.bfir` IR text or brainfuck source by extension,
+/// or a Brainloller PNG by extension or signature.
+///
+/// Source is optimized at `level` for a program starting on `start`, images
+/// at `level`, both to run with `overflow`; bytecode and IR text are taken
+/// as they are. Source is lexed as it is read rather than loaded first, so it
+/// may be any size and need not be UTF-8.
+pub fn load_program(
+    path: impl AsRef<Path>,
+    level: OptLevel,
+    start: StartTape,
+    overflow: CellOverflow,
+) -> Result<Program, VmError> {
+    let path = path.as_ref();
+    let extension = path.extension().and_then(OsStr::to_str);
+    if extension == Some("bfc") {
+        return Ok(Program::from_bytecode(&fs::read(path)?)?);
+    }
+    if extension == Some("bfir") {
+        return Ok(Program::from_ir_text(&fs::read_to_string(path)?)?);
+    }
+    let mut file = io::BufReader::new(fs::File::open(path)?);
+    if extension == Some("png") || is_png(file.fill_buf()?) {
+        let mut bytes = vec![];
+        file.read_to_end(&mut bytes)?;
+        return load_image(&bytes, level, overflow);
+    }
+    // a `#!` line goes but its newline stays, as with `strip_shebang`
+    let shebang = file.fill_buf()?.starts_with(b"#!");
+    if shebang {
+        file.skip_until(b'\n')?;
+    }
+    let newline = &b"\n"[..usize::from(shebang)];
+    let ops = tokenizer::lex_reader(newline.chain(file), tokenizer::Dialect::Standard)?;
+    Ok(Program::compile_ops(&ops, level, start, overflow)?)
+}
+
+impl<'t> VM<'t> {
+    /// A VM whose cells are `tape`, starting from whatever it holds.
+    ///
+    /// Nothing is allocated here or while running, and the final state is
+    /// left in the buffer once the VM is dropped. The tape is exactly as
+    /// long as the slice, so moving off its end is `PointerOverFlow`.
+    pub fn with_tape(
+        program: impl Into<Arc<Program>>,
+        tape: &'t mut [u8],
+    ) -> Result<Self, VmError> {
+        VM::build(program.into(), Tape::Borrowed(tape))
+    }
+
+    pub(crate) fn build(program: Arc<Program>, mem: Tape<'t>) -> Result<Self, VmError> {
+        Ok(VM {
+            mem_len: mem.len(),
+            mem,
+            inst_len: program.tokens().len(),
+            program,
+            options: VmOptions::default(),
+            input: InputBuffer::new(std::io::stdin()),
+            output: Box::new(ConsoleOutput::stdout(false)),
+            stats: RunStats::default(),
+            pc: 0,
+            point: 0,
+            origin: 0,
+            high_water: 0,
+            storage: 0,
+            loop_counts: None,
+            profile: None,
+            trace: None,
+            progress: None,
+            progress_seen: progress::requests(),
+            last_report: (Instant::now(), 0),
+        })
+    }
+
+    pub fn with_options(mut self, options: VmOptions) -> Self {
+        self.origin = options.tape_mode.origin(self.mem_len);
+        self.options = options;
+        self.rewind();
+        self
+    }
+
+    pub fn with_io(mut self, input: impl Read + 't, output: impl Write + 't) -> Self {
+        self.input = InputBuffer::new(input);
+        self.output = Box::new(output);
+        self
+    }
+
+    /// `with_io` for the output alone, keeping the input.
+    pub fn with_output(mut self, output: impl Write + 't) -> Self {
+        self.output = Box::new(output);
+        self
+    }
+
+    /// Send the status lines asked for with `progress::request` to `sink`
+    /// instead of stderr.
+    pub fn with_progress(mut self, sink: impl Write + 't) -> Self {
+        self.progress = Some(Box::new(sink));
+        self
+    }
+
+    /// Record every instruction run from here on to `tracer`.
+    pub fn with_trace(mut self, tracer: Tracer) -> Self {
+        self.trace = Some(tracer);
+        self
+    }
+
+    /// Stop tracing, handing back the tracer.
+    pub fn take_trace(&mut self) -> Option<Tracer> {
+        self.trace.take()
+    }
+
+    /// How many times each instruction ran, by index, when profiling.
+    ///
+    /// An instruction that failed counts as having run.
+    pub fn profile(&self) -> Option<&[u64]> {
+        self.profile.as_deref()
+    }
+
+    pub fn stats(&self) -> &RunStats {
+        &self.stats
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn pointer(&self) -> usize {
+        self.point
+    }
+
+    /// The cell the pointer starts at, 0 unless the tape is
+    /// `TapeMode::Bidirectional`, where cells left of it are reachable too.
+    pub fn origin(&self) -> usize {
+        self.origin
+    }
+
+    /// The furthest cell the pointer has reached since the run started;
+    /// nothing past it has been written.
+    pub fn high_water(&self) -> usize {
+        self.high_water
+    }
+
+    pub fn tape_len(&self) -> usize {
+        self.mem_len
+    }
+
+    /// A copy of the cells in `range`.
+    pub fn cells(&self, range: Range<usize>) -> Vec<u8> {
+        self.mem.cells(range)
+    }
+
+    /// The cells in `range` without a copy, unless a fork shares them.
+    pub fn memory(&self, range: Range<usize>) -> Cow<'_, [u8]> {
+        self.mem.memory(range)
+    }
+
+    /// All the cells, to seed before a run; `run` starts from what they hold.
+    /// A forked tape stops sharing its cells here.
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        self.mem.flat()
+    }
+
+    /// Zero the tape and go back to the first instruction, so the program
+    /// runs again as if on a fresh VM. A grown tape keeps its length.
+    pub fn reset(&mut self) {
+        self.mem.clear(0..self.mem_len);
+        self.rewind();
+    }
+
+    /// Where the VM is, to carry on from later with `restore`, here or in
+    /// another process.
+    pub fn snapshot(&self) -> Snapshot {
+        let cells = self.memory(0..self.mem_len);
+        Snapshot {
+            program: checkpoint::program_hash(&self.program),
+            pc: self.pc,
+            pointer: self.point,
+            high_water: self.high_water,
+            storage: self.storage,
+            tape_len: self.mem_len,
+            runs: checkpoint::nonzero_runs(&cells),
+        }
+    }
+
+    /// Put the VM where `snapshot` was taken, on a tape holding only its
+    /// cells, for the next `resume`. Counts and limits start over.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), VmError> {
+        let expected = checkpoint::program_hash(&self.program);
+        if snapshot.program != expected {
+            return Err(SnapshotError::OtherProgram {
+                expected,
+                found: snapshot.program,
+            }
+            .into());
+        }
+        let end = snapshot
+            .runs
+            .last()
+            .map_or(0, |(start, run)| start + run.len());
+        let needed = end.max(snapshot.high_water + 1).max(snapshot.pointer + 1);
+        if snapshot.pc > self.inst_len || !self.reach(needed - 1) {
+            return Err(SnapshotError::TooLarge(needed).into());
+        }
+        self.rewind();
+        self.mem.clear(0..self.mem_len);
+        for (start, run) in &snapshot.runs {
+            for (i, &cell) in run.iter().enumerate() {
+                self.mem.set(start + i, cell);
+            }
+        }
+        self.pc = snapshot.pc;
+        self.point = snapshot.pointer;
+        self.high_water = snapshot.high_water;
+        self.storage = snapshot.storage;
+        Ok(())
+    }
+
+    /// A VM paused at the same point with its own copy of the tape and no
+    /// I/O attached; give it some with `with_io`. Large tapes are shared
+    /// copy-on-write between the two rather than copied.
+    pub fn fork(&mut self) -> VM<'static> {
+        VM {
+            inst_len: self.inst_len,
+            program: Arc::clone(&self.program),
+            mem_len: self.mem_len,
+            mem: self.mem.fork(),
+            options: self.options.clone(),
+            input: InputBuffer::new(std::io::empty()),
+            output: Box::new(std::io::sink()),
+            stats: self.stats.clone(),
+            pc: self.pc,
+            point: self.point,
+            origin: self.origin,
+            high_water: self.high_water,
+            storage: self.storage,
+            loop_counts: self.loop_counts.clone(),
+            profile: self.profile.clone(),
+            trace: None,
+            progress: None,
+            progress_seen: self.progress_seen,
+            last_report: self.last_report,
+        }
+    }
+
+    /// Source position of the instruction at `pc`, the one that failed after
+    /// an error; `None` past the end or when the program has no spans.
+    pub fn current_span(&self) -> Option<Span> {
+        self.program
+            .spans()
+            .get(self.pc)
+            .copied()
+            .filter(|span| span.line > 0)
+    }
+
+    /// Whether the program has run to its end; `step()` does nothing after.
+    pub fn halted(&self) -> bool {
+        self.pc >= self.inst_len
+    }
+
+    /// Run the program from its first instruction, to its end or to the
+    /// first breakpoint of `Dialect::Debug`, which leaves `halted()` false.
+    pub fn run(&mut self) -> Result<(), VmError> {
+        self.rewind();
+        self.resume()
+    }
+
+    /// Carry on from where the VM stopped, past the breakpoint it stopped at,
+    /// to the end or the next breakpoint.
+    pub fn resume(&mut self) -> Result<(), VmError> {
+        let result = self.step_to_stop();
+        self.output.flush()?;
+        if let Some(trace) = &mut self.trace {
+            trace.flush()?;
+        }
+        result
+    }
+
+    /// Run `program` in place of this VM's, on the tape and from the pointer
+    /// the last one left, as if it went on from there. Stats, counters and
+    /// limits start over.
+    pub fn execute(&mut self, program: impl Into<Arc<Program>>) -> Result<(), VmError> {
+        let (point, high_water, storage) = (self.point, self.high_water, self.storage);
+        self.program = program.into();
+        self.inst_len = self.program.tokens().len();
+        self.rewind();
+        (self.point, self.high_water, self.storage) = (point, high_water, storage);
+        self.resume()
+    }
+
+    /// `run` as native code for this machine.
+    ///
+    /// The tape, pointer and termination end up as the interpreter would
+    /// leave them, stopping at breakpoints as it does, but no instructions
+    /// are counted. Nothing runs when there is no backend for the host or an
+    /// option asks for what native code cannot do; both are a `JitError`.
+    pub fn run_jit(&mut self) -> Result<(), VmError> {
+        self.rewind();
+        self.resume_jit()
+    }
+
+    /// `resume` as native code, from wherever `run_jit`, `run` or stepping
+    /// left the VM.
+    pub fn resume_jit(&mut self) -> Result<(), VmError> {
+        if self.halted() {
+            return Ok(());
+        }
+        if let Some(option) = self.options.jit_unsupported() {
+            return Err(JitError::Unsupported(option).into());
+        }
+        let backend = Backend::host().unwrap_or(Backend::X86_64);
+        let code = match self.options.fuel {
+            Some(fuel) => jit::compile_with_fuel(backend, self.program.tokens(), fuel)?,
+            None => jit::compile(backend, self.program.tokens())?,
+        };
+        let tape = self.mem.flat();
+        let options = jit::RunOptions {
+            progress: self
+                .progress
+                .as_deref_mut()
+                .map(|sink| sink as &mut dyn Write),
+            from_pc: self.pc,
+            stop_at_breakpoints: true,
+        };
+        let result = code.run_with(
+            tape,
+            self.point,
+            &mut self.input,
+            &mut self.output,
+            self.options.eof,
+            options,
+        );
+        self.output.flush()?;
+        let exit = match result {
+            Ok(exit) => exit,
+            // stopped where the interpreter would have
+            Err(VmError::PointerOverFlow(Some(fault))) => {
+                (self.pc, self.point) = (fault.pc, fault.pointer);
+                return Err(self.overflow());
+            }
+            Err(e) => return Err(e),
+        };
+        self.point = exit.pointer;
+        self.high_water = self.high_water.max(exit.pointer);
+        match exit.breakpoint {
+            Some(pc) => self.pc = pc + 1,
+            None => {
+                self.stats.termination = exit.termination;
+                self.pc = self.inst_len;
+            }
+        }
+        Ok(())
+    }
+
+    // `PointerOverFlow` at the instruction about to run
+    fn overflow(&self) -> VmError {
+        VmError::PointerOverFlow(Some(Fault {
+            pc: self.pc,
+            span: self.current_span(),
+            pointer: self.point,
+        }))
+    }
+
+    // back to the first instruction, keeping the tape as it is
+    fn rewind(&mut self) {
+        self.pc = 0;
+        self.point = self.origin;
+        self.high_water = self.origin;
+        self.storage = 0;
+        self.stats = RunStats::default();
+        self.last_report = (Instant::now(), 0);
+        self.loop_counts = self
+            .options
+            .max_loop_iterations
+            .map(|_| vec![0_u64; self.inst_len]);
+        self.profile = self.options.profile.then(|| vec![0_u64; self.inst_len]);
+    }
+
+    fn step_to_stop(&mut self) -> Result<(), VmError> {
+        while let StepResult::Running = self.step()? {}
+        Ok(())
+    }
+
+    /// Execute a single instruction, saying whether the program has ended
+    /// or stopped at a breakpoint. Output is not flushed until one of them
+    /// happens, unless the writer does so itself like the default one on a
+    /// terminal.
+    ///
+    /// An error leaves the VM on the failing instruction, so a `,` whose input
+    /// reported `WouldBlock` can simply be stepped again once data arrives.
+    pub fn step(&mut self) -> Result<StepResult, VmError> {
+        if self.trace.is_none() || self.halted() {
+            return self.step_untraced();
+        }
+        let (pc, point) = (self.pc, self.point);
+        let before = self.mem.get(point);
+        let result = self.step_untraced()?;
+        let token = self.program.tokens()[pc];
+        let after = self.mem.get(point);
+        let trace = self.trace.as_mut().unwrap();
+        trace.record(pc, token, point, before, after)?;
+        Ok(result)
+    }
+
+    #[inline(always)]
+    fn step_untraced(&mut self) -> Result<StepResult, VmError> {
+        if self.halted() {
+            return Ok(StepResult::Halted);
+        }
+        let (pc, point) = (self.pc, self.point);
+        if self
+            .options
+            .fuel
+            .is_some_and(|fuel| self.stats.steps >= fuel)
+        {
+            return Err(VmError::OutOfFuel(self.stats.steps));
+        }
+        if let Some(counts) = &mut self.profile {
+            counts[pc] += 1;
+        }
+
+        use Token::*;
+        match self.program.tokens()[pc] {
+            ClearRange { start_offset, len } => {
+                // one bounds check for the whole range
+                let start = self.offset_cell(start_offset as isize);
+                let end = start.and_then(|start| start.checked_add(len as usize));
+                match (start, end) {
+                    (Some(start), Some(end)) if end <= self.mem_len || self.reach(end - 1) => {
+                        self.mem.clear(start..end)
+                    }
+                    _ => return Err(self.overflow()),
+                }
+            }
+            MulAdd { offset, factor } => {
+                let cell = self.mem.get(point);
+                if cell != 0 {
+                    let Some(at) = self.offset_cell(offset as isize) else {
+                        return Err(self.overflow());
+                    };
+                    // a factor above 127 stands for a loop that subtracts
+                    let sum = self.mem.get(at) as i32 + cell as i32 * factor as i8 as i32;
+                    self.set_checked(at, sum as u8, !(0..=255).contains(&sum))?;
+                }
+            }
+            AddAt { offset, value } => {
+                let Some(at) = self.offset_cell(offset as isize) else {
+                    return Err(self.overflow());
+                };
+                self.high_water = self.high_water.max(at);
+                // a value above 127 stands for a run of `-`
+                let sum = self.mem.get(at) as i32 + value as i8 as i32;
+                self.set_checked(at, sum as u8, !(0..=255).contains(&sum))?;
+            }
+            ScanRight(x) => {
+                // every cell past the end is zero, so a grown tape ends the
+                // scan on the first of them the stride lands on
+                let beyond = || {
+                    let steps = (self.mem_len - point).div_ceil(x);
+                    steps.checked_mul(x)?.checked_add(point)
+                };
+                let found = self.mem.find_zero(point, x, true);
+                let Some(at) = found.or_else(beyond).filter(|&at| self.reach(at)) else {
+                    return Err(self.overflow());
+                };
+                self.point = at;
+                self.high_water = self.high_water.max(at);
+            }
+            ScanLeft(x) => {
+                // past the front, as past the end, every cell is zero
+                let found = match self.mem.find_zero(point, x, false) {
+                    Some(at) => Some(at),
+                    None => (point / x + 1)
+                        .checked_mul(x)
+                        .and_then(|by| self.left_of(by)),
+                };
+                let Some(at) = found else {
+                    return Err(self.overflow());
+                };
+                self.point = at;
+            }
+            IncrementData(x) => {
+                let (cell, over) = self.mem.get(point).overflowing_add(x);
+                self.set_checked(point, cell, over)?;
+            }
+            DecrementData(x) => {
+                let (cell, over) = self.mem.get(point).overflowing_sub(x);
+                self.set_checked(point, cell, over)?;
+            }
+            IncrementPointer(x) => {
+                let Some(at) = point.checked_add(x).filter(|&at| self.reach(at)) else {
+                    return Err(self.overflow());
+                };
+                self.point = at;
+                self.high_water = self.high_water.max(at);
+            }
+            DecrementPointer(x) => {
+                let Some(at) = self.left_of(x) else {
+                    return Err(self.overflow());
+                };
+                self.point = at;
+            }
+            Output => {
+                self.reserve_output(1)?;
+                let mut buf = [0_u8];
+                buf[0] = self.mem.get(point);
+                match self.output.write_all(&buf) {
+                    Ok(()) => {}
+                    Err(e) => return Err(VmError::IO(e)),
+                }
+                self.stats.output_bytes += 1;
+            }
+            OutputRepeat(n) => {
+                self.reserve_output(n as u64)?;
+                if let Err(e) = write_repeated(&mut self.output, self.mem.get(point), n) {
+                    return Err(VmError::IO(e));
+                }
+                self.stats.output_bytes += n as u64;
+            }
+            Print(byte) => {
+                self.reserve_output(1)?;
+                if let Err(e) = self.output.write_all(&[byte]) {
+                    return Err(VmError::IO(e));
+                }
+                self.stats.output_bytes += 1;
+            }
+            Input => match self.input.next_byte() {
+                Ok(None) => match self.options.eof {
+                    EofBehavior::Unchanged => {}
+                    EofBehavior::SetZero => self.mem.set(point, 0),
+                    EofBehavior::SetMinusOne => self.mem.set(point, 255),
+                    EofBehavior::Halt => {
+                        self.stats.termination = Termination::EofHalt { pc };
+                        self.stats.steps += 1;
+                        self.pc = self.inst_len;
+                        self.output.flush()?;
+                        return Ok(StepResult::Halted);
+                    }
+                },
+                Ok(Some(byte)) => self.mem.set(point, byte),
+                Err(e) => return Err(VmError::IO(e)),
+            },
+            LoopStart(x) => {
+                if let Some(counts) = &mut self.loop_counts {
+                    counts[pc] = 0;
+                }
+                if self.mem.get(point) == 0 && x as usize <= self.inst_len {
+                    self.pc = x as usize;
+                }
+            }
+            IfStart(x) => {
+                if self.mem.get(point) == 0 && x as usize <= self.inst_len {
+                    self.pc = x as usize;
+                }
+            }
+            IfEnd(_) | Breakpoint => {}
+            Ext(op) => match op.apply(self.mem.get(point), &mut self.storage) {
+                Some(cell) => self.mem.set(point, cell),
+                None => {
+                    self.stats.termination = Termination::Halted { pc };
+                    self.stats.steps += 1;
+                    self.pc = self.inst_len;
+                    self.output.flush()?;
+                    return Ok(StepResult::Halted);
+                }
+            },
+            LoopEnd(x) => {
+                if self.mem.get(point) != 0 && x as usize <= self.inst_len {
+                    if let Some(counts) = &mut self.loop_counts {
+                        let org = x as usize;
+                        counts[org] += 1;
+                        if Some(counts[org]) > self.options.max_loop_iterations {
+                            return Err(VmError::LoopIterationLimit {
+                                start: self.program.spans()[org],
+                                end: self.program.spans()[pc],
+                                iterations: counts[org],
+                                window: TapeWindow::capture(&self.mem, point),
+                            });
+                        }
+                    }
+                    if progress::requests() != self.progress_seen {
+                        self.report_progress();
+                    }
+                    self.pc = x as usize;
+                }
+            }
+        }
+        self.stats.steps += 1;
+        self.pc += 1;
+        if self.halted() {
+            self.output.flush()?;
+            return Ok(StepResult::Halted);
+        }
+        if let Breakpoint = self.program.tokens()[pc] {
+            // whatever ran before the breakpoint is out where a debugger sees it
+            self.output.flush()?;
+            return Ok(StepResult::Breakpoint);
+        }
+        Ok(StepResult::Running)
+    }
+}
+
+impl VM<'_> {
+    // whether cell `at` is on the tape, growing it there under
+    // `TapeMode::Grow`
+    fn reach(&mut self, at: usize) -> bool {
+        if at < self.mem_len {
+            return true;
+        }
+        let (TapeMode::Grow { max } | TapeMode::Bidirectional { max }) = self.options.tape_mode
+        else {
+            return false;
+        };
+        let len = self.mem_len.saturating_mul(2).max(at + 1).min(max);
+        if at >= len || !self.mem.grow(len) {
+            return false;
+        }
+        self.mem_len = len;
+        true
+    }
+
+    // the cell `by` left of the pointer, growing the tape at the front for it
+    // under `TapeMode::Bidirectional`, which moves every cell, the pointer
+    // among them
+    fn left_of(&mut self, by: usize) -> Option<usize> {
+        if let Some(at) = self.point.checked_sub(by) {
+            return Some(at);
+        }
+        let TapeMode::Bidirectional { max } = self.options.tape_mode else {
+            return None;
+        };
+        let short = by - self.point;
+        let extra = self
+            .mem_len
+            .max(short)
+            .min(max.saturating_sub(self.mem_len));
+        if extra < short || !self.mem.grow_front(extra) {
+            return None;
+        }
+        self.mem_len += extra;
+        self.origin += extra;
+        self.point += extra;
+        self.high_water += extra;
+        Some(self.point - by)
+    }
+
+    // the cell `offset` away from the pointer, where the tape reaches it
+    fn offset_cell(&mut self, offset: isize) -> Option<usize> {
+        let at = match self.point.checked_add_signed(offset) {
+            Some(at) => at,
+            None => self.left_of(offset.unsigned_abs())?,
+        };
+        self.reach(at).then_some(at)
+    }
+
+    // store the result of `+` or `-`, unless it overflowed and that is an
+    // error
+    fn set_checked(&mut self, point: usize, cell: u8, overflowed: bool) -> Result<(), VmError> {
+        if overflowed && self.options.cell_overflow == CellOverflow::Error {
+            return Err(VmError::CellOverflow(point));
+        }
+        self.mem.set(point, cell);
+        Ok(())
+    }
+
+    // fail instead of writing `n` more bytes past `max_output`
+    fn reserve_output(&self, n: u64) -> Result<(), VmError> {
+        match self.options.max_output {
+            Some(max) if self.stats.output_bytes + n > max => Err(VmError::OutputLimit(max)),
+            _ => Ok(()),
+        }
+    }
+
+    // one status line for the instruction at `pc`, rate since the last one
+    fn report_progress(&mut self) {
+        self.progress_seen = progress::requests();
+        let (then, steps) = self.last_report;
+        let now = Instant::now();
+        let rate = (self.stats.steps - steps) as f64 / (now - then).as_secs_f64().max(1e-9);
+        self.last_report = (now, self.stats.steps);
+        let at = match self.current_span() {
+            Some(span) => span.to_string(),
+            None => "?".to_string(),
+        };
+        let line = format!(
+            "progress: {} instructions, {:.0} instr/s, pc {} at {}, pointer {}, {} output bytes",
+            self.stats.steps, rate, self.pc, at, self.point, self.stats.output_bytes
+        );
+        match &mut self.progress {
+            // a status line is best effort and never fails the run
+            Some(sink) => drop(writeln!(sink, "{}", line)),
+            None => eprintln!("{}", line),
+        }
+    }
+}
+
+/// Write `n` copies of `byte` with a handful of `write_all` calls.
+pub(crate) fn write_repeated(output: &mut dyn Write, byte: u8, n: usize) -> std::io::Result<()> {
+    let buf = [byte; 256];
+    let mut left = n;
+    while left > 0 {
+        let len = left.min(buf.len());
+        output.write_all(&buf[..len])?;
+        left -= len;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_vm_run() {
+    for level in OptLevel::ALL {
+        let vm = VM::new_from_file("bfcode/hellow.bf", level);
+        vm.unwrap().run().unwrap();
+    }
+    let err = VM::new_from_file(Path::new("bfcode/missing.bf"), OptLevel::default())
+        .err()
+        .unwrap();
+    assert!(matches!(err, VmError::IO(_)), "{}", err);
+
+    // source straight from a string, its errors returned rather than raised
+    let src = fs::read_to_string("bfcode/hellow.bf").unwrap();
+    let mut output = vec![];
+    VM::new_from_str(&src)
+        .unwrap()
+        .with_io(&b""[..], &mut output)
+        .run()
+        .unwrap();
+    assert!(output.starts_with(b"Hello"), "{:?}", output);
+    let err = VM::new_from_str("+[").err().unwrap();
+    assert_eq!(err.code(), "E0102");
+    // no instructions at all, say a program that is all comment, is a run
+    // that does nothing
+    for src in ["", "no code here"] {
+        let mut output = vec![];
+        let mut vm = VM::new_from_str(src)
+            .unwrap()
+            .with_io(&b""[..], &mut output);
+        vm.run().unwrap();
+        assert!(vm.halted());
+        drop(vm);
+        assert!(output.is_empty());
+    }
+
+    // any reader and writer will do in place of stdin and stdout
+    let mut input = std::io::Cursor::new(b"echo\0".to_vec());
+    let mut output = std::io::Cursor::new(vec![]);
+    let mut vm = VM::new(Program::compile(",[.,]").unwrap())
+        .unwrap()
+        .with_io(&mut input, &mut output);
+    vm.run().unwrap();
+    drop(vm);
+    assert_eq!(output.into_inner(), b"echo");
+}
+
+#[test]
+fn test_loop_iteration_limit() {
+    // the first inner loop is hot across outer iterations but fine per entry;
+    // the second never terminates
+    let src = "+++[>+++++[-]<-]\n>>+[\n  >+[]<-\n]";
+    let options = VmOptions {
+        max_loop_iterations: Some(10),
+        ..Default::default()
+    };
+    let mut vm = VM::from_program(Program::compile(src).unwrap())
+        .unwrap()
+        .with_options(options);
+    match vm.run().unwrap_err() {
+        VmError::LoopIterationLimit {
+            start,
+            end,
+            iterations,
+            window,
+        } => {
+            assert_eq!((start.line, start.col), (3, 5));
+            assert_eq!((end.line, end.col), (3, 6));
+            assert_eq!(iterations, 11);
+            assert_eq!(window.pointer, 3);
+            assert_eq!(window.cells, vec![0, 0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+        }
+        e => panic!("{}", e),
+    }
+
+    let mut vm = VM::from_program(Program::compile("+++[>+++++[-]<-]").unwrap())
+        .unwrap()
+        .with_options(VmOptions {
+            max_loop_iterations: Some(10),
+            ..Default::default()
+        });
+    vm.run().unwrap();
+}
+
+// output sink that stays readable after the VM took ownership of it
+#[derive(Clone, Default)]
+pub(crate) struct SharedOutput(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+impl SharedOutput {
+    #[cfg(test)]
+    pub(crate) fn bytes(&self) -> Vec<u8> {
+        self.0.borrow().clone()
+    }
+
+    /// Drain everything written so far.
+    pub(crate) fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut self.0.borrow_mut())
+    }
+}
+
+impl Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_eof_behavior() {
+    let run = |src: &str, eof: EofBehavior| {
+        let out = SharedOutput::default();
+        let mut vm = VM::from_program(Program::compile(src).unwrap())
+            .unwrap()
+            .with_options(VmOptions {
+                eof,
+                ..Default::default()
+            })
+            .with_io(&b"cat"[..], out.clone());
+        vm.run().unwrap();
+        (out.bytes(), vm.stats().termination)
+    };
+
+    // zero convention: the loop test sees the 0 and ends normally
+    assert_eq!(
+        run(",[.,]", EofBehavior::SetZero),
+        (b"cat".to_vec(), Termination::Finished)
+    );
+    assert_eq!(
+        run("+[,.]", EofBehavior::SetZero),
+        (b"cat\0".to_vec(), Termination::Finished)
+    );
+
+    // halt convention: the program never has to test for end of input
+    assert_eq!(
+        run(",[.,]", EofBehavior::Halt),
+        (b"cat".to_vec(), Termination::EofHalt { pc: 3 })
+    );
+    assert_eq!(
+        run("+[,.]", EofBehavior::Halt),
+        (b"cat".to_vec(), Termination::EofHalt { pc: 2 })
+    );
+
+    assert_eq!(
+        run(",.,.,.,.", EofBehavior::SetMinusOne),
+        (b"cat\xff".to_vec(), Termination::Finished)
+    );
+    assert_eq!(
+        run(",.,.,.,.", EofBehavior::Unchanged),
+        (b"catt".to_vec(), Termination::Finished)
+    );
+
+    // dbfi running an echo: only the zero convention ends the inner loop,
+    // the others echo the last cell forever
+    let dbfi = std::fs::read_to_string("bfcode/dbfi.bf").unwrap();
+    let program = Arc::new(Program::compile(&dbfi).unwrap());
+    for (eof, tail) in [
+        (EofBehavior::SetZero, None),
+        (EofBehavior::Unchanged, Some(b'i')),
+        (EofBehavior::SetMinusOne, Some(255)),
+    ] {
+        let mut output = vec![];
+        let mut vm = VM::new(program.clone())
+            .unwrap()
+            .with_options(VmOptions {
+                eof,
+                max_output: Some(64),
+                ..Default::default()
+            })
+            .with_io(&b",[.,]!hi"[..], &mut output);
+        let result = vm.run();
+        drop(vm);
+        match tail {
+            None => {
+                result.unwrap();
+                assert_eq!(output, b"hi");
+            }
+            Some(byte) => {
+                assert!(matches!(result, Err(VmError::OutputLimit(_))), "{:?}", eof);
+                assert_eq!(output[..2], *b"hi");
+                assert!(output[2..].iter().all(|&b| b == byte), "{:?}", eof);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_cell_overflow() {
+    use crate::{program::OptLevel, tokenizer::Dialect};
+
+    let run = |src: &str, level: OptLevel, cell_overflow: CellOverflow| {
+        let mut tape = vec![0; 4];
+        let start = StartTape::Unknown;
+        let program = Program::compile_for(src, level, Dialect::Standard, start, cell_overflow);
+        let mut vm = VM::with_tape(program.unwrap(), &mut tape)
+            .unwrap()
+            .with_options(VmOptions {
+                cell_overflow,
+                ..Default::default()
+            })
+            .with_io(&b""[..], std::io::sink());
+        let result = vm.run();
+        drop(vm);
+        result.map(|()| tape)
+    };
+
+    // cells wrap by default, through zero and below it
+    for level in OptLevel::ALL {
+        let wrap = CellOverflow::Wrap;
+        assert_eq!(
+            run("+[+]>-->+++[-]-", level, wrap).unwrap(),
+            [0, 254, 255, 0]
+        );
+        assert_eq!(run(&"+".repeat(300), level, wrap).unwrap()[0], 44);
+    }
+    // or fail on the cell that went past, however far it is optimized
+    let strict = CellOverflow::Error;
+    let err = run("+[+]", OptLevel::O0, strict).unwrap_err();
+    assert!(matches!(err, VmError::CellOverflow(0)), "{}", err);
+    assert_eq!(err.code(), "E0407");
+    let multiply = format!("{}[->+++<]", "+".repeat(100));
+    for level in OptLevel::ALL {
+        for (src, cell) in [
+            ("+[+]", 0),
+            ("-+", 0),
+            (">-", 1),
+            (&"+".repeat(300), 0),
+            ("+[---]", 0),
+            (&multiply, 1),
+        ] {
+            let result = run(src, level, strict);
+            assert!(
+                matches!(result, Err(VmError::CellOverflow(at)) if at == cell),
+                "{} at {}: {:?}",
+                src,
+                level,
+                result
+            );
+        }
+        assert_eq!(run("+++[-]>+", level, strict).unwrap(), [0, 1, 0, 0]);
+        assert_eq!(run("++-->++[-<+>]", level, strict).unwrap(), [2, 0, 0, 0]);
+    }
+    assert_eq!(CellOverflow::from_name("error"), Some(strict));
+}
+
+#[test]
+fn test_fork() {
+    use crate::program::OptLevel;
+
+    let program = Program::compile_with("++>,[<+>-]<.", OptLevel::O0).unwrap();
+    let out = SharedOutput::default();
+    let mut parent = VM::from_program(program)
+        .unwrap()
+        .with_io(&b"\x03"[..], out.clone());
+    // stop on the `,`
+    while parent.pc() < 3 {
+        parent.step().unwrap();
+    }
+
+    let child_out = SharedOutput::default();
+    let mut child = parent.fork().with_io(&b"\x05"[..], child_out.clone());
+    assert!(matches!(parent.mem, Tape::Cow(_)));
+    assert_eq!((child.pc(), child.pointer()), (3, 1));
+    while child.step().unwrap() != StepResult::Halted {}
+    while parent.step().unwrap() != StepResult::Halted {}
+    assert_eq!((out.bytes(), child_out.bytes()), (vec![5], vec![7]));
+    assert_eq!(parent.cells(0..2), vec![5, 0]);
+    assert_eq!(child.cells(0..2), vec![7, 0]);
+    assert_eq!(child.stats().steps, parent.stats().steps + 2 * 5);
+}
+
+#[test]
+fn test_output_repeat() {
+    use crate::program::OptLevel;
+
+    // 600 dots, more than one buffer's worth
+    let src = format!("{}{}>+++[<.>-]", "+".repeat(65), ".".repeat(600));
+    let run = |level: OptLevel| {
+        let out = SharedOutput::default();
+        let mut vm = VM::from_program(Program::compile_with(&src, level).unwrap())
+            .unwrap()
+            .with_io(std::io::empty(), out.clone());
+        vm.run().unwrap();
+        (out.bytes(), vm.stats().steps)
+    };
+    let (plain, plain_steps) = run(OptLevel::O0);
+    let (fused, fused_steps) = run(OptLevel::O1);
+    assert_eq!(plain, [b'A'; 603]);
+    assert_eq!(fused, plain);
+    // one dispatch for the dots, one each for the `+` runs
+    assert_eq!(plain_steps - fused_steps, 599 + 64 + 2);
+}
+
+#[test]
+fn test_buffered_input() {
+    use std::{cell::Cell, rc::Rc};
+
+    // hands out at most `limit` bytes per read, counting the calls
+    struct Counting {
+        data: Vec<u8>,
+        pos: usize,
+        limit: usize,
+        reads: Rc<Cell<usize>>,
+    }
+    impl Read for Counting {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.reads.set(self.reads.get() + 1);
+            let n = buf.len().min(self.limit).min(self.data.len() - self.pos);
+            buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+            self.pos += n;
+            Ok(n)
+        }
+    }
+
+    let cat = |data: Vec<u8>, limit: usize| {
+        let reads = Rc::new(Cell::new(0));
+        let out = SharedOutput::default();
+        let input = Counting {
+            data,
+            pos: 0,
+            limit,
+            reads: reads.clone(),
+        };
+        let mut vm = VM::from_program(Program::compile(",[.,]").unwrap())
+            .unwrap()
+            .with_options(VmOptions {
+                eof: EofBehavior::SetZero,
+                ..Default::default()
+            })
+            .with_io(input, out.clone());
+        vm.run().unwrap();
+        (out.bytes(), reads.get())
+    };
+
+    // 1 MiB of input takes a read per chunk plus the one that sees EOF
+    let data: Vec<u8> = (0..1 << 20).map(|i| (i % 255 + 1) as u8).collect();
+    let (out, reads) = cat(data.clone(), usize::MAX);
+    assert_eq!(out, data);
+    assert_eq!(reads, (1 << 20) / INPUT_CHUNK + 1);
+
+    // short reads, like a terminal delivering a line at a time, are used as
+    // they come; ending exactly on a chunk boundary loses nothing
+    let (out, reads) = cat(data[..3 * INPUT_CHUNK].to_vec(), 7);
+    assert_eq!(out, &data[..3 * INPUT_CHUNK]);
+    assert_eq!(reads, (3 * INPUT_CHUNK).div_ceil(7) + 1);
+}
+
+#[test]
+fn test_with_tape() {
+    let mut tape = [0_u8; 32];
+    tape[1] = 3;
+    let program = Program::compile(">[<++>-]<.>>>+").unwrap();
+    let out = SharedOutput::default();
+    let mut vm = VM::with_tape(program, &mut tape)
+        .unwrap()
+        .with_io(std::io::empty(), out.clone());
+    vm.run().unwrap();
+    assert_eq!((vm.pointer(), vm.tape_len()), (3, 32));
+    drop(vm);
+    assert_eq!(out.bytes(), [6]);
+    assert_eq!(tape[..4], [6, 0, 0, 1]);
+    assert!(tape[4..].iter().all(|&cell| cell == 0));
+
+    // the last cell is reachable, one more is not
+    let mut tape = [0_u8; 32];
+    let inst = vec![Token::IncrementPointer(31), Token::IncrementData(1)];
+    VM::with_tape(Program::new(inst), &mut tape)
+        .unwrap()
+        .run()
+        .unwrap();
+    assert_eq!(tape[31], 1);
+    let inst = vec![Token::IncrementPointer(32)];
+    let mut vm = VM::with_tape(Program::new(inst), &mut tape).unwrap();
+    assert!(matches!(vm.run(), Err(VmError::PointerOverFlow(_))));
+
+    // moves past either end fail where they are rather than wrapping, and
+    // `>><<<<` as the `<<` it nets to; `>>+<<<<` adds two cells on and then
+    // makes the same move
+    for (src, pointer, pc) in [("<", 0, 0), (">>+<<<<", 0, 1), (">><<<<", 0, 0)] {
+        let mut tape = [0_u8; 32];
+        let mut vm = VM::with_tape(Program::compile(src).unwrap(), &mut tape).unwrap();
+        assert!(
+            matches!(vm.run(), Err(VmError::PointerOverFlow(_))),
+            "{}",
+            src
+        );
+        assert_eq!((vm.pointer(), vm.pc()), (pointer, pc), "{}", src);
+    }
+    for x in [1, usize::MAX] {
+        let mut tape = [0_u8; 32];
+        let inst = vec![Token::IncrementPointer(31), Token::IncrementPointer(x)];
+        let mut vm = VM::with_tape(Program::new(inst), &mut tape).unwrap();
+        assert!(matches!(vm.run(), Err(VmError::PointerOverFlow(_))));
+        assert_eq!((vm.pointer(), vm.pc()), (31, 1));
+    }
+
+    // a fork copies the caller's buffer rather than sharing it
+    let mut tape = [0_u8; 32];
+    let inst = vec![Token::IncrementData(5), Token::IncrementData(1)];
+    let mut vm = VM::with_tape(Program::new(inst), &mut tape).unwrap();
+    assert_eq!(vm.step().unwrap(), StepResult::Running);
+    let mut child = vm.fork();
+    child.resume().unwrap();
+    assert_eq!(child.cells(0..1), [6]);
+    drop(vm);
+    assert_eq!(tape[0], 5);
+}
+
+#[test]
+fn test_clear_range() {
+    use crate::program::OptLevel;
+
+    // fill some cells, walk back and clear `len` of them in either direction
+    let run = |src: &str, level: OptLevel| {
+        let mut vm = VM::from_program(Program::compile_with(src, level).unwrap())
+            .unwrap()
+            .with_io(std::io::empty(), std::io::sink());
+        vm.run().unwrap();
+        (vm.cells(0..100), vm.pointer())
+    };
+    for len in [1, 2, 64] {
+        let fill = "+++>".repeat(70);
+        let right = format!("{}{}{}+", fill, "<".repeat(68), "[-]>".repeat(len));
+        let left = format!("{}{}{}+", fill, "<".repeat(2), "[-]<".repeat(len));
+        for src in [right, left] {
+            // the clear is at an offset from cell 0, where the fill began
+            let fused = Program::compile(&src).unwrap();
+            assert!(fused.tokens().contains(&Token::ClearRange {
+                start_offset: if src.ends_with("<+") {
+                    69 - len as i32
+                } else {
+                    2
+                },
+                len: len as u32,
+            }));
+            assert_eq!(run(&src, OptLevel::O2), run(&src, OptLevel::O0), "{}", src);
+        }
+    }
+
+    // the whole range is checked before anything is cleared
+    let inst = vec![
+        Token::IncrementData(1),
+        Token::ClearRange {
+            start_offset: -1,
+            len: 2,
+        },
+    ];
+    let mut vm = VM::new(Program::new(inst)).unwrap();
+    assert!(matches!(vm.run(), Err(VmError::PointerOverFlow(_))));
+    assert_eq!((vm.pc(), vm.cells(0..1)), (1, vec![1]));
+}
+
+#[test]
+fn test_scan() {
+    use crate::program::OptLevel;
+
+    // a long stretch of ones with a zero on each stride's lattice at a
+    // different distance, so every stride has zeros to step over
+    let mut ones = vec![1_u8; 100_000];
+    for at in [60_001, 80_002, 90_000] {
+        ones[at] = 0;
+    }
+    let run = |src: &str, level: OptLevel| {
+        let program = Program::compile_with(src, level).unwrap();
+        let mut tape = ones.clone();
+        let mut vm = VM::with_tape(program, &mut tape).unwrap();
+        // a fork of a large tape searches the shared chunks instead
+        let mut fork = vm.fork();
+        vm.run().unwrap();
+        fork.run().unwrap();
+        assert_eq!(fork.pointer(), vm.pointer());
+        (vm.pointer(), vm.stats().steps)
+    };
+    for (stride, right, left) in [
+        (1, 60_001, 90_000),
+        (2, 80_002, 60_001),
+        (3, 90_000, 90_000),
+    ] {
+        let src = format!("[{}]", ">".repeat(stride));
+        let fused = Program::compile(&src).unwrap();
+        assert_eq!(fused.tokens(), [Token::ScanRight(stride)]);
+        assert_eq!(run(&src, OptLevel::O2), (right, 1));
+        assert_eq!(run(&src, OptLevel::O0).0, right);
+
+        let src = format!("{}[{}]", ">".repeat(99_999), "<".repeat(stride));
+        let fused = Program::compile(&src).unwrap();
+        assert_eq!(fused.tokens()[1], Token::ScanLeft(stride));
+        assert_eq!(run(&src, OptLevel::O2), (left, 2));
+        assert_eq!(run(&src, OptLevel::O0).0, left);
+    }
+
+    // no zero before either end of the tape
+    for inst in [
+        vec![Token::ScanRight(2)],
+        vec![Token::IncrementPointer(2), Token::ScanLeft(3)],
+    ] {
+        let mut tape = [1_u8; 8];
+        let mut vm = VM::with_tape(Program::new(inst), &mut tape).unwrap();
+        assert!(matches!(vm.run(), Err(VmError::PointerOverFlow(_))));
+    }
+}
+
+#[test]
+fn test_tape_size() {
+    use crate::{
+        engine::{Engine, ExecContext, Interpreter, X86_64Jit},
+        program::OptLevel,
+    };
+
+    let build = |src: &str, level: OptLevel, cells: usize, tape_mode: TapeMode| {
+        VM::builder(Program::compile_with(src, level).unwrap())
+            .memory_size(cells)
+            .options(VmOptions {
+                tape_mode,
+                ..Default::default()
+            })
+            .build()
+            .unwrap()
+            .with_io(std::io::empty(), std::io::sink())
+    };
+    let mut vm = build(">>>>>>>>+", OptLevel::O2, 8, TapeMode::Fixed);
+    assert_eq!(vm.tape_len(), 8);
+    assert!(matches!(vm.run(), Err(VmError::PointerOverFlow(_))));
+
+    // the tape doubles, or grows straight to the cell reached
+    let grow = TapeMode::Grow { max: 100 };
+    let mut vm = build(">>>>>>>>+", OptLevel::O2, 8, grow);
+    vm.run().unwrap();
+    assert_eq!((vm.pointer(), vm.tape_len()), (8, 16));
+    assert_eq!(vm.cells(8..16), [1, 0, 0, 0, 0, 0, 0, 0]);
+    let mut vm = build(&format!("{}+", ">".repeat(40)), OptLevel::O2, 8, grow);
+    vm.run().unwrap();
+    assert_eq!((vm.pointer(), vm.tape_len()), (40, 41));
+    // up to the limit and no further
+    let mut vm = build("+[>+]", OptLevel::O2, 8, grow);
+    assert!(matches!(vm.run(), Err(VmError::PointerOverFlow(_))));
+    assert_eq!((vm.pointer(), vm.tape_len()), (99, 100));
+
+    // what the optimizer fused reaches the new cells as the loops would
+    for src in [
+        "+>+>+>+<<<[>]+",
+        "+>+>+>+<<<[>>>]+",
+        "+++[->>>>>>++<<<<<<]>>>>>>.",
+    ] {
+        let run = |level| {
+            let mut vm = build(src, level, 4, grow);
+            vm.run().unwrap();
+            (vm.pointer(), vm.cells(0..vm.pointer() + 1))
+        };
+        assert_eq!(run(OptLevel::O2), run(OptLevel::O0), "{}", src);
+    }
+
+    // a shared tape grows as well, a lent one stays as it is
+    let mut vm = build(
+        &format!("{}+", ">".repeat(70_000)),
+        OptLevel::O2,
+        65_536,
+        grow,
+    );
+    let mut fork = vm.fork();
+    fork.options.tape_mode = TapeMode::Grow { max: 1 << 20 };
+    fork.run().unwrap();
+    assert_eq!(
+        (fork.tape_len(), fork.cells(70_000..70_001)),
+        (131_072, vec![1])
+    );
+    let mut tape = [0_u8; 8];
+    let mut vm = VM::with_tape(Program::compile(">>>>>>>>").unwrap(), &mut tape)
+        .unwrap()
+        .with_options(VmOptions {
+            tape_mode: grow,
+            ..Default::default()
+        });
+    assert!(matches!(vm.run(), Err(VmError::PointerOverFlow(_))));
+
+    // the interpreter engine hands back the cells that fit, the JIT refuses
+    let program = Program::compile(">>>+<<+>>>>>>+").unwrap();
+    let mut tape = [0_u8; 4];
+    let (mut input, mut output) = (std::io::empty(), vec![]);
+    let options = VmOptions {
+        tape_mode: grow,
+        ..Default::default()
+    };
+    let mut ctx = ExecContext::new(&mut tape, &mut input, &mut output).with_options(options);
+    assert_eq!(Interpreter.run(&program, &mut ctx).unwrap().pointer, 7);
+    if X86_64Jit::supported() {
+        assert!(matches!(
+            X86_64Jit.run(&program, &mut ctx),
+            Err(VmError::Jit(crate::jit::JitError::Unsupported("tape_mode")))
+        ));
+    }
+    assert_eq!(tape, [0, 1, 0, 1]);
+}
+
+#[test]
+fn test_progress_report() {
+    // a step of two keeps the loop from becoming a multiply
+    let program = Program::compile("++++++++++++++++[>++++++++<--]>+.").unwrap();
+    let (output, status) = (SharedOutput::default(), SharedOutput::default());
+    let mut vm = VM::from_program(program)
+        .unwrap()
+        .with_io(std::io::empty(), output.clone())
+        .with_progress(status.clone());
+    for _ in 0..20 {
+        assert_eq!(vm.step().unwrap(), StepResult::Running);
+    }
+    assert!(status.bytes().is_empty());
+    progress::request();
+    while vm.step().unwrap() != StepResult::Halted {}
+    assert_eq!(output.bytes(), b"A");
+
+    // reported once, at the first back-edge after the request
+    let status = String::from_utf8(status.bytes()).unwrap();
+    let fields: Vec<_> = status
+        .strip_prefix("progress: ")
+        .and_then(|line| line.strip_suffix('\n'))
+        .unwrap()
+        .split(", ")
+        .collect();
+    assert_eq!(fields.len(), 5, "{}", status);
+    let steps: u64 = fields[0]
+        .strip_suffix(" instructions")
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(steps >= 20 && steps < vm.stats().steps, "{}", status);
+    fields[1]
+        .strip_suffix(" instr/s")
+        .unwrap()
+        .parse::<f64>()
+        .unwrap();
+    assert_eq!(fields[2], "pc 4 at 1:30");
+    assert!(fields[3].starts_with("pointer "));
+    assert_eq!(fields[4], "0 output bytes");
+
+    // native code reports at its back-edges too, here after a request made
+    // by reading the input
+    struct Requesting;
+    impl std::io::Read for Requesting {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            progress::request();
+            buf[0] = 16;
+            Ok(1)
+        }
+    }
+    let program = Program::compile(".,[>++++++++<--]>+.").unwrap();
+    let (output, status) = (SharedOutput::default(), SharedOutput::default());
+    let mut vm = VM::from_program(program)
+        .unwrap()
+        .with_io(Requesting, output.clone())
+        .with_progress(status.clone());
+    vm.run_jit().unwrap();
+    assert_eq!(output.bytes(), b"\0A");
+    let status = String::from_utf8(status.bytes()).unwrap();
+    assert_eq!(
+        status.lines().next(),
+        Some("progress: native code, pc 5, pointer 0, 1 output bytes")
+    );
+}
+
+#[test]
+fn test_extended_dialect() {
+    use crate::{
+        jit::JitError,
+        program::OptLevel,
+        tokenizer::{Dialect, ExtOp},
+    };
+
+    let src = std::fs::read_to_string("bfcode/ops.ebf").unwrap();
+    let expected = [130, 32, 223, 223 ^ 65, 0, 65, 65, 2];
+    for level in OptLevel::ALL {
+        let program = Program::compile_dialect(&src, level, Dialect::Ebf1).unwrap();
+        let ops = program
+            .tokens()
+            .iter()
+            .filter(|t| matches!(t, Token::Ext(_)));
+        assert_eq!(ops.count(), ExtOp::ALL.len() + 2, "{}", level);
+        let halt = program
+            .tokens()
+            .iter()
+            .position(|&t| t == Token::Ext(ExtOp::Halt))
+            .unwrap();
+        let mut output = vec![];
+        let mut vm = VM::from_program(program)
+            .unwrap()
+            .with_io(&b""[..], &mut output);
+        vm.run().unwrap();
+        let stats = vm.stats().clone();
+        drop(vm);
+        assert_eq!(output, expected, "{}", level);
+        assert_eq!(stats.termination, Termination::Halted { pc: halt });
+    }
+
+    // the plain dialect reads the same file as comments around the eight
+    let plain = Program::compile(&src).unwrap();
+    assert!(!plain.tokens().iter().any(|t| matches!(t, Token::Ext(_))));
+    let program = Program::compile_dialect(&src, OptLevel::O2, Dialect::Ebf1).unwrap();
+    let err = crate::jit::compile(crate::jit::Backend::X86_64, program.tokens()).err();
+    if crate::jit::Backend::host().is_some() {
+        assert!(matches!(
+            err,
+            Some(JitError::UnsupportedToken(Token::Ext(_)))
+        ));
+    }
+    let text = program.to_ir_text();
+    assert!(text.contains("ext shl\n") && text.contains("ext halt\n"));
+    assert_eq!(
+        Program::from_ir_text(&text).unwrap().tokens(),
+        program.tokens()
+    );
+    let bytes = program.to_bytecode(true);
+    assert_eq!(
+        Program::from_bytecode(&bytes).unwrap().tokens(),
+        program.tokens()
+    );
+}
+
+#[test]
+fn test_shared_program() {
+    use std::{sync::Barrier, thread};
+
+    // echo the input up to a zero, then print two letters
+    let src = ",[.,]++++++++[>++++++++<-]>+.+.";
+    let program = Arc::new(Program::compile(src).unwrap());
+    let barrier = Barrier::new(9);
+    let outputs: Vec<Vec<u8>> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let (program, barrier) = (Arc::clone(&program), &barrier);
+                scope.spawn(move || {
+                    let input = format!("thread {}\n\0", i % 2).into_bytes();
+                    let mut output = vec![];
+                    let mut vm = VM::new(program).unwrap().with_io(&input[..], &mut output);
+                    // all VMs exist, then all run at once
+                    barrier.wait();
+                    barrier.wait();
+                    vm.run().unwrap();
+                    drop(vm);
+                    output
+                })
+            })
+            .collect();
+        // every VM holds the same instructions, none a copy
+        barrier.wait();
+        assert_eq!(Arc::strong_count(&program), 9);
+        barrier.wait();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+    assert_eq!(Arc::strong_count(&program), 1);
+    for (i, output) in outputs.iter().enumerate() {
+        assert_eq!(output, &outputs[i % 2]);
+        assert_eq!(*output, format!("thread {}\nAB", i % 2).into_bytes());
+    }
+
+    // a fork shares the instructions too
+    let mut vm = VM::new(Arc::clone(&program)).unwrap();
+    let child = vm.fork();
+    assert_eq!(Arc::strong_count(&program), 3);
+    drop((vm, child));
+    assert_eq!(Arc::strong_count(&program), 1);
+}
+
+#[test]
+fn test_bidirectional_tape() {
+    use crate::{
+        engine::{Engine, ExecContext, Interpreter, X86_64Jit},
+        program::OptLevel,
+    };
+
+    let run = |src: &str, level: OptLevel, cells: usize, tape_mode: TapeMode| {
+        let out = SharedOutput::default();
+        let mut vm = VM::builder(Program::compile_with(src, level).unwrap())
+            .memory_size(cells)
+            .options(VmOptions {
+                tape_mode,
+                ..Default::default()
+            })
+            .build()
+            .unwrap()
+            .with_io(std::io::empty(), out.clone());
+        let result = vm.run();
+        (result, out.take(), vm)
+    };
+    let both = TapeMode::Bidirectional { max: 100 };
+    let (result, out, vm) = run("<+.", OptLevel::O2, 8, both);
+    result.unwrap();
+    assert_eq!(out, [1]);
+    assert_eq!((vm.origin(), vm.pointer()), (4, 3));
+    let (result, ..) = run("<+.", OptLevel::O2, 8, TapeMode::Fixed);
+    assert!(matches!(result, Err(VmError::PointerOverFlow(_))));
+
+    // past the cells left of the start the tape grows at the front, and
+    // cell 0 moves along with what is already there
+    let (result, out, vm) = run(&format!("+{}+.", "<".repeat(10)), OptLevel::O2, 8, both);
+    result.unwrap();
+    assert_eq!(out, [1]);
+    assert!(vm.tape_len() > 8);
+    assert_eq!(vm.cells(vm.origin()..vm.origin() + 1), [1]);
+    assert_eq!(vm.origin() - vm.pointer(), 10);
+    // up to the limit and no further
+    let (result, _, vm) = run("+[<+]", OptLevel::O2, 8, both);
+    assert!(matches!(result, Err(VmError::PointerOverFlow(_))));
+    assert_eq!(vm.tape_len(), 100);
+
+    // scans and offset adds below the start grow it as the plain loops would
+    for src in [
+        "+<+<+<+>>>[<]+.",
+        "+<<+<<+<<+>>>>>>[<<]+.",
+        "+++[-<<<<<<++>>>>>>]<<<<<<.",
+        "+<<<<<<<<<<<<[-]>>>>>>>>>>>>>+<<<<<<<<<<<<<.",
+    ] {
+        let at = |level| {
+            let (result, out, vm) = run(src, level, 4, both);
+            result.unwrap();
+            let cells = vm.cells(vm.pointer()..vm.origin() + 1);
+            (out, vm.origin() - vm.pointer(), cells)
+        };
+        assert_eq!(at(OptLevel::O2), at(OptLevel::O0), "{}", src);
+    }
+
+    // the engine hands back the tape with the pointer counted from its
+    // first cell; native code, which would run out to the left where the
+    // interpreter grows, refuses before anything runs
+    let program = Program::compile("<<+>>>+<<<<.").unwrap();
+    let options = VmOptions {
+        tape_mode: TapeMode::Bidirectional { max: 16 },
+        ..Default::default()
+    };
+    let mut tape = [0_u8; 8];
+    let (mut input, mut output) = (std::io::empty(), vec![]);
+    let mut ctx =
+        ExecContext::new(&mut tape, &mut input, &mut output).with_options(options.clone());
+    assert_eq!(Interpreter.run(&program, &mut ctx).unwrap().pointer, 1);
+    assert_eq!(output, [0]);
+    assert_eq!(tape, [0, 0, 1, 0, 0, 1, 0, 0]);
+    let mut tape = [0_u8; 8];
+    let mut ctx = ExecContext::new(&mut tape, &mut input, &mut output).with_options(options);
+    let err = X86_64Jit.run(&program, &mut ctx).unwrap_err();
+    assert!(
+        matches!(err, VmError::Jit(JitError::Unsupported("tape_mode"))),
+        "{}",
+        err
+    );
+    assert_eq!(tape, [0; 8]);
+}
+
+#[test]
+fn test_run_jit() {
+    use crate::engine::{Engine, X86_64Jit};
+
+    let both = |src: &str, input: &[u8], eof: EofBehavior| {
+        let run = |jit: bool| {
+            let out = SharedOutput::default();
+            let options = VmOptions {
+                eof,
+                ..Default::default()
+            };
+            let mut vm = VM::new(Program::compile(src).unwrap())
+                .unwrap()
+                .with_options(options)
+                .with_io(input, out.clone());
+            match jit {
+                true => vm.run_jit().unwrap(),
+                false => vm.run().unwrap(),
+            }
+            assert!(vm.halted());
+            (out.bytes(), vm.pointer(), vm.cells(0..64))
+        };
+        let interpreted = run(false);
+        if X86_64Jit::supported() {
+            assert_eq!(run(true), interpreted, "{}", src);
+        }
+        interpreted
+    };
+    let hellow = fs::read_to_string("bfcode/hellow.bf").unwrap();
+    let (out, ..) = both(&hellow, b"", EofBehavior::Unchanged);
+    assert!(out.starts_with(b"Hello"), "{:?}", out);
+    let (out, pointer, _) = both(",[.>,]", b"native", EofBehavior::SetZero);
+    assert_eq!((&out[..], pointer), (&b"native"[..], 6));
+    both("+++[>++[>+<-]<-]>>[<]", b"", EofBehavior::Unchanged);
+
+    // what only the interpreter keeps is refused before anything runs
+    let mut vm = VM::new(Program::compile("+[.]").unwrap())
+        .unwrap()
+        .with_options(VmOptions {
+            max_output: Some(10),
+            ..Default::default()
+        });
+    let err = vm.run_jit().unwrap_err();
+    assert!(
+        matches!(err, VmError::Jit(JitError::Unsupported("max_output"))),
+        "{}",
+        err
+    );
+    assert_eq!(vm.pc(), 0);
+}
+
+#[test]
+fn test_overflow_position() {
+    use crate::engine::{Engine, X86_64Jit};
+
+    let program = Arc::new(Program::compile("+++<").unwrap());
+    let want = Fault {
+        pc: 1,
+        span: Some(Span { line: 1, col: 4 }),
+        pointer: 0,
+    };
+    let mut vm = VM::new(program.clone()).unwrap();
+    let err = vm.run().unwrap_err();
+    assert!(
+        matches!(err, VmError::PointerOverFlow(Some(at)) if at == want),
+        "{:?}",
+        err
+    );
+    assert_eq!(
+        err.to_string(),
+        "E0403 Pointer OverFlow Error at 1:4 (pc 1, pointer 0)"
+    );
+    // native code finds the same place
+    if X86_64Jit::supported() {
+        let mut vm = VM::new(program).unwrap();
+        let err = vm.run_jit().unwrap_err();
+        assert!(
+            matches!(err, VmError::PointerOverFlow(Some(at)) if at == want),
+            "{:?}",
+            err
+        );
+        assert_eq!((vm.pc(), vm.current_span()), (1, want.span));
+    }
+
+    // further in, and without positions once stripped
+    let src = "++>>\n>[-<]<<<<<";
+    let mut vm = VM::new(Program::compile(src).unwrap()).unwrap();
+    let err = vm.run().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "E0403 Pointer OverFlow Error at 2:6 (pc 6, pointer 3)"
+    );
+    let stripped = Program::from_bytecode(&Program::compile(src).unwrap().to_bytecode(true));
+    let mut vm = VM::new(stripped.unwrap()).unwrap();
+    let err = vm.run().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "E0403 Pointer OverFlow Error at pc 6, pointer 3"
+    );
+}
+
+#[test]
+fn test_step_breakpoint() {
+    use crate::{
+        engine::{Engine, ExecContext, Interpreter},
+        program::OptLevel,
+        tokenizer::Dialect,
+    };
+
+    // (pc, pointer, cells 0 and 1) after each step of a copy loop
+    let program = Program::compile_with("++[->+<]", OptLevel::O0).unwrap();
+    let mut vm = VM::new(program).unwrap();
+    let expected = [
+        (1, 0, [1, 0]),
+        (2, 0, [2, 0]),
+        (3, 0, [2, 0]),
+        (4, 0, [1, 0]),
+        (5, 1, [1, 0]),
+        (6, 1, [1, 1]),
+        (7, 0, [1, 1]),
+        (3, 0, [1, 1]),
+        (4, 0, [0, 1]),
+        (5, 1, [0, 1]),
+        (6, 1, [0, 2]),
+        (7, 0, [0, 2]),
+        (8, 0, [0, 2]),
+    ];
+    for (i, &(pc, pointer, cells)) in expected.iter().enumerate() {
+        let step = vm.step().unwrap();
+        let last = i + 1 == expected.len();
+        assert_eq!(step == StepResult::Halted, last, "step {}", i);
+        assert_eq!((vm.pc(), vm.pointer()), (pc, pointer), "step {}", i);
+        assert_eq!(*vm.memory(0..2), cells, "step {}", i);
+    }
+    assert_eq!(vm.step().unwrap(), StepResult::Halted);
+    assert_eq!(vm.stats().steps, expected.len() as u64);
+    assert!(matches!(vm.memory(0..2), Cow::Borrowed(_)));
+
+    // `run` stops after each `#` with what came before written out, and
+    // `resume` goes on from there; a `#` at the very end just ends the run
+    let src = "+.#+.#";
+    let program = Program::compile_dialect(src, OptLevel::O2, Dialect::Debug).unwrap();
+    assert_eq!(program.tokens()[2], Token::Breakpoint);
+    let output = SharedOutput::default();
+    let mut vm = VM::new(program.clone())
+        .unwrap()
+        .with_io(std::io::empty(), output.clone());
+    vm.run().unwrap();
+    assert_eq!((vm.halted(), vm.pc(), output.bytes()), (false, 3, vec![1]));
+    assert_eq!(vm.step().unwrap(), StepResult::Running);
+    vm.resume().unwrap();
+    assert!(vm.halted());
+    assert_eq!((output.bytes(), vm.cells(0..1)), (vec![1, 2], vec![2]));
+    // the engine runs straight through them
+    let mut tape = vec![0; 4];
+    let (mut input, mut out) = (std::io::empty(), vec![]);
+    let mut ctx = ExecContext::new(&mut tape, &mut input, &mut out);
+    Interpreter.run(&program, &mut ctx).unwrap();
+    assert_eq!(out, [1, 2]);
+    // and other dialects leave `#` a comment
+    let program = Program::compile_with(src, OptLevel::O2).unwrap();
+    assert!(!program.tokens().contains(&Token::Breakpoint));
+}
+
+#[test]
+fn test_jit_breakpoints() {
+    use crate::{
+        engine::{Engine, ExecContext, X86_64Jit},
+        program::OptLevel,
+        tokenizer::Dialect,
+    };
+
+    if !X86_64Jit::supported() {
+        return;
+    }
+    // a scripted session: run, then resume from each stop until the end,
+    // noting what a debugger sees at every stop
+    let src = "++#[->+#<]>.#,[-#]#.";
+    let program = Program::compile_dialect(src, OptLevel::O2, Dialect::Debug).unwrap();
+    type Stop = (bool, usize, usize, Vec<u8>, Vec<u8>);
+    let session = |jit_run: bool, jit_resume: bool| {
+        let output = SharedOutput::default();
+        let mut vm = VM::new(program.clone())
+            .unwrap()
+            .with_io(&b"\x03"[..], output.clone());
+        let mut stops: Vec<Stop> = vec![];
+        match jit_run {
+            true => vm.run_jit().unwrap(),
+            false => vm.run().unwrap(),
+        }
+        loop {
+            let memory = vm.memory(0..3).into_owned();
+            stops.push((vm.halted(), vm.pc(), vm.pointer(), memory, output.bytes()));
+            if vm.halted() {
+                break stops;
+            }
+            match jit_resume {
+                true => vm.resume_jit().unwrap(),
+                false => vm.resume().unwrap(),
+            }
+        }
+    };
+    let interpreted = session(false, false);
+    assert_eq!(interpreted.len(), 9, "{:?}", interpreted);
+    assert_eq!(interpreted[0], (false, 2, 0, vec![2, 0, 0], vec![]));
+    assert_eq!(interpreted[3], (false, 12, 1, vec![0, 2, 0], vec![2]));
+    assert_eq!(session(true, true), interpreted);
+    // either engine can go on from where the other stopped
+    assert_eq!(session(true, false), interpreted);
+    assert_eq!(session(false, true), interpreted);
+
+    // an engine run goes straight through them
+    let mut tape = vec![0; 4];
+    let (mut input, mut out) = (&b"\x03"[..], vec![]);
+    let mut ctx = ExecContext::new(&mut tape, &mut input, &mut out);
+    X86_64Jit.run(&program, &mut ctx).unwrap();
+    assert_eq!(out, [2, 0]);
+}
+
+#[test]
+fn test_memory() {
+    // a program as a function of its tape: cell 1 ends up twice cell 0
+    let mut vm = VM::new(Program::compile("[->++<]>").unwrap()).unwrap();
+    vm.memory_mut()[0] = 21;
+    vm.run().unwrap();
+    assert!(vm.halted());
+    assert_eq!((vm.pointer(), &vm.memory(0..3)[..]), (1, &[0, 42, 0][..]));
+
+    // run again without compiling again
+    vm.reset();
+    assert_eq!((vm.pc(), vm.pointer()), (0, 0));
+    assert!(vm.memory(0..vm.tape_len()).iter().all(|&cell| cell == 0));
+    vm.memory_mut()[0] = 100;
+    vm.run().unwrap();
+    assert_eq!(vm.cells(0..2), [0, 200]);
+
+    // seeding a fork leaves the tape it was forked from alone
+    let mut fork = vm.fork();
+    fork.reset();
+    fork.memory_mut()[0] = 7;
+    fork.run().unwrap();
+    assert_eq!(
+        (fork.cells(0..2), vm.cells(0..2)),
+        (vec![0, 14], vec![0, 200])
+    );
+}
+
+#[test]
+fn test_preloaded_tape() {
+    use crate::engine::{Engine, X86_64Jit};
+
+    // cell 0 starts at 255, so `+` wraps it to zero and the loops never run
+    for src in ["+[>+.<-]", "+[>+<--]"] {
+        for level in OptLevel::ALL {
+            let program = Arc::new(Program::compile_with(src, level).unwrap());
+            let run = |jit: bool| {
+                let out = SharedOutput::default();
+                let mut vm = VM::new(program.clone())
+                    .unwrap()
+                    .with_io(std::io::empty(), out.clone());
+                vm.memory_mut()[0] = 255;
+                match jit {
+                    true => vm.run_jit().unwrap(),
+                    false => vm.run().unwrap(),
+                }
+                (out.take(), vm.cells(0..2))
+            };
+            assert_eq!(run(false), (vec![], vec![0, 0]), "{} at {}", src, level);
+            if X86_64Jit::supported() {
+                assert_eq!(run(true), (vec![], vec![0, 0]), "{} at {}", src, level);
+            }
+            let mut tape = [255, 0];
+            VM::with_tape(program.clone(), &mut tape)
+                .unwrap()
+                .with_io(std::io::empty(), std::io::sink())
+                .run()
+                .unwrap();
+            assert_eq!(tape, [0, 0], "{} at {}", src, level);
+        }
+    }
+}
+
+#[test]
+fn test_execute() {
+    // each program goes on from the tape and pointer of the one before
+    let mut vm = VM::new(Program::compile("+++>++").unwrap()).unwrap();
+    vm.run().unwrap();
+    vm.execute(Program::compile("[-<+>]<").unwrap()).unwrap();
+    assert_eq!((vm.pointer(), vm.cells(0..2)), (0, vec![5, 0]));
+    assert_eq!((vm.stats().steps, vm.high_water()), (3, 1));
+    // and fails where it is
+    let err = vm.execute(Program::compile("+<").unwrap()).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "E0403 Pointer OverFlow Error at 1:2 (pc 1, pointer 0)"
+    );
+    vm.execute(Program::compile(">").unwrap()).unwrap();
+    assert_eq!((vm.pointer(), vm.cells(0..1)), (1, vec![6]));
+}
+
+#[test]
+fn test_fuel() {
+    use crate::engine::{Engine, X86_64Jit};
+
+    let limited = VmOptions {
+        fuel: Some(10_000),
+        ..Default::default()
+    };
+    let spin = Arc::new(Program::compile("+[]").unwrap());
+    let mut vm = VM::new(spin.clone()).unwrap().with_options(limited.clone());
+    let err = vm.run().unwrap_err();
+    assert!(matches!(err, VmError::OutOfFuel(10_000)), "{}", err);
+    assert_eq!((vm.stats().steps, vm.pc()), (10_000, 2));
+
+    // a program within its fuel runs as it would without
+    let hellow = Program::compile(&fs::read_to_string("bfcode/hellow.bf").unwrap()).unwrap();
+    let hellow = Arc::new(hellow);
+    let run = |options: VmOptions, jit: bool| {
+        let output = SharedOutput::default();
+        let mut vm = VM::new(hellow.clone())
+            .unwrap()
+            .with_options(options)
+            .with_io(std::io::empty(), output.clone());
+        match jit {
+            true => vm.run_jit().unwrap(),
+            false => vm.run().unwrap(),
+        }
+        output.bytes()
+    };
+    let plain = run(VmOptions::default(), false);
+    assert_eq!(run(limited.clone(), false), plain);
+
+    // native code counts a loop's length at each back-edge
+    if X86_64Jit::supported() {
+        assert_eq!(run(limited.clone(), true), plain);
+        let mut vm = VM::new(spin).unwrap().with_options(limited.clone());
+        let err = vm.run_jit().unwrap_err();
+        assert!(matches!(err, VmError::NativeOutOfFuel(10_000)), "{}", err);
+        assert!(err.to_string().contains("roughly"), "{}", err);
+        // nested loops come out within a hundred instructions of the count
+        let counted =
+            Program::compile("++++++++++[>++++++++++[>++++++++++[>+>+<<-]<-]<-]").unwrap();
+        let mut vm = VM::new(counted.clone()).unwrap();
+        vm.run().unwrap();
+        let steps = vm.stats().steps;
+        for (fuel, ends) in [(steps + 100, true), (steps - 100, false)] {
+            let options = VmOptions {
+                fuel: Some(fuel),
+                ..Default::default()
+            };
+            let mut vm = VM::new(counted.clone()).unwrap().with_options(options);
+            assert_eq!(vm.run_jit().is_ok(), ends, "{} of {}", fuel, steps);
+        }
+    }
+}
@@ -0,0 +1,416 @@
+//! Cells of 16 or 32 bits, for `--cell-width`.
+//!
+//! Like byte cells, `+` and `-` wrap, only at 2^16 or 2^32. Under
+//! `IoWidth::Byte` `.` writes the low byte and `,` sets the cell to the byte
+//! read; the other widths move the whole cell as a group of bytes. The
+//! optimized tokens cannot carry this: `+` runs fold mod 256 and the O2
+//! passes lean on byte wrapping, so this reads the unoptimized tokens and
+//! folds runs exactly, like `bigcell`, then applies each fold mod the width.
+
+use std::io::{Read, Write};
+
+use crate::{
+    bigcell::{self, Op},
+    tokenizer,
+    vm::{EofBehavior, VmError},
+};
+
+/// How wide a cell is.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CellWidth {
+    #[default]
+    W8,
+    W16,
+    W32,
+}
+
+impl CellWidth {
+    /// `8`, `16` or `32`, as `--cell-width` takes it.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "8" => Some(CellWidth::W8),
+            "16" => Some(CellWidth::W16),
+            "32" => Some(CellWidth::W32),
+            _ => None,
+        }
+    }
+
+    pub fn bits(self) -> u32 {
+        match self {
+            CellWidth::W8 => 8,
+            CellWidth::W16 => 16,
+            CellWidth::W32 => 32,
+        }
+    }
+}
+
+/// How many bytes `,` and `.` move, for `--io-width`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IoWidth {
+    /// One byte: `.` writes the low byte, `,` sets the cell to the byte.
+    #[default]
+    Byte,
+    /// Cell-width groups, least significant byte first.
+    CellLE,
+    /// Cell-width groups, most significant byte first.
+    CellBE,
+}
+
+impl IoWidth {
+    /// `byte`, `le` or `be`, as `--io-width` takes it.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "byte" => Some(IoWidth::Byte),
+            "le" => Some(IoWidth::CellLE),
+            "be" => Some(IoWidth::CellBE),
+            _ => None,
+        }
+    }
+
+    // `cell` as the group of `len` bytes, in its first `len` bytes
+    fn encode(self, cell: u32, len: usize) -> [u8; 4] {
+        let mut group = cell.to_le_bytes();
+        if self == IoWidth::CellBE {
+            group[..len].reverse();
+        }
+        group
+    }
+
+    fn decode(self, mut group: [u8; 4], len: usize) -> u32 {
+        if self == IoWidth::CellBE {
+            group[..len].reverse();
+        }
+        u32::from_le_bytes(group)
+    }
+}
+
+trait Cell: Copy + Default + Eq {
+    const MAX: Self;
+    const BYTES: usize;
+    // `delta` mod 2^width, added with wrapping
+    fn add(self, delta: i64) -> Self;
+    fn from_byte(byte: u8) -> Self;
+    fn from_u32(cell: u32) -> Self;
+    fn low_byte(self) -> u8;
+    fn widen(self) -> u32;
+}
+
+macro_rules! cell {
+    ($($t:ty),*) => {$(
+        impl Cell for $t {
+            const MAX: Self = <$t>::MAX;
+            const BYTES: usize = std::mem::size_of::<$t>();
+            fn add(self, delta: i64) -> Self {
+                self.wrapping_add(delta as $t)
+            }
+            fn from_byte(byte: u8) -> Self {
+                byte as $t
+            }
+            fn from_u32(cell: u32) -> Self {
+                cell as $t
+            }
+            fn low_byte(self) -> u8 {
+                self as u8
+            }
+            fn widen(self) -> u32 {
+                self as u32
+            }
+        }
+    )*};
+}
+
+cell!(u8, u16, u32);
+
+/// The tape after a run, every cell widened to 32 bits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WideTape {
+    pub cells: Vec<u32>,
+    pub pointer: usize,
+}
+
+impl WideTape {
+    /// `len` zero cells, the pointer on the first.
+    pub fn new(len: usize) -> Self {
+        WideTape {
+            cells: vec![0; len],
+            pointer: 0,
+        }
+    }
+}
+
+/// Run `src` on a tape of `len` cells of `width`, moving `io` bytes per `,`
+/// and `.`.
+///
+/// A group that input ends partway through keeps the bytes that came and
+/// gives the missing ones to `eof`: left as the cell had them, zero, or
+/// 0xff, or under `Halt` the run stops with the cell as it was.
+pub fn run(
+    width: CellWidth,
+    io: IoWidth,
+    src: &[u8],
+    len: usize,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+    eof: EofBehavior,
+) -> Result<WideTape, VmError> {
+    let mut tape = WideTape::new(len);
+    run_on(width, io, src, &mut tape, input, output, eof)?;
+    Ok(tape)
+}
+
+/// `run` on `tape`, which is left as the run left it whether or not it
+/// failed, so it can be dumped either way.
+pub fn run_on(
+    width: CellWidth,
+    io: IoWidth,
+    src: &[u8],
+    tape: &mut WideTape,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+    eof: EofBehavior,
+) -> Result<(), VmError> {
+    let ops = bigcell::compile(&tokenizer::tokenizer_from_reader(src)?);
+    match width {
+        CellWidth::W8 => run_cells::<u8>(&ops, io, tape, input, output, eof),
+        CellWidth::W16 => run_cells::<u16>(&ops, io, tape, input, output, eof),
+        CellWidth::W32 => run_cells::<u32>(&ops, io, tape, input, output, eof),
+    }
+}
+
+// as many bytes of `group` as input has, up to all of them
+fn read_group(input: &mut dyn Read, group: &mut [u8]) -> std::io::Result<usize> {
+    let mut read = 0;
+    let mut buf = [0; 4];
+    while read < group.len() {
+        match input.read(&mut buf[..group.len() - read])? {
+            0 => break,
+            n => {
+                group[read..read + n].copy_from_slice(&buf[..n]);
+                read += n;
+            }
+        }
+    }
+    Ok(read)
+}
+
+fn run_cells<C: Cell>(
+    ops: &[Op],
+    io: IoWidth,
+    tape: &mut WideTape,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+    eof: EofBehavior,
+) -> Result<(), VmError> {
+    let mut cells: Vec<C> = tape.cells.iter().map(|&cell| C::from_u32(cell)).collect();
+    let result = steps(ops, io, &mut cells, &mut tape.pointer, input, output, eof);
+    // written back even when a step failed
+    tape.cells = cells.into_iter().map(Cell::widen).collect();
+    result?;
+    output.flush()?;
+    Ok(())
+}
+
+fn steps<C: Cell>(
+    ops: &[Op],
+    io: IoWidth,
+    cells: &mut [C],
+    pointer: &mut usize,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+    eof: EofBehavior,
+) -> Result<(), VmError> {
+    let len = cells.len();
+    let mut pc = 0;
+    if len == 0 && !ops.is_empty() {
+        return Err(VmError::PointerOverFlow(None));
+    }
+    while pc < ops.len() {
+        let at = *pointer;
+        let zero = cells[at] == C::default();
+        match ops[pc] {
+            Op::Add(delta) => cells[at] = cells[at].add(delta),
+            Op::Move(delta) => {
+                *pointer = at
+                    .checked_add_signed(delta)
+                    .filter(|&p| p < len)
+                    .ok_or(VmError::PointerOverFlow(None))?;
+            }
+            Op::Input if io != IoWidth::Byte => {
+                let mut group = io.encode(cells[at].widen(), C::BYTES);
+                let read = read_group(input, &mut group[..C::BYTES])?;
+                if read < C::BYTES {
+                    let fill = match eof {
+                        EofBehavior::Unchanged => None,
+                        EofBehavior::SetZero => Some(0),
+                        EofBehavior::SetMinusOne => Some(0xff),
+                        EofBehavior::Halt => break,
+                    };
+                    if let Some(byte) = fill {
+                        group[read..C::BYTES].fill(byte);
+                    }
+                }
+                cells[at] = C::from_u32(io.decode(group, C::BYTES));
+            }
+            Op::Input => {
+                let mut byte = [0];
+                if input.read(&mut byte)? == 1 {
+                    cells[at] = C::from_byte(byte[0]);
+                } else {
+                    match eof {
+                        EofBehavior::Unchanged => {}
+                        EofBehavior::SetZero => cells[at] = C::default(),
+                        EofBehavior::SetMinusOne => cells[at] = C::MAX,
+                        EofBehavior::Halt => break,
+                    }
+                }
+            }
+            Op::Output if io != IoWidth::Byte => {
+                let group = io.encode(cells[at].widen(), C::BYTES);
+                output.write_all(&group[..C::BYTES])?
+            }
+            Op::Output => output.write_all(&[cells[at].low_byte()])?,
+            Op::Open(close) if zero => pc = close,
+            Op::Close(open) if !zero => pc = open,
+            Op::Open(_) | Op::Close(_) => {}
+        }
+        pc += 1;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_cell_width() {
+    use CellWidth::*;
+    let run = |width: CellWidth, src: &str, input: &[u8]| {
+        let mut output = vec![];
+        let eof = EofBehavior::SetMinusOne;
+        let io = IoWidth::Byte;
+        let tape = run(
+            width,
+            io,
+            src.as_bytes(),
+            4,
+            &mut &input[..],
+            &mut output,
+            eof,
+        );
+        let tape = tape.unwrap();
+        (tape.cells, output)
+    };
+
+    // one below zero is the largest cell, and one more is zero again
+    for (width, max) in [(W8, 0xff), (W16, 0xffff), (W32, u32::MAX)] {
+        assert_eq!(run(width, "-", b"").0[0], max, "{:?}", width);
+        assert_eq!(run(width, "-+", b"").0[0], 0);
+        assert_eq!(run(width, ",", b"").0[0], max);
+        assert_eq!(CellWidth::from_name(&width.bits().to_string()), Some(width));
+    }
+    // 256 and 65536 wrap only at the narrower widths
+    let byte = "+".repeat(256);
+    assert_eq!(run(W8, &byte, b"").0[0], 0);
+    assert_eq!(run(W16, &byte, b"").0[0], 256);
+    let half = "+".repeat(65536);
+    assert_eq!(run(W16, &half, b"").0[0], 0);
+    assert_eq!(run(W32, &half, b"").0[0], 65536);
+    // so does a loop counting 16 * 16
+    let counted = "++++++++++++++++[>++++++++++++++++<-]>[>+<[-]]";
+    assert_eq!(run(W8, counted, b"").0[2], 0);
+    assert_eq!(run(W16, counted, b"").0[2], 1);
+    assert_eq!(CellWidth::from_name("64"), None);
+
+    // `.` writes the low byte, `,` fills the cell with the byte read
+    let (cells, output) = run(W16, &format!("{}.,.", "+".repeat(300)), b"A");
+    assert_eq!((cells[0], output), (65, vec![44, 65]));
+    let (_, output) = run(W32, "-.", b"");
+    assert_eq!(output, [255]);
+
+    // the tape is as long as asked, and no further
+    let mut output = vec![];
+    let err = self::run(
+        W16,
+        IoWidth::Byte,
+        b">>",
+        2,
+        &mut &b""[..],
+        &mut output,
+        EofBehavior::Unchanged,
+    );
+    assert!(matches!(err, Err(VmError::PointerOverFlow(_))));
+    // and a run that fails leaves its tape behind
+    let mut tape = WideTape::new(2);
+    let err = run_on(
+        W16,
+        IoWidth::Byte,
+        b"->+>",
+        &mut tape,
+        &mut &b""[..],
+        &mut output,
+        EofBehavior::Unchanged,
+    );
+    assert!(err.is_err());
+    assert_eq!(
+        tape,
+        WideTape {
+            cells: vec![0xffff, 1],
+            pointer: 1
+        }
+    );
+}
+
+#[test]
+fn test_io_width() {
+    use CellWidth::*;
+    let run = |width: CellWidth, io: IoWidth, eof: EofBehavior, src: &str, input: &[u8]| {
+        let mut output = vec![];
+        let tape = run(
+            width,
+            io,
+            src.as_bytes(),
+            4,
+            &mut &input[..],
+            &mut output,
+            eof,
+        );
+        let tape = tape.unwrap();
+        (tape.cells[0], output)
+    };
+    let zero = EofBehavior::SetZero;
+
+    // a 16-bit echo gives back every pair of bytes as it came, either way round
+    let pairs: Vec<u8> = (0..=u16::MAX).flat_map(u16::to_le_bytes).collect();
+    for io in [IoWidth::CellLE, IoWidth::CellBE] {
+        let (_, output) = run(W16, io, EofBehavior::Halt, "+[>,.<]", &pairs);
+        assert!(output == pairs, "{:?}", io);
+    }
+    assert_eq!(run(W16, IoWidth::CellLE, zero, ",", &[1, 2]).0, 0x0201);
+    assert_eq!(run(W16, IoWidth::CellBE, zero, ",", &[1, 2]).0, 0x0102);
+    let src = format!("{}.", "+".repeat(0x0102));
+    assert_eq!(run(W16, IoWidth::CellLE, zero, &src, b"").1, [2, 1]);
+    assert_eq!(run(W16, IoWidth::CellBE, zero, &src, b"").1, [1, 2]);
+    let (cell, output) = run(W32, IoWidth::CellBE, zero, ",.", &[1, 2, 3, 4]);
+    assert_eq!((cell, output), (0x01020304, vec![1, 2, 3, 4]));
+    // a byte cell's group is the one byte
+    assert_eq!(run(W8, IoWidth::CellLE, zero, "-.", b"").1, [255]);
+
+    // input ending mid-group: the bytes that came stay, the rest go to `eof`
+    let src = format!("{},", "+".repeat(0x0304));
+    let partial = |io, eof| run(W16, io, eof, &src, &[9]).0;
+    assert_eq!(partial(IoWidth::CellLE, EofBehavior::Unchanged), 0x0309);
+    assert_eq!(partial(IoWidth::CellBE, EofBehavior::Unchanged), 0x0904);
+    assert_eq!(partial(IoWidth::CellLE, zero), 0x0009);
+    assert_eq!(partial(IoWidth::CellBE, zero), 0x0900);
+    assert_eq!(partial(IoWidth::CellLE, EofBehavior::SetMinusOne), 0xff09);
+    assert_eq!(partial(IoWidth::CellBE, EofBehavior::Halt), 0x0304);
+    let (_, output) = run(W16, IoWidth::CellLE, EofBehavior::Halt, ",.", &[9]);
+    assert!(output.is_empty());
+    // and with none of it come, that is `eof` on the whole cell
+    assert_eq!(
+        run(W16, IoWidth::CellLE, EofBehavior::SetMinusOne, ",", b"").0,
+        0xffff
+    );
+
+    assert_eq!(IoWidth::from_name("le"), Some(IoWidth::CellLE));
+    assert_eq!(IoWidth::from_name("be"), Some(IoWidth::CellBE));
+    assert_eq!(IoWidth::from_name("byte"), Some(IoWidth::Byte));
+    assert_eq!(IoWidth::from_name("cell"), None);
+}
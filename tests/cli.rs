@@ -0,0 +1,173 @@
+//! The binary as a shell sees it: what goes to stderr and the exit status.
+
+use std::{
+    fs,
+    process::{Command, Output, Stdio},
+};
+
+fn bfjit(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_bfjit"))
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .unwrap()
+}
+
+fn source(name: &str, src: impl AsRef<[u8]>) -> String {
+    let path = std::env::temp_dir().join(format!("bfjit-cli-{}-{}", std::process::id(), name));
+    fs::write(&path, src).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn test_exit_status() {
+    let output = bfjit(&["--no-such-flag"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    assert!(
+        stderr(&output).starts_with("usage bfjit"),
+        "{}",
+        stderr(&output)
+    );
+
+    let output = bfjit(&["/no/such/file.bf"]);
+    assert_eq!(output.status.code(), Some(4));
+    assert!(stderr(&output).contains("E0402"), "{}", stderr(&output));
+
+    let path = source("unopened.bf", "]");
+    let output = bfjit(&["--no-ir-cache", &path]);
+    assert_eq!(output.status.code(), Some(2));
+    let text = stderr(&output);
+    assert!(text.contains("E0101") && text.contains("1:1"), "{}", text);
+
+    let path = source("left.bf", "<");
+    for engine in ["--interp", "--jit"] {
+        let output = bfjit(&[engine, "--no-ir-cache", &path]);
+        assert_eq!(output.status.code(), Some(3), "{}", engine);
+        let text = stderr(&output);
+        assert!(text.contains("E0403 Pointer OverFlow"), "{}", text);
+        assert!(!text.contains("VmError"), "{}", text);
+    }
+
+    // a step limit the cell width runs cannot keep is refused, not ignored
+    let path = source("forever.bf", "+[]");
+    for cells in ["--cell-width=16", "--cells=big"] {
+        let output = bfjit(&[cells, "--max-steps=10", &path]);
+        assert_eq!(output.status.code(), Some(1), "{}", cells);
+    }
+}
+
+#[test]
+fn test_non_utf8_source() {
+    // bytes that are not UTF-8 are comment, with the cache and without
+    let mut src = vec![];
+    for _ in 0..65 {
+        src.extend_from_slice(b"+\xff");
+    }
+    src.push(b'.');
+    let path = source("latin1.bf", &src);
+    for flags in [
+        &["--no-ir-cache"][..],
+        &[],
+        &["--cell-width=16"],
+        &["--cells=big"],
+    ] {
+        let output = bfjit(&[flags, &[path.as_str()]].concat());
+        assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+        assert_eq!(output.stdout, b"A", "{:?}", flags);
+    }
+    // and to what reads the source without running it
+    let output = bfjit(&["--dump-ir", &path]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    let output = bfjit(&["--pass-sizes", "--no-ir-cache", &path]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert!(stderr(&output).starts_with("linked"), "{}", stderr(&output));
+
+    let path = source("latin1-bang.bf", [&src[..], b",.!\xfe"].concat());
+    let output = bfjit(&["--bang-input", "--no-ir-cache", &path]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(output.stdout, b"A\xfe");
+}
+
+#[test]
+fn test_bidirectional_tape() {
+    // past the cells left of the start the tape grows, so the JIT hands the
+    // run to the interpreter rather than fail there
+    let path = source("leftwards.bf", "<<<<<<+.");
+    for engine in ["--interp", "--jit"] {
+        let output = bfjit(&[
+            engine,
+            "--tape=bidirectional",
+            "--mem-size=4",
+            "--no-ir-cache",
+            &path,
+        ]);
+        assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+        assert_eq!(output.stdout, [1], "{}", engine);
+    }
+}
+
+#[test]
+fn test_preloaded_tape_file() {
+    // with 255 in cell 0 the loop is skipped at every level, by either engine
+    let path = source("preloaded.bf", "+[>+.<-]");
+    let tape =
+        std::env::temp_dir().join(format!("bfjit-cli-{}-preloaded.tape", std::process::id()));
+    let tape = tape.to_str().unwrap();
+    for level in ["-O0", "-O1", "-O2"] {
+        for engine in ["--interp", "--jit"] {
+            fs::write(tape, [255, 0, 0, 0]).unwrap();
+            let spec = format!("--tape-file={}", tape);
+            let output = bfjit(&[engine, level, "--no-ir-cache", &spec, &path]);
+            assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+            assert!(output.stdout.is_empty(), "{} {}", engine, level);
+            assert_eq!(fs::read(tape).unwrap(), [0, 0, 0, 0]);
+        }
+    }
+    let _ = fs::remove_file(tape);
+}
+
+#[test]
+fn test_io_width() {
+    // a wide cell goes out as its whole group of bytes, in the order asked
+    let path = source("wide.bf", format!("{}.", "+".repeat(0x0102)));
+    for (io, out) in [("--io-width=le", [2, 1]), ("--io-width=be", [1, 2])] {
+        let output = bfjit(&["--cell-width=16", io, &path]);
+        assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+        assert_eq!(output.stdout, out);
+    }
+
+    // on byte cells, or with the UTF-8 output modes, it is refused
+    for flags in [
+        &["--io-width=le"][..],
+        &["--cell-width=8", "--io-width=be"],
+        &["--cells=big", "--io-width=le"],
+        &["--cell-width=16", "--io-width=le", "--output-utf8=strict"],
+        &["--cell-width=32", "--console-unicode"],
+    ] {
+        let output = bfjit(&[flags, &[path.as_str()]].concat());
+        assert_eq!(output.status.code(), Some(1), "{:?}", flags);
+        assert!(output.stdout.is_empty(), "{:?}", flags);
+    }
+}
+
+#[test]
+fn test_wide_dump_tape() {
+    // wide cells dump as little-endian groups, after a failed run as well
+    let path = source("wide-dump.bf", format!("{}>-<<", "+".repeat(0x0102)));
+    let output = bfjit(&["--cell-width=16", "--dump-tape=4", &path]);
+    assert_eq!(output.status.code(), Some(3), "{}", stderr(&output));
+    let text = stderr(&output);
+    assert!(
+        text.starts_with("00000000: 0201>ffff 0000 0000"),
+        "{}",
+        text
+    );
+
+    let output = bfjit(&["--cells=big", "--dump-tape", &path]);
+    assert_eq!(output.status.code(), Some(1));
+}